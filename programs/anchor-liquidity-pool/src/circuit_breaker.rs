@@ -0,0 +1,188 @@
+//! Circuit breaker of a pair, rejecting the swaps that move the spot price
+//! too far, either at once or cumulatively within a window of slots.
+
+use crate::error::Error;
+use crate::fees::{price_move, spot_price};
+use anchor_lang::prelude::*;
+
+/// Limits of the circuit breaker, in basis points of the spot price. A zero
+/// limit is not enforced.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct CircuitBreakerParameters {
+    /// Maximum price move of a single swap
+    pub max_price_impact_bps: u16,
+    /// Maximum price move of the swaps of a window, measured against the
+    /// reserves at its start
+    pub max_window_move_bps: u16,
+    /// Length of the window in slots
+    pub window_slots: u16,
+}
+
+impl CircuitBreakerParameters {
+    pub const LEN: usize = 2 * 3;
+
+    /// Validate that the window limit has a window to apply to
+    pub fn validate(&self) -> Result<()> {
+        if self.max_window_move_bps > 0 && self.window_slots == 0 {
+            return Err(Error::InvalidCircuitBreaker.into());
+        }
+        Ok(())
+    }
+}
+
+/// Window of the circuit breaker, tracked across swaps
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct CircuitBreakerState {
+    /// Slot at which the current window started
+    pub window_start_slot: u64,
+    /// Spot price of the reserves at the start of the window, see
+    /// `spot_price`, zero until the first swap
+    pub window_price: u128,
+}
+
+impl CircuitBreakerState {
+    pub const LEN: usize = 8 + 16;
+
+    /// Checks the price move of a swap from the amounts of token A and B
+    /// before it to those after it. A window elapsed by `slot` is restarted
+    /// from the amounts before the swap.
+    pub fn check(
+        &mut self,
+        parameters: &CircuitBreakerParameters,
+        slot: u64,
+        amounts_before: (u128, u128),
+        amounts_after: (u128, u128),
+    ) -> Result<()> {
+        let price_before = spot_price(amounts_before.0, amounts_before.1)?;
+        let price_after = spot_price(amounts_after.0, amounts_after.1)?;
+        if parameters.max_price_impact_bps > 0 {
+            let price_impact = price_move(price_after, price_before)?;
+            msg!("price_impact={}", price_impact);
+            if price_impact > u128::from(parameters.max_price_impact_bps) {
+                return Err(Error::CircuitBreakerTripped.into());
+            }
+        }
+
+        let window_end = self
+            .window_start_slot
+            .saturating_add(u64::from(parameters.window_slots));
+        if self.window_price == 0 || slot >= window_end {
+            self.window_start_slot = slot;
+            self.window_price = price_before;
+        }
+        if parameters.max_window_move_bps > 0 {
+            let window_move = price_move(price_after, self.window_price)?;
+            msg!("window_move={}", window_move);
+            if window_move > u128::from(parameters.max_window_move_bps) {
+                return Err(Error::CircuitBreakerTripped.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error<T: std::fmt::Debug>(result: Result<T>) -> ProgramError {
+        result.unwrap_err().into()
+    }
+
+    fn parameters(
+        max_price_impact_bps: u16,
+        max_window_move_bps: u16,
+        window_slots: u16,
+    ) -> CircuitBreakerParameters {
+        CircuitBreakerParameters {
+            max_price_impact_bps,
+            max_window_move_bps,
+            window_slots,
+        }
+    }
+
+    #[test]
+    fn window_limit_needs_a_window() {
+        assert_eq!(
+            error(parameters(0, 100, 0).validate()),
+            Error::InvalidCircuitBreaker.into()
+        );
+        parameters(0, 100, 1).validate().unwrap();
+        parameters(100, 0, 0).validate().unwrap();
+    }
+
+    #[test]
+    fn zero_limits_are_not_enforced() {
+        let mut state = CircuitBreakerState::default();
+        state
+            .check(
+                &CircuitBreakerParameters::default(),
+                1,
+                (10_000, 10_000),
+                (5_000, 20_000),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn price_impact_of_a_single_swap() {
+        let parameters = parameters(100, 0, 0);
+        let mut state = CircuitBreakerState::default();
+        // a move of about 60 bps, then one of about 120 bps
+        state
+            .check(&parameters, 1, (10_000, 10_000), (10_000, 10_060))
+            .unwrap();
+        assert_eq!(
+            error(state.check(&parameters, 2, (10_000, 10_000), (10_000, 10_120))),
+            Error::CircuitBreakerTripped.into()
+        );
+        // the moves down are limited as well
+        assert_eq!(
+            error(state.check(&parameters, 3, (10_000, 10_000), (10_000, 9_880))),
+            Error::CircuitBreakerTripped.into()
+        );
+    }
+
+    #[test]
+    fn window_move_is_cumulative_until_the_window_restarts() {
+        let parameters = parameters(0, 100, 10);
+        let mut state = CircuitBreakerState::default();
+        // the first swap starts the window at the price before it
+        state
+            .check(&parameters, 5, (10_000, 10_000), (10_000, 10_060))
+            .unwrap();
+        assert_eq!(state.window_start_slot, 5);
+        assert_eq!(state.window_price, spot_price(10_000, 10_000).unwrap());
+
+        // a second move of 60 bps adds up to 120 bps from the window price
+        assert_eq!(
+            error(state.check(&parameters, 14, (10_000, 10_060), (10_000, 10_120))),
+            Error::CircuitBreakerTripped.into()
+        );
+        // moving back towards the window price is measured from it as well
+        state
+            .check(&parameters, 14, (10_000, 10_060), (10_000, 9_950))
+            .unwrap();
+        assert_eq!(state.window_start_slot, 5);
+
+        // once the window elapses, it restarts from the price before the swap
+        state
+            .check(&parameters, 15, (10_000, 10_060), (10_000, 10_120))
+            .unwrap();
+        assert_eq!(state.window_start_slot, 15);
+        assert_eq!(state.window_price, spot_price(10_000, 10_060).unwrap());
+        assert_eq!(
+            error(state.check(&parameters, 16, (10_000, 10_120), (10_000, 10_180))),
+            Error::CircuitBreakerTripped.into()
+        );
+    }
+
+    #[test]
+    fn empty_pool_fails() {
+        let mut state = CircuitBreakerState::default();
+        assert_eq!(
+            error(state.check(&parameters(100, 0, 0), 1, (0, 10_000), (10_000, 10_000))),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+}
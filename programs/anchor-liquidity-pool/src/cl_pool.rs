@@ -0,0 +1,1219 @@
+//! Concentrated liquidity pools, whose positions provide liquidity within a
+//! range of ticks, see `concentrated` for the math.
+
+use crate::concentrated::{
+    MAX_SQRT_PRICE, MAX_TICK, MAX_TICK_SPACING, MIN_SQRT_PRICE, MIN_TICK, TICK_ARRAY_SIZE,
+};
+use crate::error::Error;
+use crate::fees::{Fees, FIXED_CONSTRAINTS};
+use crate::token_interface::{is_held_by, is_token_program, mint, token_account, TransferChecked};
+use crate::{admin_pubkey, concentrated, pool_signer_seeds, to_u128, to_u64, token_interface};
+use anchor_lang::prelude::*;
+
+pub fn initialize(
+    ctx: Context<InitializeClPool>,
+    fees: Fees,
+    tick_spacing: u16,
+    sqrt_price: u128,
+) -> Result<()> {
+    msg!(
+        "Instruction CL Pool Init {:?},{},{}",
+        fees,
+        tick_spacing,
+        sqrt_price
+    );
+    FIXED_CONSTRAINTS.validate_fees(&fees)?;
+    fees.validate()?;
+    if tick_spacing == 0 || tick_spacing > MAX_TICK_SPACING {
+        return Err(Error::InvalidTickSpacing.into());
+    }
+    let tick_current = concentrated::tick_at_sqrt_price(sqrt_price)?;
+
+    let cl_pool = &mut ctx.accounts.cl_pool;
+    cl_pool.token_a_mint = ctx.accounts.token_a_mint.key();
+    cl_pool.token_b_mint = ctx.accounts.token_b_mint.key();
+    cl_pool.token_a_account = ctx.accounts.token_a_for_pda.key();
+    cl_pool.token_b_account = ctx.accounts.token_b_for_pda.key();
+    cl_pool.token_program = ctx.accounts.token_program.key();
+    cl_pool.fees = fees;
+    cl_pool.tick_spacing = tick_spacing;
+    cl_pool.sqrt_price = sqrt_price;
+    cl_pool.tick_current = tick_current;
+    cl_pool.liquidity = 0;
+    cl_pool.fee_growth_global_a = 0;
+    cl_pool.fee_growth_global_b = 0;
+    cl_pool.protocol_fee_token_a_amount = 0;
+    cl_pool.protocol_fee_token_b_amount = 0;
+    cl_pool.bump = *ctx
+        .bumps
+        .get("cl_pool")
+        .ok_or(Error::InvalidProgramAddress)?;
+    Ok(())
+}
+
+pub fn initialize_tick_array(
+    ctx: Context<InitializeTickArray>,
+    start_tick_index: i32,
+) -> Result<()> {
+    msg!("Instruction Tick Array Init {}", start_tick_index);
+    if !ctx
+        .accounts
+        .cl_pool
+        .is_valid_tick_array_start(start_tick_index)
+    {
+        return Err(Error::InvalidTickArray.into());
+    }
+
+    let mut tick_array = ctx.accounts.tick_array.load_init()?;
+    tick_array.cl_pool = ctx.accounts.cl_pool.key();
+    tick_array.start_tick_index = start_tick_index;
+    Ok(())
+}
+
+pub fn open_position(ctx: Context<OpenPosition>, tick_lower: i32, tick_upper: i32) -> Result<()> {
+    msg!("Instruction Open Position {},{}", tick_lower, tick_upper);
+    if !ctx
+        .accounts
+        .cl_pool
+        .is_valid_tick_range(tick_lower, tick_upper)
+    {
+        return Err(Error::InvalidTickRange.into());
+    }
+
+    let position = &mut ctx.accounts.position;
+    position.cl_pool = ctx.accounts.cl_pool.key();
+    position.owner = ctx.accounts.owner.key();
+    position.tick_lower = tick_lower;
+    position.tick_upper = tick_upper;
+    position.liquidity = 0;
+    position.fee_growth_inside_a_last = 0;
+    position.fee_growth_inside_b_last = 0;
+    position.tokens_owed_a = 0;
+    position.tokens_owed_b = 0;
+    position.bump = *ctx
+        .bumps
+        .get("position")
+        .ok_or(Error::InvalidProgramAddress)?;
+    Ok(())
+}
+
+pub fn increase_liquidity(
+    ctx: Context<ModifyPosition>,
+    liquidity: u128,
+    maximum_token_a_amount: u64,
+    maximum_token_b_amount: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Increase Liquidity {},{},{}",
+        liquidity,
+        maximum_token_a_amount,
+        maximum_token_b_amount
+    );
+    let liquidity_delta = i128::try_from(liquidity).map_err(|_| Error::ConversionFailure)?;
+    if liquidity_delta == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+    let (token_a_amount, token_b_amount) = ctx.accounts.modify_liquidity(liquidity_delta)?;
+    msg!(
+        "token_a_amount={},token_b_amount={}",
+        token_a_amount,
+        token_b_amount
+    );
+    if token_a_amount == 0 && token_b_amount == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+
+    // the pool receives the amounts after the transfer fee
+    let token_a_transfer_amount = token_interface::amount_before_transfer_fee(
+        &ctx.accounts.token_a_mint,
+        to_u64(token_a_amount)?,
+    )?;
+    let token_b_transfer_amount = token_interface::amount_before_transfer_fee(
+        &ctx.accounts.token_b_mint,
+        to_u64(token_b_amount)?,
+    )?;
+    if token_a_transfer_amount > maximum_token_a_amount
+        || token_b_transfer_amount > maximum_token_b_amount
+    {
+        return Err(Error::ExceededSlippage.into());
+    }
+    if token_a_transfer_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_a_in_context(),
+            token_a_transfer_amount,
+        )?;
+    }
+    if token_b_transfer_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_b_in_context(),
+            token_b_transfer_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn decrease_liquidity(
+    ctx: Context<ModifyPosition>,
+    liquidity: u128,
+    minimum_token_a_amount: u64,
+    minimum_token_b_amount: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Decrease Liquidity {},{},{}",
+        liquidity,
+        minimum_token_a_amount,
+        minimum_token_b_amount
+    );
+    let liquidity_delta = i128::try_from(liquidity)
+        .ok()
+        .and_then(i128::checked_neg)
+        .ok_or(Error::ConversionFailure)?;
+    if liquidity_delta == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+    let (token_a_amount, token_b_amount) = ctx.accounts.modify_liquidity(liquidity_delta)?;
+    let token_a_amount = to_u64(token_a_amount)?;
+    let token_b_amount = to_u64(token_b_amount)?;
+    msg!(
+        "token_a_amount={},token_b_amount={}",
+        token_a_amount,
+        token_b_amount
+    );
+
+    // the minimum amounts are what the owner receives after the transfer fee
+    let token_a_received = token_a_amount
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_a_mint,
+            token_a_amount,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let token_b_received = token_b_amount
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_b_mint,
+            token_b_amount,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    if token_a_received < minimum_token_a_amount || token_b_received < minimum_token_b_amount {
+        return Err(Error::ExceededSlippage.into());
+    }
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.cl_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if token_a_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_a_out_context()
+                .with_signer(signer_seeds),
+            token_a_amount,
+        )?;
+    }
+    if token_b_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_b_out_context()
+                .with_signer(signer_seeds),
+            token_b_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn collect_fees(ctx: Context<ModifyPosition>) -> Result<()> {
+    msg!("Instruction Collect Fees {}", ctx.accounts.position.key());
+    // settle the fees earned since the last update of the position
+    if ctx.accounts.position.liquidity > 0 {
+        ctx.accounts.modify_liquidity(0)?;
+    }
+    let position = &mut ctx.accounts.position;
+    let token_a_amount = position.tokens_owed_a;
+    let token_b_amount = position.tokens_owed_b;
+    msg!(
+        "token_a_amount={},token_b_amount={}",
+        token_a_amount,
+        token_b_amount
+    );
+    position.tokens_owed_a = 0;
+    position.tokens_owed_b = 0;
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.cl_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if token_a_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_a_out_context()
+                .with_signer(signer_seeds),
+            token_a_amount,
+        )?;
+    }
+    if token_b_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_b_out_context()
+                .with_signer(signer_seeds),
+            token_b_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+    msg!("Instruction Close Position {}", ctx.accounts.position.key());
+    let position = &ctx.accounts.position;
+    if position.liquidity > 0 || position.tokens_owed_a > 0 || position.tokens_owed_b > 0 {
+        return Err(Error::PositionNotEmpty.into());
+    }
+    Ok(())
+}
+
+pub fn swap<'info>(
+    ctx: Context<'_, '_, '_, 'info, ClSwap<'info>>,
+    amount_in: u64,
+    minimum_amount_out: u64,
+    sqrt_price_limit: u128,
+) -> Result<()> {
+    msg!(
+        "Instruction CL Swap {},{},{}",
+        amount_in,
+        minimum_amount_out,
+        sqrt_price_limit
+    );
+
+    let a_to_b = ctx.accounts.token_source_for_pda.key() == ctx.accounts.cl_pool.token_a_account;
+    // only the amount left after the transfer fee of the source mint reaches the pool
+    let amount_in_received = amount_in
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_source_mint,
+            amount_in,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let cl_pool_key = ctx.accounts.cl_pool.key();
+    let (source_amount_swapped, destination_amount_swapped) = ctx.accounts.cl_pool.swap(
+        &cl_pool_key,
+        ctx.remaining_accounts,
+        to_u128(amount_in_received)?,
+        a_to_b,
+        sqrt_price_limit,
+    )?;
+    msg!(
+        "source_amount_swapped={},destination_amount_swapped={}",
+        source_amount_swapped,
+        destination_amount_swapped
+    );
+    if destination_amount_swapped == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+
+    // the minimum is what the swapper receives after the transfer fee of
+    // the destination mint, while the pool sends the full amount
+    let destination_amount_sent = to_u64(destination_amount_swapped)?;
+    let destination_amount_received = destination_amount_sent
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_destination_mint,
+            destination_amount_sent,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    if destination_amount_received < minimum_amount_out {
+        return Err(Error::ExceededSlippage.into());
+    }
+
+    // the swap stops early at the price limit or at the end of the tick
+    // arrays, in which case only the part of the input swapped is paid
+    token_interface::transfer_checked(
+        ctx.accounts.to_transfer_source_context(),
+        token_interface::amount_before_transfer_fee(
+            &ctx.accounts.token_source_mint,
+            to_u64(source_amount_swapped)?,
+        )?,
+    )?;
+    let signer_seeds = pool_signer_seeds(&cl_pool_key, &ctx.accounts.pda, ctx.program_id)?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::transfer_checked(
+        ctx.accounts
+            .to_transfer_destination_context()
+            .with_signer(signer_seeds),
+        destination_amount_sent,
+    )?;
+
+    Ok(())
+}
+
+pub fn collect_protocol_fees(ctx: Context<CollectClProtocolFees>) -> Result<()> {
+    let token_a_amount = ctx.accounts.cl_pool.protocol_fee_token_a_amount;
+    let token_b_amount = ctx.accounts.cl_pool.protocol_fee_token_b_amount;
+    msg!(
+        "Instruction Collect CL Protocol Fees {},{}",
+        token_a_amount,
+        token_b_amount
+    );
+
+    ctx.accounts.cl_pool.protocol_fee_token_a_amount = 0;
+    ctx.accounts.cl_pool.protocol_fee_token_b_amount = 0;
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.cl_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if token_a_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_a_context()
+                .with_signer(signer_seeds),
+            token_a_amount,
+        )?;
+    }
+    if token_b_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_b_context()
+                .with_signer(signer_seeds),
+            token_b_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(fees: Fees, tick_spacing: u16)]
+pub struct InitializeClPool<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = mint(&token_a_mint, token_program.key)?.is_initialized
+    )]
+    pub token_a_mint: UncheckedAccount<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = mint(&token_b_mint, token_program.key)?.is_initialized,
+        constraint = token_b_mint.key() != token_a_mint.key() @ Error::RepeatedMint,
+        constraint = token_a_mint.key() < token_b_mint.key() @ Error::InvalidMintOrder
+    )]
+    pub token_b_mint: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + ClPool::LEN,
+        seeds = [
+            b"cl_pool".as_ref(),
+            token_a_mint.key().as_ref(),
+            token_b_mint.key().as_ref(),
+            tick_spacing.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub cl_pool: Box<Account<'info, ClPool>>,
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(seeds = [b"pool".as_ref(), cl_pool.key().as_ref()], bump)]
+    pub pda: AccountInfo<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_a_for_pda, token_program.key)?.mint == token_a_mint.key(),
+        constraint = is_held_by(&token_account(&token_a_for_pda, token_program.key)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_b_for_pda, token_program.key)?.mint == token_b_mint.key(),
+        constraint = is_held_by(&token_account(&token_b_for_pda, token_program.key)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+    /// CHECK: SPL Token or Token-2022, checked against the allowlist
+    #[account(
+        constraint = is_token_program(token_program.key) @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(start_tick_index: i32)]
+pub struct InitializeTickArray<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub cl_pool: Box<Account<'info, ClPool>>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + TickArray::LEN,
+        seeds = [
+            b"tick_array".as_ref(),
+            cl_pool.key().as_ref(),
+            start_tick_index.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub tick_array: AccountLoader<'info, TickArray>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(tick_lower: i32, tick_upper: i32)]
+pub struct OpenPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub cl_pool: Box<Account<'info, ClPool>>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Position::LEN,
+        seeds = [
+            b"position".as_ref(),
+            cl_pool.key().as_ref(),
+            owner.key().as_ref(),
+            tick_lower.to_le_bytes().as_ref(),
+            tick_upper.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ModifyPosition<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub owner: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = cl_pool.token_a_account == token_a_for_pda.key(),
+        constraint = cl_pool.token_b_account == token_b_for_pda.key(),
+        constraint = cl_pool.token_a_mint == token_a_mint.key(),
+        constraint = cl_pool.token_b_mint == token_b_mint.key(),
+    )]
+    pub cl_pool: Box<Account<'info, ClPool>>,
+
+    #[account(
+        mut,
+        constraint = position.cl_pool == cl_pool.key(),
+        constraint = position.owner == owner.key(),
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        mut,
+        constraint = tick_array_lower.load()?.cl_pool == cl_pool.key() @ Error::InvalidTickArray
+    )]
+    pub tick_array_lower: AccountLoader<'info, TickArray>,
+
+    #[account(
+        mut,
+        constraint = tick_array_upper.load()?.cl_pool == cl_pool.key() @ Error::InvalidTickArray
+    )]
+    pub tick_array_upper: AccountLoader<'info, TickArray>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pool
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pool
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_owner, &cl_pool.token_program)?.mint == token_a_mint.key(),
+        constraint = is_held_by(&token_account(&token_a_for_owner, &cl_pool.token_program)?, &owner.key())
+    )]
+    pub token_a_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_owner, &cl_pool.token_program)?.mint == token_b_mint.key(),
+        constraint = is_held_by(&token_account(&token_b_for_owner, &cl_pool.token_program)?, &owner.key())
+    )]
+    pub token_b_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pool
+    #[account(mut)]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pool
+    #[account(mut)]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pool
+    #[account(
+        constraint = token_program.key() == cl_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        close = owner,
+        constraint = position.owner == owner.key(),
+    )]
+    pub position: Box<Account<'info, Position>>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount_in: u64, minimum_amount_out: u64, sqrt_price_limit: u128)]
+pub struct ClSwap<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub swapper: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = cl_pool.token_a_account == token_source_for_pda.key() || cl_pool.token_a_account == token_destination_for_pda.key(),
+        constraint = cl_pool.token_b_account == token_source_for_pda.key() || cl_pool.token_b_account == token_destination_for_pda.key(),
+        constraint = cl_pool.token_a_mint == token_source_mint.key() || cl_pool.token_a_mint == token_destination_mint.key(),
+        constraint = cl_pool.token_b_mint == token_source_mint.key() || cl_pool.token_b_mint == token_destination_mint.key(),
+    )]
+    pub cl_pool: Box<Account<'info, ClPool>>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pool
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pool
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_swapper, &cl_pool.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_swapper, &cl_pool.token_program)?, &swapper.key())
+    )]
+    pub token_source_for_swapper: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_swapper, &cl_pool.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_swapper, &cl_pool.token_program)?, &swapper.key())
+    )]
+    pub token_destination_for_swapper: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_pda, &cl_pool.token_program)?.mint == token_source_mint.key(),
+    )]
+    pub token_source_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_pda, &cl_pool.token_program)?.mint == token_destination_mint.key(),
+    )]
+    pub token_destination_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pool
+    #[account(
+        constraint = token_program.key() == cl_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectClProtocolFees<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = cl_pool.token_a_account == token_a_for_pda.key(),
+        constraint = cl_pool.token_b_account == token_b_for_pda.key(),
+        constraint = cl_pool.token_a_mint == token_a_mint.key(),
+        constraint = cl_pool.token_b_mint == token_b_mint.key(),
+    )]
+    pub cl_pool: Box<Account<'info, ClPool>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pool
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pool
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pool
+    #[account(mut)]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pool
+    #[account(mut)]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_admin, &cl_pool.token_program)?.owner == admin.key(),
+    )]
+    pub token_a_for_admin: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_admin, &cl_pool.token_program)?.owner == admin.key(),
+    )]
+    pub token_b_for_admin: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pool
+    #[account(
+        constraint = token_program.key() == cl_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> ModifyPosition<'info> {
+    /// Adds the liquidity delta to the position, to its ticks and to the pool
+    /// when its range holds the current tick, after settling the fees earned
+    /// by the position, and returns the amounts of token A and B it is worth.
+    fn modify_liquidity(&mut self, liquidity_delta: i128) -> Result<(u128, u128)> {
+        let cl_pool = &mut self.cl_pool;
+        let position = &mut self.position;
+        let (tick_lower, tick_upper) = (position.tick_lower, position.tick_upper);
+        // both ticks may be in the same tick array, which can only be borrowed once
+        let (fee_growth_outside_lower, fee_growth_outside_upper) =
+            if self.tick_array_lower.key() == self.tick_array_upper.key() {
+                let mut tick_array = self.tick_array_lower.load_mut()?;
+                (
+                    tick_array
+                        .tick_mut(tick_lower, cl_pool.tick_spacing)?
+                        .update(tick_lower, liquidity_delta, false, cl_pool)?,
+                    tick_array
+                        .tick_mut(tick_upper, cl_pool.tick_spacing)?
+                        .update(tick_upper, liquidity_delta, true, cl_pool)?,
+                )
+            } else {
+                let mut tick_array_lower = self.tick_array_lower.load_mut()?;
+                let mut tick_array_upper = self.tick_array_upper.load_mut()?;
+                (
+                    tick_array_lower
+                        .tick_mut(tick_lower, cl_pool.tick_spacing)?
+                        .update(tick_lower, liquidity_delta, false, cl_pool)?,
+                    tick_array_upper
+                        .tick_mut(tick_upper, cl_pool.tick_spacing)?
+                        .update(tick_upper, liquidity_delta, true, cl_pool)?,
+                )
+            };
+
+        let fee_growth_inside_a = concentrated::fee_growth_inside(
+            cl_pool.tick_current,
+            tick_lower,
+            fee_growth_outside_lower.0,
+            tick_upper,
+            fee_growth_outside_upper.0,
+            cl_pool.fee_growth_global_a,
+        );
+        let fee_growth_inside_b = concentrated::fee_growth_inside(
+            cl_pool.tick_current,
+            tick_lower,
+            fee_growth_outside_lower.1,
+            tick_upper,
+            fee_growth_outside_upper.1,
+            cl_pool.fee_growth_global_b,
+        );
+        position.update(liquidity_delta, fee_growth_inside_a, fee_growth_inside_b)?;
+        if (tick_lower..tick_upper).contains(&cl_pool.tick_current) {
+            cl_pool.liquidity = add_liquidity_delta(cl_pool.liquidity, liquidity_delta)?;
+        }
+
+        // round up the amounts deposited and down the amounts withdrawn
+        concentrated::amounts_for_liquidity(
+            cl_pool.sqrt_price,
+            tick_lower,
+            tick_upper,
+            liquidity_delta.unsigned_abs(),
+            liquidity_delta > 0,
+        )
+    }
+
+    fn to_transfer_a_in_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_owner.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_pda.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_in_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_owner.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_pda.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_a_out_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_pda.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_owner.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_out_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_pda.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_owner.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> ClSwap<'info> {
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_swapper.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_pda.to_account_info().clone(),
+            authority: self.swapper.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_destination_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_for_pda.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self.token_destination_for_swapper.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> CollectClProtocolFees<'info> {
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_pda.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_admin.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_pda.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_admin.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// A concentrated liquidity pool of two tokens, created at the PDA derived
+/// from `["cl_pool", token_a_mint, token_b_mint, tick_spacing]`. Its token
+/// accounts are held by the PDA `["pool", cl_pool]`, and its liquidity is
+/// provided by positions over tick ranges instead of a pool mint.
+#[account]
+pub struct ClPool {
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub token_a_account: Pubkey,
+    pub token_b_account: Pubkey,
+    /// SPL Token or Token-2022, owning the mints and token accounts of the pool
+    pub token_program: Pubkey,
+    /// The trade fee goes to the positions in range and the owner trade fee
+    /// to the protocol, the withdraw and host fees are not charged
+    pub fees: Fees,
+    /// Positions start and end at multiples of the tick spacing
+    pub tick_spacing: u16,
+    /// Square root of the price of token A in token B, in Q64.64
+    pub sqrt_price: u128,
+    /// Greatest tick whose square root price is at most `sqrt_price`
+    pub tick_current: i32,
+    /// Liquidity of the positions whose range holds the current tick
+    pub liquidity: u128,
+    /// Trade fees of token A earned per unit of liquidity, in Q64.64
+    pub fee_growth_global_a: u128,
+    /// Trade fees of token B earned per unit of liquidity, in Q64.64
+    pub fee_growth_global_b: u128,
+    /// Owner trade fees held in the token accounts until collected
+    pub protocol_fee_token_a_amount: u64,
+    pub protocol_fee_token_b_amount: u64,
+    pub bump: u8,
+}
+
+impl ClPool {
+    pub const LEN: usize = 32 * 5 + Fees::LEN + 2 + 16 + 4 + 16 + 16 * 2 + 8 * 2 + 1;
+
+    /// Range of ticks covered by a tick array
+    fn tick_array_span(&self) -> i32 {
+        i32::from(self.tick_spacing) * TICK_ARRAY_SIZE
+    }
+
+    fn is_valid_tick_array_start(&self, start_tick_index: i32) -> bool {
+        let span = self.tick_array_span();
+        start_tick_index % span == 0
+            && start_tick_index <= MAX_TICK
+            && start_tick_index + span > MIN_TICK
+    }
+
+    fn is_valid_tick_range(&self, tick_lower: i32, tick_upper: i32) -> bool {
+        let tick_spacing = i32::from(self.tick_spacing);
+        tick_lower < tick_upper
+            && tick_lower >= MIN_TICK
+            && tick_upper <= MAX_TICK
+            && tick_lower % tick_spacing == 0
+            && tick_upper % tick_spacing == 0
+    }
+
+    /// Swaps `amount` of input through the tick arrays, given in the direction
+    /// of the swap from the one holding the current tick, crossing their
+    /// initialized ticks. Stops at the price limit, zero for none, or at the
+    /// end of the last tick array, and returns the input and output amounts.
+    fn swap(
+        &mut self,
+        cl_pool: &Pubkey,
+        tick_arrays: &[AccountInfo],
+        amount: u128,
+        a_to_b: bool,
+        sqrt_price_limit: u128,
+    ) -> Result<(u128, u128)> {
+        let sqrt_price_limit = match sqrt_price_limit {
+            0 if a_to_b => MIN_SQRT_PRICE,
+            0 => MAX_SQRT_PRICE,
+            sqrt_price_limit => sqrt_price_limit,
+        };
+        let is_valid_limit = if a_to_b {
+            (MIN_SQRT_PRICE..self.sqrt_price).contains(&sqrt_price_limit)
+        } else {
+            sqrt_price_limit > self.sqrt_price && sqrt_price_limit <= MAX_SQRT_PRICE
+        };
+        if !is_valid_limit {
+            return Err(Error::InvalidSqrtPrice.into());
+        }
+
+        // when selling token B, the next tick is above the current one, which
+        // may be the last tick below the start of a tick array
+        let span = self.tick_array_span();
+        let tick = if a_to_b {
+            self.tick_current
+        } else {
+            self.tick_current + i32::from(self.tick_spacing)
+        };
+        let mut start_tick_index = tick.div_euclid(span) * span;
+        let tick_arrays = tick_arrays
+            .iter()
+            .map(|info| {
+                let tick_array = AccountLoader::<TickArray>::try_from(info)?;
+                {
+                    let tick_array = tick_array.load()?;
+                    if !info.is_writable
+                        || tick_array.cl_pool != *cl_pool
+                        || tick_array.start_tick_index != start_tick_index
+                    {
+                        return Err(Error::InvalidTickArray.into());
+                    }
+                }
+                start_tick_index += if a_to_b { -span } else { span };
+                Ok(tick_array)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if tick_arrays.is_empty() {
+            return Err(Error::InvalidTickArray.into());
+        }
+
+        let (trade_fee_rate, owner_fee_rate) = concentrated::fee_rates(&self.fees)?;
+        let fee_rate = trade_fee_rate
+            .checked_add(owner_fee_rate)
+            .ok_or(Error::FeeCalculationFailure)?;
+        let mut amount_remaining = amount;
+        let mut amount_out: u128 = 0;
+        let mut array_index = 0;
+        while amount_remaining > 0 && self.sqrt_price != sqrt_price_limit {
+            let mut tick_array = tick_arrays[array_index].load_mut()?;
+            let (tick_next, initialized) = match tick_array.next_initialized_tick(
+                self.tick_current,
+                self.tick_spacing,
+                a_to_b,
+            ) {
+                Some(tick_next) => (tick_next, true),
+                None if array_index + 1 < tick_arrays.len() => {
+                    // the liquidity is constant up to the next tick array
+                    array_index += 1;
+                    continue;
+                }
+                None => {
+                    let tick_end = if a_to_b {
+                        tick_array.start_tick_index
+                    } else {
+                        tick_array.start_tick_index + span
+                    };
+                    (tick_end.clamp(MIN_TICK, MAX_TICK), false)
+                }
+            };
+            let sqrt_price_next_tick = concentrated::sqrt_price_at_tick(tick_next)?;
+            if !initialized && sqrt_price_next_tick == self.sqrt_price {
+                // at the end of the last tick array
+                break;
+            }
+            let sqrt_price_target = if a_to_b {
+                std::cmp::max(sqrt_price_next_tick, sqrt_price_limit)
+            } else {
+                std::cmp::min(sqrt_price_next_tick, sqrt_price_limit)
+            };
+
+            let step = concentrated::compute_swap_step(
+                self.sqrt_price,
+                sqrt_price_target,
+                self.liquidity,
+                amount_remaining,
+                fee_rate,
+            )?;
+            amount_remaining = amount_remaining
+                .checked_sub(step.amount_in)
+                .and_then(|amount| amount.checked_sub(step.fee_amount))
+                .ok_or(Error::CalculationFailure)?;
+            amount_out = amount_out
+                .checked_add(step.amount_out)
+                .ok_or(Error::CalculationFailure)?;
+
+            let owner_fee =
+                concentrated::owner_fee(step.fee_amount, trade_fee_rate, owner_fee_rate)?;
+            let fee_growth = concentrated::fee_growth(step.fee_amount - owner_fee, self.liquidity)?;
+            if a_to_b {
+                self.protocol_fee_token_a_amount = self
+                    .protocol_fee_token_a_amount
+                    .checked_add(to_u64(owner_fee)?)
+                    .ok_or(Error::FeeCalculationFailure)?;
+                self.fee_growth_global_a = self.fee_growth_global_a.wrapping_add(fee_growth);
+            } else {
+                self.protocol_fee_token_b_amount = self
+                    .protocol_fee_token_b_amount
+                    .checked_add(to_u64(owner_fee)?)
+                    .ok_or(Error::FeeCalculationFailure)?;
+                self.fee_growth_global_b = self.fee_growth_global_b.wrapping_add(fee_growth);
+            }
+
+            if step.sqrt_price_next == sqrt_price_next_tick {
+                if initialized {
+                    let liquidity_net = tick_array
+                        .tick_mut(tick_next, self.tick_spacing)?
+                        .cross(self.fee_growth_global_a, self.fee_growth_global_b);
+                    // the liquidity net is added when crossing the tick upwards
+                    let liquidity_delta = if a_to_b {
+                        liquidity_net
+                            .checked_neg()
+                            .ok_or(Error::CalculationFailure)?
+                    } else {
+                        liquidity_net
+                    };
+                    self.liquidity = add_liquidity_delta(self.liquidity, liquidity_delta)?;
+                }
+                self.tick_current = if a_to_b { tick_next - 1 } else { tick_next };
+            } else if step.sqrt_price_next != self.sqrt_price {
+                self.tick_current = concentrated::tick_at_sqrt_price(step.sqrt_price_next)?;
+            }
+            self.sqrt_price = step.sqrt_price_next;
+        }
+
+        Ok((amount - amount_remaining, amount_out))
+    }
+}
+
+/// Ticks of a concentrated liquidity pool from `start_tick_index`, one every
+/// tick spacing, created at the PDA derived from
+/// `["tick_array", cl_pool, start_tick_index]`
+#[account(zero_copy)]
+pub struct TickArray {
+    pub ticks: [Tick; 64],
+    pub cl_pool: Pubkey,
+    /// Multiple of the tick spacing times the size of the array
+    pub start_tick_index: i32,
+    pub _padding: [u8; 12],
+}
+
+impl TickArray {
+    pub const LEN: usize = Tick::LEN * 64 + 32 + 4 + 12;
+
+    fn tick_mut(&mut self, tick_index: i32, tick_spacing: u16) -> Result<&mut Tick> {
+        let tick_spacing = i32::from(tick_spacing);
+        let offset = tick_index - self.start_tick_index;
+        if tick_index % tick_spacing != 0 || !(0..tick_spacing * TICK_ARRAY_SIZE).contains(&offset)
+        {
+            return Err(Error::InvalidTickArray.into());
+        }
+        Ok(&mut self.ticks[(offset / tick_spacing) as usize])
+    }
+
+    /// Get the next initialized tick of the array in the direction of the
+    /// swap, at or below the current tick when selling token A and above it
+    /// when selling token B
+    fn next_initialized_tick(
+        &self,
+        tick_current: i32,
+        tick_spacing: u16,
+        a_to_b: bool,
+    ) -> Option<i32> {
+        let tick_spacing = i32::from(tick_spacing);
+        let offset = (tick_current - self.start_tick_index).div_euclid(tick_spacing);
+        let is_initialized = |offset: &i32| self.ticks[*offset as usize].is_initialized();
+        if a_to_b {
+            (0..std::cmp::min(offset + 1, TICK_ARRAY_SIZE))
+                .rev()
+                .find(is_initialized)
+        } else {
+            (std::cmp::max(offset + 1, 0)..TICK_ARRAY_SIZE).find(is_initialized)
+        }
+        .map(|offset| self.start_tick_index + offset * tick_spacing)
+    }
+}
+
+/// A tick at which the liquidity in range changes, initialized as long as a
+/// position starts or ends at it
+#[zero_copy]
+pub struct Tick {
+    /// Liquidity added when the price crosses the tick upwards
+    pub liquidity_net: i128,
+    /// Liquidity of the positions starting or ending at the tick
+    pub liquidity_gross: u128,
+    /// Fee growth of token A on the other side of the tick from the current
+    /// tick, in Q64.64
+    pub fee_growth_outside_a: u128,
+    /// Fee growth of token B on the other side of the tick from the current
+    /// tick, in Q64.64
+    pub fee_growth_outside_b: u128,
+}
+
+impl Tick {
+    pub const LEN: usize = 16 * 4;
+
+    fn is_initialized(&self) -> bool {
+        self.liquidity_gross > 0
+    }
+
+    /// Adds the liquidity delta of a position starting at the tick, or ending
+    /// at it when `upper`, and returns the fee growth outside of the tick
+    fn update(
+        &mut self,
+        tick_index: i32,
+        liquidity_delta: i128,
+        upper: bool,
+        cl_pool: &ClPool,
+    ) -> Result<(u128, u128)> {
+        if !self.is_initialized() && tick_index <= cl_pool.tick_current {
+            // by convention, all the fees were earned below the tick
+            self.fee_growth_outside_a = cl_pool.fee_growth_global_a;
+            self.fee_growth_outside_b = cl_pool.fee_growth_global_b;
+        }
+        self.liquidity_gross = add_liquidity_delta(self.liquidity_gross, liquidity_delta)?;
+        self.liquidity_net = if upper {
+            self.liquidity_net.checked_sub(liquidity_delta)
+        } else {
+            self.liquidity_net.checked_add(liquidity_delta)
+        }
+        .ok_or(Error::CalculationFailure)?;
+
+        let fee_growth_outside = (self.fee_growth_outside_a, self.fee_growth_outside_b);
+        if !self.is_initialized() {
+            self.liquidity_net = 0;
+            self.fee_growth_outside_a = 0;
+            self.fee_growth_outside_b = 0;
+        }
+        Ok(fee_growth_outside)
+    }
+
+    /// Flips the fee growth outside of the tick as the price crosses it, and
+    /// returns the liquidity net
+    fn cross(&mut self, fee_growth_global_a: u128, fee_growth_global_b: u128) -> i128 {
+        self.fee_growth_outside_a = fee_growth_global_a.wrapping_sub(self.fee_growth_outside_a);
+        self.fee_growth_outside_b = fee_growth_global_b.wrapping_sub(self.fee_growth_outside_b);
+        self.liquidity_net
+    }
+}
+
+/// Liquidity provided to a concentrated liquidity pool in
+/// `[tick_lower, tick_upper)`, created at the PDA derived from
+/// `["position", cl_pool, owner, tick_lower, tick_upper]`
+#[account]
+pub struct Position {
+    pub cl_pool: Pubkey,
+    pub owner: Pubkey,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    /// Fee growth inside the range at the last update of the position
+    pub fee_growth_inside_a_last: u128,
+    pub fee_growth_inside_b_last: u128,
+    /// Fees earned up to the last update of the position, not yet collected
+    pub tokens_owed_a: u64,
+    pub tokens_owed_b: u64,
+    pub bump: u8,
+}
+
+impl Position {
+    pub const LEN: usize = 32 * 2 + 4 * 2 + 16 * 3 + 8 * 2 + 1;
+
+    /// Settles the fees earned since the last update then adds the liquidity
+    /// delta
+    fn update(
+        &mut self,
+        liquidity_delta: i128,
+        fee_growth_inside_a: u128,
+        fee_growth_inside_b: u128,
+    ) -> Result<()> {
+        let fees_a = concentrated::fees_earned(
+            self.liquidity,
+            fee_growth_inside_a,
+            self.fee_growth_inside_a_last,
+        )?;
+        let fees_b = concentrated::fees_earned(
+            self.liquidity,
+            fee_growth_inside_b,
+            self.fee_growth_inside_b_last,
+        )?;
+        self.tokens_owed_a = self
+            .tokens_owed_a
+            .checked_add(to_u64(fees_a)?)
+            .ok_or(Error::FeeCalculationFailure)?;
+        self.tokens_owed_b = self
+            .tokens_owed_b
+            .checked_add(to_u64(fees_b)?)
+            .ok_or(Error::FeeCalculationFailure)?;
+        self.fee_growth_inside_a_last = fee_growth_inside_a;
+        self.fee_growth_inside_b_last = fee_growth_inside_b;
+        self.liquidity = add_liquidity_delta(self.liquidity, liquidity_delta)?;
+        Ok(())
+    }
+}
+
+fn add_liquidity_delta(liquidity: u128, liquidity_delta: i128) -> Result<u128> {
+    if liquidity_delta < 0 {
+        liquidity.checked_sub(liquidity_delta.unsigned_abs())
+    } else {
+        liquidity.checked_add(liquidity_delta.unsigned_abs())
+    }
+    .ok_or_else(|| Error::CalculationFailure.into())
+}
@@ -0,0 +1,549 @@
+//! Math of the concentrated liquidity pools.
+//!
+//! As in Uniswap v3, the price of token A in token B is `1.0001 ^ tick`, and
+//! prices are stored as their square root in Q64.64 fixed point. Liquidity
+//! `L` provided in `[tick_lower, tick_upper)` is worth
+//! `L * (1 / sqrt(p_lower) - 1 / sqrt(p_upper))` of token A below the range
+//! and `L * (sqrt(p_upper) - sqrt(p_lower))` of token B above it. Fee growth
+//! per unit of liquidity is also Q64.64 and wraps around like Uniswap's.
+
+use crate::error::Error;
+use crate::Fees;
+use anchor_lang::prelude::*;
+use spl_math::uint::U256;
+
+/// Lowest tick, whose square root price still fits in Q64.64
+pub const MIN_TICK: i32 = -443636;
+/// Highest tick, whose square root price still fits in Q64.64
+pub const MAX_TICK: i32 = 443636;
+/// `sqrt_price_at_tick(MIN_TICK)`
+pub const MIN_SQRT_PRICE: u128 = 4295048017;
+/// `sqrt_price_at_tick(MAX_TICK)`
+pub const MAX_SQRT_PRICE: u128 = 79226673515401279992447579062;
+/// Number of initializable ticks held by a tick array
+pub const TICK_ARRAY_SIZE: i32 = 64;
+/// Largest tick spacing a pool can be created with
+pub const MAX_TICK_SPACING: u16 = 16384;
+/// Fee rates are expressed in millionths
+pub const FEE_RATE_DENOMINATOR: u128 = 1_000_000;
+
+/// `2^128 / sqrt(1.0001) ^ (2 ^ i)` for the bit `i` of the absolute tick
+const TICK_RATIOS: [u128; 19] = [
+    0xfffcb933bd6fad37aa2d162d1a594001,
+    0xfff97272373d413259a46990580e2139,
+    0xfff2e50f5f656932ef12357cf3c7fdcb,
+    0xffe5caca7e10e4e61c3624eaa0941ccf,
+    0xffcb9843d60f6159c9db58835c926643,
+    0xff973b41fa98c081472e6896dfb254bf,
+    0xff2ea16466c96a3843ec78b326b52860,
+    0xfe5dee046a99a2a811c461f1969c3052,
+    0xfcbe86c7900a88aedcffc83b479aa3a3,
+    0xf987a7253ac413176f2b074cf7815e53,
+    0xf3392b0822b70005940c7a398e4b70f2,
+    0xe7159475a2c29b7443b29c7fa6e889d8,
+    0xd097f3bdfd2022b8845ad8f792aa5825,
+    0xa9f746462d870fdf8a65dc1f90e061e4,
+    0x70d869a156d2a1b890bb3df62baf32f6,
+    0x31be135f97d08fd981231505542fcfa5,
+    0x9aa508b5b7a84e1c677de54f3e99bc8,
+    0x5d6af8dedb81196699c329225ee604,
+    0x2216e584f5fa1ea926041bedfe97,
+];
+
+/// Get the Q64.64 square root price at the given tick, as Uniswap's
+/// `getSqrtRatioAtTick` multiplies the ratios of the bits of the tick.
+pub fn sqrt_price_at_tick(tick: i32) -> Result<u128> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(Error::InvalidTickRange.into());
+    }
+    let abs_tick = tick.unsigned_abs();
+    // Q128 ratio for the negative tick
+    let mut ratio = U256::one() << 128;
+    for (i, tick_ratio) in TICK_RATIOS.iter().enumerate() {
+        if abs_tick & (1 << i) != 0 {
+            ratio = (ratio * U256::from(*tick_ratio)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+    // round up to Q64.64 so that `tick_at_sqrt_price` is consistent
+    let remainder = ratio & U256::from(u64::MAX);
+    // at most `MAX_SQRT_PRICE` within the ticks, far below `u128::MAX`
+    let sqrt_price = (ratio >> 64).as_u128();
+    if remainder.is_zero() {
+        Ok(sqrt_price)
+    } else {
+        Ok(sqrt_price + 1)
+    }
+}
+
+/// Get the greatest tick whose square root price is at most `sqrt_price`
+pub fn tick_at_sqrt_price(sqrt_price: u128) -> Result<i32> {
+    if !(MIN_SQRT_PRICE..=MAX_SQRT_PRICE).contains(&sqrt_price) {
+        return Err(Error::InvalidSqrtPrice.into());
+    }
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        // round the midpoint up so that the search always moves
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid)? <= sqrt_price {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+/// Get the amount of token A between the two prices for the liquidity,
+/// `L * (sqrt_upper - sqrt_lower) / (sqrt_upper * sqrt_lower)`
+pub fn amount_a_delta(
+    sqrt_price_lower: u128,
+    sqrt_price_upper: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128> {
+    let (sqrt_price_lower, sqrt_price_upper) = ordered(sqrt_price_lower, sqrt_price_upper);
+    if sqrt_price_lower == 0 {
+        return Err(Error::InvalidSqrtPrice.into());
+    }
+    // a u128 shifted by 64 bits times a u128 fits in U256
+    let numerator = (U256::from(liquidity) << 64) * U256::from(sqrt_price_upper - sqrt_price_lower);
+    let amount = if round_up {
+        div_ceil(
+            div_ceil(numerator, U256::from(sqrt_price_upper))?,
+            U256::from(sqrt_price_lower),
+        )?
+    } else {
+        numerator / U256::from(sqrt_price_upper) / U256::from(sqrt_price_lower)
+    };
+    to_u128(amount)
+}
+
+/// Get the amount of token B between the two prices for the liquidity,
+/// `L * (sqrt_upper - sqrt_lower)`
+pub fn amount_b_delta(
+    sqrt_price_lower: u128,
+    sqrt_price_upper: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128> {
+    let (sqrt_price_lower, sqrt_price_upper) = ordered(sqrt_price_lower, sqrt_price_upper);
+    let product = U256::from(liquidity) * U256::from(sqrt_price_upper - sqrt_price_lower);
+    let amount = if round_up {
+        div_ceil(product, U256::one() << 64)?
+    } else {
+        product >> 64
+    };
+    to_u128(amount)
+}
+
+/// Get the price after adding `amount` of token A, rounded up so that the
+/// price never moves further than the input pays for
+fn next_sqrt_price_from_amount_a(sqrt_price: u128, liquidity: u128, amount: u128) -> Result<u128> {
+    if amount == 0 {
+        return Ok(sqrt_price);
+    }
+    // the products of u128 and the shifted liquidity fit in U256
+    let numerator = U256::from(liquidity) << 64;
+    let denominator = numerator + U256::from(amount) * U256::from(sqrt_price);
+    to_u128(div_ceil(numerator * U256::from(sqrt_price), denominator)?)
+}
+
+/// Get the price after adding `amount` of token B, rounded down so that the
+/// price never moves further than the input pays for
+fn next_sqrt_price_from_amount_b(sqrt_price: u128, liquidity: u128, amount: u128) -> Result<u128> {
+    if liquidity == 0 {
+        return Err(Error::InsufficientLiquidity.into());
+    }
+    let delta = (U256::from(amount) << 64) / U256::from(liquidity);
+    to_u128(U256::from(sqrt_price) + delta)
+}
+
+/// Result of swapping within a range of constant liquidity
+#[derive(Debug, PartialEq)]
+pub struct SwapStep {
+    pub sqrt_price_next: u128,
+    pub amount_in: u128,
+    pub amount_out: u128,
+    pub fee_amount: u128,
+}
+
+/// Swap up to `amount_remaining` of input, fee included, from
+/// `sqrt_price_current` towards `sqrt_price_target` with constant
+/// `liquidity`, as Uniswap's `computeSwapStep` for an exact input. Token A is
+/// sold for B when the target is below the current price.
+pub fn compute_swap_step(
+    sqrt_price_current: u128,
+    sqrt_price_target: u128,
+    liquidity: u128,
+    amount_remaining: u128,
+    fee_rate: u128,
+) -> Result<SwapStep> {
+    if fee_rate >= FEE_RATE_DENOMINATOR {
+        return Err(Error::InvalidFee.into());
+    }
+    let a_to_b = sqrt_price_current >= sqrt_price_target;
+    let amount_remaining_less_fee = to_u128(
+        U256::from(amount_remaining) * U256::from(FEE_RATE_DENOMINATOR - fee_rate)
+            / U256::from(FEE_RATE_DENOMINATOR),
+    )?;
+    let amount_in_to_target = if a_to_b {
+        amount_a_delta(sqrt_price_target, sqrt_price_current, liquidity, true)?
+    } else {
+        amount_b_delta(sqrt_price_current, sqrt_price_target, liquidity, true)?
+    };
+    let sqrt_price_next = if liquidity == 0 || amount_remaining_less_fee >= amount_in_to_target {
+        sqrt_price_target
+    } else if a_to_b {
+        next_sqrt_price_from_amount_a(sqrt_price_current, liquidity, amount_remaining_less_fee)?
+    } else {
+        next_sqrt_price_from_amount_b(sqrt_price_current, liquidity, amount_remaining_less_fee)?
+    };
+    let reached_target = sqrt_price_next == sqrt_price_target;
+
+    let (amount_in, amount_out) = if a_to_b {
+        (
+            amount_a_delta(sqrt_price_next, sqrt_price_current, liquidity, true)?,
+            amount_b_delta(sqrt_price_next, sqrt_price_current, liquidity, false)?,
+        )
+    } else {
+        (
+            amount_b_delta(sqrt_price_current, sqrt_price_next, liquidity, true)?,
+            amount_a_delta(sqrt_price_current, sqrt_price_next, liquidity, false)?,
+        )
+    };
+    let fee_amount = if reached_target {
+        // ceil(amount_in * fee_rate / (1 - fee_rate))
+        to_u128(div_ceil(
+            U256::from(amount_in) * U256::from(fee_rate),
+            U256::from(FEE_RATE_DENOMINATOR - fee_rate),
+        )?)?
+    } else {
+        // the remaining input goes to the fee
+        amount_remaining
+            .checked_sub(amount_in)
+            .ok_or(Error::CalculationFailure)?
+    };
+    Ok(SwapStep {
+        sqrt_price_next,
+        amount_in,
+        amount_out,
+        fee_amount,
+    })
+}
+
+/// Get the amounts of token A and B for the liquidity in
+/// `[tick_lower, tick_upper)` at the current price
+pub fn amounts_for_liquidity(
+    sqrt_price: u128,
+    tick_lower: i32,
+    tick_upper: i32,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<(u128, u128)> {
+    let sqrt_price_lower = sqrt_price_at_tick(tick_lower)?;
+    let sqrt_price_upper = sqrt_price_at_tick(tick_upper)?;
+    if sqrt_price <= sqrt_price_lower {
+        Ok((
+            amount_a_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+            0,
+        ))
+    } else if sqrt_price < sqrt_price_upper {
+        Ok((
+            amount_a_delta(sqrt_price, sqrt_price_upper, liquidity, round_up)?,
+            amount_b_delta(sqrt_price_lower, sqrt_price, liquidity, round_up)?,
+        ))
+    } else {
+        Ok((
+            0,
+            amount_b_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+        ))
+    }
+}
+
+/// Get the fee growth inside `[tick_lower, tick_upper)` out of the global fee
+/// growth and the fee growth outside of the two ticks, with wrapping
+/// arithmetic as Uniswap's `getFeeGrowthInside`
+pub fn fee_growth_inside(
+    tick_current: i32,
+    tick_lower: i32,
+    fee_growth_outside_lower: u128,
+    tick_upper: i32,
+    fee_growth_outside_upper: u128,
+    fee_growth_global: u128,
+) -> u128 {
+    let fee_growth_below = if tick_current >= tick_lower {
+        fee_growth_outside_lower
+    } else {
+        fee_growth_global.wrapping_sub(fee_growth_outside_lower)
+    };
+    let fee_growth_above = if tick_current < tick_upper {
+        fee_growth_outside_upper
+    } else {
+        fee_growth_global.wrapping_sub(fee_growth_outside_upper)
+    };
+    fee_growth_global
+        .wrapping_sub(fee_growth_below)
+        .wrapping_sub(fee_growth_above)
+}
+
+/// Get the fees earned by the liquidity since `fee_growth_inside_last`
+pub fn fees_earned(
+    liquidity: u128,
+    fee_growth_inside: u128,
+    fee_growth_inside_last: u128,
+) -> Result<u128> {
+    let growth = fee_growth_inside.wrapping_sub(fee_growth_inside_last);
+    to_u128((U256::from(liquidity) * U256::from(growth)) >> 64)
+}
+
+/// Get the fee growth per unit of liquidity for the fee, in Q64.64
+pub fn fee_growth(fee_amount: u128, liquidity: u128) -> Result<u128> {
+    if liquidity == 0 {
+        return Ok(0);
+    }
+    to_u128((U256::from(fee_amount) << 64) / U256::from(liquidity))
+}
+
+/// Get the trade fee and the owner trade fee of the pool in millionths, both
+/// charged on the input of the swap
+pub fn fee_rates(fees: &Fees) -> Result<(u128, u128)> {
+    Ok((
+        to_fee_rate(fees.trade_fee_numerator, fees.trade_fee_denominator)?,
+        to_fee_rate(
+            fees.owner_trade_fee_numerator,
+            fees.owner_trade_fee_denominator,
+        )?,
+    ))
+}
+
+/// Get the part of the fee of a swap step owed to the owner, rounded down
+pub fn owner_fee(fee_amount: u128, trade_fee_rate: u128, owner_fee_rate: u128) -> Result<u128> {
+    if owner_fee_rate == 0 {
+        return Ok(0);
+    }
+    // the owner part of the fee is at most the fee
+    to_u128(
+        U256::from(fee_amount) * U256::from(owner_fee_rate)
+            / (U256::from(trade_fee_rate) + U256::from(owner_fee_rate)),
+    )
+}
+
+fn to_fee_rate(numerator: u64, denominator: u64) -> Result<u128> {
+    if numerator == 0 {
+        return Ok(0);
+    }
+    if denominator == 0 {
+        return Err(Error::InvalidFee.into());
+    }
+    // a u64 in millionths fits in u128
+    Ok(u128::from(numerator) * FEE_RATE_DENOMINATOR / u128::from(denominator))
+}
+
+fn ordered(a: u128, b: u128) -> (u128, u128) {
+    if a > b {
+        (b, a)
+    } else {
+        (a, b)
+    }
+}
+
+fn div_ceil(numerator: U256, denominator: U256) -> Result<U256> {
+    if denominator.is_zero() {
+        return Err(Error::CalculationFailure.into());
+    }
+    let quotient = numerator / denominator;
+    if (numerator % denominator).is_zero() {
+        Ok(quotient)
+    } else {
+        // the remainder isn't zero, so the quotient is below `U256::MAX`
+        Ok(quotient + 1)
+    }
+}
+
+fn to_u128(value: U256) -> Result<u128> {
+    if value > U256::from(u128::MAX) {
+        Err(Error::ConversionFailure.into())
+    } else {
+        Ok(value.as_u128())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Square root price of 1 in Q64.64
+    const Q64: u128 = 1 << 64;
+
+    fn error<T: std::fmt::Debug>(result: Result<T>) -> ProgramError {
+        result.unwrap_err().into()
+    }
+
+    #[test]
+    fn sqrt_price_at_tick_bounds() {
+        assert_eq!(sqrt_price_at_tick(MIN_TICK).unwrap(), MIN_SQRT_PRICE);
+        assert_eq!(sqrt_price_at_tick(MAX_TICK).unwrap(), MAX_SQRT_PRICE);
+        assert_eq!(sqrt_price_at_tick(0).unwrap(), Q64);
+        assert_eq!(
+            error(sqrt_price_at_tick(MIN_TICK - 1)),
+            Error::InvalidTickRange.into()
+        );
+        assert_eq!(
+            error(sqrt_price_at_tick(MAX_TICK + 1)),
+            Error::InvalidTickRange.into()
+        );
+        assert_eq!(
+            error(tick_at_sqrt_price(MIN_SQRT_PRICE - 1)),
+            Error::InvalidSqrtPrice.into()
+        );
+        assert_eq!(
+            error(tick_at_sqrt_price(MAX_SQRT_PRICE + 1)),
+            Error::InvalidSqrtPrice.into()
+        );
+    }
+
+    #[test]
+    fn tick_sqrt_price_round_trip() {
+        for tick in [
+            MIN_TICK,
+            MIN_TICK + 1,
+            -100_000,
+            -1,
+            0,
+            1,
+            100_000,
+            MAX_TICK - 1,
+            MAX_TICK,
+        ] {
+            let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+            assert_eq!(tick_at_sqrt_price(sqrt_price).unwrap(), tick);
+            if tick < MAX_TICK {
+                // every price up to the next tick belongs to the tick
+                let next_sqrt_price = sqrt_price_at_tick(tick + 1).unwrap();
+                assert!(next_sqrt_price > sqrt_price);
+                assert_eq!(tick_at_sqrt_price(next_sqrt_price - 1).unwrap(), tick);
+            }
+        }
+    }
+
+    #[test]
+    fn amount_deltas_round_in_favor_of_the_pool() {
+        let sqrt_price_lower = sqrt_price_at_tick(-1_000).unwrap();
+        let sqrt_price_upper = sqrt_price_at_tick(1_000).unwrap();
+        let liquidity = 1_000_000_007;
+        let a_down = amount_a_delta(sqrt_price_lower, sqrt_price_upper, liquidity, false).unwrap();
+        let a_up = amount_a_delta(sqrt_price_lower, sqrt_price_upper, liquidity, true).unwrap();
+        let b_down = amount_b_delta(sqrt_price_lower, sqrt_price_upper, liquidity, false).unwrap();
+        let b_up = amount_b_delta(sqrt_price_lower, sqrt_price_upper, liquidity, true).unwrap();
+        assert_eq!(a_up, a_down + 1);
+        assert_eq!(b_up, b_down + 1);
+        // about L * 2 * sinh(0.05) on both sides of a price of 1
+        assert!((100_000_000..100_050_000).contains(&a_down));
+        assert_eq!(a_down, b_down);
+        // the order of the prices doesn't matter
+        assert_eq!(
+            amount_a_delta(sqrt_price_upper, sqrt_price_lower, liquidity, false).unwrap(),
+            a_down
+        );
+        assert_eq!(amount_b_delta(Q64, Q64, liquidity, true).unwrap(), 0);
+        assert_eq!(
+            error(amount_a_delta(0, Q64, liquidity, true)),
+            Error::InvalidSqrtPrice.into()
+        );
+        // the full range of u128 liquidity overflows
+        assert_eq!(
+            error(amount_b_delta(
+                MIN_SQRT_PRICE,
+                MAX_SQRT_PRICE,
+                u128::MAX,
+                true
+            )),
+            Error::ConversionFailure.into()
+        );
+    }
+
+    #[test]
+    fn compute_swap_step_reaches_the_target() {
+        let sqrt_price_target = sqrt_price_at_tick(-10).unwrap();
+        let liquidity = 1_000_000_000;
+        let step =
+            compute_swap_step(Q64, sqrt_price_target, liquidity, u64::MAX as u128, 3_000).unwrap();
+        assert_eq!(step.sqrt_price_next, sqrt_price_target);
+        assert_eq!(
+            step.amount_in,
+            amount_a_delta(sqrt_price_target, Q64, liquidity, true).unwrap()
+        );
+        assert_eq!(
+            step.amount_out,
+            amount_b_delta(sqrt_price_target, Q64, liquidity, false).unwrap()
+        );
+        assert!(step.amount_out < step.amount_in);
+        // ceil(amount_in * 0.3% / 99.7%)
+        assert_eq!(step.fee_amount, (step.amount_in * 3_000).div_ceil(997_000));
+    }
+
+    #[test]
+    fn compute_swap_step_within_the_range() {
+        let liquidity = 1_000_000_000;
+        let amount_remaining = 1_000;
+        for sqrt_price_target in [MIN_SQRT_PRICE, MAX_SQRT_PRICE] {
+            let step =
+                compute_swap_step(Q64, sqrt_price_target, liquidity, amount_remaining, 3_000)
+                    .unwrap();
+            assert_ne!(step.sqrt_price_next, sqrt_price_target);
+            // the whole input is spent, and the rest of it is the fee
+            assert_eq!(step.amount_in + step.fee_amount, amount_remaining);
+            assert_eq!(step.fee_amount, 3);
+            // the output at a price of about 1 is less than the input
+            assert!(step.amount_out < step.amount_in);
+            assert!(step.amount_out >= step.amount_in - 1);
+        }
+    }
+
+    #[test]
+    fn compute_swap_step_without_liquidity() {
+        let sqrt_price_target = sqrt_price_at_tick(10).unwrap();
+        let step = compute_swap_step(Q64, sqrt_price_target, 0, 1_000, 3_000).unwrap();
+        // the price crosses the empty range for free
+        assert_eq!(
+            step,
+            SwapStep {
+                sqrt_price_next: sqrt_price_target,
+                amount_in: 0,
+                amount_out: 0,
+                fee_amount: 0,
+            }
+        );
+        assert_eq!(
+            error(compute_swap_step(
+                Q64,
+                sqrt_price_target,
+                0,
+                1_000,
+                FEE_RATE_DENOMINATOR + 1
+            )),
+            Error::InvalidFee.into()
+        );
+    }
+
+    #[test]
+    fn amounts_for_liquidity_around_the_range() {
+        let liquidity = 1_000_000_000;
+        let (below_a, below_b) =
+            amounts_for_liquidity(MIN_SQRT_PRICE, -100, 100, liquidity, true).unwrap();
+        assert!(below_a > 0);
+        assert_eq!(below_b, 0);
+        let (above_a, above_b) =
+            amounts_for_liquidity(MAX_SQRT_PRICE, -100, 100, liquidity, true).unwrap();
+        assert_eq!(above_a, 0);
+        assert!(above_b > 0);
+        // at the middle of a symmetric range, `L * (1 - 1.0001 ^ -50)` of each
+        let (a, b) = amounts_for_liquidity(Q64, -100, 100, liquidity, false).unwrap();
+        assert!(a < below_a && b < above_b);
+        assert!(a.abs_diff(b) <= 1);
+        assert!((4_980_000..4_990_000).contains(&a));
+    }
+}
@@ -1,8 +1,10 @@
 use crate::error::Error;
+use crate::oracle::Oracle;
 use crate::Fees;
 use anchor_lang::prelude::*;
 use spl_math::checked_ceil_div::CheckedCeilDiv;
 use spl_math::precise_number::PreciseNumber;
+use spl_math::uint::U256;
 
 /// Initial amount of pool tokens for swap contract, hard-coded to something
 /// "sensible" given a maximum of u128.
@@ -10,6 +12,7 @@ use spl_math::precise_number::PreciseNumber;
 /// input amounts, and Balancer uses 100 * 10 ^ 18.
 pub const INITIAL_SWAP_POOL_AMOUNT: u128 = 1_000_000_000;
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
 pub enum TradeDirection {
     AtoB,
     BtoA,
@@ -66,7 +69,7 @@ impl ConstantProduct {
         swap_token_a_amount: u128,
         swap_token_b_amount: u128,
         round_direction: RoundDirection,
-    ) -> Option<(u128, u128)> {
+    ) -> Result<(u128, u128)> {
         msg!(
             "pool_tokens={}, pool_token_supply={}, swap_token_a_amount={}, swap_token_b_amount={}",
             pool_tokens,
@@ -74,35 +77,94 @@ impl ConstantProduct {
             swap_token_a_amount,
             swap_token_b_amount
         );
-        let mut token_a_amount = pool_tokens
-            .checked_mul(swap_token_a_amount)?
-            .checked_div(pool_token_supply)?;
-        let mut token_b_amount = pool_tokens
-            .checked_mul(swap_token_b_amount)?
-            .checked_div(pool_token_supply)?;
-
-        match round_direction {
-            RoundDirection::Floor => Some((token_a_amount, token_b_amount)),
-            RoundDirection::Ceiling => {
-                let token_a_remainder = pool_tokens
-                    .checked_mul(swap_token_a_amount)?
-                    .checked_rem(pool_token_supply)?;
+        // the products are computed in U256, for they overflow u128 with the
+        // supplies of high-decimal tokens
+        let pool_tokens = U256::from(pool_tokens);
+        let pool_token_supply = U256::from(pool_token_supply);
+        let trading_tokens = |swap_token_amount: u128| -> Result<u128> {
+            let product = pool_tokens
+                .checked_mul(U256::from(swap_token_amount))
+                .ok_or(Error::CalculationFailure)?;
+            let token_amount = product
+                .checked_div(pool_token_supply)
+                .ok_or(Error::CalculationFailure)?;
+            let token_amount = match round_direction {
                 // Also check for 0 token A and B amount to avoid taking too much
                 // for tiny amounts of pool tokens.  For example, if someone asks
                 // for 1 pool token, which is worth 0.01 token A, we avoid the
                 // ceiling of taking 1 token A and instead return 0, for it to be
                 // rejected later in processing.
-                if token_a_remainder > 0 && token_a_amount > 0 {
-                    token_a_amount += 1;
+                RoundDirection::Ceiling
+                    if !(product % pool_token_supply).is_zero() && !token_amount.is_zero() =>
+                {
+                    token_amount + 1
                 }
-                let token_b_remainder = pool_tokens
-                    .checked_mul(swap_token_b_amount)?
-                    .checked_rem(pool_token_supply)?;
-                if token_b_remainder > 0 && token_b_amount > 0 {
-                    token_b_amount += 1;
-                }
-                Some((token_a_amount, token_b_amount))
-            }
+                _ => token_amount,
+            };
+            u256_to_u128(token_amount)
+        };
+        Ok((
+            trading_tokens(swap_token_a_amount)?,
+            trading_tokens(swap_token_b_amount)?,
+        ))
+    }
+
+    /// Get the amount of pool tokens for the given amounts of token A and B,
+    /// the inverse of `pool_tokens_to_trading_tokens` rounded down, so that
+    /// the trading tokens for those pool tokens never exceed the given amounts.
+    pub fn trading_tokens_to_pool_tokens(
+        &self,
+        token_a_amount: u128,
+        token_b_amount: u128,
+        pool_token_supply: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Result<u128> {
+        let pool_tokens_for_a = mul_div_u256(
+            U256::from(token_a_amount),
+            U256::from(pool_token_supply),
+            U256::from(swap_token_a_amount),
+            &RoundDirection::Floor,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        let pool_tokens_for_b = mul_div_u256(
+            U256::from(token_b_amount),
+            U256::from(pool_token_supply),
+            U256::from(swap_token_b_amount),
+            &RoundDirection::Floor,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        u256_to_u128(std::cmp::min(pool_tokens_for_a, pool_tokens_for_b))
+    }
+
+    /// Get the amounts of token A and B to deposit out of the desired amounts,
+    /// as Uniswap's `addLiquidity`: one side is used in full and the other is
+    /// its counterpart at the current ratio of the swap, rounded down.
+    pub fn optimal_deposit_amounts(
+        &self,
+        token_a_desired: u128,
+        token_b_desired: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Result<(u128, u128)> {
+        let token_b_optimal = mul_div_u256(
+            U256::from(token_a_desired),
+            U256::from(swap_token_b_amount),
+            U256::from(swap_token_a_amount),
+            &RoundDirection::Floor,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        if token_b_optimal <= U256::from(token_b_desired) {
+            Ok((token_a_desired, u256_to_u128(token_b_optimal)?))
+        } else {
+            let token_a_optimal = mul_div_u256(
+                U256::from(token_b_desired),
+                U256::from(swap_token_a_amount),
+                U256::from(swap_token_b_amount),
+                &RoundDirection::Floor,
+            )
+            .ok_or(Error::CalculationFailure)?;
+            Ok((u256_to_u128(token_a_optimal)?, token_b_desired))
         }
     }
 
@@ -127,14 +189,16 @@ impl ConstantProduct {
         pool_supply: u128,
         trade_direction: &TradeDirection,
         fees: &Fees,
-    ) -> Option<u128> {
+    ) -> Result<u128> {
         if source_amount == 0 {
-            return Some(0);
+            return Ok(0);
         }
-        let half_source_amount = std::cmp::max(1, source_amount.checked_div(2)?);
+        let half_source_amount = std::cmp::max(1, source_amount / 2);
         let trade_fee = fees.trading_fee(half_source_amount)?;
-        let source_amount = source_amount.checked_sub(trade_fee)?;
-        deposit_single_token_type(
+        let source_amount = source_amount
+            .checked_sub(trade_fee)
+            .ok_or(Error::ZeroTradingTokens)?;
+        Ok(deposit_single_token_type(
             source_amount,
             swap_token_a_amount,
             swap_token_b_amount,
@@ -142,6 +206,70 @@ impl ConstantProduct {
             trade_direction,
             RoundDirection::Floor,
         )
+        .ok_or(Error::CalculationFailure)?)
+    }
+
+    /// Get the amount of token A or B to deposit for minting exactly
+    /// `pool_tokens`, rounded up, including the trade fee charged on the half
+    /// that is implicitly swapped.
+    pub fn deposit_single_token_type_exact_out(
+        &self,
+        pool_tokens: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: &TradeDirection,
+        fees: &Fees,
+    ) -> Result<u128> {
+        if pool_tokens == 0 {
+            return Ok(0);
+        }
+        let source_amount_less_fee = deposit_single_token_type_exact_out(
+            pool_tokens,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            trade_direction,
+            RoundDirection::Ceiling,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        // `deposit_single_token_type` debits the fee on half of the deposit,
+        // so gross up by `2 * denominator / (2 * denominator - numerator)`,
+        // rounded up. The fee is rounded down, so this covers the fee-less
+        // amount unless the minimum fee of one token kicks in, which one more
+        // token covers.
+        let numerator = U256::from(fees.trade_fee_numerator);
+        let double_denominator = U256::from(fees.trade_fee_denominator) * U256::from(2);
+        let source_amount = if numerator.is_zero() {
+            source_amount_less_fee
+        } else {
+            let divisor = double_denominator
+                .checked_sub(numerator)
+                .ok_or(Error::FeeCalculationFailure)?;
+            u256_to_u128(
+                ceil_div_u256(
+                    U256::from(source_amount_less_fee) * double_denominator,
+                    divisor,
+                )
+                .ok_or(Error::FeeCalculationFailure)?,
+            )?
+        };
+        let covers = |source_amount: u128| -> Result<bool> {
+            let half_source_amount = std::cmp::max(1, source_amount / 2);
+            let trade_fee = fees.trading_fee(half_source_amount)?;
+            Ok(source_amount.saturating_sub(trade_fee) >= source_amount_less_fee)
+        };
+        if covers(source_amount)? {
+            return Ok(source_amount);
+        }
+        let source_amount = source_amount
+            .checked_add(1)
+            .ok_or(Error::CalculationFailure)?;
+        if covers(source_amount)? {
+            Ok(source_amount)
+        } else {
+            Err(Error::FeeCalculationFailure.into())
+        }
     }
 
     pub fn withdraw_single_token_type_exact_out(
@@ -152,14 +280,23 @@ impl ConstantProduct {
         pool_supply: u128,
         trade_direction: &TradeDirection,
         fees: &Fees,
-    ) -> Option<u128> {
+    ) -> Result<u128> {
         if source_amount == 0 {
-            return Some(0);
+            return Ok(0);
         }
-        let half_source_amount = std::cmp::max(1, source_amount.checked_div(2)?);
+        let swap_destination_amount = match trade_direction {
+            TradeDirection::AtoB => swap_token_a_amount,
+            TradeDirection::BtoA => swap_token_b_amount,
+        };
+        if source_amount > swap_destination_amount {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let half_source_amount = std::cmp::max(1, source_amount / 2);
         let trade_fee = fees.trading_fee(half_source_amount)?;
-        let source_amount = source_amount.checked_sub(trade_fee)?;
-        withdraw_single_token_type_exact_out(
+        let source_amount = source_amount
+            .checked_sub(trade_fee)
+            .ok_or(Error::ZeroTradingTokens)?;
+        Ok(withdraw_single_token_type_exact_out(
             source_amount,
             swap_token_a_amount,
             swap_token_b_amount,
@@ -167,6 +304,142 @@ impl ConstantProduct {
             trade_direction,
             RoundDirection::Ceiling,
         )
+        .ok_or(Error::CalculationFailure)?)
+    }
+
+    /// Get the amount of token A or B received for burning exactly
+    /// `pool_tokens`, rounded down, with the trade fee charged on the half
+    /// that is implicitly swapped.
+    pub fn withdraw_single_token_type_exact_in(
+        &self,
+        pool_tokens: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: &TradeDirection,
+        fees: &Fees,
+    ) -> Result<u128> {
+        if pool_tokens == 0 {
+            return Ok(0);
+        }
+        if pool_tokens > pool_supply {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let destination_amount = withdraw_single_token_type_exact_in(
+            pool_tokens,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            trade_direction,
+            RoundDirection::Floor,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        let half_destination_amount = std::cmp::max(1, destination_amount / 2);
+        let trade_fee = fees.trading_fee(half_destination_amount)?;
+        Ok(destination_amount
+            .checked_sub(trade_fee)
+            .ok_or(Error::ZeroTradingTokens)?)
+    }
+
+    /// Get the amount of the deposited token A or B to swap so that the rest
+    /// and the swapped output match the ratio of the swap afterwards, for
+    /// depositing both sides at once.
+    ///
+    /// With `r = 1 - fee`, swapping `s` out of `source_amount` leaves the
+    /// proportions unchanged when
+    /// `s = (sqrt((R * (1 + r)) ^ 2 + 4 * r * source_amount * R) - R * (1 + r)) / (2 * r)`
+    /// where `R` is the swap source amount, rounded down.
+    pub fn zap_in_swap_amount(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        fees: &Fees,
+    ) -> Result<u128> {
+        Ok(zap_in_swap_amount(source_amount, swap_source_amount, fees)
+            .ok_or(Error::CalculationFailure)?)
+    }
+
+    /// Get the largest amount of the source token that a limit order selling
+    /// `amount_in` for at least `amount_out` can swap while receiving at
+    /// least its limit price on average, rounded down.
+    ///
+    /// With `r = 1 - fee`, the average price of swapping `x` is
+    /// `D * r / (R + x * r)`, which is at least `amount_out / amount_in` when
+    /// `x <= (D * r * amount_in - R * amount_out) / (r * amount_out)`, where
+    /// `R` and `D` are the swap source and destination amounts.
+    pub fn limit_order_fill_amount(
+        &self,
+        amount_in: u128,
+        amount_out: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        fees: &Fees,
+    ) -> Result<u128> {
+        Ok(limit_order_fill_amount(
+            amount_in,
+            amount_out,
+            swap_source_amount,
+            swap_destination_amount,
+            fees,
+        )
+        .ok_or(Error::CalculationFailure)?)
+    }
+
+    /// Swap of `source_amount` filling a limit order, or `None` when the pool
+    /// has nothing to fill it with, as the swap rounds down to nothing or the
+    /// pool can't pay it out. Any other failure is an error.
+    pub fn limit_order_swap(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        fees: &Fees,
+    ) -> Result<Option<SwapResult>> {
+        match self.swap(
+            source_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            fees,
+        ) {
+            Ok(result) => Ok(Some(result)),
+            Err(anchor_lang::error::Error::ProgramError(error))
+                if error.program_error == Error::ZeroTradingTokens.into()
+                    || error.program_error == Error::InsufficientLiquidity.into() =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the amounts of token A and token B paid out by the pool when
+    /// virtual orders sell `token_a_amount` and `token_b_amount`, net of fees,
+    /// at constant rates over the same period, rounded down.
+    ///
+    /// This is the closed form of the TWAMM: with `k = x * y`,
+    /// `p = sqrt(x * B)`, `q = sqrt(y * A)` and `e = exp(2 * sqrt(A * B / k))`,
+    /// the pool ends at `x' = x * q * (e * (p + q) + p - q) / (p * (e * (p + q) - p + q))`
+    /// and `y' = k / x'`, where `x`, `y` are the amounts of token A and B in
+    /// the pool and `A`, `B` the amounts sold. When a single side sells, the
+    /// orders are a plain constant product swap of the amount sold.
+    pub fn virtual_orders_swap(
+        &self,
+        token_a_amount: u128,
+        token_b_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Result<(u128, u128)> {
+        if swap_token_a_amount == 0 || swap_token_b_amount == 0 {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let (token_a_out, token_b_out) = virtual_orders_swap(
+            token_a_amount,
+            token_b_amount,
+            swap_token_a_amount,
+            swap_token_b_amount,
+        )
+        .ok_or(Error::CalculationFailure)?;
+        Ok((u256_to_u128(token_a_out)?, u256_to_u128(token_b_out)?))
     }
 
     pub fn swap(
@@ -175,13 +448,17 @@ impl ConstantProduct {
         swap_source_amount: u128,
         swap_destination_amount: u128,
         fees: &Fees,
-    ) -> Option<SwapResult> {
+    ) -> Result<SwapResult> {
         // debit the fee to calculate the amount swapped
         let trade_fee = fees.trading_fee(source_amount)?;
         let owner_fee = fees.owner_trading_fee(source_amount)?;
 
-        let total_fees = trade_fee.checked_add(owner_fee)?;
-        let source_amount_less_fees = source_amount.checked_sub(total_fees)?;
+        let total_fees = trade_fee
+            .checked_add(owner_fee)
+            .ok_or(Error::FeeCalculationFailure)?;
+        let source_amount_less_fees = source_amount
+            .checked_sub(total_fees)
+            .ok_or(Error::ZeroTradingTokens)?;
 
         let (source_amount_swapped, destination_amount_swapped) = swap(
             source_amount_less_fees,
@@ -189,11 +466,13 @@ impl ConstantProduct {
             swap_destination_amount,
         )?;
 
-        let source_amount_swapped = source_amount_swapped.checked_add(total_fees)?;
-        Some(SwapResult {
-            new_swap_source_amount: swap_source_amount.checked_add(source_amount_swapped)?,
-            new_swap_destination_amount: swap_destination_amount
-                .checked_sub(destination_amount_swapped)?,
+        // the fees were taken out of the source amount, which fits in u128
+        let source_amount_swapped = source_amount_swapped + total_fees;
+        Ok(SwapResult {
+            new_swap_source_amount: swap_source_amount
+                .checked_add(source_amount_swapped)
+                .ok_or(Error::CalculationFailure)?,
+            new_swap_destination_amount: swap_destination_amount - destination_amount_swapped,
             source_amount_swapped,
             destination_amount_swapped,
             trade_fee,
@@ -202,6 +481,350 @@ impl ConstantProduct {
     }
 }
 
+/// Denominator of the `k` parameter of the PMM curve
+pub const PMM_K_DENOMINATOR: u64 = 10_000;
+
+/// Parameters of the PMM curve of a pair
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PmmParameters {
+    /// `PriceFeed` account of the guide price, read by every swap
+    pub price_feed: Pubkey,
+    /// Slippage around the guide price over `PMM_K_DENOMINATOR`, from a
+    /// constant sum at 0 to a curve as steep as the constant product at
+    /// `PMM_K_DENOMINATOR`
+    pub k: u64,
+    /// Maximum age of the guide price, in seconds, beyond which the pair
+    /// falls back to the constant product
+    pub max_staleness: i64,
+}
+
+impl PmmParameters {
+    pub const LEN: usize = 32 + 8 + 8;
+
+    /// Validate that `k` is within its denominator and the staleness is not
+    /// negative
+    pub fn validate(&self) -> Result<()> {
+        if self.k > PMM_K_DENOMINATOR || self.max_staleness < 0 {
+            return Err(Error::InvalidCurve.into());
+        }
+        Ok(())
+    }
+
+    /// The curve at the guide price of the oracle, or `None` for the constant
+    /// product when the price is stale
+    pub fn curve(&self, oracle: &impl Oracle, now: i64) -> Result<Option<ProactiveMarketMaker>> {
+        let oracle_price = oracle.price()?;
+        if oracle_price.price == 0 || oracle_price.is_stale(now, self.max_staleness) {
+            msg!("Stale guide price, falling back to the constant product");
+            return Ok(None);
+        }
+        Ok(Some(ProactiveMarketMaker {
+            price: oracle_price.price,
+            k: self.k,
+        }))
+    }
+}
+
+/// DODO's proactive market maker, which concentrates the liquidity around a
+/// guide price `i`.
+///
+/// The pool is at its targets `x0`, `y0` when both sides are worth the same
+/// at the guide price. The side `x` short of its target is priced
+/// `i * (1 - k + k * (x0 / x) ^ 2)`, which integrates to the curve
+/// `y = y0 + i * (x0 - x) * (1 - k + k * x0 / x)`, and the other side mirrors
+/// it. The targets are solved from the amounts in the pool at each swap.
+pub struct ProactiveMarketMaker {
+    /// Guide price of token A in token B, as a 64.64 fixed point number
+    pub price: u128,
+    /// Over `PMM_K_DENOMINATOR`
+    pub k: u64,
+}
+
+/// Price of a token X in a token Y as a fraction
+struct Price {
+    numerator: U256,
+    denominator: U256,
+}
+
+impl Price {
+    fn to_y(&self, x: U256, round_direction: &RoundDirection) -> Option<U256> {
+        mul_div_u256(x, self.numerator, self.denominator, round_direction)
+    }
+
+    fn to_x(&self, y: U256, round_direction: &RoundDirection) -> Option<U256> {
+        mul_div_u256(y, self.denominator, self.numerator, round_direction)
+    }
+}
+
+impl ProactiveMarketMaker {
+    /// Price of the source token in the destination token
+    fn source_price(&self, trade_direction: &TradeDirection) -> Price {
+        let one = U256::one() << 64;
+        match trade_direction {
+            TradeDirection::AtoB => Price {
+                numerator: U256::from(self.price),
+                denominator: one,
+            },
+            TradeDirection::BtoA => Price {
+                numerator: one,
+                denominator: U256::from(self.price),
+            },
+        }
+    }
+
+    /// Targets of the amounts `x` and `y` in the pool, solved from the side
+    /// short of its target
+    fn targets(&self, x: U256, y: U256, price: &Price) -> Option<(U256, U256)> {
+        if x.checked_mul(price.numerator)? < y.checked_mul(price.denominator)? {
+            let x0 = self.target(x, price.to_x(y, &RoundDirection::Floor)?)?;
+            Some((x0, price.to_y(x0, &RoundDirection::Floor)?))
+        } else {
+            let y0 = self.target(y, price.to_y(x, &RoundDirection::Floor)?)?;
+            Some((price.to_x(y0, &RoundDirection::Floor)?, y0))
+        }
+    }
+
+    /// Target `x0` of the short side with amount `x`, given the `value` of
+    /// the other side in the short token, rounded down.
+    ///
+    /// The curve at `x` gives `value = x0 + (x0 - x) * (1 - k + k * x0 / x)`,
+    /// solved as `x0 = (sqrt(x * ((1 - k) * x + k * value)) - (1 - k) * x) / k`.
+    fn target(&self, x: U256, value: U256) -> Option<U256> {
+        if self.k == 0 {
+            return Some(x.checked_add(value)? >> 1);
+        }
+        let k = U256::from(self.k);
+        let retained = U256::from(PMM_K_DENOMINATOR - self.k).checked_mul(x)?;
+        let root = sqrt_u256(
+            U256::from(PMM_K_DENOMINATOR)
+                .checked_mul(x)?
+                .checked_mul(retained.checked_add(k.checked_mul(value)?)?)?,
+        );
+        let x0 = root.saturating_sub(retained).checked_div(k)?;
+        Some(std::cmp::max(x0, x))
+    }
+
+    /// Amount `y` on the curve of the targets `x0`, `y0` at the amount `x`
+    fn curve(
+        &self,
+        x: U256,
+        x0: U256,
+        y0: U256,
+        price: &Price,
+        round_direction: &RoundDirection,
+    ) -> Option<U256> {
+        let denominator = U256::from(PMM_K_DENOMINATOR);
+        let k = U256::from(self.k);
+        let retained = U256::from(PMM_K_DENOMINATOR - self.k);
+        if x <= x0 {
+            // y = y0 + i * (x0 - x) * ((1 - k) * x + k * x0) / x
+            let value = mul_div_u256(
+                x0.checked_sub(x)?,
+                retained.checked_mul(x)?.checked_add(k.checked_mul(x0)?)?,
+                denominator.checked_mul(x)?,
+                round_direction,
+            )?;
+            return y0.checked_add(price.to_y(value, round_direction)?);
+        }
+        // y solves (1 - k) * y ^ 2 + (d - (1 - 2 * k) * y0) * y - k * y0 ^ 2 = 0
+        // for d = i * (x - x0), the mirror of the curve below the target
+        let opposite_direction = match round_direction {
+            RoundDirection::Floor => RoundDirection::Ceiling,
+            RoundDirection::Ceiling => RoundDirection::Floor,
+        };
+        let d = denominator.checked_mul(price.to_y(x.checked_sub(x0)?, &opposite_direction)?)?;
+        let k2 = k.checked_mul(U256::from(2))?;
+        let (b, b_negative) = if k2 >= denominator {
+            (
+                d.checked_add(k2.checked_sub(denominator)?.checked_mul(y0)?)?,
+                false,
+            )
+        } else {
+            let offset = denominator.checked_sub(k2)?.checked_mul(y0)?;
+            if d >= offset {
+                (d.checked_sub(offset)?, false)
+            } else {
+                (offset.checked_sub(d)?, true)
+            }
+        };
+        let c = k.checked_mul(y0)?.checked_mul(y0)?;
+        let discriminant = b
+            .checked_mul(b)?
+            .checked_add(U256::from(4).checked_mul(retained)?.checked_mul(c)?)?;
+        let root = sqrt_u256(discriminant);
+        let root_ceiling = if root.checked_mul(root)? < discriminant {
+            root.checked_add(U256::one())?
+        } else {
+            root
+        };
+        if b_negative {
+            // (-b + sqrt(b ^ 2 + 4 * (1 - k) * k * y0 ^ 2)) / (2 * (1 - k))
+            let root = match round_direction {
+                RoundDirection::Floor => root,
+                RoundDirection::Ceiling => root_ceiling,
+            };
+            mul_div_u256(
+                b.checked_add(root)?,
+                U256::one(),
+                U256::from(2).checked_mul(retained)?,
+                round_direction,
+            )
+        } else {
+            // the conjugate 2 * k * y0 ^ 2 / (b + sqrt(...)), stable when k = 1
+            let root = match round_direction {
+                RoundDirection::Floor => root_ceiling,
+                RoundDirection::Ceiling => root,
+            };
+            let divisor = b.checked_add(root)?;
+            if divisor.is_zero() {
+                return Some(U256::zero());
+            }
+            mul_div_u256(
+                U256::from(2).checked_mul(c)?,
+                U256::one(),
+                divisor,
+                round_direction,
+            )
+        }
+    }
+
+    /// Value of the amounts of token A and B in token B at the guide price,
+    /// as a 64.64 fixed point number
+    fn value(&self, token_a_amount: u128, token_b_amount: u128) -> Option<U256> {
+        U256::from(token_a_amount)
+            .checked_mul(U256::from(self.price))?
+            .checked_add(U256::from(token_b_amount) << 64)
+    }
+
+    /// Value of an amount of the token A or B in token B at the guide price
+    fn side_value(&self, amount: u128, trade_direction: &TradeDirection) -> Option<U256> {
+        match trade_direction {
+            TradeDirection::AtoB => self.value(amount, 0),
+            TradeDirection::BtoA => self.value(0, amount),
+        }
+    }
+
+    pub fn swap(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: &TradeDirection,
+        fees: &Fees,
+    ) -> Result<SwapResult> {
+        // debit the fee to calculate the amount swapped
+        let trade_fee = fees.trading_fee(source_amount)?;
+        let owner_fee = fees.owner_trading_fee(source_amount)?;
+
+        let total_fees = trade_fee
+            .checked_add(owner_fee)
+            .ok_or(Error::FeeCalculationFailure)?;
+        let source_amount_less_fees = source_amount
+            .checked_sub(total_fees)
+            .ok_or(Error::ZeroTradingTokens)?;
+
+        if swap_source_amount == 0 && swap_destination_amount == 0 {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let price = self.source_price(trade_direction);
+        let x = U256::from(swap_source_amount);
+        let y = U256::from(swap_destination_amount);
+        let (x0, y0) = self
+            .targets(x, y, &price)
+            .ok_or(Error::CalculationFailure)?;
+        // the amount left in the pool is rounded up in its favor
+        let y_before = self
+            .curve(x, x0, y0, &price, &RoundDirection::Floor)
+            .ok_or(Error::CalculationFailure)?;
+        let y_after = self
+            .curve(
+                x + U256::from(source_amount_less_fees),
+                x0,
+                y0,
+                &price,
+                &RoundDirection::Ceiling,
+            )
+            .ok_or(Error::CalculationFailure)?;
+        let destination_amount_swapped = u256_to_u128(y_before.saturating_sub(y_after))?;
+        if destination_amount_swapped == 0 {
+            return Err(Error::ZeroTradingTokens.into());
+        }
+        if destination_amount_swapped >= swap_destination_amount {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+
+        Ok(SwapResult {
+            new_swap_source_amount: swap_source_amount
+                .checked_add(source_amount)
+                .ok_or(Error::CalculationFailure)?,
+            new_swap_destination_amount: swap_destination_amount - destination_amount_swapped,
+            source_amount_swapped: source_amount,
+            destination_amount_swapped,
+            trade_fee,
+            owner_fee,
+        })
+    }
+
+    /// Get the amount of pool tokens to burn for the withdrawn amount of
+    /// token A or B, valued at the guide price with the trade fee on half of
+    /// it, as if that half were swapped, rounded up
+    pub fn withdraw_single_token_type_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: &TradeDirection,
+        fees: &Fees,
+    ) -> Result<u128> {
+        if destination_amount == 0 {
+            return Ok(0);
+        }
+        let swap_destination_amount = match trade_direction {
+            TradeDirection::AtoB => swap_token_a_amount,
+            TradeDirection::BtoA => swap_token_b_amount,
+        };
+        if destination_amount >= swap_destination_amount {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let half_destination_amount = std::cmp::max(1, destination_amount / 2);
+        let trade_fee = fees.trading_fee(half_destination_amount)?;
+        // the destination amount is below the pool amount, so adding the fee
+        // on half of it fits in u128
+        let value = self
+            .side_value(destination_amount + trade_fee, trade_direction)
+            .ok_or(Error::CalculationFailure)?;
+        self.pool_tokens(
+            value,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            &RoundDirection::Ceiling,
+        )
+    }
+
+    /// Share of the pool supply worth `value` out of the value of the pool
+    fn pool_tokens(
+        &self,
+        value: U256,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        round_direction: &RoundDirection,
+    ) -> Result<u128> {
+        let pool_value = self
+            .value(swap_token_a_amount, swap_token_b_amount)
+            .ok_or(Error::CalculationFailure)?;
+        if pool_value.is_zero() {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        u256_to_u128(
+            mul_div_u256(U256::from(pool_supply), value, pool_value, round_direction)
+                .ok_or(Error::CalculationFailure)?,
+        )
+    }
+}
+
 /// Get the amount of pool tokens for the deposited amount of token A or B.
 ///
 /// The constant product implementation uses the Balancer formulas found at
@@ -233,6 +856,37 @@ fn deposit_single_token_type(
     }
 }
 
+/// Get the amount of token A or B to deposit for the minted amount of pool tokens.
+///
+/// This is the inverse of `deposit_single_token_type`: with
+/// `pool_tokens = pool_supply * (sqrt(1 + amount / swap_amount) - 1)`, the
+/// amount is `swap_amount * ((1 + pool_tokens / pool_supply) ^ 2 - 1)`.
+fn deposit_single_token_type_exact_out(
+    pool_tokens: u128,
+    swap_token_a_amount: u128,
+    swap_token_b_amount: u128,
+    pool_supply: u128,
+    trade_direction: &TradeDirection,
+    round_direction: RoundDirection,
+) -> Option<u128> {
+    let swap_source_amount = match trade_direction {
+        TradeDirection::AtoB => swap_token_a_amount,
+        TradeDirection::BtoA => swap_token_b_amount,
+    };
+    let swap_source_amount = PreciseNumber::new(swap_source_amount)?;
+    let pool_tokens = PreciseNumber::new(pool_tokens)?;
+    let pool_supply = PreciseNumber::new(pool_supply)?;
+    let ratio = pool_tokens.checked_div(&pool_supply)?;
+    let one = PreciseNumber::new(1)?;
+    let base = one.checked_add(&ratio)?;
+    let root = base.checked_pow(2)?.checked_sub(&one)?;
+    let source_amount = swap_source_amount.checked_mul(&root)?;
+    match round_direction {
+        RoundDirection::Floor => source_amount.floor()?.to_imprecise(),
+        RoundDirection::Ceiling => source_amount.ceiling()?.to_imprecise(),
+    }
+}
+
 /// Get the amount of pool tokens for the withdrawn amount of token A or B.
 ///
 /// The constant product implementation uses the Balancer formulas found at
@@ -264,34 +918,875 @@ fn withdraw_single_token_type_exact_out(
     }
 }
 
+/// Get the amount of token A or B for the withdrawn amount of pool tokens.
+///
+/// This is the inverse of `withdraw_single_token_type_exact_out`: with
+/// `pool_tokens = pool_supply * (1 - sqrt(1 - amount / swap_amount))`, the
+/// amount is `swap_amount * (1 - (1 - pool_tokens / pool_supply) ^ 2)`.
+fn withdraw_single_token_type_exact_in(
+    pool_tokens: u128,
+    swap_token_a_amount: u128,
+    swap_token_b_amount: u128,
+    pool_supply: u128,
+    trade_direction: &TradeDirection,
+    round_direction: RoundDirection,
+) -> Option<u128> {
+    let swap_destination_amount = match trade_direction {
+        TradeDirection::AtoB => swap_token_a_amount,
+        TradeDirection::BtoA => swap_token_b_amount,
+    };
+    let swap_destination_amount = PreciseNumber::new(swap_destination_amount)?;
+    let pool_tokens = PreciseNumber::new(pool_tokens)?;
+    let pool_supply = PreciseNumber::new(pool_supply)?;
+    let ratio = pool_tokens.checked_div(&pool_supply)?;
+    let one = PreciseNumber::new(1)?;
+    let base = one.checked_sub(&ratio)?;
+    let root = one.checked_sub(&base.checked_pow(2)?)?;
+    let destination_amount = swap_destination_amount.checked_mul(&root)?;
+    match round_direction {
+        RoundDirection::Floor => destination_amount.floor()?.to_imprecise(),
+        RoundDirection::Ceiling => destination_amount.ceiling()?.to_imprecise(),
+    }
+}
+
+/// The zap in swap amount calculation, factored out of its class.
+fn zap_in_swap_amount(source_amount: u128, swap_source_amount: u128, fees: &Fees) -> Option<u128> {
+    if source_amount == 0 {
+        return Some(0);
+    }
+    let fee = fee_fraction(fees.trade_fee_numerator, fees.trade_fee_denominator)?.checked_add(
+        &fee_fraction(
+            fees.owner_trade_fee_numerator,
+            fees.owner_trade_fee_denominator,
+        )?,
+    )?;
+    let one = PreciseNumber::new(1)?;
+    let two = PreciseNumber::new(2)?;
+    let four = PreciseNumber::new(4)?;
+    let retained = one.checked_sub(&fee)?;
+    let source_amount = PreciseNumber::new(source_amount)?;
+    let swap_source_amount = PreciseNumber::new(swap_source_amount)?;
+    let b = swap_source_amount.checked_mul(&one.checked_add(&retained)?)?;
+    let discriminant = b.checked_mul(&b)?.checked_add(
+        &four
+            .checked_mul(&retained)?
+            .checked_mul(&source_amount)?
+            .checked_mul(&swap_source_amount)?,
+    )?;
+    let swap_amount = discriminant
+        .sqrt()?
+        .checked_sub(&b)?
+        .checked_div(&two.checked_mul(&retained)?)?;
+    let swap_amount = swap_amount.floor()?.to_imprecise()?;
+    Some(std::cmp::min(swap_amount, source_amount.to_imprecise()?))
+}
+
+/// The limit order fill amount calculation, factored out of its class.
+fn limit_order_fill_amount(
+    amount_in: u128,
+    amount_out: u128,
+    swap_source_amount: u128,
+    swap_destination_amount: u128,
+    fees: &Fees,
+) -> Option<u128> {
+    let fee = fee_fraction(fees.trade_fee_numerator, fees.trade_fee_denominator)?.checked_add(
+        &fee_fraction(
+            fees.owner_trade_fee_numerator,
+            fees.owner_trade_fee_denominator,
+        )?,
+    )?;
+    let retained = PreciseNumber::new(1)?.checked_sub(&fee)?;
+    let amount_out = PreciseNumber::new(amount_out)?;
+    let pool_value = PreciseNumber::new(swap_destination_amount)?
+        .checked_mul(&retained)?
+        .checked_mul(&PreciseNumber::new(amount_in)?)?;
+    let limit_value = PreciseNumber::new(swap_source_amount)?.checked_mul(&amount_out)?;
+    if pool_value.less_than_or_equal(&limit_value) {
+        return Some(0);
+    }
+    pool_value
+        .checked_sub(&limit_value)?
+        .checked_div(&retained.checked_mul(&amount_out)?)?
+        .floor()?
+        .to_imprecise()
+}
+
+/// The TWAMM calculation, factored out of its class, for a pool holding both
+/// tokens.
+fn virtual_orders_swap(
+    token_a_amount: u128,
+    token_b_amount: u128,
+    swap_token_a_amount: u128,
+    swap_token_b_amount: u128,
+) -> Option<(U256, U256)> {
+    let x = U256::from(swap_token_a_amount);
+    let y = U256::from(swap_token_b_amount);
+    let sold_a = U256::from(token_a_amount);
+    let sold_b = U256::from(token_b_amount);
+    let invariant = x.checked_mul(y)?;
+    if sold_a.is_zero() && sold_b.is_zero() {
+        return Some((U256::zero(), U256::zero()));
+    }
+
+    // the amount of the other token is rounded up in favor of the pool
+    let (new_swap_token_a_amount, new_swap_token_b_amount) = if sold_b.is_zero() {
+        let new_swap_token_a_amount = x.checked_add(sold_a)?;
+        (
+            new_swap_token_a_amount,
+            ceil_div_u256(invariant, new_swap_token_a_amount)?,
+        )
+    } else if sold_a.is_zero() {
+        let new_swap_token_b_amount = y.checked_add(sold_b)?;
+        (
+            ceil_div_u256(invariant, new_swap_token_b_amount)?,
+            new_swap_token_b_amount,
+        )
+    } else {
+        let p = sqrt_u256(checked_shl(x.checked_mul(sold_b)?, 64)?);
+        let q = sqrt_u256(checked_shl(y.checked_mul(sold_a)?, 64)?);
+        // 2 * sqrt(A / x) * sqrt(B / y) in Q64
+        let exponent = sqrt_u256(checked_shl(sold_a, 128)?.checked_div(x)?)
+            .checked_mul(sqrt_u256(checked_shl(sold_b, 128)?.checked_div(y)?))?
+            >> 63;
+        let e = exp_q64(exponent)?;
+        let e_sum = e.checked_mul(p.checked_add(q)?)?;
+        let numerator = e_sum
+            .checked_add(checked_shl(p, 64)?)?
+            .checked_sub(checked_shl(q, 64)?)?;
+        let denominator = e_sum
+            .checked_add(checked_shl(q, 64)?)?
+            .checked_sub(checked_shl(p, 64)?)?;
+        // scale both down so that the Q64 ratio cannot overflow
+        let shift = denominator.bits().saturating_sub(128);
+        let ratio = checked_shl(numerator >> shift, 64)?.checked_div(denominator >> shift)?;
+        let factor = q.checked_mul(ratio)?.checked_div(p)?;
+        let new_swap_token_a_amount =
+            x.checked_mul(factor)?.checked_add(U256::from(u64::MAX))? >> 64;
+        (
+            new_swap_token_a_amount,
+            ceil_div_u256(invariant, new_swap_token_a_amount)?,
+        )
+    };
+
+    let token_a_out = x
+        .checked_add(sold_a)?
+        .saturating_sub(new_swap_token_a_amount);
+    let token_b_out = y
+        .checked_add(sold_b)?
+        .saturating_sub(new_swap_token_b_amount);
+    Some((token_a_out, token_b_out))
+}
+
+/// Exponents above this are capped, for `exp(40)` already makes the TWAMM
+/// ratio 1 within the precision of Q64
+const MAX_EXPONENT: u64 = 40;
+
+/// `exp(value)` of a Q64 fixed point number, computed as the 64th power of
+/// the Taylor series of `exp(value / 64)`
+fn exp_q64(value: U256) -> Option<U256> {
+    let one = U256::one() << 64;
+    let value = std::cmp::min(value, U256::from(MAX_EXPONENT) << 64) >> 6;
+    let mut sum = one;
+    let mut term = one;
+    for k in 1..=20u64 {
+        term = (term.checked_mul(value)? >> 64).checked_div(U256::from(k))?;
+        if term.is_zero() {
+            break;
+        }
+        sum = sum.checked_add(term)?;
+    }
+    for _ in 0..6 {
+        sum = sum.checked_mul(sum)? >> 64;
+    }
+    Some(sum)
+}
+
+/// Integer square root, rounded down
+fn sqrt_u256(value: U256) -> U256 {
+    if value.is_zero() {
+        return value;
+    }
+    // starts above the root, from which the iterations decrease monotonically
+    let mut root = U256::one() << (value.bits() / 2 + 1);
+    loop {
+        let next = (root + value / root) >> 1;
+        if next >= root {
+            return root;
+        }
+        root = next;
+    }
+}
+
+fn ceil_div_u256(numerator: U256, denominator: U256) -> Option<U256> {
+    numerator
+        .checked_add(denominator.checked_sub(U256::one())?)?
+        .checked_div(denominator)
+}
+
+/// `a * b / c`, rounded in the given direction
+fn mul_div_u256(a: U256, b: U256, c: U256, round_direction: &RoundDirection) -> Option<U256> {
+    let product = a.checked_mul(b)?;
+    match round_direction {
+        RoundDirection::Floor => product.checked_div(c),
+        RoundDirection::Ceiling => ceil_div_u256(product, c),
+    }
+}
+
+fn checked_shl(value: U256, shift: usize) -> Option<U256> {
+    if value.bits() + shift > 256 {
+        None
+    } else {
+        Some(value << shift)
+    }
+}
+
+fn u256_to_u128(value: U256) -> Result<u128> {
+    if value > U256::from(u128::MAX) {
+        Err(Error::ConversionFailure.into())
+    } else {
+        Ok(value.as_u128())
+    }
+}
+
+fn fee_fraction(numerator: u64, denominator: u64) -> Option<PreciseNumber> {
+    if numerator == 0 {
+        return PreciseNumber::new(0);
+    }
+    PreciseNumber::new(u128::from(numerator))?
+        .checked_div(&PreciseNumber::new(u128::from(denominator))?)
+}
+
 /// The constant product swap calculation, factored out of its class for reuse.
 ///
-/// This is guaranteed to work for all values such that:
-///  - 1 <= swap_source_amount * swap_destination_amount <= u128::MAX
-///  - 1 <= source_amount <= u64::MAX
+/// The invariant is computed in U256, so this works for all amounts up to
+/// u128::MAX as long as `swap_source_amount + source_amount` fits in u128.
+/// It fails with `InsufficientLiquidity` when the pool cannot pay out the
+/// swap and with `ZeroTradingTokens` when the swap rounds down to nothing.
 pub fn swap(
     source_amount: u128,
     swap_source_amount: u128,
     swap_destination_amount: u128,
-) -> Option<(u128, u128)> {
-    let invariant = swap_source_amount.checked_mul(swap_destination_amount)?;
+) -> Result<(u128, u128)> {
+    if swap_source_amount == 0 || swap_destination_amount == 0 {
+        return Err(Error::InsufficientLiquidity.into());
+    }
+    let invariant = U256::from(swap_source_amount) * U256::from(swap_destination_amount);
 
-    let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
-    let (new_swap_destination_amount, new_swap_source_amount) =
-        invariant.checked_ceil_div(new_swap_source_amount)?;
+    let new_swap_source_amount = swap_source_amount
+        .checked_add(source_amount)
+        .ok_or(Error::CalculationFailure)?;
+    // the quotient is zero when the swap would take the whole destination amount
+    let (new_swap_destination_amount, new_swap_source_amount) = invariant
+        .checked_ceil_div(U256::from(new_swap_source_amount))
+        .ok_or(Error::InsufficientLiquidity)?;
 
-    let source_amount_swapped = new_swap_source_amount.checked_sub(swap_source_amount)?;
+    let source_amount_swapped = u256_to_u128(new_swap_source_amount)? - swap_source_amount;
     let destination_amount_swapped =
-        map_zero_to_none(swap_destination_amount.checked_sub(new_swap_destination_amount)?)?;
+        swap_destination_amount - u256_to_u128(new_swap_destination_amount)?;
+    if destination_amount_swapped == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
 
-    Some((source_amount_swapped, destination_amount_swapped))
+    Ok((source_amount_swapped, destination_amount_swapped))
 }
 
-/// Helper function for mapping to SwapError::CalculationFailure
-fn map_zero_to_none(x: u128) -> Option<u128> {
-    if x == 0 {
-        None
-    } else {
-        Some(x)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oracle::OraclePrice;
+
+    const MAX: u128 = u64::MAX as u128;
+
+    fn error<T: std::fmt::Debug>(result: Result<T>) -> ProgramError {
+        result.unwrap_err().into()
+    }
+
+    fn fees() -> Fees {
+        Fees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 10_000,
+            owner_withdraw_fee_numerator: 0,
+            owner_withdraw_fee_denominator: 0,
+            host_fee_numerator: 20,
+            host_fee_denominator: 100,
+        }
+    }
+
+    #[test]
+    fn swap_near_u64_max_on_both_sides() {
+        let (source_amount_swapped, destination_amount_swapped) = swap(MAX / 2, MAX, MAX).unwrap();
+        // the source amount is the least one leaving the rounded up destination amount
+        assert_eq!(source_amount_swapped, MAX / 2 - 1);
+        // a third of the destination amount, rounded in favor of the pool
+        assert_eq!(destination_amount_swapped, MAX / 3 - 1);
+
+        let (source_amount_swapped, destination_amount_swapped) = swap(1_000, MAX, MAX).unwrap();
+        assert_eq!(source_amount_swapped, 1_000);
+        assert_eq!(destination_amount_swapped, 999);
+    }
+
+    #[test]
+    fn swap_keeps_the_invariant_beyond_u128_products() {
+        // the invariant of these amounts overflows u128
+        let swap_source_amount = MAX * 1_000;
+        let swap_destination_amount = MAX * 3_000;
+        let source_amount = MAX;
+        let (source_amount_swapped, destination_amount_swapped) =
+            swap(source_amount, swap_source_amount, swap_destination_amount).unwrap();
+        let invariant = U256::from(swap_source_amount) * U256::from(swap_destination_amount);
+        let new_invariant = U256::from(swap_source_amount + source_amount_swapped)
+            * U256::from(swap_destination_amount - destination_amount_swapped);
+        assert!(new_invariant >= invariant);
+        assert_eq!(source_amount_swapped, source_amount);
+        // three times the source amount, less the slippage of 1 / 1001
+        assert_eq!(destination_amount_swapped, MAX * 3_000 / 1_001);
+    }
+
+    #[test]
+    fn swap_result_near_u64_max() {
+        let result = ConstantProduct {}.swap(MAX, MAX, MAX, &fees()).unwrap();
+        assert_eq!(result.trade_fee, MAX * 25 / 10_000);
+        assert_eq!(result.owner_fee, MAX * 5 / 10_000);
+        assert_eq!(
+            result.new_swap_source_amount,
+            MAX + result.source_amount_swapped
+        );
+        assert_eq!(
+            result.new_swap_destination_amount,
+            MAX - result.destination_amount_swapped
+        );
+        assert!(result.destination_amount_swapped < MAX / 2);
+    }
+
+    #[test]
+    fn swap_too_small_fails() {
+        assert_eq!(error(swap(1, MAX, MAX)), Error::ZeroTradingTokens.into());
+    }
+
+    #[test]
+    fn swap_without_liquidity_fails() {
+        assert_eq!(error(swap(10, 0, 10)), Error::InsufficientLiquidity.into());
+        assert_eq!(error(swap(10, 10, 0)), Error::InsufficientLiquidity.into());
+        // the last token of the destination can't be bought
+        assert_eq!(error(swap(1, MAX, 1)), Error::InsufficientLiquidity.into());
+    }
+
+    #[test]
+    fn swap_overflow_fails() {
+        assert_eq!(
+            error(swap(1, u128::MAX, 1)),
+            Error::CalculationFailure.into()
+        );
+    }
+
+    #[test]
+    fn withdraw_more_than_the_pool_holds_fails() {
+        assert_eq!(
+            error(ConstantProduct {}.withdraw_single_token_type_exact_out(
+                MAX + 1,
+                MAX,
+                MAX,
+                MAX,
+                &TradeDirection::AtoB,
+                &fees()
+            )),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn fee_with_zero_denominator_fails() {
+        assert_eq!(
+            error(crate::fees::calculate_fee(1, 1, 0)),
+            Error::FeeCalculationFailure.into()
+        );
+        let fees = Fees {
+            trade_fee_denominator: 0,
+            ..fees()
+        };
+        assert_eq!(
+            error(ConstantProduct {}.swap(MAX, MAX, MAX, &fees)),
+            Error::FeeCalculationFailure.into()
+        );
+    }
+
+    #[test]
+    fn limit_order_swap_fails_only_on_errors() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            curve.limit_order_swap(1_000, MAX, MAX, &fees()).unwrap(),
+            Some(curve.swap(1_000, MAX, MAX, &fees()).unwrap())
+        );
+        // nothing to fill the order with
+        assert_eq!(curve.limit_order_swap(1, MAX, MAX, &fees()).unwrap(), None);
+        assert_eq!(
+            curve.limit_order_swap(1_000, 0, MAX, &fees()).unwrap(),
+            None
+        );
+        assert_eq!(
+            curve.limit_order_swap(1_000, MAX, 1, &fees()).unwrap(),
+            None
+        );
+        // failures of the calculation
+        assert_eq!(
+            error(curve.limit_order_swap(1_000, u128::MAX, 1, &fees())),
+            Error::CalculationFailure.into()
+        );
+        let fees = Fees {
+            trade_fee_denominator: 0,
+            ..fees()
+        };
+        assert_eq!(
+            error(curve.limit_order_swap(1_000, MAX, MAX, &fees)),
+            Error::FeeCalculationFailure.into()
+        );
+    }
+
+    #[test]
+    fn deposit_single_token_type_exact_out_covers_the_fee() {
+        let curve = ConstantProduct {};
+        let fee_configs = [
+            fees(),
+            // the minimum fee of one token exceeds the proportional fee
+            Fees {
+                trade_fee_numerator: 1,
+                ..fees()
+            },
+            Fees {
+                trade_fee_numerator: 9_999,
+                ..fees()
+            },
+            Fees {
+                trade_fee_numerator: 0,
+                ..fees()
+            },
+        ];
+        for fees in fee_configs.iter() {
+            for (pool_tokens, swap_amount, pool_supply) in (1..200)
+                .map(|pool_tokens| (pool_tokens, 1_000, 10_000))
+                .chain([(MAX / 4, MAX, MAX), (1, MAX, 1)])
+            {
+                let source_amount = curve
+                    .deposit_single_token_type_exact_out(
+                        pool_tokens,
+                        swap_amount,
+                        swap_amount,
+                        pool_supply,
+                        &TradeDirection::AtoB,
+                        fees,
+                    )
+                    .unwrap();
+                let source_amount_less_fee = deposit_single_token_type_exact_out(
+                    pool_tokens,
+                    swap_amount,
+                    swap_amount,
+                    pool_supply,
+                    &TradeDirection::AtoB,
+                    RoundDirection::Ceiling,
+                )
+                .unwrap();
+                let trade_fee = fees
+                    .trading_fee(std::cmp::max(1, source_amount / 2))
+                    .unwrap();
+                // the fee is covered, with at most the rounding of the
+                // gross-up left over
+                let excess = (source_amount - trade_fee)
+                    .checked_sub(source_amount_less_fee)
+                    .unwrap();
+                assert!(excess <= 2, "{} over for {}", excess, pool_tokens);
+            }
+        }
+    }
+
+    /// The amounts of token A and B left in the pool by the virtual orders,
+    /// whose product must not fall below the invariant
+    fn virtual_orders_pool(
+        token_a_amount: u128,
+        token_b_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> (u128, u128) {
+        let (token_a_out, token_b_out) = ConstantProduct {}
+            .virtual_orders_swap(
+                token_a_amount,
+                token_b_amount,
+                swap_token_a_amount,
+                swap_token_b_amount,
+            )
+            .unwrap();
+        let new_swap_token_a_amount = swap_token_a_amount + token_a_amount - token_a_out;
+        let new_swap_token_b_amount = swap_token_b_amount + token_b_amount - token_b_out;
+        assert!(
+            U256::from(new_swap_token_a_amount) * U256::from(new_swap_token_b_amount)
+                >= U256::from(swap_token_a_amount) * U256::from(swap_token_b_amount)
+        );
+        (new_swap_token_a_amount, new_swap_token_b_amount)
+    }
+
+    #[test]
+    fn virtual_orders_of_one_side_are_a_swap() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            curve
+                .virtual_orders_swap(1_000, 0, 1_000_000, 2_000_000)
+                .unwrap(),
+            // 2_000_000 - ceil(2 * 10^12 / 1_001_000)
+            (0, 1_998)
+        );
+        assert_eq!(
+            curve
+                .virtual_orders_swap(0, 1_000, 1_000_000, 2_000_000)
+                .unwrap(),
+            // 1_000_000 - ceil(2 * 10^12 / 2_001_000)
+            (499, 0)
+        );
+        assert_eq!(
+            curve
+                .virtual_orders_swap(0, 0, 1_000_000, 2_000_000)
+                .unwrap(),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn virtual_orders_of_both_sides_keep_the_invariant() {
+        for (token_a_amount, token_b_amount, swap_token_a_amount, swap_token_b_amount) in [
+            (1_000, 1_000, 1_000_000, 1_000_000),
+            (1_000, 3_000, 1_000_000, 2_000_000),
+            (1, 1_000_000, 1_000_000, 1_000_000),
+            (MAX, MAX / 2, MAX, MAX),
+            (1_000_000_000, 7, 3, 1_000_000_000_000),
+        ] {
+            virtual_orders_pool(
+                token_a_amount,
+                token_b_amount,
+                swap_token_a_amount,
+                swap_token_b_amount,
+            );
+        }
+    }
+
+    #[test]
+    fn virtual_orders_of_both_sides_at_the_pool_price() {
+        // the orders cross at the price of the pool, which doesn't move
+        let (new_swap_token_a_amount, new_swap_token_b_amount) =
+            virtual_orders_pool(1_000, 2_000, 1_000_000, 2_000_000);
+        assert!(new_swap_token_a_amount.abs_diff(1_000_000) <= 1);
+        assert!(new_swap_token_b_amount.abs_diff(2_000_000) <= 2);
+    }
+
+    #[test]
+    fn virtual_orders_much_larger_than_the_pool() {
+        // the exponent saturates and the pool ends at the price of the orders,
+        // `x' = sqrt(k * A / B)`
+        let (new_swap_token_a_amount, new_swap_token_b_amount) =
+            virtual_orders_pool(1_000_000_000_000, 4_000_000_000_000, 1_000_000, 1_000_000);
+        assert!(new_swap_token_a_amount.abs_diff(500_000) <= 5_000);
+        assert!(new_swap_token_b_amount.abs_diff(2_000_000) <= 20_000);
+    }
+
+    #[test]
+    fn virtual_orders_without_liquidity_fail() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            error(curve.virtual_orders_swap(1_000, 1_000, 0, 1_000_000)),
+            Error::InsufficientLiquidity.into()
+        );
+        assert_eq!(
+            error(curve.virtual_orders_swap(1_000, 1_000, 1_000_000, 0)),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+
+    const ONE: u128 = 1 << 64;
+
+    fn fees_free() -> Fees {
+        Fees {
+            trade_fee_numerator: 0,
+            owner_trade_fee_numerator: 0,
+            ..fees()
+        }
+    }
+
+    impl Oracle for OraclePrice {
+        fn price(&self) -> Result<OraclePrice> {
+            Ok(*self)
+        }
+    }
+
+    /// Amount of the destination token of a swap on the PMM curve without fees
+    fn pmm_swap(
+        pmm: &ProactiveMarketMaker,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: &TradeDirection,
+    ) -> u128 {
+        pmm.swap(
+            source_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+            &fees_free(),
+        )
+        .unwrap()
+        .destination_amount_swapped
+    }
+
+    #[test]
+    fn pmm_with_k_0_is_a_constant_sum() {
+        let pmm = ProactiveMarketMaker { price: ONE, k: 0 };
+        for source_amount in [1, 1_000, 500_000, 999_999] {
+            assert_eq!(
+                pmm_swap(
+                    &pmm,
+                    source_amount,
+                    1_000_000,
+                    1_000_000,
+                    &TradeDirection::AtoB
+                ),
+                source_amount
+            );
+        }
+        // token A is worth 2 token B, away from the targets too
+        let pmm = ProactiveMarketMaker {
+            price: 2 * ONE,
+            k: 0,
+        };
+        assert_eq!(
+            pmm_swap(&pmm, 1_000, 1_000_000, 2_000_000, &TradeDirection::AtoB),
+            2_000
+        );
+        assert_eq!(
+            pmm_swap(&pmm, 2_000, 2_000_000, 1_000_000, &TradeDirection::BtoA),
+            1_000
+        );
+        assert_eq!(
+            pmm_swap(&pmm, 1_000, 1_500_000, 500_000, &TradeDirection::AtoB),
+            2_000
+        );
+    }
+
+    #[test]
+    fn pmm_with_k_max_matches_the_constant_product() {
+        // the pool is at its targets at the guide price of 2 token B per A
+        let pmm = ProactiveMarketMaker {
+            price: 2 * ONE,
+            k: PMM_K_DENOMINATOR,
+        };
+        for (source_amount, swap_source_amount, swap_destination_amount, trade_direction) in [
+            (1_000, 1_000_000, 2_000_000, TradeDirection::AtoB),
+            (500_000, 1_000_000, 2_000_000, TradeDirection::AtoB),
+            (1_000, 2_000_000, 1_000_000, TradeDirection::BtoA),
+            (3_000_000, 2_000_000, 1_000_000, TradeDirection::BtoA),
+        ] {
+            let (_, destination_amount_swapped) =
+                swap(source_amount, swap_source_amount, swap_destination_amount).unwrap();
+            let pmm_destination_amount_swapped = pmm_swap(
+                &pmm,
+                source_amount,
+                swap_source_amount,
+                swap_destination_amount,
+                &trade_direction,
+            );
+            assert!(pmm_destination_amount_swapped <= destination_amount_swapped);
+            assert!(destination_amount_swapped - pmm_destination_amount_swapped <= 1);
+        }
+    }
+
+    #[test]
+    fn pmm_swap_stays_on_the_curve() {
+        for k in [0, 1, 100, 5_000, 9_999, PMM_K_DENOMINATOR] {
+            let pmm = ProactiveMarketMaker { price: ONE / 3, k };
+            for (source_amount, swap_source_amount, swap_destination_amount, trade_direction) in [
+                (1_000, 3_000_000, 1_000_000, TradeDirection::AtoB),
+                (1_000_000, 2_000_000, 1_000_000, TradeDirection::AtoB),
+                (7, 1_000_000, 1_000_000, TradeDirection::BtoA),
+                (100_000, 1_000_000, 4_000_000, TradeDirection::BtoA),
+                (MAX, MAX, MAX, TradeDirection::AtoB),
+            ] {
+                let price = pmm.source_price(&trade_direction);
+                let (x0, y0) = pmm
+                    .targets(
+                        U256::from(swap_source_amount),
+                        U256::from(swap_destination_amount),
+                        &price,
+                    )
+                    .unwrap();
+                let destination_amount_swapped = pmm_swap(
+                    &pmm,
+                    source_amount,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    &trade_direction,
+                );
+                // the pool stays on or above the curve of its targets
+                let new_y = pmm
+                    .curve(
+                        U256::from(swap_source_amount + source_amount),
+                        x0,
+                        y0,
+                        &price,
+                        &RoundDirection::Ceiling,
+                    )
+                    .unwrap();
+                assert!(U256::from(swap_destination_amount - destination_amount_swapped) >= new_y);
+            }
+        }
+    }
+
+    #[test]
+    fn pmm_round_trip_favors_the_pool() {
+        for k in [0, 100, 5_000, PMM_K_DENOMINATOR] {
+            let pmm = ProactiveMarketMaker { price: ONE / 3, k };
+            for source_amount in [1, 2, 3, 999, 100_000, 2_000_000] {
+                let (swap_token_a_amount, swap_token_b_amount) = (3_000_000, 1_000_000);
+                let token_b_amount = match pmm.swap(
+                    source_amount,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    &TradeDirection::AtoB,
+                    &fees_free(),
+                ) {
+                    Ok(result) => result.destination_amount_swapped,
+                    Err(_) => continue,
+                };
+                let token_a_amount = pmm
+                    .swap(
+                        token_b_amount,
+                        swap_token_b_amount - token_b_amount,
+                        swap_token_a_amount + source_amount,
+                        &TradeDirection::BtoA,
+                        &fees_free(),
+                    )
+                    .map_or(0, |result| result.destination_amount_swapped);
+                assert!(token_a_amount <= source_amount);
+            }
+        }
+    }
+
+    #[test]
+    fn pmm_swap_failures() {
+        let pmm = ProactiveMarketMaker { price: ONE, k: 100 };
+        assert_eq!(
+            error(pmm.swap(1_000, 0, 0, &TradeDirection::AtoB, &fees())),
+            Error::InsufficientLiquidity.into()
+        );
+        // the constant sum can't pay out more than the destination token
+        let pmm = ProactiveMarketMaker { price: ONE, k: 0 };
+        assert_eq!(
+            error(pmm.swap(
+                2_000_000,
+                1_000_000,
+                1_000,
+                &TradeDirection::AtoB,
+                &fees_free()
+            )),
+            Error::InsufficientLiquidity.into()
+        );
+        // token A is worth too little to get any token B
+        let pmm = ProactiveMarketMaker {
+            price: ONE / 3,
+            k: 0,
+        };
+        assert_eq!(
+            error(pmm.swap(1, 1_000_000, 1_000_000, &TradeDirection::AtoB, &fees_free())),
+            Error::ZeroTradingTokens.into()
+        );
+    }
+
+    #[test]
+    fn pmm_falls_back_to_the_constant_product_when_stale() {
+        let parameters = PmmParameters {
+            price_feed: Pubkey::default(),
+            k: 100,
+            max_staleness: 60,
+        };
+        let oracle_price = OraclePrice {
+            price: ONE,
+            publish_ts: 1_000,
+        };
+        assert!(parameters.curve(&oracle_price, 1_060).unwrap().is_some());
+        assert!(parameters.curve(&oracle_price, 1_061).unwrap().is_none());
+        let oracle_price = OraclePrice {
+            price: 0,
+            publish_ts: 1_000,
+        };
+        assert!(parameters.curve(&oracle_price, 1_000).unwrap().is_none());
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_near_u64_max() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            curve
+                .pool_tokens_to_trading_tokens(MAX, MAX, MAX, MAX, RoundDirection::Floor)
+                .unwrap(),
+            (MAX, MAX)
+        );
+        assert_eq!(
+            curve
+                .pool_tokens_to_trading_tokens(MAX - 1, MAX, MAX, MAX - 1, RoundDirection::Floor)
+                .unwrap(),
+            (MAX - 1, MAX - 2)
+        );
+        assert_eq!(
+            curve
+                .pool_tokens_to_trading_tokens(MAX - 1, MAX, MAX, MAX - 1, RoundDirection::Ceiling)
+                .unwrap(),
+            (MAX - 1, MAX - 1)
+        );
+        // the products overflow u128 with high-decimal supplies
+        assert_eq!(
+            curve
+                .pool_tokens_to_trading_tokens(
+                    1 << 126,
+                    1 << 127,
+                    MAX * 4,
+                    MAX * 6,
+                    RoundDirection::Floor
+                )
+                .unwrap(),
+            (MAX * 2, MAX * 3)
+        );
+    }
+
+    #[test]
+    fn trading_tokens_to_pool_tokens_near_u64_max() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            curve
+                .trading_tokens_to_pool_tokens(MAX, MAX, MAX, MAX, MAX)
+                .unwrap(),
+            MAX
+        );
+        assert_eq!(
+            curve
+                .trading_tokens_to_pool_tokens(MAX, MAX * 2, 1 << 127, MAX * 2, MAX * 4)
+                .unwrap(),
+            1 << 126
+        );
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_failures() {
+        let curve = ConstantProduct {};
+        assert_eq!(
+            error(curve.pool_tokens_to_trading_tokens(MAX, 0, MAX, MAX, RoundDirection::Floor)),
+            Error::CalculationFailure.into()
+        );
+        // the amounts don't fit u128
+        assert_eq!(
+            error(curve.pool_tokens_to_trading_tokens(
+                u128::MAX,
+                1,
+                u128::MAX,
+                u128::MAX,
+                RoundDirection::Floor
+            )),
+            Error::ConversionFailure.into()
+        );
     }
 }
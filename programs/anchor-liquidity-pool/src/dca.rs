@@ -0,0 +1,650 @@
+//! DCA orders, swapping a deposit into the pool in equal amounts at the
+//! interval of the order, each cranked by anyone.
+
+use crate::error::Error;
+use crate::order_book::load_order_book;
+use crate::token_interface::{is_held_by, mint, token_account, CloseAccount, TransferChecked};
+use crate::twamm::{SyncTwamm, TwammAccounts};
+use crate::{
+    load_pmm_price_feed, load_price_feed, to_u128, to_u64, token_interface, SwapAccounts, SwapPair,
+};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_option::COption;
+
+pub fn open_dca(
+    ctx: Context<OpenDca>,
+    id: u64,
+    amount: u64,
+    amount_per_cycle: u64,
+    interval: i64,
+    minimum_amount_out_per_cycle: u64,
+    tip: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Open DCA {},{},{},{},{},{}",
+        id,
+        amount,
+        amount_per_cycle,
+        interval,
+        minimum_amount_out_per_cycle,
+        tip
+    );
+    if amount == 0 || amount_per_cycle == 0 || interval <= 0 {
+        return Err(Error::InvalidDcaOrder.into());
+    }
+
+    let dca_order = &mut ctx.accounts.dca_order;
+    dca_order.pair = ctx.accounts.pair.key();
+    dca_order.owner = ctx.accounts.owner.key();
+    dca_order.id = id;
+    dca_order.token_source_escrow = ctx.accounts.token_source_escrow.key();
+    dca_order.token_destination_escrow = ctx.accounts.token_destination_escrow.key();
+    dca_order.amount_per_cycle = amount_per_cycle;
+    dca_order.interval = interval;
+    dca_order.next_cycle_ts = Clock::get()?.unix_timestamp;
+    dca_order.minimum_amount_out_per_cycle = minimum_amount_out_per_cycle;
+    dca_order.tip = tip;
+    dca_order.amount_swapped = 0;
+    dca_order.amount_bought = 0;
+    dca_order.bump = *ctx
+        .bumps
+        .get("dca_order")
+        .ok_or(Error::InvalidProgramAddress)?;
+    ctx.accounts.pair.add_dependent_account()?;
+
+    token_interface::transfer_checked(ctx.accounts.to_transfer_source_context(), amount)?;
+    Ok(())
+}
+
+pub fn crank_dca<'info>(ctx: Context<'_, '_, '_, 'info, CrankDca<'info>>) -> Result<()> {
+    msg!("Instruction Crank DCA {}", ctx.accounts.dca_order.key());
+
+    let (mut order_book, remaining_accounts) =
+        load_order_book(&ctx.accounts.pair, ctx.remaining_accounts)?;
+    let remaining_accounts = ctx
+        .accounts
+        .sync_twamm(remaining_accounts, ctx.program_id)?;
+    let (price_feed, remaining_accounts) = load_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+    let (pmm_price_feed, _) = load_pmm_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+    ctx.accounts
+        .dca_order
+        .start_cycle(Clock::get()?.unix_timestamp)?;
+    let escrow_amount = token_account(
+        &ctx.accounts.token_source_escrow,
+        &ctx.accounts.pair.token_program,
+    )?
+    .amount;
+    let (amount_in, tip, minimum_amount_out) =
+        ctx.accounts.dca_order.cycle_amounts(escrow_amount)?;
+    msg!(
+        "amount_in={},tip={},minimum_amount_out={}",
+        amount_in,
+        tip,
+        minimum_amount_out
+    );
+
+    let dca_order = ctx.accounts.dca_order.to_account_info();
+    let signer_seeds = ctx.accounts.dca_order.signer_seeds();
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    let amount_bought = ctx.accounts.swap_accounts(&dca_order).swap(
+        amount_in,
+        minimum_amount_out,
+        &mut order_book,
+        &price_feed,
+        &pmm_price_feed,
+        &mut None,
+        signer_seeds,
+        ctx.program_id,
+    )?;
+    if tip > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_tip_context(&dca_order)
+                .with_signer(signer_seeds),
+            tip,
+        )?;
+    }
+
+    let dca_order = &mut ctx.accounts.dca_order;
+    dca_order.amount_swapped = dca_order
+        .amount_swapped
+        .checked_add(amount_in)
+        .ok_or(Error::CalculationFailure)?;
+    dca_order.amount_bought = dca_order
+        .amount_bought
+        .checked_add(to_u64(amount_bought)?)
+        .ok_or(Error::CalculationFailure)?;
+    Ok(())
+}
+
+pub fn withdraw_dca(ctx: Context<WithdrawDca>) -> Result<()> {
+    msg!("Instruction Withdraw DCA {}", ctx.accounts.dca_order.key());
+
+    let amount = token_account(
+        &ctx.accounts.token_destination_escrow,
+        &ctx.accounts.pair.token_program,
+    )?
+    .amount;
+    msg!("amount={}", amount);
+    if amount > 0 {
+        let signer_seeds = ctx.accounts.dca_order.signer_seeds();
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_destination_context()
+                .with_signer(&[&signer_seeds.value()[..]]),
+            amount,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn close_dca(ctx: Context<CloseDca>) -> Result<()> {
+    msg!("Instruction Close DCA {}", ctx.accounts.dca_order.key());
+
+    let source_amount = token_account(
+        &ctx.accounts.token_source_escrow,
+        &ctx.accounts.pair.token_program,
+    )?
+    .amount;
+    let destination_amount = token_account(
+        &ctx.accounts.token_destination_escrow,
+        &ctx.accounts.pair.token_program,
+    )?
+    .amount;
+    msg!(
+        "source_amount={},destination_amount={}",
+        source_amount,
+        destination_amount
+    );
+
+    let signer_seeds = ctx.accounts.dca_order.signer_seeds();
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if source_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_source_context()
+                .with_signer(signer_seeds),
+            source_amount,
+        )?;
+    }
+    if destination_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_destination_context()
+                .with_signer(signer_seeds),
+            destination_amount,
+        )?;
+    }
+    token_interface::close_account(
+        ctx.accounts
+            .to_close_source_context()
+            .with_signer(signer_seeds),
+    )?;
+    token_interface::close_account(
+        ctx.accounts
+            .to_close_destination_context()
+            .with_signer(signer_seeds),
+    )?;
+    ctx.accounts.pair.remove_dependent_account()?;
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct OpenDca<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = (pair.token_a_mint == token_source_mint.key() && pair.token_b_mint == token_destination_mint.key())
+            || (pair.token_b_mint == token_source_mint.key() && pair.token_a_mint == token_destination_mint.key())
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + DcaOrder::LEN,
+        seeds = [
+            b"dca_order".as_ref(),
+            pair.key().as_ref(),
+            owner.key().as_ref(),
+            id.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_owner, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_owner, &pair.token_program)?, &owner.key())
+    )]
+    pub token_source_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_escrow, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_escrow, &pair.token_program)?, &dca_order.key())
+    )]
+    pub token_source_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_destination_escrow, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_escrow, &pair.token_program)?, &dca_order.key())
+    )]
+    pub token_destination_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CrankDca<'info> {
+    pub cranker: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"dca_order".as_ref(),
+            dca_order.pair.as_ref(),
+            dca_order.owner.as_ref(),
+            dca_order.id.to_le_bytes().as_ref()
+        ],
+        bump = dca_order.bump,
+        constraint = dca_order.pair == pair.key() @ Error::InvalidDcaOrder,
+        constraint = dca_order.token_source_escrow == token_source_escrow.key() @ Error::InvalidDcaOrder,
+        constraint = dca_order.token_destination_escrow == token_destination_escrow.key() @ Error::InvalidDcaOrder,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_source_for_pda.key() || pair.token_a_account == token_destination_for_pda.key(),
+        constraint = pair.token_b_account == token_source_for_pda.key() || pair.token_b_account == token_destination_for_pda.key(),
+        constraint = pair.token_a_mint == token_source_mint.key() || pair.token_a_mint == token_destination_mint.key(),
+        constraint = pair.token_b_mint == token_source_mint.key() || pair.token_b_mint == token_destination_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_escrow, &pair.token_program)?.mint == token_source_mint.key()
+    )]
+    pub token_source_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_escrow, &pair.token_program)?.mint == token_destination_mint.key()
+    )]
+    pub token_destination_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_pda, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_source_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_pda, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_destination_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_cranker, &pair.token_program)?.mint == token_source_mint.key()
+    )]
+    pub token_source_for_cranker: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawDca<'info> {
+    pub owner: Signer<'info>,
+
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        constraint = dca_order.owner == owner.key() @ Error::InvalidOwner,
+        constraint = dca_order.pair == pair.key() @ Error::InvalidDcaOrder,
+        constraint = dca_order.token_destination_escrow == token_destination_escrow.key() @ Error::InvalidDcaOrder,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the escrow
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_escrow, &pair.token_program)?.mint == token_destination_mint.key()
+    )]
+    pub token_destination_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_owner, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = token_account(&token_destination_for_owner, &pair.token_program)?.owner == owner.key()
+    )]
+    pub token_destination_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseDca<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(mut)]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        mut,
+        close = owner,
+        constraint = dca_order.owner == owner.key() @ Error::InvalidOwner,
+        constraint = dca_order.pair == pair.key() @ Error::InvalidDcaOrder,
+        constraint = dca_order.token_source_escrow == token_source_escrow.key() @ Error::InvalidDcaOrder,
+        constraint = dca_order.token_destination_escrow == token_destination_escrow.key() @ Error::InvalidDcaOrder,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the escrow
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the escrow
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_escrow, &pair.token_program)?.mint == token_source_mint.key()
+    )]
+    pub token_source_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_escrow, &pair.token_program)?.mint == token_destination_mint.key()
+    )]
+    pub token_destination_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_owner, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = token_account(&token_source_for_owner, &pair.token_program)?.owner == owner.key()
+    )]
+    pub token_source_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_owner, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = token_account(&token_destination_for_owner, &pair.token_program)?.owner == owner.key()
+    )]
+    pub token_destination_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> OpenDca<'info> {
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_owner.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_escrow.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> SyncTwamm<'info> for CrankDca<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let dca_order = self.dca_order.to_account_info();
+        let mut accounts = self.swap_accounts(&dca_order);
+        let trade_direction = accounts.trade_direction();
+        accounts.execute_virtual_orders(&trade_direction, twamm, program_id)
+    }
+}
+
+impl<'info> CrankDca<'info> {
+    fn swap_accounts<'a>(
+        &'a mut self,
+        dca_order: &'a AccountInfo<'info>,
+    ) -> SwapAccounts<'a, 'info> {
+        SwapAccounts {
+            swapper: dca_order,
+            pda: &self.pda,
+            pool: &self.pool,
+            pair: &mut self.pair,
+            token_source_mint: &self.token_source_mint,
+            token_destination_mint: &self.token_destination_mint,
+            token_source_for_swapper: &self.token_source_escrow,
+            token_destination_for_swapper: &self.token_destination_escrow,
+            token_source_for_pda: &self.token_source_for_pda,
+            token_destination_for_pda: &self.token_destination_for_pda,
+            pool_fee_account: &self.pool_fee_account,
+            token_program: &self.token_program,
+        }
+    }
+
+    fn to_transfer_tip_context(
+        &self,
+        dca_order: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_escrow.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_cranker.to_account_info().clone(),
+            authority: dca_order.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> WithdrawDca<'info> {
+    fn to_transfer_destination_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_escrow.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self.token_destination_for_owner.to_account_info().clone(),
+            authority: self.dca_order.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> CloseDca<'info> {
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_escrow.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_owner.to_account_info().clone(),
+            authority: self.dca_order.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_destination_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_escrow.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self.token_destination_for_owner.to_account_info().clone(),
+            authority: self.dca_order.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_close_source_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.token_source_escrow.to_account_info().clone(),
+            destination: self.owner.to_account_info().clone(),
+            authority: self.dca_order.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_close_destination_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.token_destination_escrow.to_account_info().clone(),
+            destination: self.owner.to_account_info().clone(),
+            authority: self.dca_order.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Recurring order of `owner` swapping `amount_per_cycle` of the source token
+/// every `interval` seconds, created at the PDA derived from
+/// `["dca_order", pair, owner, id]`. The PDA holds both escrows and is the
+/// swapper of the cycles, whose proceeds stay in the destination escrow until
+/// withdrawn.
+#[account]
+pub struct DcaOrder {
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub id: u64,
+    pub token_source_escrow: Pubkey,
+    pub token_destination_escrow: Pubkey,
+    pub amount_per_cycle: u64,
+    pub interval: i64,
+    /// Time from which the next cycle can be cranked
+    pub next_cycle_ts: i64,
+    /// Minimum amount bought by a full cycle, scaled down for a shorter last one
+    pub minimum_amount_out_per_cycle: u64,
+    /// Source tokens paid to the cranker of each cycle
+    pub tip: u64,
+    pub amount_swapped: u64,
+    pub amount_bought: u64,
+    pub bump: u8,
+}
+
+impl DcaOrder {
+    pub const LEN: usize = 32 * 4 + 8 * 9 + 1;
+
+    /// Moves the schedule to the first cycle after `now`, skipping the cycles
+    /// missed by the cranks rather than catching up on them
+    fn start_cycle(&mut self, now: i64) -> Result<()> {
+        if now < self.next_cycle_ts {
+            return Err(Error::DcaCycleNotDue.into());
+        }
+        let cycles = (now - self.next_cycle_ts)
+            .checked_div(self.interval)
+            .and_then(|missed| missed.checked_add(1))
+            .ok_or(Error::CalculationFailure)?;
+        self.next_cycle_ts = self
+            .interval
+            .checked_mul(cycles)
+            .and_then(|delay| self.next_cycle_ts.checked_add(delay))
+            .ok_or(Error::CalculationFailure)?;
+        Ok(())
+    }
+
+    /// Amount swapped, tip and minimum amount out of the next cycle, the last
+    /// one swapping whatever the tip leaves in the escrow
+    fn cycle_amounts(&self, escrow_amount: u64) -> Result<(u64, u64, u64)> {
+        let tip = std::cmp::min(self.tip, escrow_amount);
+        let amount_in = std::cmp::min(self.amount_per_cycle, escrow_amount - tip);
+        if amount_in == 0 {
+            return Err(Error::DcaOrderCompleted.into());
+        }
+        let minimum_amount_out = to_u128(self.minimum_amount_out_per_cycle)?
+            .checked_mul(to_u128(amount_in)?)
+            .and_then(|amount| amount.checked_div(to_u128(self.amount_per_cycle).ok()?))
+            .ok_or(Error::CalculationFailure)?;
+        Ok((amount_in, tip, to_u64(minimum_amount_out)?))
+    }
+
+    fn signer_seeds(&self) -> DcaOrderSignerSeeds {
+        DcaOrderSignerSeeds(self.pair, self.owner, self.id.to_le_bytes(), [self.bump])
+    }
+}
+
+/// Seeds of a `DcaOrder`, which signs for its escrows
+struct DcaOrderSignerSeeds(Pubkey, Pubkey, [u8; 8], [u8; 1]);
+
+impl DcaOrderSignerSeeds {
+    pub fn value(&self) -> [&[u8]; 5] {
+        [
+            b"dca_order".as_ref(),
+            self.0.as_ref(),
+            self.1.as_ref(),
+            &self.2,
+            &self.3,
+        ]
+    }
+}
@@ -103,6 +103,117 @@ pub enum Error {
     /// The operation cannot be performed on the given curve
     #[error("The operation cannot be performed on the given curve")]
     UnsupportedCurveOperation,
+    /// The provided host is not registered for the swap pair
+    #[error("The provided host is not registered for the swap pair")]
+    InvalidHost,
+    /// The fee tier is not allowed by the config or does not match the fees
+    #[error("The fee tier is not allowed by the config or does not match the fees")]
+    InvalidFeeTier,
+
+    // 30.
+    /// The swap token mints are not in ascending order
+    #[error("The swap token mints are not in ascending order")]
+    InvalidMintOrder,
+    /// The swap token accounts still hold tokens
+    #[error("The swap token accounts still hold tokens")]
+    PoolNotEmpty,
+    /// The multi pool must hold between 3 and 8 tokens
+    #[error("The multi pool must hold between 3 and 8 tokens")]
+    InvalidTokenCount,
+    /// The tick spacing must be between 1 and 16384
+    #[error("The tick spacing must be between 1 and 16384")]
+    InvalidTickSpacing,
+    /// The ticks must be ordered multiples of the tick spacing within bounds
+    #[error("The ticks must be ordered multiples of the tick spacing within bounds")]
+    InvalidTickRange,
+
+    // 35.
+    /// The tick arrays do not cover the ticks, in order
+    #[error("The tick arrays do not cover the ticks, in order")]
+    InvalidTickArray,
+    /// The square root price is out of bounds or on the wrong side of the price
+    #[error("The square root price is out of bounds or on the wrong side of the price")]
+    InvalidSqrtPrice,
+    /// The position still holds liquidity or owed tokens
+    #[error("The position still holds liquidity or owed tokens")]
+    PositionNotEmpty,
+    /// The order book of the pair is full
+    #[error("The order book of the pair is full")]
+    OrderBookFull,
+    /// The order does not exist in the order book
+    #[error("The order does not exist in the order book")]
+    OrderNotFound,
+
+    // 40.
+    /// The order book accounts of the pair are missing or incorrect
+    #[error("The order book accounts of the pair are missing or incorrect")]
+    InvalidOrderBook,
+    /// The duration of the long-term order is zero or longer than its amount
+    #[error("The duration of the long-term order is zero or longer than its amount")]
+    InvalidOrderDuration,
+    /// The TWAMM accounts of the pair are missing or incorrect
+    #[error("The TWAMM accounts of the pair are missing or incorrect")]
+    InvalidTwamm,
+    /// The mint has the TransferFee extension, which is not supported here
+    #[error("The mint has the TransferFee extension, which is not supported here")]
+    TransferFeeNotSupported,
+    /// The DCA order is invalid
+    #[error("The DCA order is invalid")]
+    InvalidDcaOrder,
+
+    // 45.
+    /// The next cycle of the DCA order is not due yet
+    #[error("The next cycle of the DCA order is not due yet")]
+    DcaCycleNotDue,
+    /// The DCA order has nothing left to swap
+    #[error("The DCA order has nothing left to swap")]
+    DcaOrderCompleted,
+    /// The farm is paused
+    #[error("The farm is paused")]
+    FarmPaused,
+    /// The reward accounts of the farm are missing or incorrect
+    #[error("The reward accounts of the farm are missing or incorrect")]
+    InvalidFarmReward,
+    /// The stake is smaller than the amount unstaked
+    #[error("The stake is smaller than the amount unstaked")]
+    InsufficientStake,
+
+    // 50.
+    /// The position is locked until its unlock time
+    #[error("The position is locked until its unlock time")]
+    PositionLocked,
+    /// The unlock time must be in the future
+    #[error("The unlock time must be in the future")]
+    InvalidUnlockTime,
+    /// The locked position or its accounts are invalid
+    #[error("The locked position or its accounts are invalid")]
+    InvalidLockedPosition,
+    /// The swap moves the price beyond the limits of the circuit breaker
+    #[error("The swap moves the price beyond the limits of the circuit breaker")]
+    CircuitBreakerTripped,
+    /// The circuit breaker parameters are invalid
+    #[error("The circuit breaker parameters are invalid")]
+    InvalidCircuitBreaker,
+
+    // 55.
+    /// The price feed is missing or invalid
+    #[error("The price feed is missing or invalid")]
+    InvalidPriceFeed,
+    /// The oracle price is stale
+    #[error("The oracle price is stale")]
+    StaleOraclePrice,
+    /// The swap moves the price too far from the oracle price
+    #[error("The swap moves the price too far from the oracle price")]
+    OraclePriceDeviation,
+    /// The pool does not hold enough tokens for the operation
+    #[error("The pool does not hold enough tokens for the operation")]
+    InsufficientLiquidity,
+    /// The pair still has an order book, a TWAMM or dependent accounts
+    #[error("The pair still has an order book, a TWAMM or dependent accounts")]
+    PairInUse,
+    /// The account is not a DCA order, farm or locked position of the pair
+    #[error("The account is not a DCA order, farm or locked position of the pair")]
+    InvalidDependentAccount,
 }
 
 impl From<Error> for ProgramError {
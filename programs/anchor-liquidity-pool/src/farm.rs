@@ -0,0 +1,515 @@
+//! Farms of a pair, streaming rewards to the pool tokens staked in them.
+
+use crate::error::Error;
+use crate::token_interface::{is_held_by, mint, token_account, TransferChecked};
+use crate::{admin_pubkey, concentrated, to_u128, to_u64, token_interface, SwapPair};
+use anchor_lang::prelude::*;
+
+pub fn initialize(ctx: Context<InitializeFarm>) -> Result<()> {
+    msg!("Instruction Farm Init {}", ctx.accounts.pair.key());
+
+    // the stake vault must receive exactly what is staked
+    if token_interface::has_transfer_fee(&ctx.accounts.pool)? {
+        return Err(Error::TransferFeeNotSupported.into());
+    }
+
+    let farm = &mut ctx.accounts.farm;
+    farm.pair = ctx.accounts.pair.key();
+    farm.pool_mint = ctx.accounts.pool.key();
+    farm.stake_vault = ctx.accounts.stake_vault.key();
+    farm.token_program = ctx.accounts.pair.token_program;
+    farm.paused = false;
+    farm.total_shares = 0;
+    farm.last_update_ts = Clock::get()?.unix_timestamp;
+    farm.rewards = Vec::new();
+    farm.bump = *ctx.bumps.get("farm").ok_or(Error::InvalidProgramAddress)?;
+    ctx.accounts.pair.add_dependent_account()?;
+    Ok(())
+}
+
+pub fn add_reward(ctx: Context<AddFarmReward>, emission_rate: u64) -> Result<()> {
+    msg!("Instruction Add Farm Reward {}", emission_rate);
+
+    let farm = &mut ctx.accounts.farm;
+    if farm.rewards.len() >= Farm::MAX_REWARDS {
+        return Err(Error::InvalidFarmReward.into());
+    }
+    farm.update(Clock::get()?.unix_timestamp)?;
+    farm.rewards.push(FarmReward {
+        mint: ctx.accounts.reward_mint.key(),
+        vault: ctx.accounts.reward_vault.key(),
+        emission_rate,
+        reward_growth: 0,
+    });
+    Ok(())
+}
+
+pub fn set_emission_rate(
+    ctx: Context<UpdateFarm>,
+    reward_index: u8,
+    emission_rate: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Set Farm Emission Rate {},{}",
+        reward_index,
+        emission_rate
+    );
+
+    let farm = &mut ctx.accounts.farm;
+    farm.update(Clock::get()?.unix_timestamp)?;
+    farm.rewards
+        .get_mut(usize::from(reward_index))
+        .ok_or(Error::InvalidFarmReward)?
+        .emission_rate = emission_rate;
+    Ok(())
+}
+
+pub fn set_paused(ctx: Context<UpdateFarm>, paused: bool) -> Result<()> {
+    msg!("Instruction Set Farm Paused {}", paused);
+
+    let farm = &mut ctx.accounts.farm;
+    farm.update(Clock::get()?.unix_timestamp)?;
+    farm.paused = paused;
+    Ok(())
+}
+
+pub fn initialize_stake(ctx: Context<InitializeFarmStake>) -> Result<()> {
+    msg!("Instruction Farm Stake Init {}", ctx.accounts.farm.key());
+
+    let farm_stake = &mut ctx.accounts.farm_stake;
+    farm_stake.farm = ctx.accounts.farm.key();
+    farm_stake.owner = ctx.accounts.owner.key();
+    farm_stake.amount = 0;
+    farm_stake.locked_shares = 0;
+    farm_stake.rewards = Vec::new();
+    farm_stake.bump = *ctx
+        .bumps
+        .get("farm_stake")
+        .ok_or(Error::InvalidProgramAddress)?;
+    Ok(())
+}
+
+pub fn stake(ctx: Context<ModifyStake>, amount: u64) -> Result<()> {
+    msg!("Instruction Stake {}", amount);
+    if ctx.accounts.farm.paused {
+        return Err(Error::FarmPaused.into());
+    }
+
+    ctx.accounts.update(i128::from(amount))?;
+    token_interface::transfer_checked(ctx.accounts.to_transfer_in_context(), amount)?;
+    Ok(())
+}
+
+pub fn unstake(ctx: Context<ModifyStake>, amount: u64) -> Result<()> {
+    msg!("Instruction Unstake {}", amount);
+
+    ctx.accounts.update(-i128::from(amount))?;
+    let signer_seeds = ctx.accounts.farm.signer_seeds();
+    token_interface::transfer_checked(
+        ctx.accounts
+            .to_transfer_out_context()
+            .with_signer(&[&signer_seeds.value()[..]]),
+        amount,
+    )?;
+    Ok(())
+}
+
+pub fn claim_rewards<'info>(ctx: Context<'_, '_, '_, 'info, ClaimRewards<'info>>) -> Result<()> {
+    msg!(
+        "Instruction Claim Rewards {}",
+        ctx.accounts.farm_stake.key()
+    );
+
+    let farm = &mut ctx.accounts.farm;
+    farm.update(Clock::get()?.unix_timestamp)?;
+    let farm_stake = &mut ctx.accounts.farm_stake;
+    farm_stake.update(farm, 0)?;
+    let accounts = farm.reward_transfer_accounts(ctx.remaining_accounts)?;
+
+    let signer_seeds = farm.signer_seeds();
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    for ((reward_mint, reward_vault, reward_for_owner), reward) in
+        accounts.into_iter().zip(farm_stake.rewards.iter_mut())
+    {
+        let vault_amount = token_account(reward_vault, &farm.token_program)?.amount;
+        let amount = std::cmp::min(reward.rewards_owed, vault_amount);
+        msg!("{}={}", reward_mint.key(), amount);
+        if amount == 0 {
+            continue;
+        }
+        reward.rewards_owed -= amount;
+        let cpi_accounts = TransferChecked {
+            from: reward_vault.clone(),
+            mint: reward_mint.clone(),
+            to: reward_for_owner.clone(),
+            authority: farm.to_account_info(),
+        };
+        token_interface::transfer_checked(
+            CpiContext::new(ctx.accounts.token_program.clone(), cpi_accounts)
+                .with_signer(signer_seeds),
+            amount,
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeFarm<'info> {
+    #[account(mut, constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(mut, constraint = pair.pool_mint == pool.key())]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Farm::LEN,
+        seeds = [b"farm".as_ref(), pair.key().as_ref()],
+        bump
+    )]
+    pub farm: Box<Account<'info, Farm>>,
+
+    /// CHECK: This is not dangerous because it must be the pool mint of the pair
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&stake_vault, &pair.token_program)?.mint == pool.key(),
+        constraint = is_held_by(&token_account(&stake_vault, &pair.token_program)?, &farm.key())
+    )]
+    pub stake_vault: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddFarmReward<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub farm: Box<Account<'info, Farm>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = mint(&reward_mint, &farm.token_program).is_ok() @ Error::InvalidFarmReward
+    )]
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&reward_vault, &farm.token_program)?.mint == reward_mint.key(),
+        constraint = is_held_by(&token_account(&reward_vault, &farm.token_program)?, &farm.key()),
+        constraint = reward_vault.key() != farm.stake_vault
+    )]
+    pub reward_vault: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFarm<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub farm: Box<Account<'info, Farm>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeFarmStake<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub farm: Box<Account<'info, Farm>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + FarmStake::LEN,
+        seeds = [b"farm_stake".as_ref(), farm.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub farm_stake: Box<Account<'info, FarmStake>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ModifyStake<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = farm.pool_mint == pool.key(),
+        constraint = farm.stake_vault == stake_vault.key(),
+    )]
+    pub farm: Box<Account<'info, Farm>>,
+
+    #[account(
+        mut,
+        constraint = farm_stake.farm == farm.key(),
+        constraint = farm_stake.owner == owner.key() @ Error::InvalidOwner,
+    )]
+    pub farm_stake: Box<Account<'info, FarmStake>>,
+
+    /// CHECK: This is not dangerous because it must be the pool mint of the farm
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the stake vault of the farm
+    #[account(mut)]
+    pub stake_vault: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_owner, &farm.token_program)?.mint == pool.key(),
+        constraint = token_account(&token_pool_for_owner, &farm.token_program)?.owner == owner.key()
+    )]
+    pub token_pool_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the farm
+    #[account(
+        constraint = token_program.key() == farm.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(mut)]
+    pub farm: Box<Account<'info, Farm>>,
+
+    #[account(
+        mut,
+        constraint = farm_stake.farm == farm.key(),
+        constraint = farm_stake.owner == owner.key() @ Error::InvalidOwner,
+    )]
+    pub farm_stake: Box<Account<'info, FarmStake>>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the farm
+    #[account(
+        constraint = token_program.key() == farm.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> ModifyStake<'info> {
+    /// Accrues the rewards of the farm and settles those of the stake, then
+    /// adds the amount delta to both
+    fn update(&mut self, amount_delta: i128) -> Result<()> {
+        self.farm.update(Clock::get()?.unix_timestamp)?;
+        self.farm_stake.update(&self.farm, amount_delta)?;
+        self.farm.total_shares = add_stake_delta(self.farm.total_shares, amount_delta)?;
+        msg!(
+            "amount={},total_shares={}",
+            self.farm_stake.amount,
+            self.farm.total_shares
+        );
+        Ok(())
+    }
+
+    fn to_transfer_in_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_owner.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.stake_vault.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_out_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.stake_vault.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_owner.to_account_info().clone(),
+            authority: self.farm.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Liquidity mining of the LP tokens of a pair, created by the admin at the
+/// PDA derived from `["farm", pair]`. The farm holds the staked LP tokens and
+/// the reward vaults, which pay the accrued rewards up to their balance.
+#[account]
+pub struct Farm {
+    pub pair: Pubkey,
+    pub pool_mint: Pubkey,
+    pub stake_vault: Pubkey,
+    pub token_program: Pubkey,
+    pub paused: bool,
+    /// LP tokens staked plus the shares of the locked positions boosting the
+    /// stakes
+    pub total_shares: u64,
+    /// Time up to which the rewards have been accrued
+    pub last_update_ts: i64,
+    pub rewards: Vec<FarmReward>,
+    pub bump: u8,
+}
+
+impl Farm {
+    pub const MAX_REWARDS: usize = 4;
+    pub const LEN: usize = 32 * 4 + 1 + 8 + 8 + (4 + FarmReward::LEN * Farm::MAX_REWARDS) + 1;
+
+    /// Accrues the emissions since `last_update_ts` to the reward growth of
+    /// every reward, unless the farm is paused or nothing is staked
+    pub fn update(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_update_ts {
+            return Ok(());
+        }
+        let elapsed =
+            u64::try_from(now - self.last_update_ts).map_err(|_| Error::ConversionFailure)?;
+        self.last_update_ts = now;
+        if self.paused || self.total_shares == 0 {
+            return Ok(());
+        }
+        for reward in self.rewards.iter_mut() {
+            let emitted = to_u128(reward.emission_rate)?
+                .checked_mul(to_u128(elapsed)?)
+                .ok_or(Error::CalculationFailure)?;
+            // the same Q64.64 accumulator as the fee growth of the concentrated pools
+            let growth = concentrated::fee_growth(emitted, to_u128(self.total_shares)?)?;
+            reward.reward_growth = reward.reward_growth.wrapping_add(growth);
+        }
+        Ok(())
+    }
+
+    /// Splits the remaining accounts into the mint, the vault and the token
+    /// account of the owner for every reward in order
+    fn reward_transfer_accounts<'a, 'info>(
+        &self,
+        remaining_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<
+        Vec<(
+            &'a AccountInfo<'info>,
+            &'a AccountInfo<'info>,
+            &'a AccountInfo<'info>,
+        )>,
+    > {
+        if remaining_accounts.len() != 3 * self.rewards.len() {
+            return Err(Error::InvalidFarmReward.into());
+        }
+        remaining_accounts
+            .chunks(3)
+            .zip(self.rewards.iter())
+            .map(|(accounts, reward)| {
+                let (reward_mint, reward_vault, reward_for_owner) =
+                    (&accounts[0], &accounts[1], &accounts[2]);
+                if reward_mint.key() != reward.mint
+                    || reward_vault.key() != reward.vault
+                    || token_account(reward_for_owner, &self.token_program)?.mint != reward.mint
+                {
+                    return Err(Error::InvalidFarmReward.into());
+                }
+                Ok((reward_mint, reward_vault, reward_for_owner))
+            })
+            .collect()
+    }
+
+    fn signer_seeds(&self) -> FarmSignerSeeds {
+        FarmSignerSeeds(self.pair, [self.bump])
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct FarmReward {
+    pub mint: Pubkey,
+    /// Token account of the mint held by the farm
+    pub vault: Pubkey,
+    /// Tokens emitted per second to the stakers
+    pub emission_rate: u64,
+    /// Rewards earned per share, in Q64.64
+    pub reward_growth: u128,
+}
+
+impl FarmReward {
+    pub const LEN: usize = 32 * 2 + 8 + 16;
+}
+
+/// LP tokens staked by `owner` in a farm, created at the PDA derived from
+/// `["farm_stake", farm, owner]`
+#[account]
+pub struct FarmStake {
+    pub farm: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    /// Shares of the locked positions boosting the stake
+    pub locked_shares: u64,
+    /// Rewards of the stake, in the order of the rewards of the farm
+    pub rewards: Vec<StakeReward>,
+    pub bump: u8,
+}
+
+impl FarmStake {
+    pub const LEN: usize = 32 * 2 + 8 * 2 + (4 + StakeReward::LEN * Farm::MAX_REWARDS) + 1;
+
+    /// Settles the rewards earned since the last update then adds the amount
+    /// delta
+    fn update(&mut self, farm: &Farm, amount_delta: i128) -> Result<()> {
+        self.settle(farm)?;
+        self.amount = add_stake_delta(self.amount, amount_delta)?;
+        Ok(())
+    }
+
+    /// Settles the rewards earned since the last update then adds the delta
+    /// of the shares of the locked positions
+    pub fn update_locked_shares(&mut self, farm: &Farm, shares_delta: i128) -> Result<()> {
+        self.settle(farm)?;
+        self.locked_shares = add_stake_delta(self.locked_shares, shares_delta)?;
+        Ok(())
+    }
+
+    fn settle(&mut self, farm: &Farm) -> Result<()> {
+        let shares = self
+            .amount
+            .checked_add(self.locked_shares)
+            .ok_or(Error::CalculationFailure)?;
+        for (index, farm_reward) in farm.rewards.iter().enumerate() {
+            // a reward added to the farm after the last update has grown from zero
+            if index == self.rewards.len() {
+                self.rewards.push(StakeReward::default());
+            }
+            let reward = &mut self.rewards[index];
+            let earned = concentrated::fees_earned(
+                to_u128(shares)?,
+                farm_reward.reward_growth,
+                reward.reward_growth_last,
+            )?;
+            reward.rewards_owed = reward
+                .rewards_owed
+                .checked_add(to_u64(earned)?)
+                .ok_or(Error::CalculationFailure)?;
+            reward.reward_growth_last = farm_reward.reward_growth;
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct StakeReward {
+    pub reward_growth_last: u128,
+    pub rewards_owed: u64,
+}
+
+impl StakeReward {
+    pub const LEN: usize = 16 + 8;
+}
+
+/// Seeds of a `Farm`, which signs for the stake and reward vaults
+struct FarmSignerSeeds(Pubkey, [u8; 1]);
+
+impl FarmSignerSeeds {
+    pub fn value(&self) -> [&[u8]; 3] {
+        [b"farm".as_ref(), self.0.as_ref(), &self.1]
+    }
+}
+
+pub fn add_stake_delta(amount: u64, amount_delta: i128) -> Result<u64> {
+    i128::from(amount)
+        .checked_add(amount_delta)
+        .and_then(|amount| u64::try_from(amount).ok())
+        .ok_or_else(|| Error::InsufficientStake.into())
+}
@@ -1,5 +1,6 @@
 use crate::error::Error;
 use anchor_lang::prelude::*;
+use spl_math::uint::U256;
 
 /// Encapsulates all fee information and calculations for swap operations
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
@@ -35,43 +36,75 @@ pub struct Fees {
 }
 
 impl Fees {
+    pub const LEN: usize = 8 * 8;
+
     /// Calculate the withdraw fee in pool tokens
-    pub fn owner_withdraw_fee(&self, pool_tokens: u128) -> Option<u128> {
+    pub fn owner_withdraw_fee(&self, pool_tokens: u128) -> Result<u128> {
         calculate_fee(
             pool_tokens,
-            u128::try_from(self.owner_withdraw_fee_numerator).ok()?,
-            u128::try_from(self.owner_withdraw_fee_denominator).ok()?,
+            u128::from(self.owner_withdraw_fee_numerator),
+            u128::from(self.owner_withdraw_fee_denominator),
         )
     }
 
     /// Calculate the trading fee in trading tokens
-    pub fn trading_fee(&self, trading_tokens: u128) -> Option<u128> {
+    pub fn trading_fee(&self, trading_tokens: u128) -> Result<u128> {
         calculate_fee(
             trading_tokens,
-            u128::try_from(self.trade_fee_numerator).ok()?,
-            u128::try_from(self.trade_fee_denominator).ok()?,
+            u128::from(self.trade_fee_numerator),
+            u128::from(self.trade_fee_denominator),
         )
     }
 
     /// Calculate the owner trading fee in trading tokens
-    pub fn owner_trading_fee(&self, trading_tokens: u128) -> Option<u128> {
+    pub fn owner_trading_fee(&self, trading_tokens: u128) -> Result<u128> {
         calculate_fee(
             trading_tokens,
-            u128::try_from(self.owner_trade_fee_numerator).ok()?,
-            u128::try_from(self.owner_trade_fee_denominator).ok()?,
+            u128::from(self.owner_trade_fee_numerator),
+            u128::from(self.owner_trade_fee_denominator),
         )
     }
 
     /// Calculate the host fee based on the owner fee, only used in production
     /// situations where a program is hosted by multiple frontends
-    pub fn host_fee(&self, owner_fee: u128) -> Option<u128> {
+    pub fn host_fee(&self, owner_fee: u128) -> Result<u128> {
         calculate_fee(
             owner_fee,
-            u128::try_from(self.host_fee_numerator).ok()?,
-            u128::try_from(self.host_fee_denominator).ok()?,
+            u128::from(self.host_fee_numerator),
+            u128::from(self.host_fee_denominator),
         )
     }
 
+    /// Calculate the host fee with a host specific numerator, used for hosts
+    /// registered with their own fee share
+    pub fn host_fee_with_numerator(
+        &self,
+        owner_fee: u128,
+        host_fee_numerator: u64,
+    ) -> Result<u128> {
+        calculate_fee(
+            owner_fee,
+            u128::from(host_fee_numerator),
+            u128::from(self.host_fee_denominator),
+        )
+    }
+
+    /// Validate that a host specific fee numerator is reasonable
+    pub fn validate_host_fee_numerator(&self, host_fee_numerator: u64) -> Result<()> {
+        validate_fraction(host_fee_numerator, self.host_fee_denominator)
+    }
+
+    /// Validate that the trade fee is the given fee tier, in basis points
+    pub fn validate_fee_tier(&self, fee_tier: u16) -> Result<()> {
+        if u128::from(self.trade_fee_denominator) == BASIS_POINTS_DENOMINATOR
+            && self.trade_fee_numerator == u64::from(fee_tier)
+        {
+            Ok(())
+        } else {
+            Err(Error::InvalidFeeTier.into())
+        }
+    }
+
     /// Validate that the fees are reasonable
     pub fn validate(&self) -> Result<()> {
         validate_fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
@@ -88,12 +121,178 @@ impl Fees {
     }
 }
 
+/// How the owner's share of the trading fee is paid out
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OwnerFeeMode {
+    /// The owner fee is converted to pool tokens, which are minted to the
+    /// pool fee account (and the host fee account, if any).
+    PoolToken,
+    /// The owner fee stays in the swap token accounts as token A or B and is
+    /// tracked on the pair until it is swept by `collect_protocol_fees`.
+    /// Hosts can't be registered for a pair in this mode.
+    UnderlyingToken,
+}
+
+/// Basis points denominator, used for the dynamic fee reduction factor and
+/// the measured price moves
+pub const BASIS_POINTS_DENOMINATOR: u128 = 10_000;
+
+/// Precision of `DynamicFeeParameters::variable_fee_control`, applied to the
+/// square of the volatility accumulator
+pub const VARIABLE_FEE_PRECISION: u128 = 1_000_000;
+
+/// Parameters of the dynamic fee mode, in which the trade fee scales with a
+/// volatility accumulator derived from recent price moves, similar to the
+/// variable fee of Trader Joe's Liquidity Book.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct DynamicFeeParameters {
+    /// Lowest trade fee numerator, over `Fees::trade_fee_denominator`
+    pub min_fee_numerator: u64,
+    /// Highest trade fee numerator, over `Fees::trade_fee_denominator`
+    pub max_fee_numerator: u64,
+    /// Seconds during which swaps keep accumulating on the same references
+    pub filter_period: i64,
+    /// Seconds without swaps after which the volatility reference is reset
+    pub decay_period: i64,
+    /// Share of the volatility accumulator kept as the volatility reference
+    /// once the filter period has elapsed, in basis points
+    pub reduction_factor: u64,
+    /// Trade fee numerator added per squared basis point of volatility,
+    /// scaled by `VARIABLE_FEE_PRECISION`
+    pub variable_fee_control: u64,
+    /// Upper bound of the volatility accumulator, in basis points
+    pub max_volatility_accumulator: u64,
+}
+
+impl DynamicFeeParameters {
+    pub const LEN: usize = 8 * 7;
+
+    /// Validate that the dynamic fee parameters are reasonable
+    pub fn validate(&self, fees: &Fees) -> Result<()> {
+        validate_fraction(self.max_fee_numerator, fees.trade_fee_denominator)?;
+        if self.min_fee_numerator > self.max_fee_numerator
+            || self.filter_period < 0
+            || self.filter_period > self.decay_period
+            || u128::from(self.reduction_factor) > BASIS_POINTS_DENOMINATOR
+        {
+            return Err(Error::InvalidFee.into());
+        }
+        Ok(())
+    }
+}
+
+/// Volatility tracked across swaps for the dynamic fee mode
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct DynamicFeeState {
+    /// Price move since `price_reference` plus the volatility reference, in
+    /// basis points
+    pub volatility_accumulator: u64,
+    /// Decayed volatility carried over from previous swaps, in basis points
+    pub volatility_reference: u64,
+    /// Spot price the price moves are measured against, see `spot_price`
+    pub price_reference: u128,
+    /// Unix timestamp of the last reference update
+    pub last_update_timestamp: i64,
+}
+
+impl DynamicFeeState {
+    pub const LEN: usize = 8 + 8 + 16 + 8;
+
+    /// Moves the references forward once the filter period has elapsed,
+    /// decaying the volatility reference or resetting it after the decay period.
+    pub fn update_references(
+        &mut self,
+        parameters: &DynamicFeeParameters,
+        now: i64,
+        price: u128,
+    ) -> Result<()> {
+        let elapsed = now
+            .checked_sub(self.last_update_timestamp)
+            .ok_or(Error::CalculationFailure)?;
+        if self.price_reference == 0 || elapsed >= parameters.filter_period {
+            self.volatility_reference = if elapsed < parameters.decay_period {
+                // the product of two u64 fits in u128
+                u64::try_from(
+                    u128::from(self.volatility_accumulator)
+                        * u128::from(parameters.reduction_factor)
+                        / BASIS_POINTS_DENOMINATOR,
+                )
+                .map_err(|_| Error::ConversionFailure)?
+            } else {
+                0
+            };
+            self.price_reference = price;
+            self.last_update_timestamp = now;
+        }
+        Ok(())
+    }
+
+    /// Adds the price move measured against the price reference to the
+    /// volatility reference.
+    pub fn update_volatility_accumulator(
+        &mut self,
+        parameters: &DynamicFeeParameters,
+        price: u128,
+    ) -> Result<()> {
+        if self.price_reference == 0 {
+            return Ok(());
+        }
+        let price_move = price_move(price, self.price_reference)?;
+        // capped at the maximum accumulator, which fits in u64
+        self.volatility_accumulator = u128::from(self.volatility_reference)
+            .saturating_add(price_move)
+            .min(u128::from(parameters.max_volatility_accumulator))
+            as u64;
+        Ok(())
+    }
+
+    /// The trade fee numerator for the current volatility, bounded by the
+    /// minimum and maximum fee
+    pub fn trade_fee_numerator(&self, parameters: &DynamicFeeParameters) -> Result<u64> {
+        let volatility_accumulator = u128::from(self.volatility_accumulator);
+        // the square of a u64 fits in u128, and the product saturates rather
+        // than overflows, for the fee is capped at the maximum fee anyway
+        let variable_fee = (volatility_accumulator * volatility_accumulator)
+            .saturating_mul(u128::from(parameters.variable_fee_control))
+            / VARIABLE_FEE_PRECISION;
+        let fee = u128::from(parameters.min_fee_numerator)
+            .saturating_add(variable_fee)
+            .min(u128::from(parameters.max_fee_numerator));
+        u64::try_from(fee).map_err(|_| Error::ConversionFailure.into())
+    }
+}
+
+/// Spot price of token A in token B as a 64.64 fixed point number
+pub fn spot_price(token_a_amount: u128, token_b_amount: u128) -> Result<u128> {
+    if token_a_amount == 0 {
+        return Err(Error::InsufficientLiquidity.into());
+    }
+    let price = (U256::from(token_b_amount) << 64) / U256::from(token_a_amount);
+    if price > U256::from(u128::MAX) {
+        return Err(Error::ConversionFailure.into());
+    }
+    Ok(price.as_u128())
+}
+
+/// Move from `reference` to `price`, in basis points of `reference`
+pub fn price_move(price: u128, reference: u128) -> Result<u128> {
+    if reference == 0 {
+        return Err(Error::CalculationFailure.into());
+    }
+    let price_move = U256::from(price.abs_diff(reference)) * U256::from(BASIS_POINTS_DENOMINATOR)
+        / U256::from(reference);
+    if price_move > U256::from(u128::MAX) {
+        return Err(Error::ConversionFailure.into());
+    }
+    Ok(price_move.as_u128())
+}
+
 /// Helper function for calculating swap fee
 pub fn calculate_fee(
     token_amount: u128,
     fee_numerator: u128,
     fee_denominator: u128,
-) -> Option<u128> {
+) -> Result<u128> {
     msg!(
         "calculate_fee {}, {}, {}",
         token_amount,
@@ -101,15 +300,21 @@ pub fn calculate_fee(
         fee_denominator
     );
     if fee_numerator == 0 || token_amount == 0 {
-        Some(0)
+        Ok(0)
     } else {
-        let fee = token_amount
-            .checked_mul(fee_numerator)?
-            .checked_div(fee_denominator)?;
+        // computed in U256, for large amounts of high-decimal tokens
+        // overflow u128 once multiplied by the numerator
+        let fee = (U256::from(token_amount) * U256::from(fee_numerator))
+            .checked_div(U256::from(fee_denominator))
+            .ok_or(Error::FeeCalculationFailure)?;
+        if fee > U256::from(u128::MAX) {
+            return Err(Error::FeeCalculationFailure.into());
+        }
+        let fee = fee.as_u128();
         if fee == 0 {
-            Some(1) // minimum fee of one token
+            Ok(1) // minimum fee of one token
         } else {
-            Some(fee)
+            Ok(fee)
         }
     }
 }
@@ -158,3 +363,103 @@ pub const FIXED_CONSTRAINTS: SwapConstraints = SwapConstraints {
         host_fee_denominator: 100,
     },
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: u128 = 1_000_000;
+
+    fn parameters() -> DynamicFeeParameters {
+        DynamicFeeParameters {
+            min_fee_numerator: 10,
+            max_fee_numerator: 100,
+            filter_period: 10,
+            decay_period: 60,
+            reduction_factor: 5_000,
+            variable_fee_control: 1_000,
+            max_volatility_accumulator: 300,
+        }
+    }
+
+    /// Updates the state as a swap from `price_before` to `price_after` at
+    /// `now` does, returning the trade fee numerator it pays
+    fn swap(state: &mut DynamicFeeState, now: i64, price_before: u128, price_after: u128) -> u64 {
+        let parameters = parameters();
+        state
+            .update_references(&parameters, now, price_before)
+            .unwrap();
+        let fee = state.trade_fee_numerator(&parameters).unwrap();
+        state
+            .update_volatility_accumulator(&parameters, price_after)
+            .unwrap();
+        fee
+    }
+
+    #[test]
+    fn dynamic_fee_rises_with_volatility() {
+        let mut state = DynamicFeeState::default();
+        assert_eq!(swap(&mut state, 0, PRICE, PRICE * 101 / 100), 10);
+        // 100 basis points of volatility add 100^2 * 1000 / 10^6
+        assert_eq!(
+            swap(&mut state, 1, PRICE * 101 / 100, PRICE * 102 / 100),
+            20
+        );
+        assert_eq!(swap(&mut state, 2, PRICE * 102 / 100, PRICE * 2), 50);
+        // the accumulator is capped at 300 basis points, the fee at the maximum
+        assert_eq!(state.volatility_accumulator, 300);
+        assert_eq!(swap(&mut state, 3, PRICE * 2, PRICE * 2), 100);
+    }
+
+    #[test]
+    fn dynamic_fee_decays_without_volatility() {
+        let mut state = DynamicFeeState::default();
+        swap(&mut state, 0, PRICE, PRICE * 2);
+        assert_eq!(swap(&mut state, 1, PRICE * 2, PRICE * 2), 100);
+        // after the filter period, half the accumulator is kept as the reference
+        assert_eq!(swap(&mut state, 15, PRICE * 2, PRICE * 2), 100);
+        assert_eq!(state.volatility_reference, 150);
+        assert_eq!(swap(&mut state, 16, PRICE * 2, PRICE * 2), 32);
+        // after the decay period, the reference is reset
+        assert_eq!(swap(&mut state, 100, PRICE * 2, PRICE * 2), 32);
+        assert_eq!(state.volatility_reference, 0);
+        assert_eq!(swap(&mut state, 101, PRICE * 2, PRICE * 2), 10);
+    }
+
+    #[test]
+    fn spot_price_and_price_move() {
+        assert_eq!(spot_price(2, 1).unwrap(), 1 << 63);
+        assert_eq!(
+            spot_price(1, u64::MAX.into()).unwrap(),
+            u128::from(u64::MAX) << 64
+        );
+        assert_eq!(price_move(PRICE * 101 / 100, PRICE).unwrap(), 100);
+        assert_eq!(price_move(PRICE * 99 / 100, PRICE).unwrap(), 100);
+        let error = |result: Result<u128>| -> ProgramError { result.unwrap_err().into() };
+        assert_eq!(error(spot_price(0, 1)), Error::InsufficientLiquidity.into());
+        assert_eq!(
+            error(spot_price(1, u128::MAX)),
+            Error::ConversionFailure.into()
+        );
+        assert_eq!(
+            error(price_move(PRICE, 0)),
+            Error::CalculationFailure.into()
+        );
+    }
+
+    #[test]
+    fn dynamic_fee_stays_within_bounds() {
+        let parameters = DynamicFeeParameters {
+            variable_fee_control: u64::MAX,
+            max_volatility_accumulator: u64::MAX,
+            ..parameters()
+        };
+        let mut state = DynamicFeeState::default();
+        assert_eq!(state.trade_fee_numerator(&parameters).unwrap(), 10);
+        state.volatility_accumulator = 1;
+        assert_eq!(state.trade_fee_numerator(&parameters).unwrap(), 100);
+        // the variable fee saturates instead of overflowing
+        state.volatility_accumulator = u64::MAX;
+        assert_eq!(state.trade_fee_numerator(&parameters).unwrap(), 100);
+    }
+}
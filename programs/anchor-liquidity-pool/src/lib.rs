@@ -1,12 +1,40 @@
+pub mod circuit_breaker;
+pub mod cl_pool;
+pub mod concentrated;
 pub mod curve;
+pub mod dca;
 pub mod error;
+pub mod farm;
 pub mod fees;
-
-use crate::curve::{ConstantProduct, TradeDirection};
-use crate::fees::{Fees, FIXED_CONSTRAINTS};
+pub mod locked_position;
+pub mod multi_pool;
+pub mod oracle;
+pub mod order_book;
+pub mod stable_swap;
+pub mod token_interface;
+pub mod twamm;
+
+use crate::circuit_breaker::{CircuitBreakerParameters, CircuitBreakerState};
+use crate::cl_pool::*;
+use crate::curve::{
+    ConstantProduct, PmmParameters, ProactiveMarketMaker, SwapResult, TradeDirection,
+};
+use crate::dca::*;
+use crate::farm::*;
+use crate::fees::{
+    spot_price, DynamicFeeParameters, DynamicFeeState, Fees, OwnerFeeMode, FIXED_CONSTRAINTS,
+};
+use crate::locked_position::*;
+use crate::multi_pool::*;
+use crate::oracle::{Oracle, OracleGuard, OraclePrice};
+use crate::order_book::*;
+use crate::token_interface::{
+    is_held_by, is_token_program, mint, token_account, Burn, CloseAccount, MintTo, TransferChecked,
+};
+use crate::twamm::*;
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::program_option::COption;
-use anchor_spl::token::{self, Burn, Mint, MintTo, TokenAccount, Transfer};
+use anchor_lang::system_program;
 
 declare_id!("HEnMwtqH2T6bVHGwTkbbj2WBKJs6G4TztVSeUC9w1Tb1");
 
@@ -15,38 +43,103 @@ pub mod anchor_liquidity_pool {
     use super::*;
     use crate::curve::{RoundDirection, TradeDirection};
 
-    pub fn initialize(ctx: Context<Initialize>, fees: Fees) -> Result<()> {
-        msg!("Instruction Pool Init {:?}", fees);
+    pub fn initialize_config(ctx: Context<InitializeConfig>) -> Result<()> {
+        msg!("Instruction Config Init");
+        ctx.accounts.config.fee_tiers = Vec::new();
+        ctx.accounts.config.bump = *ctx
+            .bumps
+            .get("config")
+            .ok_or(crate::error::Error::InvalidProgramAddress)?;
+        Ok(())
+    }
+
+    pub fn add_fee_tier(ctx: Context<UpdateConfig>, fee_tier: u16) -> Result<()> {
+        msg!("Instruction Add Fee Tier {}", fee_tier);
+        ctx.accounts.config.add_fee_tier(fee_tier)
+    }
+
+    pub fn remove_fee_tier(ctx: Context<UpdateConfig>, fee_tier: u16) -> Result<()> {
+        msg!("Instruction Remove Fee Tier {}", fee_tier);
+        ctx.accounts.config.remove_fee_tier(fee_tier)
+    }
+
+    pub fn initialize(
+        ctx: Context<Initialize>,
+        fees: Fees,
+        owner_fee_mode: OwnerFeeMode,
+        fee_tier: u16,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Pool Init {:?},{:?},{}",
+            fees,
+            owner_fee_mode,
+            fee_tier
+        );
         let curve = ConstantProduct {};
         curve.validate_supply(
-            ctx.accounts.token_a_for_pda.amount,
-            ctx.accounts.token_b_for_pda.amount,
+            token_account(
+                &ctx.accounts.token_a_for_pda,
+                ctx.accounts.token_program.key,
+            )?
+            .amount,
+            token_account(
+                &ctx.accounts.token_b_for_pda,
+                ctx.accounts.token_program.key,
+            )?
+            .amount,
         )?;
         FIXED_CONSTRAINTS.validate_fees(&fees)?;
         fees.validate()?;
+        if !ctx.accounts.config.fee_tiers.contains(&fee_tier) {
+            return Err(crate::error::Error::InvalidFeeTier.into());
+        }
+        fees.validate_fee_tier(fee_tier)?;
 
         ctx.accounts.pair.token_a_account = ctx.accounts.token_a_for_pda.key();
         ctx.accounts.pair.token_b_account = ctx.accounts.token_b_for_pda.key();
         ctx.accounts.pair.pool_mint = ctx.accounts.pool.key();
         ctx.accounts.pair.pool_fee_account = ctx.accounts.token_pool_for_fee_receiver.key();
-        ctx.accounts.pair.token_a_mint = ctx.accounts.token_a_for_pda.mint.key();
-        ctx.accounts.pair.token_b_mint = ctx.accounts.token_b_for_pda.mint.key();
+        ctx.accounts.pair.token_a_mint = ctx.accounts.token_a_mint.key();
+        ctx.accounts.pair.token_b_mint = ctx.accounts.token_b_mint.key();
+        ctx.accounts.pair.token_program = ctx.accounts.token_program.key();
         ctx.accounts.pair.fees = fees;
+        ctx.accounts.pair.fee_tier = fee_tier;
+        ctx.accounts.pair.owner_fee_mode = owner_fee_mode;
+        ctx.accounts.pair.protocol_fee_token_a_amount = 0;
+        ctx.accounts.pair.protocol_fee_token_b_amount = 0;
+        ctx.accounts.pair.dynamic_fee_parameters = None;
+        ctx.accounts.pair.dynamic_fee_state = DynamicFeeState::default();
+        ctx.accounts.pair.bump = *ctx
+            .bumps
+            .get("pair")
+            .ok_or(crate::error::Error::InvalidProgramAddress)?;
+        ctx.accounts.pair.version = SwapPair::VERSION;
+        ctx.accounts.pair.order_book = None;
+        ctx.accounts.pair.twamm = false;
+        ctx.accounts.pair.circuit_breaker = CircuitBreakerParameters::default();
+        ctx.accounts.pair.circuit_breaker_state = CircuitBreakerState::default();
+        ctx.accounts.pair.oracle_guard = None;
+        ctx.accounts.pair.pmm = None;
+        ctx.accounts.pair.dependent_accounts = 0;
+        ctx.accounts.pair.dependent_accounts_counted = true;
 
         let signer_seeds = ctx
             .accounts
             .pair
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
-        token::mint_to(
+        token_interface::mint_to(
             ctx.accounts.to_mint_context().with_signer(signer_seeds),
             to_u64(curve.new_pool_supply())?,
         )?;
         Ok(())
     }
 
-    pub fn deposit_all(
-        ctx: Context<DepositAll>,
+    /// Once the pair has a TWAMM, the remaining accounts must be the `Twamm`
+    /// and its token A and B escrows, whose virtual orders are executed before
+    /// the reserves are read, as in every deposit, withdrawal and zap.
+    pub fn deposit_all<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositAll<'info>>,
         pool_token_amount: u64,
         maximum_token_a_amount: u64,
         maximum_token_b_amount: u64,
@@ -58,37 +151,49 @@ pub mod anchor_liquidity_pool {
             maximum_token_b_amount
         );
 
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
         let curve = ConstantProduct {};
-        let current_pool_mint_supply = to_u128(ctx.accounts.pool.supply)?;
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        let current_pool_mint_supply =
+            to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?;
         let (pool_token_amount, pool_mint_supply) = if current_pool_mint_supply > 0 {
             (to_u128(pool_token_amount)?, current_pool_mint_supply)
         } else {
             (curve.new_pool_supply(), curve.new_pool_supply())
         };
 
-        let (token_a_amount, token_b_amount) = curve
-            .pool_tokens_to_trading_tokens(
-                pool_token_amount,
-                pool_mint_supply,
-                to_u128(ctx.accounts.token_a_for_pda.amount)?,
-                to_u128(ctx.accounts.token_b_for_pda.amount)?,
-                RoundDirection::Ceiling,
-            )
-            .ok_or(crate::error::Error::ZeroTradingTokens)?;
+        let (token_a_amount, token_b_amount) = curve.pool_tokens_to_trading_tokens(
+            pool_token_amount,
+            pool_mint_supply,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            RoundDirection::Ceiling,
+        )?;
 
         msg!(
             "Pooling token amount of A is {} and B is {}",
             token_a_amount,
             token_b_amount
         );
-        let token_a_amount = to_u64(token_a_amount)?;
+        // the depositor covers the transfer fees so that the pool receives the amounts
+        let token_a_amount = token_interface::amount_before_transfer_fee(
+            &ctx.accounts.token_a_mint,
+            to_u64(token_a_amount)?,
+        )?;
         if token_a_amount > maximum_token_a_amount {
             return Err(crate::error::Error::ExceededSlippage.into());
         }
         if token_a_amount == 0 {
             return Err(crate::error::Error::ZeroTradingTokens.into());
         }
-        let token_b_amount = to_u64(token_b_amount)?;
+        let token_b_amount = token_interface::amount_before_transfer_fee(
+            &ctx.accounts.token_b_mint,
+            to_u64(token_b_amount)?,
+        )?;
         if token_b_amount > maximum_token_b_amount {
             return Err(crate::error::Error::ExceededSlippage.into());
         }
@@ -102,9 +207,128 @@ pub mod anchor_liquidity_pool {
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
 
-        token::transfer(ctx.accounts.to_transfer_a_context(), token_a_amount)?;
-        token::transfer(ctx.accounts.to_transfer_b_context(), token_b_amount)?;
-        token::mint_to(
+        token_interface::transfer_checked(ctx.accounts.to_transfer_a_context(), token_a_amount)?;
+        token_interface::transfer_checked(ctx.accounts.to_transfer_b_context(), token_b_amount)?;
+        token_interface::mint_to(
+            ctx.accounts.to_mint_context().with_signer(signer_seeds),
+            to_u64(pool_token_amount)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Deposits at most the desired amounts of token A and B, the depositor
+    /// covering their transfer fees. The minimum amounts are checked against
+    /// what the pool receives.
+    pub fn deposit_all_by_tokens<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositAll<'info>>,
+        amount_a_desired: u64,
+        amount_b_desired: u64,
+        amount_a_min: u64,
+        amount_b_min: u64,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Pool Deposit By Tokens {},{},{},{}",
+            amount_a_desired,
+            amount_b_desired,
+            amount_a_min,
+            amount_b_min
+        );
+
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
+        let curve = ConstantProduct {};
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        let amount_a_desired = amount_a_desired
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_a_mint,
+                amount_a_desired,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        let amount_b_desired = amount_b_desired
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_b_mint,
+                amount_b_desired,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+
+        let current_pool_mint_supply =
+            to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?;
+        let (pool_token_amount, pool_mint_supply) = if current_pool_mint_supply > 0 {
+            let (token_a_amount, token_b_amount) = curve.optimal_deposit_amounts(
+                to_u128(amount_a_desired)?,
+                to_u128(amount_b_desired)?,
+                to_u128(swap_token_a_amount)?,
+                to_u128(swap_token_b_amount)?,
+            )?;
+            let pool_token_amount = curve.trading_tokens_to_pool_tokens(
+                token_a_amount,
+                token_b_amount,
+                current_pool_mint_supply,
+                to_u128(swap_token_a_amount)?,
+                to_u128(swap_token_b_amount)?,
+            )?;
+            (pool_token_amount, current_pool_mint_supply)
+        } else {
+            (curve.new_pool_supply(), curve.new_pool_supply())
+        };
+        if pool_token_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+
+        let (token_a_amount, token_b_amount) = curve.pool_tokens_to_trading_tokens(
+            pool_token_amount,
+            pool_mint_supply,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            RoundDirection::Ceiling,
+        )?;
+
+        msg!(
+            "pool_token_amount={}, token_a_amount={}, token_b_amount={}",
+            pool_token_amount,
+            token_a_amount,
+            token_b_amount
+        );
+        let token_a_amount = to_u64(token_a_amount)?;
+        if token_a_amount > amount_a_desired || token_a_amount < amount_a_min {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
+        if token_a_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+        let token_b_amount = to_u64(token_b_amount)?;
+        if token_b_amount > amount_b_desired || token_b_amount < amount_b_min {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
+        if token_b_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_a_context(),
+            token_interface::amount_before_transfer_fee(
+                &ctx.accounts.token_a_mint,
+                token_a_amount,
+            )?,
+        )?;
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_b_context(),
+            token_interface::amount_before_transfer_fee(
+                &ctx.accounts.token_b_mint,
+                token_b_amount,
+            )?,
+        )?;
+        token_interface::mint_to(
             ctx.accounts.to_mint_context().with_signer(signer_seeds),
             to_u64(pool_token_amount)?,
         )?;
@@ -112,8 +336,9 @@ pub mod anchor_liquidity_pool {
         Ok(())
     }
 
-    pub fn deposit_single(
-        ctx: Context<DepositSingle>,
+    /// Takes the remaining accounts of `deposit_all`. Fails on a PMM pair.
+    pub fn deposit_single<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositSingle<'info>>,
         source_token_amount: u64,
         minimum_pool_token_amount: u64,
     ) -> Result<()> {
@@ -123,19 +348,32 @@ pub mod anchor_liquidity_pool {
             minimum_pool_token_amount,
         );
 
+        ctx.accounts.pair.require_constant_product()?;
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
         let trade_direction = ctx.accounts.trade_direction()?;
         let curve = ConstantProduct {};
-        let pool_token_amount = if ctx.accounts.pool.supply > 0 {
-            curve
-                .deposit_single_token_type(
-                    to_u128(source_token_amount)?,
-                    to_u128(ctx.accounts.token_a_for_pda.amount)?,
-                    to_u128(ctx.accounts.token_b_for_pda.amount)?,
-                    to_u128(ctx.accounts.pool.supply)?,
-                    &trade_direction,
-                    &ctx.accounts.pair.fees,
-                )
-                .ok_or(crate::error::Error::ZeroTradingTokens)?
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        let pool_mint_supply = ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?;
+        // only the amount left after the transfer fee is deposited to the pool
+        let source_amount_received = source_token_amount
+            .checked_sub(token_interface::transfer_fee(
+                ctx.accounts.source_mint(&trade_direction),
+                source_token_amount,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        let pool_token_amount = if pool_mint_supply > 0 {
+            curve.deposit_single_token_type(
+                to_u128(source_amount_received)?,
+                to_u128(swap_token_a_amount)?,
+                to_u128(swap_token_b_amount)?,
+                to_u128(pool_mint_supply)?,
+                &trade_direction,
+                &ctx.accounts.pair.fees,
+            )?
         } else {
             curve.new_pool_supply()
         };
@@ -153,11 +391,79 @@ pub mod anchor_liquidity_pool {
             .pair
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
-        token::transfer(
-            ctx.accounts.to_transfer_context(trade_direction),
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_context(&trade_direction),
+            source_token_amount,
+        )?;
+        token_interface::mint_to(
+            ctx.accounts.to_mint_context().with_signer(signer_seeds),
+            pool_token_amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn deposit_single_exact_out<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositSingle<'info>>,
+        pool_token_amount: u64,
+        maximum_source_amount: u64,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Pool Deposit Single Exact Out {},{}",
+            pool_token_amount,
+            maximum_source_amount,
+        );
+
+        ctx.accounts.pair.require_constant_product()?;
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
+        let trade_direction = ctx.accounts.trade_direction()?;
+        let curve = ConstantProduct {};
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        let pool_mint_supply = ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?;
+        if pool_mint_supply == 0 {
+            return Err(crate::error::Error::EmptySupply.into());
+        }
+
+        let source_amount_received = curve.deposit_single_token_type_exact_out(
+            to_u128(pool_token_amount)?,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            to_u128(pool_mint_supply)?,
+            &trade_direction,
+            &ctx.accounts.pair.fees,
+        )?;
+        // the depositor also pays the transfer fee on top of what the pool receives
+        let source_token_amount = token_interface::amount_before_transfer_fee(
+            ctx.accounts.source_mint(&trade_direction),
+            to_u64(source_amount_received)?,
+        )?;
+        msg!(
+            "source_token_amount={}, source_amount_received={}",
+            source_token_amount,
+            source_amount_received
+        );
+
+        if source_token_amount > maximum_source_amount {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
+        if source_token_amount == 0 || pool_token_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_context(&trade_direction),
             source_token_amount,
         )?;
-        token::mint_to(
+        token_interface::mint_to(
             ctx.accounts.to_mint_context().with_signer(signer_seeds),
             pool_token_amount,
         )?;
@@ -165,8 +471,8 @@ pub mod anchor_liquidity_pool {
         Ok(())
     }
 
-    pub fn withdraw_all(
-        ctx: Context<WithdrawAll>,
+    pub fn withdraw_all<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawAll<'info>>,
         pool_token_amount: u64,
         minimum_token_a_amount: u64,
         minimum_token_b_amount: u64,
@@ -178,25 +484,28 @@ pub mod anchor_liquidity_pool {
             minimum_token_b_amount
         );
 
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
         let curve = ConstantProduct {};
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
         let withdraw_fee = ctx.accounts.withdraw_fee(pool_token_amount)?;
 
         let pool_token_amount = to_u128(pool_token_amount)?
             .checked_sub(withdraw_fee)
             .ok_or(crate::error::Error::FeeCalculationFailure)?;
 
-        let (token_a_amount, token_b_amount) = curve
-            .pool_tokens_to_trading_tokens(
-                pool_token_amount,
-                to_u128(ctx.accounts.pool.supply)?,
-                to_u128(ctx.accounts.token_a_for_pda.amount)?,
-                to_u128(ctx.accounts.token_b_for_pda.amount)?,
-                RoundDirection::Floor,
-            )
-            .ok_or(crate::error::Error::ZeroTradingTokens)?;
-
-        let token_a_amount =
-            std::cmp::min(ctx.accounts.token_a_for_pda.amount, to_u64(token_a_amount)?);
+        let (token_a_amount, token_b_amount) = curve.pool_tokens_to_trading_tokens(
+            pool_token_amount,
+            to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            RoundDirection::Floor,
+        )?;
+
+        let token_a_amount = std::cmp::min(swap_token_a_amount, to_u64(token_a_amount)?);
         msg!(
             "pool_token_amount={}, token_a_amount={}, token_b_amount={}, withdraw_fee={}",
             pool_token_amount,
@@ -205,28 +514,40 @@ pub mod anchor_liquidity_pool {
             withdraw_fee
         );
 
-        if token_a_amount < minimum_token_a_amount {
+        // the minimum amounts are checked against what the depositor receives
+        let token_a_amount_received = token_a_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_a_mint,
+                token_a_amount,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        if token_a_amount_received < minimum_token_a_amount {
             return Err(crate::error::Error::ExceededSlippage.into());
         }
-        if token_a_amount == 0 && ctx.accounts.token_a_for_pda.amount != 0 {
+        if token_a_amount == 0 && swap_token_a_amount != 0 {
             return Err(crate::error::Error::ZeroTradingTokens.into());
         }
-        let token_b_amount =
-            std::cmp::min(ctx.accounts.token_b_for_pda.amount, to_u64(token_b_amount)?);
-        if token_b_amount < minimum_token_b_amount {
+        let token_b_amount = std::cmp::min(swap_token_b_amount, to_u64(token_b_amount)?);
+        let token_b_amount_received = token_b_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_b_mint,
+                token_b_amount,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        if token_b_amount_received < minimum_token_b_amount {
             return Err(crate::error::Error::ExceededSlippage.into());
         }
-        if token_b_amount == 0 && ctx.accounts.token_b_for_pda.amount != 0 {
+        if token_b_amount == 0 && swap_token_b_amount != 0 {
             return Err(crate::error::Error::ZeroTradingTokens.into());
         }
 
         if withdraw_fee > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 ctx.accounts.to_transfer_fee_context(),
                 to_u64(withdraw_fee)?,
             )?
         }
-        token::burn(ctx.accounts.to_burn_context(), to_u64(pool_token_amount)?)?;
+        token_interface::burn(ctx.accounts.to_burn_context(), to_u64(pool_token_amount)?)?;
 
         let signer_seeds = ctx
             .accounts
@@ -234,7 +555,7 @@ pub mod anchor_liquidity_pool {
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
         if token_a_amount > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 ctx.accounts
                     .to_transfer_a_context()
                     .with_signer(signer_seeds),
@@ -242,7 +563,7 @@ pub mod anchor_liquidity_pool {
             )?;
         }
         if token_b_amount > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 ctx.accounts
                     .to_transfer_b_context()
                     .with_signer(signer_seeds),
@@ -253,8 +574,9 @@ pub mod anchor_liquidity_pool {
         Ok(())
     }
 
-    pub fn withdraw_single(
-        ctx: Context<WithdrawSingle>,
+    /// Takes the remaining accounts of `deposit_all`. Fails on a PMM pair.
+    pub fn withdraw_single<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawSingle<'info>>,
         destination_token_amount: u64,
         maximum_pool_token_amount: u64,
     ) -> Result<()> {
@@ -264,18 +586,29 @@ pub mod anchor_liquidity_pool {
             maximum_pool_token_amount
         );
 
+        ctx.accounts.pair.require_constant_product()?;
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
         let trade_direction = ctx.accounts.trade_direction()?;
         let curve = ConstantProduct {};
-        let burn_pool_token_amount = curve
-            .withdraw_single_token_type_exact_out(
-                to_u128(destination_token_amount)?,
-                to_u128(ctx.accounts.token_a_for_pda.amount)?,
-                to_u128(ctx.accounts.token_b_for_pda.amount)?,
-                to_u128(ctx.accounts.pool.supply)?,
-                &trade_direction,
-                &ctx.accounts.pair.fees,
-            )
-            .ok_or(crate::error::Error::ZeroTradingTokens)?;
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        // the pool sends enough for the depositor to receive the amount after the transfer fee
+        let destination_token_amount = token_interface::amount_before_transfer_fee(
+            ctx.accounts.destination_mint(&trade_direction),
+            destination_token_amount,
+        )?;
+        let pool_mint_supply = ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?;
+        let burn_pool_token_amount = curve.withdraw_single_token_type_exact_out(
+            to_u128(destination_token_amount)?,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            to_u128(pool_mint_supply)?,
+            &trade_direction,
+            &ctx.accounts.pair.fees,
+        )?;
 
         let withdraw_fee = ctx.accounts.withdraw_fee(burn_pool_token_amount)?;
         let pool_token_amount = burn_pool_token_amount
@@ -297,12 +630,12 @@ pub mod anchor_liquidity_pool {
         }
 
         if withdraw_fee > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 ctx.accounts.to_transfer_fee_context(),
                 to_u64(withdraw_fee)?,
             )?
         }
-        token::burn(
+        token_interface::burn(
             ctx.accounts.to_burn_context(),
             to_u64(burn_pool_token_amount)?,
         )?;
@@ -312,9 +645,9 @@ pub mod anchor_liquidity_pool {
             .pair
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
-        token::transfer(
+        token_interface::transfer_checked(
             ctx.accounts
-                .to_transfer_context(trade_direction)
+                .to_transfer_context(&trade_direction)
                 .with_signer(signer_seeds),
             destination_token_amount,
         )?;
@@ -322,277 +655,1583 @@ pub mod anchor_liquidity_pool {
         Ok(())
     }
 
-    pub fn swap(ctx: Context<Swap>, amount_in: u64, minimum_amount_out: u64) -> Result<()> {
-        msg!("Instruction Swap {},{}", amount_in, minimum_amount_out,);
+    pub fn withdraw_single_exact_in<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawSingle<'info>>,
+        pool_token_amount: u64,
+        minimum_destination_amount: u64,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Pool Withdraw Single Exact In {},{}",
+            pool_token_amount,
+            minimum_destination_amount
+        );
 
-        let trade_direction = ctx.accounts.trade_direction();
+        ctx.accounts.pair.require_constant_product()?;
+        ctx.accounts
+            .sync_twamm(ctx.remaining_accounts, ctx.program_id)?;
+        let trade_direction = ctx.accounts.trade_direction()?;
         let curve = ConstantProduct {};
-        let result = curve
-            .swap(
-                to_u128(amount_in)?,
-                to_u128(ctx.accounts.token_source_for_pda.amount)?,
-                to_u128(ctx.accounts.token_destination_for_pda.amount)?,
-                &ctx.accounts.pair.fees,
-            )
-            .ok_or(crate::error::Error::ZeroTradingTokens)?;
+        let (swap_token_a_amount, swap_token_b_amount) = ctx
+            .accounts
+            .pair
+            .swap_token_amounts(&ctx.accounts.token_a_for_pda, &ctx.accounts.token_b_for_pda)?;
+        let withdraw_fee = ctx.accounts.withdraw_fee(to_u128(pool_token_amount)?)?;
+        let burn_pool_token_amount = to_u128(pool_token_amount)?
+            .checked_sub(withdraw_fee)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
 
-        msg!("{:?}", result);
-        if result.destination_amount_swapped < to_u128(minimum_amount_out)? {
+        let destination_token_amount = curve.withdraw_single_token_type_exact_in(
+            burn_pool_token_amount,
+            to_u128(swap_token_a_amount)?,
+            to_u128(swap_token_b_amount)?,
+            to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?,
+            &trade_direction,
+            &ctx.accounts.pair.fees,
+        )?;
+        let destination_token_amount = to_u64(destination_token_amount)?;
+        // the minimum is checked against what the depositor receives after the transfer fee
+        let destination_amount_received = destination_token_amount
+            .checked_sub(token_interface::transfer_fee(
+                ctx.accounts.destination_mint(&trade_direction),
+                destination_token_amount,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        msg!(
+            "burn_token_amount={}, destination_token_amount={}, withdraw_fee={}",
+            burn_pool_token_amount,
+            destination_token_amount,
+            withdraw_fee
+        );
+
+        if destination_amount_received < minimum_destination_amount {
             return Err(crate::error::Error::ExceededSlippage.into());
         }
+        if destination_token_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
 
-        let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
-            TradeDirection::AtoB => (
-                result.new_swap_source_amount,
-                result.new_swap_destination_amount,
-            ),
-            TradeDirection::BtoA => (
-                result.new_swap_destination_amount,
-                result.new_swap_source_amount,
-            ),
-        };
-
-        let mut pool_token_amount = curve
-            .withdraw_single_token_type_exact_out(
-                result.owner_fee,
-                swap_token_a_amount,
-                swap_token_b_amount,
-                to_u128(ctx.accounts.pool.supply)?,
-                &trade_direction,
-                &ctx.accounts.pair.fees,
-            )
-            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        if withdraw_fee > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts.to_transfer_fee_context(),
+                to_u64(withdraw_fee)?,
+            )?
+        }
+        token_interface::burn(
+            ctx.accounts.to_burn_context(),
+            to_u64(burn_pool_token_amount)?,
+        )?;
 
-        msg!("pool_token_amount={}", pool_token_amount);
         let signer_seeds = ctx
             .accounts
             .pair
             .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
         let signer_seeds = &[&signer_seeds.value()[..]];
-
-        if pool_token_amount > 0 {
-            let host_fee = ctx
-                .accounts
-                .pair
-                .fees
-                .host_fee(pool_token_amount)
-                .ok_or(crate::error::Error::FeeCalculationFailure)?;
-            if host_fee > 0 {
-                token::mint_to(
-                    ctx.accounts
-                        .to_mint_host_fee_context()
-                        .with_signer(signer_seeds),
-                    to_u64(host_fee)?,
-                )?;
-                pool_token_amount = pool_token_amount
-                    .checked_sub(host_fee)
-                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
-            }
-            token::mint_to(
-                ctx.accounts
-                    .to_mint_pool_fee_context()
-                    .with_signer(signer_seeds),
-                to_u64(pool_token_amount)?,
-            )?;
-        }
-
-        token::transfer(
-            ctx.accounts.to_transfer_source_context(),
-            to_u64(result.source_amount_swapped)?,
-        )?;
-        token::transfer(
+        token_interface::transfer_checked(
             ctx.accounts
-                .to_transfer_destination_context()
+                .to_transfer_context(&trade_direction)
                 .with_signer(signer_seeds),
-            to_u64(result.destination_amount_swapped)?,
+            destination_token_amount,
         )?;
 
         Ok(())
     }
-}
-
-#[derive(Accounts)]
-#[instruction(fees: Fees)]
-pub struct Initialize<'info> {
-    #[account(zero)]
-    pub pair: Box<Account<'info, SwapPair>>,
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub pda: AccountInfo<'info>,
-    #[account(
-        mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
-    )]
-    pub pool: Account<'info, Mint>,
-    #[account(
-        mut,
-        constraint = token_a_for_pda.mint != pool.key(),
-        constraint = token_a_for_pda.owner == pda.key(),
-        constraint = token_a_for_pda.delegate.is_none(),
-        constraint = token_a_for_pda.close_authority.is_none()
-    )]
-    pub token_a_for_pda: Account<'info, TokenAccount>,
-    #[account(
-        mut,
-        constraint = token_b_for_pda.mint != pool.key(),
-        constraint = token_b_for_pda.owner == pda.key(),
-        constraint = token_b_for_pda.delegate.is_none(),
-        constraint = token_b_for_pda.close_authority.is_none()
-    )]
-    pub token_b_for_pda: Account<'info, TokenAccount>,
-    #[account(
-        mut,
-        constraint = token_pool_for_initializer.mint == token_pool_for_fee_receiver.mint,
-        constraint = token_pool_for_initializer.owner == token_pool_for_fee_receiver.owner
-    )]
-    pub token_pool_for_initializer: Account<'info, TokenAccount>,
-    #[account(
-        mut,
-        constraint = token_pool_for_fee_receiver.mint == pool.key(),
-        constraint = token_pool_for_fee_receiver.owner == admin_pubkey()?
-    )]
-    pub token_pool_for_fee_receiver: Account<'info, TokenAccount>,
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub token_program: AccountInfo<'info>,
-}
 
-#[derive(Accounts)]
-#[instruction(pool_token_amount: u64, maximum_token_a_amount: u64, maximum_token_b_amount: u64)]
-pub struct DepositAll<'info> {
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub depositor: Signer<'info>,
+    pub fn register_host(
+        ctx: Context<RegisterHost>,
+        host_fee_numerator: Option<u64>,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Register Host {},{:?}",
+            ctx.accounts.wallet.key(),
+            host_fee_numerator
+        );
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub pda: AccountInfo<'info>,
+        // the host fee is paid in pool tokens, which this mode doesn't mint
+        if ctx.accounts.pair.owner_fee_mode == OwnerFeeMode::UnderlyingToken {
+            return Err(crate::error::Error::InvalidHost.into());
+        }
+        if let Some(host_fee_numerator) = host_fee_numerator {
+            ctx.accounts
+                .pair
+                .fees
+                .validate_host_fee_numerator(host_fee_numerator)?;
+        }
+
+        ctx.accounts.host.pair = ctx.accounts.pair.key();
+        ctx.accounts.host.wallet = ctx.accounts.wallet.key();
+        ctx.accounts.host.host_fee_numerator = host_fee_numerator;
+        ctx.accounts.host.volume_token_a = 0;
+        ctx.accounts.host.volume_token_b = 0;
+        ctx.accounts.host.fees_earned = 0;
+        ctx.accounts.host.bump = *ctx
+            .bumps
+            .get("host")
+            .ok_or(crate::error::Error::InvalidProgramAddress)?;
+        Ok(())
+    }
+
+    /// Once the pair has an order book, the first three remaining accounts
+    /// must be the `OrderBook` and its token A and B escrows, whose orders are
+    /// filled against the pool after the swap. Once it has a TWAMM, the next
+    /// three must be the `Twamm` and its token A and B escrows, whose virtual
+    /// orders are executed before the swap. Once it has an oracle guard, the
+    /// next one must be its `PriceFeed`, followed by the `PriceFeed` of its PMM
+    /// curve once it has one. The host is optional and passed
+    /// as the next two remaining accounts: the registered `Host` and the pool
+    /// token account owned by its wallet.
+    pub fn swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        msg!("Instruction Swap {},{}", amount_in, minimum_amount_out,);
+
+        let (mut order_book, remaining_accounts) =
+            load_order_book(&ctx.accounts.pair, ctx.remaining_accounts)?;
+        let remaining_accounts = ctx
+            .accounts
+            .sync_twamm(remaining_accounts, ctx.program_id)?;
+        let (price_feed, remaining_accounts) =
+            load_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+        let (pmm_price_feed, remaining_accounts) =
+            load_pmm_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+        let mut host = load_host(&ctx.accounts.pair, &ctx.accounts.pool, remaining_accounts)?;
+        ctx.accounts.swap_accounts().swap(
+            amount_in,
+            minimum_amount_out,
+            &mut order_book,
+            &price_feed,
+            &pmm_price_feed,
+            &mut host,
+            &[],
+            ctx.program_id,
+        )?;
+        Ok(())
+    }
+
+    /// Takes the remaining accounts of `swap`.
+    pub fn zap_in<'info>(
+        ctx: Context<'_, '_, '_, 'info, Zap<'info>>,
+        amount_in: u64,
+        minimum_pool_token_amount: u64,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Zap In {},{}",
+            amount_in,
+            minimum_pool_token_amount
+        );
+
+        ctx.accounts.pair.require_constant_product()?;
+        let (mut order_book, remaining_accounts) =
+            load_order_book(&ctx.accounts.pair, ctx.remaining_accounts)?;
+        let remaining_accounts = ctx
+            .accounts
+            .sync_twamm(remaining_accounts, ctx.program_id)?;
+        let (price_feed, remaining_accounts) =
+            load_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+        let mut host = load_host(&ctx.accounts.pair, &ctx.accounts.pool, remaining_accounts)?;
+        let trade_direction = ctx.accounts.trade_direction();
+        let curve = ConstantProduct {};
+        let (swap_source_amount, swap_destination_amount) =
+            ctx.accounts.swap_token_amounts(&trade_direction)?;
+        let pool_mint_supply = to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?;
+        if pool_mint_supply == 0 {
+            return Err(crate::error::Error::EmptySupply.into());
+        }
+        let fees = ctx.accounts.swap_fees(
+            &trade_direction,
+            to_u128(swap_source_amount)?,
+            to_u128(swap_destination_amount)?,
+        )?;
+        // only the amount left after the transfer fee of the source mint reaches the pool
+        let amount_in_received = to_u128(
+            amount_in
+                .checked_sub(token_interface::transfer_fee(
+                    &ctx.accounts.token_source_mint,
+                    amount_in,
+                )?)
+                .ok_or(crate::error::Error::FeeCalculationFailure)?,
+        )?;
+
+        // swap the optimal part of the input, the output stays in the pool
+        let swap_amount =
+            curve.zap_in_swap_amount(amount_in_received, to_u128(swap_source_amount)?, &fees)?;
+        let result = curve.swap(
+            swap_amount,
+            to_u128(swap_source_amount)?,
+            to_u128(swap_destination_amount)?,
+            &fees,
+        )?;
+        msg!("{:?}", result);
+
+        let (pool_fee_token_amount, swap_source_amount) = ctx.accounts.owner_fee(
+            &trade_direction,
+            &result,
+            pool_mint_supply,
+            &fees,
+            &price_feed,
+        )?;
+        let swap_destination_amount = result.new_swap_destination_amount;
+        let pool_mint_supply = pool_mint_supply
+            .checked_add(pool_fee_token_amount)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+
+        // deposit the rest of the input and the swapped output proportionally
+        let source_amount = amount_in_received
+            .checked_sub(result.source_amount_swapped)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let destination_amount = result.destination_amount_swapped;
+        let pool_token_amount = curve.trading_tokens_to_pool_tokens(
+            source_amount,
+            destination_amount,
+            pool_mint_supply,
+            swap_source_amount,
+            swap_destination_amount,
+        )?;
+        let (source_amount_deposited, destination_amount_deposited) = curve
+            .pool_tokens_to_trading_tokens(
+                pool_token_amount,
+                pool_mint_supply,
+                swap_source_amount,
+                swap_destination_amount,
+                RoundDirection::Ceiling,
+            )?;
+        let source_dust = source_amount
+            .checked_sub(source_amount_deposited)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let destination_dust = destination_amount
+            .checked_sub(destination_amount_deposited)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        msg!(
+            "pool_token_amount={}, source_dust={}, destination_dust={}",
+            pool_token_amount,
+            source_dust,
+            destination_dust
+        );
+
+        let pool_token_amount = to_u64(pool_token_amount)?;
+        if pool_token_amount < minimum_pool_token_amount {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
+        if pool_token_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        token_interface::transfer_checked(ctx.accounts.to_transfer_source_in_context(), amount_in)?;
+        ctx.accounts
+            .mint_owner_fee(&mut host, pool_fee_token_amount, signer_seeds)?;
+        token_interface::mint_to(
+            ctx.accounts.to_mint_context().with_signer(signer_seeds),
+            pool_token_amount,
+        )?;
+        if source_dust > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts
+                    .to_transfer_source_out_context()
+                    .with_signer(signer_seeds),
+                to_u64(source_dust)?,
+            )?;
+        }
+        if destination_dust > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts
+                    .to_transfer_destination_out_context()
+                    .with_signer(signer_seeds),
+                to_u64(destination_dust)?,
+            )?;
+        }
+
+        // the internal swap fills the orders it crosses, as `swap` does
+        if let Some(order_book) = order_book.as_mut() {
+            ctx.accounts.swap_accounts().fill_orders(
+                &trade_direction,
+                order_book,
+                &price_feed,
+                ctx.program_id,
+            )?;
+            order_book.order_book.exit(ctx.program_id)?;
+        }
+
+        if let Some((host, _)) = host.as_mut() {
+            host.record_volume(&trade_direction, result.source_amount_swapped)?;
+            host.exit(ctx.program_id)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn zap_out<'info>(
+        ctx: Context<'_, '_, '_, 'info, Zap<'info>>,
+        pool_token_amount: u64,
+        minimum_destination_amount: u64,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Zap Out {},{}",
+            pool_token_amount,
+            minimum_destination_amount
+        );
+
+        ctx.accounts.pair.require_constant_product()?;
+        let (mut order_book, remaining_accounts) =
+            load_order_book(&ctx.accounts.pair, ctx.remaining_accounts)?;
+        let remaining_accounts = ctx
+            .accounts
+            .sync_twamm(remaining_accounts, ctx.program_id)?;
+        let (price_feed, remaining_accounts) =
+            load_price_feed(&ctx.accounts.pair, remaining_accounts)?;
+        let mut host = load_host(&ctx.accounts.pair, &ctx.accounts.pool, remaining_accounts)?;
+        let trade_direction = ctx.accounts.trade_direction();
+        let curve = ConstantProduct {};
+        let (swap_source_amount, swap_destination_amount) =
+            ctx.accounts.swap_token_amounts(&trade_direction)?;
+        let pool_mint_supply = to_u128(ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)?)?;
+        let withdraw_fee = ctx.accounts.withdraw_fee(pool_token_amount)?;
+        let burn_pool_token_amount = to_u128(pool_token_amount)?
+            .checked_sub(withdraw_fee)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+
+        // withdraw both sides proportionally
+        let (source_amount, destination_amount) = curve.pool_tokens_to_trading_tokens(
+            burn_pool_token_amount,
+            pool_mint_supply,
+            to_u128(swap_source_amount)?,
+            to_u128(swap_destination_amount)?,
+            RoundDirection::Floor,
+        )?;
+        let swap_source_amount = to_u128(swap_source_amount)?
+            .checked_sub(source_amount)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let swap_destination_amount = to_u128(swap_destination_amount)?
+            .checked_sub(destination_amount)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let pool_mint_supply = pool_mint_supply
+            .checked_sub(burn_pool_token_amount)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+
+        // then swap the withdrawn source side, the input never leaves the pool
+        let fees = ctx.accounts.swap_fees(
+            &trade_direction,
+            swap_source_amount,
+            swap_destination_amount,
+        )?;
+        let result = curve.swap(
+            source_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            &fees,
+        )?;
+        msg!("{:?}", result);
+        let (pool_fee_token_amount, _) = ctx.accounts.owner_fee(
+            &trade_direction,
+            &result,
+            pool_mint_supply,
+            &fees,
+            &price_feed,
+        )?;
+
+        let destination_token_amount = to_u64(
+            destination_amount
+                .checked_add(result.destination_amount_swapped)
+                .ok_or(crate::error::Error::CalculationFailure)?,
+        )?;
+        // the minimum is checked against what the depositor receives after the transfer fee
+        let destination_amount_received = destination_token_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_destination_mint,
+                destination_token_amount,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        msg!(
+            "burn_token_amount={}, destination_token_amount={}, withdraw_fee={}",
+            burn_pool_token_amount,
+            destination_token_amount,
+            withdraw_fee
+        );
+
+        if destination_amount_received < minimum_destination_amount {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
+        if destination_token_amount == 0 {
+            return Err(crate::error::Error::ZeroTradingTokens.into());
+        }
+
+        if withdraw_fee > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts.to_transfer_fee_context(),
+                to_u64(withdraw_fee)?,
+            )?
+        }
+        token_interface::burn(
+            ctx.accounts.to_burn_context(),
+            to_u64(burn_pool_token_amount)?,
+        )?;
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        ctx.accounts
+            .mint_owner_fee(&mut host, pool_fee_token_amount, signer_seeds)?;
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_destination_out_context()
+                .with_signer(signer_seeds),
+            destination_token_amount,
+        )?;
+
+        // the internal swap fills the orders it crosses, as `swap` does
+        if let Some(order_book) = order_book.as_mut() {
+            ctx.accounts.swap_accounts().fill_orders(
+                &trade_direction,
+                order_book,
+                &price_feed,
+                ctx.program_id,
+            )?;
+            order_book.order_book.exit(ctx.program_id)?;
+        }
+
+        if let Some((host, _)) = host.as_mut() {
+            host.record_volume(&trade_direction, result.source_amount_swapped)?;
+            host.exit(ctx.program_id)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_dynamic_fee(
+        ctx: Context<UpdatePair>,
+        parameters: Option<DynamicFeeParameters>,
+    ) -> Result<()> {
+        msg!("Instruction Set Dynamic Fee {:?}", parameters);
+
+        if let Some(parameters) = &parameters {
+            parameters.validate(&ctx.accounts.pair.fees)?;
+        }
+        ctx.accounts.clear_pair_data()?;
+        ctx.accounts.pair.dynamic_fee_parameters = parameters;
+        ctx.accounts.pair.dynamic_fee_state = DynamicFeeState::default();
+        Ok(())
+    }
+
+    /// Sets the price move limits of the swaps of a pair, restarting its window
+    pub fn set_circuit_breaker(
+        ctx: Context<UpdatePair>,
+        parameters: CircuitBreakerParameters,
+    ) -> Result<()> {
+        msg!("Instruction Set Circuit Breaker {:?}", parameters);
+
+        parameters.validate()?;
+        ctx.accounts.pair.circuit_breaker = parameters;
+        ctx.accounts.pair.circuit_breaker_state = CircuitBreakerState::default();
+        Ok(())
+    }
+
+    /// Sets or removes the price band of the swaps of a pair around the price
+    /// of a `PriceFeed`
+    pub fn set_oracle_guard(
+        ctx: Context<UpdatePair>,
+        oracle_guard: Option<OracleGuard>,
+    ) -> Result<()> {
+        msg!("Instruction Set Oracle Guard {:?}", oracle_guard);
+
+        if let Some(oracle_guard) = &oracle_guard {
+            oracle_guard.validate()?;
+        }
+        ctx.accounts.clear_pair_data()?;
+        ctx.accounts.pair.oracle_guard = oracle_guard;
+        Ok(())
+    }
+
+    /// Sets or removes the PMM curve of a pair, which then swaps around the
+    /// guide price of a `PriceFeed` instead of the constant product
+    pub fn set_pmm(ctx: Context<UpdatePair>, pmm: Option<PmmParameters>) -> Result<()> {
+        msg!("Instruction Set PMM {:?}", pmm);
+
+        if let Some(pmm) = &pmm {
+            pmm.validate()?;
+            // the limit orders and virtual orders are filled on the constant product
+            if ctx.accounts.pair.order_book.is_some() || ctx.accounts.pair.twamm {
+                return Err(crate::error::Error::UnsupportedCurveOperation.into());
+            }
+        }
+        ctx.accounts.clear_pair_data()?;
+        ctx.accounts.pair.pmm = pmm;
+        Ok(())
+    }
+
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let token_a_amount = ctx.accounts.pair.protocol_fee_token_a_amount;
+        let token_b_amount = ctx.accounts.pair.protocol_fee_token_b_amount;
+        msg!(
+            "Instruction Collect Protocol Fees {},{}",
+            token_a_amount,
+            token_b_amount
+        );
+
+        ctx.accounts.pair.protocol_fee_token_a_amount = 0;
+        ctx.accounts.pair.protocol_fee_token_b_amount = 0;
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        if token_a_amount > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts
+                    .to_transfer_a_context()
+                    .with_signer(signer_seeds),
+                token_a_amount,
+            )?;
+        }
+        if token_b_amount > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts
+                    .to_transfer_b_context()
+                    .with_signer(signer_seeds),
+                token_b_amount,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn migrate_pair(ctx: Context<MigratePair>) -> Result<()> {
+        msg!("Instruction Migrate Pair {}", ctx.accounts.pair.key());
+
+        let space = 8 + SwapPair::LEN;
+        if ctx.accounts.pair.data_len() < space {
+            let lamports = Rent::get()?
+                .minimum_balance(space)
+                .saturating_sub(ctx.accounts.pair.lamports());
+            if lamports > 0 {
+                system_program::transfer(ctx.accounts.to_transfer_rent_context(), lamports)?;
+            }
+            ctx.accounts.pair.realloc(space, true)?;
+        }
+
+        let mut pair = SwapPair::try_deserialize(&mut &ctx.accounts.pair.try_borrow_data()?[..])?;
+        msg!("version={}", pair.version);
+        pair.migrate();
+        pair.try_serialize(&mut &mut ctx.accounts.pair.try_borrow_mut_data()?[..])?;
+        Ok(())
+    }
+
+    pub fn close_pool(ctx: Context<ClosePool>) -> Result<()> {
+        msg!("Instruction Close Pool {}", ctx.accounts.destination.key());
+
+        if ctx.accounts.pair.pool_mint_supply(&ctx.accounts.pool)? > 0 {
+            return Err(crate::error::Error::InvalidSupply.into());
+        }
+        let token_a_amount = token_account(
+            &ctx.accounts.token_a_for_pda,
+            &ctx.accounts.pair.token_program,
+        )?
+        .amount;
+        let token_b_amount = token_account(
+            &ctx.accounts.token_b_for_pda,
+            &ctx.accounts.pair.token_program,
+        )?
+        .amount;
+        if token_a_amount > 0 || token_b_amount > 0 {
+            return Err(crate::error::Error::PoolNotEmpty.into());
+        }
+        // the escrows of the orders and the dependent accounts need the pair
+        // to be returned, and those of a pair that doesn't count them may exist
+        let pair = &ctx.accounts.pair;
+        if pair.order_book.is_some()
+            || pair.twamm
+            || pair.dependent_accounts > 0
+            || !pair.dependent_accounts_counted
+        {
+            return Err(crate::error::Error::PairInUse.into());
+        }
+
+        let signer_seeds = ctx
+            .accounts
+            .pair
+            .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        token_interface::close_account(
+            ctx.accounts.to_close_a_context().with_signer(signer_seeds),
+        )?;
+        token_interface::close_account(
+            ctx.accounts.to_close_b_context().with_signer(signer_seeds),
+        )?;
+
+        Ok(())
+    }
+
+    /// Counts the dependent accounts of a pair migrated from before version 7,
+    /// which can't be closed until then. The remaining accounts are every DCA
+    /// order, farm and locked position of the pair.
+    pub fn recount_dependent_accounts<'info>(
+        ctx: Context<'_, '_, '_, 'info, UpdatePair<'info>>,
+    ) -> Result<()> {
+        msg!(
+            "Instruction Recount Dependent Accounts {}",
+            ctx.remaining_accounts.len()
+        );
+
+        for (i, account) in ctx.remaining_accounts.iter().enumerate() {
+            if dependent_account_pair(account)? != ctx.accounts.pair.key()
+                || ctx.remaining_accounts[..i]
+                    .iter()
+                    .any(|other| other.key() == account.key())
+            {
+                return Err(crate::error::Error::InvalidDependentAccount.into());
+            }
+        }
+        ctx.accounts.pair.dependent_accounts = u32::try_from(ctx.remaining_accounts.len())
+            .map_err(|_| crate::error::Error::ConversionFailure)?;
+        ctx.accounts.pair.dependent_accounts_counted = true;
+        Ok(())
+    }
+
+    pub fn initialize_multi_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitializeMultiPool<'info>>,
+        fees: Fees,
+        amp: u64,
+    ) -> Result<()> {
+        multi_pool::initialize(ctx, fees, amp)
+    }
+
+    pub fn multi_pool_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, MultiPoolSwap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        multi_pool::swap(ctx, amount_in, minimum_amount_out)
+    }
+
+    pub fn multi_pool_deposit_all<'info>(
+        ctx: Context<'_, '_, '_, 'info, MultiPoolDepositAll<'info>>,
+        pool_token_amount: u64,
+        maximum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        multi_pool::deposit_all(ctx, pool_token_amount, maximum_token_amounts)
+    }
+
+    pub fn multi_pool_withdraw_all<'info>(
+        ctx: Context<'_, '_, '_, 'info, MultiPoolWithdrawAll<'info>>,
+        pool_token_amount: u64,
+        minimum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        multi_pool::withdraw_all(ctx, pool_token_amount, minimum_token_amounts)
+    }
+
+    pub fn multi_pool_deposit_single<'info>(
+        ctx: Context<'_, '_, '_, 'info, MultiPoolDepositSingle<'info>>,
+        source_token_amount: u64,
+        minimum_pool_token_amount: u64,
+    ) -> Result<()> {
+        multi_pool::deposit_single(ctx, source_token_amount, minimum_pool_token_amount)
+    }
+
+    pub fn multi_pool_withdraw_single<'info>(
+        ctx: Context<'_, '_, '_, 'info, MultiPoolWithdrawSingle<'info>>,
+        pool_token_amount: u64,
+        minimum_destination_amount: u64,
+    ) -> Result<()> {
+        multi_pool::withdraw_single(ctx, pool_token_amount, minimum_destination_amount)
+    }
+
+    pub fn initialize_cl_pool(
+        ctx: Context<InitializeClPool>,
+        fees: Fees,
+        tick_spacing: u16,
+        sqrt_price: u128,
+    ) -> Result<()> {
+        cl_pool::initialize(ctx, fees, tick_spacing, sqrt_price)
+    }
+
+    pub fn initialize_tick_array(
+        ctx: Context<InitializeTickArray>,
+        start_tick_index: i32,
+    ) -> Result<()> {
+        cl_pool::initialize_tick_array(ctx, start_tick_index)
+    }
+
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        tick_lower: i32,
+        tick_upper: i32,
+    ) -> Result<()> {
+        cl_pool::open_position(ctx, tick_lower, tick_upper)
+    }
+
+    pub fn increase_liquidity(
+        ctx: Context<ModifyPosition>,
+        liquidity: u128,
+        maximum_token_a_amount: u64,
+        maximum_token_b_amount: u64,
+    ) -> Result<()> {
+        cl_pool::increase_liquidity(
+            ctx,
+            liquidity,
+            maximum_token_a_amount,
+            maximum_token_b_amount,
+        )
+    }
+
+    pub fn decrease_liquidity(
+        ctx: Context<ModifyPosition>,
+        liquidity: u128,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+    ) -> Result<()> {
+        cl_pool::decrease_liquidity(
+            ctx,
+            liquidity,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+        )
+    }
+
+    pub fn collect_fees(ctx: Context<ModifyPosition>) -> Result<()> {
+        cl_pool::collect_fees(ctx)
+    }
+
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        cl_pool::close_position(ctx)
+    }
+
+    pub fn cl_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClSwap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        sqrt_price_limit: u128,
+    ) -> Result<()> {
+        cl_pool::swap(ctx, amount_in, minimum_amount_out, sqrt_price_limit)
+    }
+
+    pub fn collect_cl_protocol_fees(ctx: Context<CollectClProtocolFees>) -> Result<()> {
+        cl_pool::collect_protocol_fees(ctx)
+    }
+
+    pub fn initialize_order_book(ctx: Context<InitializeOrderBook>) -> Result<()> {
+        order_book::initialize(ctx)
+    }
+
+    /// Places an order selling `amount_in` of the source token of
+    /// `trade_direction` for at least `amount_out` of the other token, at the
+    /// same average price when partially filled. Only the amount left after
+    /// the transfer fee of the source mint reaches the escrow and is sold.
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        amount_out: u64,
+    ) -> Result<()> {
+        order_book::place_order(ctx, trade_direction, amount_in, amount_out)
+    }
+
+    /// Removes the order and returns both its unsold input and the proceeds
+    /// of its fills to the owner.
+    pub fn cancel_order(ctx: Context<CancelOrder>, order_id: u64) -> Result<()> {
+        order_book::cancel_order(ctx, order_id)
+    }
+
+    pub fn initialize_twamm(ctx: Context<InitializeTwamm>) -> Result<()> {
+        twamm::initialize(ctx)
+    }
+
+    /// Places an order virtually selling `amount_in` of the source token of
+    /// `trade_direction` at a constant rate over the next `duration` seconds.
+    /// The remainder of `amount_in` over a whole number of tokens per second
+    /// is not sold and returned on cancel.
+    pub fn place_long_term_order(
+        ctx: Context<PlaceLongTermOrder>,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        duration: u64,
+    ) -> Result<()> {
+        twamm::place_long_term_order(ctx, trade_direction, amount_in, duration)
+    }
+
+    /// Removes the order after executing the virtual orders up to now, and
+    /// returns both its unsold input and its proceeds to the owner, which
+    /// also withdraws a completed order.
+    pub fn cancel_long_term_order(ctx: Context<CancelLongTermOrder>, order_id: u64) -> Result<()> {
+        twamm::cancel_long_term_order(ctx, order_id)
+    }
+
+    /// Permissionless crank executing the virtual orders of the pair up to now
+    pub fn execute_virtual_orders(ctx: Context<ExecuteVirtualOrders>) -> Result<()> {
+        twamm::execute_virtual_orders(ctx)
+    }
+
+    /// Opens a DCA order selling `amount` of the source token in cycles of
+    /// `amount_per_cycle` every `interval` seconds, the first being due now.
+    /// A full cycle must buy at least `minimum_amount_out_per_cycle` and pays
+    /// `tip` of the source token to its cranker.
+    pub fn open_dca(
+        ctx: Context<OpenDca>,
+        id: u64,
+        amount: u64,
+        amount_per_cycle: u64,
+        interval: i64,
+        minimum_amount_out_per_cycle: u64,
+        tip: u64,
+    ) -> Result<()> {
+        dca::open_dca(
+            ctx,
+            id,
+            amount,
+            amount_per_cycle,
+            interval,
+            minimum_amount_out_per_cycle,
+            tip,
+        )
+    }
+
+    /// Permissionless crank swapping the due cycle of a DCA order. The
+    /// remaining accounts are those of `swap`, without the host.
+    pub fn crank_dca<'info>(ctx: Context<'_, '_, '_, 'info, CrankDca<'info>>) -> Result<()> {
+        dca::crank_dca(ctx)
+    }
+
+    /// Sends the tokens bought so far by a DCA order to its owner
+    pub fn withdraw_dca(ctx: Context<WithdrawDca>) -> Result<()> {
+        dca::withdraw_dca(ctx)
+    }
+
+    /// Returns the unsold and bought tokens of a DCA order to its owner, then
+    /// closes its escrows and the order
+    pub fn close_dca(ctx: Context<CloseDca>) -> Result<()> {
+        dca::close_dca(ctx)
+    }
+
+    pub fn initialize_farm(ctx: Context<InitializeFarm>) -> Result<()> {
+        farm::initialize(ctx)
+    }
+
+    /// Adds a reward emitting `emission_rate` tokens per second to the
+    /// stakers. The vault is funded by transferring the rewards to it.
+    pub fn add_farm_reward(ctx: Context<AddFarmReward>, emission_rate: u64) -> Result<()> {
+        farm::add_reward(ctx, emission_rate)
+    }
+
+    pub fn set_farm_emission_rate(
+        ctx: Context<UpdateFarm>,
+        reward_index: u8,
+        emission_rate: u64,
+    ) -> Result<()> {
+        farm::set_emission_rate(ctx, reward_index, emission_rate)
+    }
+
+    /// Pausing stops the emissions and the stakes, while the stakers can
+    /// still unstake and claim the rewards accrued before
+    pub fn set_farm_paused(ctx: Context<UpdateFarm>, paused: bool) -> Result<()> {
+        farm::set_paused(ctx, paused)
+    }
+
+    pub fn initialize_farm_stake(ctx: Context<InitializeFarmStake>) -> Result<()> {
+        farm::initialize_stake(ctx)
+    }
+
+    pub fn stake(ctx: Context<ModifyStake>, amount: u64) -> Result<()> {
+        farm::stake(ctx, amount)
+    }
+
+    /// Unstaking is always possible, including while the farm is paused
+    pub fn unstake(ctx: Context<ModifyStake>, amount: u64) -> Result<()> {
+        farm::unstake(ctx, amount)
+    }
+
+    /// Pays the accrued rewards, up to what each vault holds. The remaining
+    /// accounts are the mint, the vault and the token account of the owner
+    /// for every reward of the farm in order.
+    pub fn claim_rewards<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimRewards<'info>>,
+    ) -> Result<()> {
+        farm::claim_rewards(ctx)
+    }
+
+    /// Escrows `amount` of LP tokens until `unlock_ts`. The optional remaining
+    /// accounts are a receipt mint, with the locked position as mint authority
+    /// and nothing minted yet, and the token account receiving the receipt
+    /// whose holder may then unlock the position instead of its owner.
+    pub fn lock_lp<'info>(
+        ctx: Context<'_, '_, '_, 'info, LockLp<'info>>,
+        id: u64,
+        amount: u64,
+        unlock_ts: i64,
+    ) -> Result<()> {
+        locked_position::lock_lp(ctx, id, amount, unlock_ts)
+    }
+
+    /// Returns the LP tokens of an expired locked position to its holder,
+    /// closing the position and its escrow. The remaining accounts are the
+    /// receipt mint and the token account of the holder when the position has
+    /// a receipt, which is burnt, followed by the farm and the farm stake when
+    /// the position boosts a stake.
+    pub fn unlock_lp<'info>(ctx: Context<'_, '_, '_, 'info, UnlockLp<'info>>) -> Result<()> {
+        locked_position::unlock_lp(ctx)
+    }
+
+    /// Adds the boosted shares of a locked position to a farm stake of its
+    /// holder until the position is unlocked. The shares are fixed when
+    /// boosting, from the lock time remaining at that moment. The remaining
+    /// accounts are the receipt mint and the token account of the holder when
+    /// the position has a receipt.
+    pub fn boost_farm_stake<'info>(
+        ctx: Context<'_, '_, '_, 'info, BoostFarmStake<'info>>,
+    ) -> Result<()> {
+        locked_position::boost_farm_stake(ctx)
+    }
+
+    pub fn initialize_price_feed(ctx: Context<InitializePriceFeed>, id: u64) -> Result<()> {
+        msg!("Instruction Price Feed Init {}", id);
+
+        let price_feed = &mut ctx.accounts.price_feed;
+        price_feed.authority = ctx.accounts.authority.key();
+        price_feed.id = id;
+        price_feed.price = 0;
+        price_feed.publish_ts = 0;
+        price_feed.bump = *ctx
+            .bumps
+            .get("price_feed")
+            .ok_or(crate::error::Error::InvalidProgramAddress)?;
+        Ok(())
+    }
+
+    /// Publishes `price`, of token A in token B as a 64.64 fixed point
+    /// number, at the current time
+    pub fn update_price_feed(ctx: Context<UpdatePriceFeed>, price: u128) -> Result<()> {
+        msg!("Instruction Update Price Feed {}", price);
+
+        let price_feed = &mut ctx.accounts.price_feed;
+        price_feed.price = price;
+        price_feed.publish_ts = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut, constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::LEN,
+        seeds = [b"config".as_ref()],
+        bump
+    )]
+    pub config: Box<Account<'info, Config>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [b"config".as_ref()], bump = config.bump)]
+    pub config: Box<Account<'info, Config>>,
+}
+
+#[derive(Accounts)]
+#[instruction(fees: Fees, owner_fee_mode: OwnerFeeMode, fee_tier: u16)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump)]
+    pub config: Box<Account<'info, Config>>,
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, token_program.key)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, token_program.key)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = mint(&token_a_mint, token_program.key)?.is_initialized,
+        constraint = token_a_mint.key() != pool.key()
+    )]
+    pub token_a_mint: UncheckedAccount<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = mint(&token_b_mint, token_program.key)?.is_initialized,
+        constraint = token_b_mint.key() != pool.key(),
+        constraint = token_b_mint.key() != token_a_mint.key() @ crate::error::Error::RepeatedMint,
+        constraint = token_a_mint.key() < token_b_mint.key() @ crate::error::Error::InvalidMintOrder
+    )]
+    pub token_b_mint: UncheckedAccount<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_pda, token_program.key)?.mint == token_a_mint.key(),
+        constraint = is_held_by(&token_account(&token_a_for_pda, token_program.key)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_pda, token_program.key)?.mint == token_b_mint.key(),
+        constraint = is_held_by(&token_account(&token_b_for_pda, token_program.key)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + SwapPair::LEN,
+        seeds = [
+            b"pair".as_ref(),
+            token_a_mint.key().as_ref(),
+            token_b_mint.key().as_ref(),
+            fee_tier.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_initializer, token_program.key)?.mint == pool.key(),
+        constraint = token_account(&token_pool_for_initializer, token_program.key)?.owner == admin_pubkey()?
+    )]
+    pub token_pool_for_initializer: UncheckedAccount<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_fee_receiver, token_program.key)?.mint == pool.key(),
+        constraint = token_account(&token_pool_for_fee_receiver, token_program.key)?.owner == admin_pubkey()?
+    )]
+    pub token_pool_for_fee_receiver: UncheckedAccount<'info>,
+    /// CHECK: SPL Token or Token-2022, checked against the allowlist
+    #[account(
+        constraint = is_token_program(token_program.key) @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(pool_token_amount: u64, maximum_token_a_amount: u64, maximum_token_b_amount: u64)]
+pub struct DepositAll<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_a_for_pda.key(),
+        constraint = pair.token_b_account == token_b_for_pda.key(),
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_depositor, &pair.token_program)?.mint == pair.token_a_mint,
+        constraint = is_held_by(&token_account(&token_a_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_a_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_depositor, &pair.token_program)?.mint == pair.token_b_mint,
+        constraint = is_held_by(&token_account(&token_b_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_b_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_a_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_b_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(source_token_amount: u64, mainimum_pool_token_amount: u64)]
+pub struct DepositSingle<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_a_for_pda.key(),
+        constraint = pair.token_b_account == token_b_for_pda.key(),
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_source_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_source_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_a_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_b_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(pool_token_amount: u64, minimum_token_a_amount: u64, minimum_token_b_amount: u64)]
+pub struct WithdrawAll<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_a_for_pda.key(),
+        constraint = pair.token_b_account == token_b_for_pda.key(),
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_depositor, &pair.token_program)?.mint == pair.token_a_mint,
+        constraint = is_held_by(&token_account(&token_a_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_a_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_depositor, &pair.token_program)?.mint == pair.token_b_mint,
+        constraint = is_held_by(&token_account(&token_b_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_b_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_a_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_b_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(destination_token_amount: u64, maximum_pool_token_amount: u64)]
+pub struct WithdrawSingle<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
 
     #[account(
         mut,
         constraint = pair.token_a_account == token_a_for_pda.key(),
         constraint = pair.token_b_account == token_b_for_pda.key(),
-        constraint = pair.token_a_mint == token_a_for_depositor.mint,
-        constraint = pair.token_b_mint == token_b_for_depositor.mint,
-        constraint = pair.token_a_mint == token_a_for_pda.mint,
-        constraint = pair.token_b_mint == token_b_for_pda.mint,
-        constraint = pair.pool_mint == pool.key()
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
     )]
     pub pair: Box<Account<'info, SwapPair>>,
 
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_destination_for_depositor, &pair.token_program)?, &depositor.key())
+    )]
+    pub token_destination_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
+        constraint = is_held_by(&token_account(&token_a_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = is_held_by(&token_account(&token_b_for_pda, &pair.token_program)?, &pda.key())
+    )]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount_in: u64, minimum_amount_out: u64)]
+pub struct Swap<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub swapper: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_source_for_pda.key() || pair.token_a_account == token_destination_for_pda.key(),
+        constraint = pair.token_b_account == token_source_for_pda.key() || pair.token_b_account == token_destination_for_pda.key(),
+        constraint = pair.token_a_mint == token_source_mint.key() || pair.token_a_mint == token_destination_mint.key(),
+        constraint = pair.token_b_mint == token_source_mint.key() || pair.token_b_mint == token_destination_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
     )]
-    pub pool: Account<'info, Mint>,
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_source_mint: UncheckedAccount<'info>,
 
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_a_for_depositor.owner == depositor.key(),
-        constraint = token_a_for_depositor.delegate.is_none(),
-        constraint = token_a_for_depositor.close_authority.is_none(),
+        constraint = token_account(&token_source_for_swapper, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_swapper, &pair.token_program)?, &swapper.key())
     )]
-    pub token_a_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_source_for_swapper: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_b_for_depositor.owner == depositor.key(),
-        constraint = token_b_for_depositor.delegate.is_none(),
-        constraint = token_b_for_depositor.close_authority.is_none(),
+        constraint = token_account(&token_destination_for_swapper, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_swapper, &pair.token_program)?, &swapper.key())
     )]
-    pub token_b_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_destination_for_swapper: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_pool_for_depositor.owner == depositor.key(),
-        constraint = token_pool_for_depositor.mint == pool.key(),
+        constraint = token_account(&token_source_for_pda, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_pda, &pair.token_program)?, &pda.key())
     )]
-    pub token_pool_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_source_for_pda: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_a_for_pda.owner == pda.key(),
-        constraint = token_a_for_pda.delegate.is_none(),
-        constraint = token_a_for_pda.close_authority.is_none()
+        constraint = token_account(&token_destination_for_pda, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_pda, &pair.token_program)?, &pda.key())
     )]
-    pub token_a_for_pda: Box<Account<'info, TokenAccount>>,
+    pub token_destination_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
 
+    /// CHECK: This is not dangerous because it must be the token program of the pair
     #[account(
-        mut,
-        constraint = token_b_for_pda.owner == pda.key(),
-        constraint = token_b_for_pda.delegate.is_none(),
-        constraint = token_b_for_pda.close_authority.is_none()
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
     )]
-    pub token_b_for_pda: Box<Account<'info, TokenAccount>>,
-
-    /// CHECK: This is not dangerous because we don't read or write from this account
     pub token_program: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(source_token_amount: u64, mainimum_pool_token_amount: u64)]
-pub struct DepositSingle<'info> {
+#[instruction(amount: u64, minimum_amount_out: u64)]
+pub struct Zap<'info> {
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub depositor: Signer<'info>,
 
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub pda: AccountInfo<'info>,
 
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = pair.token_a_account == token_a_for_pda.key(),
-        constraint = pair.token_b_account == token_b_for_pda.key(),
-        constraint = pair.token_a_mint == token_a_for_pda.mint,
-        constraint = pair.token_b_mint == token_b_for_pda.mint,
-        constraint = pair.pool_mint == pool.key()
+        constraint = mint(&pool, &pair.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &pair.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_source_for_pda.key() || pair.token_a_account == token_destination_for_pda.key(),
+        constraint = pair.token_b_account == token_source_for_pda.key() || pair.token_b_account == token_destination_for_pda.key(),
+        constraint = pair.token_a_mint == token_source_mint.key() || pair.token_a_mint == token_destination_mint.key(),
+        constraint = pair.token_b_mint == token_source_mint.key() || pair.token_b_mint == token_destination_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
     )]
     pub pair: Box<Account<'info, SwapPair>>,
 
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be a mint of the pair
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
+        constraint = token_account(&token_source_for_depositor, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_depositor, &pair.token_program)?, &depositor.key())
     )]
-    pub pool: Account<'info, Mint>,
+    pub token_source_for_depositor: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_source_for_depositor.owner == depositor.key(),
-        constraint = token_source_for_depositor.delegate.is_none(),
-        constraint = token_source_for_depositor.close_authority.is_none(),
+        constraint = token_account(&token_destination_for_depositor, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_depositor, &pair.token_program)?, &depositor.key())
     )]
-    pub token_source_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_destination_for_depositor: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_pool_for_depositor.owner == depositor.key(),
-        constraint = token_pool_for_depositor.mint == pool.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &pair.token_program)?.mint == pool.key(),
     )]
-    pub token_pool_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_a_for_pda.owner == pda.key(),
-        constraint = token_a_for_pda.delegate.is_none(),
-        constraint = token_a_for_pda.close_authority.is_none()
+        constraint = token_account(&token_source_for_pda, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_pda, &pair.token_program)?, &pda.key())
     )]
-    pub token_a_for_pda: Box<Account<'info, TokenAccount>>,
+    pub token_source_for_pda: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_b_for_pda.owner == pda.key(),
-        constraint = token_b_for_pda.delegate.is_none(),
-        constraint = token_b_for_pda.close_authority.is_none()
+        constraint = token_account(&token_destination_for_pda, &pair.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_pda, &pair.token_program)?, &pda.key())
     )]
-    pub token_b_for_pda: Box<Account<'info, TokenAccount>>,
+    pub token_destination_for_pda: UncheckedAccount<'info>,
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
     pub token_program: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(pool_token_amount: u64, minimum_token_a_amount: u64, minimum_token_b_amount: u64)]
-pub struct WithdrawAll<'info> {
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub depositor: Signer<'info>,
+#[instruction(host_fee_numerator: Option<u64>)]
+pub struct RegisterHost<'info> {
+    #[account(mut, constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    /// CHECK: This is not dangerous because we only record the address of this account
+    pub wallet: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Host::LEN,
+        seeds = [b"host".as_ref(), pair.key().as_ref(), wallet.key().as_ref()],
+        bump
+    )]
+    pub host: Box<Account<'info, Host>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts of the admin instructions setting the configuration of a pair
+#[derive(Accounts)]
+pub struct UpdatePair<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    pub pair: Box<Account<'info, SwapPair>>,
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
 
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub pda: AccountInfo<'info>,
@@ -601,314 +2240,1067 @@ pub struct WithdrawAll<'info> {
         mut,
         constraint = pair.token_a_account == token_a_for_pda.key(),
         constraint = pair.token_b_account == token_b_for_pda.key(),
-        constraint = pair.token_a_mint == token_a_for_depositor.mint,
-        constraint = pair.token_b_mint == token_b_for_depositor.mint,
-        constraint = pair.token_a_mint == token_a_for_pda.mint,
-        constraint = pair.token_b_mint == token_b_for_pda.mint,
-        constraint = pair.pool_mint == pool.key(),
-        constraint = pair.pool_fee_account == pool_fee_account.key(),
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
     )]
     pub pair: Box<Account<'info, SwapPair>>,
 
-    #[account(
-        mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
-    )]
-    pub pool: Account<'info, Mint>,
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
 
-    #[account(
-        mut,
-        constraint = token_a_for_depositor.owner == depositor.key(),
-        constraint = token_a_for_depositor.delegate.is_none(),
-        constraint = token_a_for_depositor.close_authority.is_none(),
-    )]
-    pub token_a_for_depositor: Box<Account<'info, TokenAccount>>,
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
 
-    #[account(
-        mut,
-        constraint = token_b_for_depositor.owner == depositor.key(),
-        constraint = token_b_for_depositor.delegate.is_none(),
-        constraint = token_b_for_depositor.close_authority.is_none(),
-    )]
-    pub token_b_for_depositor: Box<Account<'info, TokenAccount>>,
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_b_for_pda: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_pool_for_depositor.owner == depositor.key(),
-        constraint = token_pool_for_depositor.mint == pool.key(),
+        constraint = token_account(&token_a_for_admin, &pair.token_program)?.owner == admin.key(),
     )]
-    pub token_pool_for_depositor: Box<Account<'info, TokenAccount>>,
+    pub token_a_for_admin: UncheckedAccount<'info>,
 
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
     #[account(
         mut,
-        constraint = token_a_for_pda.owner == pda.key(),
-        constraint = token_a_for_pda.delegate.is_none(),
-        constraint = token_a_for_pda.close_authority.is_none()
+        constraint = token_account(&token_b_for_admin, &pair.token_program)?.owner == admin.key(),
     )]
-    pub token_a_for_pda: Box<Account<'info, TokenAccount>>,
+    pub token_b_for_admin: UncheckedAccount<'info>,
 
+    /// CHECK: This is not dangerous because it must be the token program of the pair
     #[account(
-        mut,
-        constraint = token_b_for_pda.owner == pda.key(),
-        constraint = token_b_for_pda.delegate.is_none(),
-        constraint = token_b_for_pda.close_authority.is_none()
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
     )]
-    pub token_b_for_pda: Box<Account<'info, TokenAccount>>,
+    pub token_program: AccountInfo<'info>,
+}
 
+#[derive(Accounts)]
+pub struct MigratePair<'info> {
     #[account(mut)]
-    pub pool_fee_account: Box<Account<'info, TokenAccount>>,
+    pub payer: Signer<'info>,
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub token_program: AccountInfo<'info>,
+    /// CHECK: SwapPair of any layout version, deserialized once reallocated
+    #[account(mut, owner = crate::ID)]
+    pub pair: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(destination_token_amount: u64, maximum_pool_token_amount: u64)]
-pub struct WithdrawSingle<'info> {
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub depositor: Signer<'info>,
+pub struct ClosePool<'info> {
+    #[account(constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
 
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub pda: AccountInfo<'info>,
 
     #[account(
         mut,
+        close = destination,
         constraint = pair.token_a_account == token_a_for_pda.key(),
         constraint = pair.token_b_account == token_b_for_pda.key(),
-        constraint = pair.token_a_mint == token_a_for_pda.mint,
-        constraint = pair.token_b_mint == token_b_for_pda.mint,
         constraint = pair.pool_mint == pool.key(),
-        constraint = pair.pool_fee_account == pool_fee_account.key(),
     )]
     pub pair: Box<Account<'info, SwapPair>>,
 
+    /// CHECK: This is not dangerous because it must be the pool mint of the pair
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it only receives the rent of the closed accounts
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ crate::error::Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct InitializePriceFeed<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + PriceFeed::LEN,
+        seeds = [b"price_feed".as_ref(), authority.key().as_ref(), id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub price_feed: Box<Account<'info, PriceFeed>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePriceFeed<'info> {
+    pub authority: Signer<'info>,
+
     #[account(
         mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
+        constraint = price_feed.authority == authority.key() @ crate::error::Error::InvalidOwner,
     )]
-    pub pool: Account<'info, Mint>,
+    pub price_feed: Box<Account<'info, PriceFeed>>,
+}
+
+impl<'info> Initialize<'info> {
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_initializer.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> SyncTwamm<'info> for DepositAll<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let accounts = VirtualOrderAccounts {
+            pda: &self.pda,
+            pool: &self.pool,
+            pool_fee_account: &self.pool_fee_account,
+            token_a_mint: &self.token_a_mint,
+            token_b_mint: &self.token_b_mint,
+            token_a_for_pda: &self.token_a_for_pda,
+            token_b_for_pda: &self.token_b_for_pda,
+            token_a_escrow: &twamm.token_a_escrow,
+            token_b_escrow: &twamm.token_b_escrow,
+            token_program: &self.token_program,
+        };
+        accounts.execute(&mut self.pair, &mut twamm.twamm, program_id)
+    }
+}
+
+impl<'info> DepositAll<'info> {
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_depositor.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_pda.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_depositor.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_pda.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> SyncTwamm<'info> for DepositSingle<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let accounts = VirtualOrderAccounts {
+            pda: &self.pda,
+            pool: &self.pool,
+            pool_fee_account: &self.pool_fee_account,
+            token_a_mint: &self.token_a_mint,
+            token_b_mint: &self.token_b_mint,
+            token_a_for_pda: &self.token_a_for_pda,
+            token_b_for_pda: &self.token_b_for_pda,
+            token_a_escrow: &twamm.token_a_escrow,
+            token_b_escrow: &twamm.token_b_escrow,
+            token_program: &self.token_program,
+        };
+        accounts.execute(&mut self.pair, &mut twamm.twamm, program_id)
+    }
+}
+
+impl<'info> DepositSingle<'info> {
+    fn to_transfer_context(
+        &self,
+        direction: &TradeDirection,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_depositor.to_account_info().clone(),
+            mint: self.source_mint(direction).clone(),
+            to: match direction {
+                TradeDirection::AtoB => self.token_a_for_pda.to_account_info().clone(),
+                TradeDirection::BtoA => self.token_b_for_pda.to_account_info().clone(),
+            },
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn source_mint(&self, direction: &TradeDirection) -> &AccountInfo<'info> {
+        match direction {
+            TradeDirection::AtoB => &self.token_a_mint,
+            TradeDirection::BtoA => &self.token_b_mint,
+        }
+    }
+
+    fn trade_direction(&self) -> Result<TradeDirection> {
+        let source_mint =
+            token_account(&self.token_source_for_depositor, &self.pair.token_program)?.mint;
+        if source_mint == self.pair.token_a_mint {
+            Ok(TradeDirection::AtoB)
+        } else if source_mint == self.pair.token_b_mint.key() {
+            Ok(TradeDirection::BtoA)
+        } else {
+            return Err(crate::error::Error::IncorrectSwapAccount.into());
+        }
+    }
+}
+
+impl<'info> SyncTwamm<'info> for WithdrawAll<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let accounts = VirtualOrderAccounts {
+            pda: &self.pda,
+            pool: &self.pool,
+            pool_fee_account: &self.pool_fee_account,
+            token_a_mint: &self.token_a_mint,
+            token_b_mint: &self.token_b_mint,
+            token_a_for_pda: &self.token_a_for_pda,
+            token_b_for_pda: &self.token_b_for_pda,
+            token_a_escrow: &twamm.token_a_escrow,
+            token_b_escrow: &twamm.token_b_escrow,
+            token_program: &self.token_program,
+        };
+        accounts.execute(&mut self.pair, &mut twamm.twamm, program_id)
+    }
+}
+
+impl<'info> WithdrawAll<'info> {
+    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_pda.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_pda.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: self.pool.to_account_info().clone(),
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn withdraw_fee(&self, pool_token_amount: u64) -> Result<u128> {
+        Ok(
+            if self.token_pool_for_depositor.key() == self.pair.pool_fee_account {
+                // withdrawing from the fee account, don't assess withdraw fee
+                0
+            } else {
+                self.pair
+                    .fees
+                    .owner_withdraw_fee(to_u128(pool_token_amount)?)?
+            },
+        )
+    }
+}
+
+impl<'info> SyncTwamm<'info> for WithdrawSingle<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let accounts = VirtualOrderAccounts {
+            pda: &self.pda,
+            pool: &self.pool,
+            pool_fee_account: &self.pool_fee_account,
+            token_a_mint: &self.token_a_mint,
+            token_b_mint: &self.token_b_mint,
+            token_a_for_pda: &self.token_a_for_pda,
+            token_b_for_pda: &self.token_b_for_pda,
+            token_a_escrow: &twamm.token_a_escrow,
+            token_b_escrow: &twamm.token_b_escrow,
+            token_program: &self.token_program,
+        };
+        accounts.execute(&mut self.pair, &mut twamm.twamm, program_id)
+    }
+}
+
+impl<'info> WithdrawSingle<'info> {
+    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_context(
+        &self,
+        direction: &TradeDirection,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: match direction {
+                TradeDirection::AtoB => self.token_a_for_pda.to_account_info().clone(),
+                TradeDirection::BtoA => self.token_b_for_pda.to_account_info().clone(),
+            },
+            mint: self.destination_mint(direction).clone(),
+            to: self
+                .token_destination_for_depositor
+                .to_account_info()
+                .clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
 
-    #[account(
-        mut,
-        constraint = token_destination_for_depositor.owner == depositor.key(),
-        constraint = token_destination_for_depositor.delegate.is_none(),
-        constraint = token_destination_for_depositor.close_authority.is_none(),
-    )]
-    pub token_destination_for_depositor: Box<Account<'info, TokenAccount>>,
+    fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: self.pool.to_account_info().clone(),
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
 
-    #[account(
-        mut,
-        constraint = token_pool_for_depositor.owner == depositor.key(),
-        constraint = token_pool_for_depositor.mint == pool.key(),
-    )]
-    pub token_pool_for_depositor: Box<Account<'info, TokenAccount>>,
+    fn destination_mint(&self, direction: &TradeDirection) -> &AccountInfo<'info> {
+        match direction {
+            TradeDirection::AtoB => &self.token_a_mint,
+            TradeDirection::BtoA => &self.token_b_mint,
+        }
+    }
 
-    #[account(
-        mut,
-        constraint = token_a_for_pda.owner == pda.key(),
-        constraint = token_a_for_pda.delegate.is_none(),
-        constraint = token_a_for_pda.close_authority.is_none()
-    )]
-    pub token_a_for_pda: Box<Account<'info, TokenAccount>>,
+    fn trade_direction(&self) -> Result<TradeDirection> {
+        let destination_mint = token_account(
+            &self.token_destination_for_depositor,
+            &self.pair.token_program,
+        )?
+        .mint;
+        if destination_mint == self.pair.token_a_mint {
+            Ok(TradeDirection::AtoB)
+        } else if destination_mint == self.pair.token_b_mint.key() {
+            Ok(TradeDirection::BtoA)
+        } else {
+            Err(crate::error::Error::IncorrectSwapAccount.into())
+        }
+    }
 
-    #[account(
-        mut,
-        constraint = token_b_for_pda.owner == pda.key(),
-        constraint = token_b_for_pda.delegate.is_none(),
-        constraint = token_b_for_pda.close_authority.is_none()
-    )]
-    pub token_b_for_pda: Box<Account<'info, TokenAccount>>,
+    fn withdraw_fee(&self, burn_pool_token_amount: u128) -> Result<u128> {
+        Ok(
+            if self.token_pool_for_depositor.key() == self.pair.pool_fee_account {
+                // withdrawing from the fee account, don't assess withdraw fee
+                0
+            } else {
+                self.pair.fees.owner_withdraw_fee(burn_pool_token_amount)?
+            },
+        )
+    }
+}
 
-    #[account(mut)]
-    pub pool_fee_account: Box<Account<'info, TokenAccount>>,
+impl<'info> SyncTwamm<'info> for Swap<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub token_program: AccountInfo<'info>,
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let mut accounts = self.swap_accounts();
+        let trade_direction = accounts.trade_direction();
+        accounts.execute_virtual_orders(&trade_direction, twamm, program_id)
+    }
 }
 
-#[derive(Accounts)]
-#[instruction(amount_in: u64, minimum_amount_out: u64)]
-pub struct Swap<'info> {
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub swapper: Signer<'info>,
+impl<'info> Swap<'info> {
+    fn swap_accounts(&mut self) -> SwapAccounts<'_, 'info> {
+        SwapAccounts {
+            swapper: &self.swapper,
+            pda: &self.pda,
+            pool: &self.pool,
+            pair: &mut self.pair,
+            token_source_mint: &self.token_source_mint,
+            token_destination_mint: &self.token_destination_mint,
+            token_source_for_swapper: &self.token_source_for_swapper,
+            token_destination_for_swapper: &self.token_destination_for_swapper,
+            token_source_for_pda: &self.token_source_for_pda,
+            token_destination_for_pda: &self.token_destination_for_pda,
+            pool_fee_account: &self.pool_fee_account,
+            token_program: &self.token_program,
+        }
+    }
+}
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub pda: AccountInfo<'info>,
+/// The accounts of `Swap`, also built by the DCA crank whose swapper is the
+/// `DcaOrder` holding the source and destination token accounts
+struct SwapAccounts<'a, 'info> {
+    swapper: &'a AccountInfo<'info>,
+    pda: &'a AccountInfo<'info>,
+    pool: &'a AccountInfo<'info>,
+    pair: &'a mut SwapPair,
+    token_source_mint: &'a AccountInfo<'info>,
+    token_destination_mint: &'a AccountInfo<'info>,
+    token_source_for_swapper: &'a AccountInfo<'info>,
+    token_destination_for_swapper: &'a AccountInfo<'info>,
+    token_source_for_pda: &'a AccountInfo<'info>,
+    token_destination_for_pda: &'a AccountInfo<'info>,
+    pool_fee_account: &'a AccountInfo<'info>,
+    token_program: &'a AccountInfo<'info>,
+}
 
-    #[account(
-        mut,
-        constraint = pool.mint_authority == COption::Some(pda.key()),
-        constraint = pool.freeze_authority.is_none()
-    )]
-    pub pool: Account<'info, Mint>,
+impl<'a, 'info> SwapAccounts<'a, 'info> {
+    /// Swaps `amount_in` from the source token account of the swapper, signed
+    /// with `source_signer_seeds` when the swapper is a PDA, and returns the
+    /// amount its destination token account receives
+    #[allow(clippy::too_many_arguments)]
+    fn swap(
+        &mut self,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        order_book: &mut Option<OrderBookAccounts<'info>>,
+        price_feed: &Option<Account<'info, PriceFeed>>,
+        pmm_price_feed: &Option<Account<'info, PriceFeed>>,
+        host: &mut Option<(Account<'info, Host>, AccountInfo<'info>)>,
+        source_signer_seeds: &[&[&[u8]]],
+        program_id: &Pubkey,
+    ) -> Result<u128> {
+        let trade_direction = self.trade_direction();
+        let curve = ConstantProduct {};
+        let pmm = self.pair.pmm_curve(pmm_price_feed)?;
+        let (swap_source_amount, swap_destination_amount) =
+            self.swap_token_amounts(&trade_direction)?;
+        let fees = match trade_direction {
+            TradeDirection::AtoB => self.pair.swap_fees(
+                to_u128(swap_source_amount)?,
+                to_u128(swap_destination_amount)?,
+            )?,
+            TradeDirection::BtoA => self.pair.swap_fees(
+                to_u128(swap_destination_amount)?,
+                to_u128(swap_source_amount)?,
+            )?,
+        };
+        // only the amount left after the transfer fee of the source mint reaches the pool
+        let amount_in_received = amount_in
+            .checked_sub(token_interface::transfer_fee(
+                self.token_source_mint,
+                amount_in,
+            )?)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        let mut result = match &pmm {
+            Some(pmm) => pmm.swap(
+                to_u128(amount_in_received)?,
+                to_u128(swap_source_amount)?,
+                to_u128(swap_destination_amount)?,
+                &trade_direction,
+                &fees,
+            ),
+            None => curve.swap(
+                to_u128(amount_in_received)?,
+                to_u128(swap_source_amount)?,
+                to_u128(swap_destination_amount)?,
+                &fees,
+            ),
+        }?;
+
+        // the result reports what the swapper receives after the transfer fee
+        // of the destination mint, while the pool sends the full amount
+        let destination_amount_sent = to_u64(result.destination_amount_swapped)?;
+        result.destination_amount_swapped = to_u128(
+            destination_amount_sent
+                .checked_sub(token_interface::transfer_fee(
+                    self.token_destination_mint,
+                    destination_amount_sent,
+                )?)
+                .ok_or(crate::error::Error::FeeCalculationFailure)?,
+        )?;
 
-    #[account(
-        mut,
-        constraint = pair.token_a_account == token_source_for_pda.key() || pair.token_a_account == token_destination_for_pda.key(),
-        constraint = pair.token_b_account == token_source_for_pda.key() || pair.token_b_account == token_destination_for_pda.key(),
-        constraint = pair.token_a_mint == token_source_for_pda.mint || pair.token_a_mint == token_destination_for_pda.mint,
-        constraint = pair.token_b_mint == token_source_for_pda.mint || pair.token_b_mint == token_destination_for_pda.mint,
-        constraint = pair.pool_mint == pool.key(),
-        constraint = pair.pool_fee_account == pool_fee_account.key(),
-    )]
-    pub pair: Box<Account<'info, SwapPair>>,
+        msg!("{:?}", result);
+        if result.destination_amount_swapped < to_u128(minimum_amount_out)? {
+            return Err(crate::error::Error::ExceededSlippage.into());
+        }
 
-    #[account(
-        mut,
-        constraint = token_source_for_swapper.owner == swapper.key(),
-        constraint = token_source_for_swapper.delegate.is_none(),
-        constraint = token_source_for_swapper.close_authority.is_none()
-    )]
-    pub token_source_for_swapper: Box<Account<'info, TokenAccount>>,
+        let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                result.new_swap_source_amount,
+                result.new_swap_destination_amount,
+            ),
+            TradeDirection::BtoA => (
+                result.new_swap_destination_amount,
+                result.new_swap_source_amount,
+            ),
+        };
+        let amounts_before = match trade_direction {
+            TradeDirection::AtoB => (
+                to_u128(swap_source_amount)?,
+                to_u128(swap_destination_amount)?,
+            ),
+            TradeDirection::BtoA => (
+                to_u128(swap_destination_amount)?,
+                to_u128(swap_source_amount)?,
+            ),
+        };
+        self.pair
+            .check_circuit_breaker(amounts_before, (swap_token_a_amount, swap_token_b_amount))?;
+        self.pair
+            .check_oracle_guard(price_feed, (swap_token_a_amount, swap_token_b_amount))?;
+        self.pair
+            .update_volatility(swap_token_a_amount, swap_token_b_amount)?;
+
+        let mut pool_token_amount = match self.pair.owner_fee_mode {
+            OwnerFeeMode::PoolToken => {
+                let pool_mint_supply = to_u128(self.pair.pool_mint_supply(self.pool)?)?;
+                match &pmm {
+                    Some(pmm) => pmm.withdraw_single_token_type_exact_out(
+                        result.owner_fee,
+                        swap_token_a_amount,
+                        swap_token_b_amount,
+                        pool_mint_supply,
+                        &trade_direction,
+                        &fees,
+                    ),
+                    None => curve.withdraw_single_token_type_exact_out(
+                        result.owner_fee,
+                        swap_token_a_amount,
+                        swap_token_b_amount,
+                        pool_mint_supply,
+                        &trade_direction,
+                        &fees,
+                    ),
+                }?
+            }
+            OwnerFeeMode::UnderlyingToken => {
+                // the owner fee stays in the source token account until it is collected
+                self.pair
+                    .accrue_protocol_fee(&trade_direction, to_u64(result.owner_fee)?)?;
+                0
+            }
+        };
 
-    #[account(
-    mut,
-        constraint = token_destination_for_swapper.owner == swapper.key(),
-        constraint = token_destination_for_swapper.delegate.is_none(),
-        constraint = token_destination_for_swapper.close_authority.is_none()
-    )]
-    pub token_destination_for_swapper: Box<Account<'info, TokenAccount>>,
+        msg!("pool_token_amount={}", pool_token_amount);
+        let signer_seeds = self.pair.signer_seeds(self.pda, program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
 
-    #[account(
-        mut,
-        constraint = token_source_for_pda.mint == token_source_for_swapper.mint,
-        constraint = token_source_for_pda.owner == pda.key(),
-        constraint = token_source_for_pda.delegate.is_none(),
-        constraint = token_source_for_pda.close_authority.is_none()
-    )]
-    pub token_source_for_pda: Box<Account<'info, TokenAccount>>,
+        if pool_token_amount > 0 {
+            if let Some((host, host_fee_account)) = host.as_mut() {
+                let host_fee = host.host_fee(&self.pair.fees, pool_token_amount)?;
+                if host_fee > 0 {
+                    token_interface::mint_to(
+                        self.to_mint_host_fee_context(host_fee_account)
+                            .with_signer(signer_seeds),
+                        to_u64(host_fee)?,
+                    )?;
+                    pool_token_amount = pool_token_amount
+                        .checked_sub(host_fee)
+                        .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                    host.fees_earned = host
+                        .fees_earned
+                        .checked_add(host_fee)
+                        .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                }
+            }
+            token_interface::mint_to(
+                self.to_mint_pool_fee_context().with_signer(signer_seeds),
+                to_u64(pool_token_amount)?,
+            )?;
+        }
 
-    #[account(
-        mut,
-        constraint = token_destination_for_pda.mint == token_destination_for_swapper.mint,
-        constraint = token_destination_for_pda.owner == pda.key(),
-        constraint = token_destination_for_pda.delegate.is_none(),
-        constraint = token_destination_for_pda.close_authority.is_none()
-    )]
-    pub token_destination_for_pda: Box<Account<'info, TokenAccount>>,
+        token_interface::transfer_checked(
+            self.to_transfer_source_context()
+                .with_signer(source_signer_seeds),
+            token_interface::amount_before_transfer_fee(
+                self.token_source_mint,
+                to_u64(result.source_amount_swapped)?,
+            )?,
+        )?;
+        token_interface::transfer_checked(
+            self.to_transfer_destination_context()
+                .with_signer(signer_seeds),
+            destination_amount_sent,
+        )?;
 
-    #[account(
-        mut,
-        constraint = pool_fee_account.mint == pool.key()
-    )]
-    pub pool_fee_account: Box<Account<'info, TokenAccount>>,
+        if let Some(order_book) = order_book.as_mut() {
+            self.fill_orders(&trade_direction, order_book, price_feed, program_id)?;
+            order_book.order_book.exit(program_id)?;
+        }
 
-    #[account(
-        mut,
-        constraint = host_fee_account.mint == pool.key()
-    )]
-    pub host_fee_account: Box<Account<'info, TokenAccount>>,
+        if let Some((host, _)) = host.as_mut() {
+            host.record_volume(&trade_direction, result.source_amount_swapped)?;
+            host.exit(program_id)?;
+        }
 
-    /// CHECK: This is not dangerous because we don't read or write from this account
-    pub token_program: AccountInfo<'info>,
-}
+        Ok(result.destination_amount_swapped)
+    }
 
-impl<'info> Initialize<'info> {
-    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+    fn to_mint_pool_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
         let cpi_accounts = MintTo {
             mint: self.pool.to_account_info().clone(),
-            to: self.token_pool_for_initializer.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
-}
 
-impl<'info> DepositAll<'info> {
-    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_a_for_depositor.to_account_info().clone(),
-            to: self.token_a_for_pda.to_account_info().clone(),
-            authority: self.depositor.to_account_info().clone(),
+    fn to_mint_host_fee_context(
+        &self,
+        host_fee_account: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: host_fee_account.clone(),
+            authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_b_for_depositor.to_account_info().clone(),
-            to: self.token_b_for_pda.to_account_info().clone(),
-            authority: self.depositor.to_account_info().clone(),
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_swapper.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_pda.to_account_info().clone(),
+            authority: self.swapper.to_account_info().clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
-        let cpi_accounts = MintTo {
-            mint: self.pool.to_account_info().clone(),
-            to: self.token_pool_for_depositor.to_account_info().clone(),
-            authority: self.pda.clone(),
+    fn to_transfer_destination_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_for_pda.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self.token_destination_for_swapper.to_account_info().clone(),
+            authority: self.pda.to_account_info().clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
-}
 
-impl<'info> DepositSingle<'info> {
-    fn to_transfer_context(
+    fn to_transfer_pda_context(
         &self,
-        direction: TradeDirection,
-    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_source_for_depositor.to_account_info().clone(),
-            to: match direction {
-                TradeDirection::AtoB => self.token_a_for_pda.to_account_info().clone(),
-                TradeDirection::BtoA => self.token_b_for_pda.to_account_info().clone(),
-            },
-            authority: self.depositor.to_account_info().clone(),
+        from: &AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+        to: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: from.clone(),
+            mint: mint.clone(),
+            to: to.clone(),
+            authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
-        let cpi_accounts = MintTo {
-            mint: self.pool.to_account_info().clone(),
-            to: self.token_pool_for_depositor.to_account_info().clone(),
-            authority: self.pda.clone(),
+    fn execute_virtual_orders(
+        &mut self,
+        trade_direction: &TradeDirection,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let (token_a_mint, token_b_mint, token_a_for_pda, token_b_for_pda) = match trade_direction {
+            TradeDirection::AtoB => (
+                self.token_source_mint,
+                self.token_destination_mint,
+                self.token_source_for_pda,
+                self.token_destination_for_pda,
+            ),
+            TradeDirection::BtoA => (
+                self.token_destination_mint,
+                self.token_source_mint,
+                self.token_destination_for_pda,
+                self.token_source_for_pda,
+            ),
         };
-        CpiContext::new(self.token_program.clone(), cpi_accounts)
+        let accounts = VirtualOrderAccounts {
+            pda: self.pda,
+            pool: self.pool,
+            pool_fee_account: self.pool_fee_account,
+            token_a_mint,
+            token_b_mint,
+            token_a_for_pda,
+            token_b_for_pda,
+            token_a_escrow: &twamm.token_a_escrow,
+            token_b_escrow: &twamm.token_b_escrow,
+            token_program: self.token_program,
+        };
+        accounts.execute(self.pair, &mut twamm.twamm, program_id)
     }
 
-    fn trade_direction(&self) -> Result<TradeDirection> {
-        if self.token_source_for_depositor.mint == self.pair.token_a_mint {
-            Ok(TradeDirection::AtoB)
-        } else if self.token_source_for_depositor.mint == self.pair.token_b_mint.key() {
-            Ok(TradeDirection::BtoA)
+    /// Fills the orders selling the destination token of the swap, which the
+    /// swap has made more expensive, best limit price first. Each fill is a
+    /// swap against the pool charged the same fees and checked by the same
+    /// guards, of the largest amount whose average price still meets the
+    /// limit of the order.
+    fn fill_orders(
+        &mut self,
+        trade_direction: &TradeDirection,
+        order_book: &mut OrderBookAccounts<'info>,
+        price_feed: &Option<Account<'info, PriceFeed>>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let (order_direction, token_source_escrow, token_destination_escrow) = match trade_direction
+        {
+            TradeDirection::AtoB => (
+                TradeDirection::BtoA,
+                order_book.token_b_escrow.clone(),
+                order_book.token_a_escrow.clone(),
+            ),
+            TradeDirection::BtoA => (
+                TradeDirection::AtoB,
+                order_book.token_a_escrow.clone(),
+                order_book.token_b_escrow.clone(),
+            ),
+        };
+        let curve = ConstantProduct {};
+        for index in order_book.order_book.fillable_orders(&order_direction) {
+            let order = order_book.order_book.orders[index].clone();
+            // the order sells into the destination token account of the swap
+            let (swap_source_amount, swap_destination_amount) =
+                self.swap_token_amounts(trade_direction)?;
+            let order_source_amount = to_u128(swap_destination_amount)?;
+            let order_destination_amount = to_u128(swap_source_amount)?;
+            let fees = match order_direction {
+                TradeDirection::AtoB => self
+                    .pair
+                    .swap_fees(order_source_amount, order_destination_amount)?,
+                TradeDirection::BtoA => self
+                    .pair
+                    .swap_fees(order_destination_amount, order_source_amount)?,
+            };
+            let fill_amount = curve.limit_order_fill_amount(
+                to_u128(order.amount_in)?,
+                to_u128(order.amount_out)?,
+                order_source_amount,
+                order_destination_amount,
+                &fees,
+            )?;
+            let mut amount_in = to_u64(std::cmp::min(
+                fill_amount,
+                to_u128(order.amount_in_remaining)?,
+            ))?;
+
+            // the transfer fees and rounding may leave the closed form amount
+            // just short of the limit, in which case it is scaled down to the
+            // amount whose output would meet it
+            let mut fill = None;
+            for _ in 0..OrderBook::MAX_FILL_ATTEMPTS {
+                if amount_in == 0 {
+                    break;
+                }
+                let amount_in_received = amount_in
+                    .checked_sub(token_interface::transfer_fee(
+                        self.token_destination_mint,
+                        amount_in,
+                    )?)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                let result = match curve.limit_order_swap(
+                    to_u128(amount_in_received)?,
+                    order_source_amount,
+                    order_destination_amount,
+                    &fees,
+                )? {
+                    Some(result) => result,
+                    None => break,
+                };
+                let amount_out_sent = to_u64(result.destination_amount_swapped)?;
+                let amount_out_received = amount_out_sent
+                    .checked_sub(token_interface::transfer_fee(
+                        self.token_source_mint,
+                        amount_out_sent,
+                    )?)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                let value = to_u128(amount_out_received)?
+                    .checked_mul(to_u128(order.amount_in)?)
+                    .ok_or(crate::error::Error::CalculationFailure)?;
+                let limit_value = to_u128(amount_in)?
+                    .checked_mul(to_u128(order.amount_out)?)
+                    .ok_or(crate::error::Error::CalculationFailure)?;
+                if value >= limit_value {
+                    fill = Some((amount_in, result, amount_out_sent, amount_out_received));
+                    break;
+                }
+                amount_in = to_u64(
+                    value
+                        .checked_div(to_u128(order.amount_out)?)
+                        .ok_or(crate::error::Error::CalculationFailure)?,
+                )?;
+            }
+            // the orders after this one have worse limits
+            let (amount_in, result, amount_out_sent, amount_out_received) = match fill {
+                Some(fill) => fill,
+                None => break,
+            };
+
+            let (swap_token_a_amount, swap_token_b_amount) = match order_direction {
+                TradeDirection::AtoB => (
+                    result.new_swap_source_amount,
+                    result.new_swap_destination_amount,
+                ),
+                TradeDirection::BtoA => (
+                    result.new_swap_destination_amount,
+                    result.new_swap_source_amount,
+                ),
+            };
+            let amounts_before = match order_direction {
+                TradeDirection::AtoB => (order_source_amount, order_destination_amount),
+                TradeDirection::BtoA => (order_destination_amount, order_source_amount),
+            };
+            self.pair.check_circuit_breaker(
+                amounts_before,
+                (swap_token_a_amount, swap_token_b_amount),
+            )?;
+            self.pair
+                .check_oracle_guard(price_feed, (swap_token_a_amount, swap_token_b_amount))?;
+            self.pair
+                .update_volatility(swap_token_a_amount, swap_token_b_amount)?;
+
+            let pool_token_amount = match self.pair.owner_fee_mode {
+                OwnerFeeMode::PoolToken => curve.withdraw_single_token_type_exact_out(
+                    result.owner_fee,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    to_u128(self.pair.pool_mint_supply(self.pool)?)?,
+                    &order_direction,
+                    &fees,
+                )?,
+                OwnerFeeMode::UnderlyingToken => {
+                    self.pair
+                        .accrue_protocol_fee(&order_direction, to_u64(result.owner_fee)?)?;
+                    0
+                }
+            };
+
+            {
+                let signer_seeds = self.pair.signer_seeds(self.pda, program_id)?;
+                let signer_seeds = &[&signer_seeds.value()[..]];
+                if pool_token_amount > 0 {
+                    token_interface::mint_to(
+                        self.to_mint_pool_fee_context().with_signer(signer_seeds),
+                        to_u64(pool_token_amount)?,
+                    )?;
+                }
+                token_interface::transfer_checked(
+                    self.to_transfer_pda_context(
+                        &token_source_escrow,
+                        self.token_destination_mint,
+                        self.token_destination_for_pda,
+                    )
+                    .with_signer(signer_seeds),
+                    amount_in,
+                )?;
+                token_interface::transfer_checked(
+                    self.to_transfer_pda_context(
+                        self.token_source_for_pda,
+                        self.token_source_mint,
+                        &token_destination_escrow,
+                    )
+                    .with_signer(signer_seeds),
+                    amount_out_sent,
+                )?;
+            }
+
+            msg!(
+                "order {} filled {} for {}",
+                order.id,
+                amount_in,
+                amount_out_received
+            );
+            order_book
+                .order_book
+                .fill(index, amount_in, amount_out_received)?;
+        }
+        Ok(())
+    }
+
+    fn trade_direction(&self) -> TradeDirection {
+        if self.token_source_for_pda.key() == self.pair.token_a_account {
+            TradeDirection::AtoB
         } else {
-            return Err(crate::error::Error::IncorrectSwapAccount.into());
+            TradeDirection::BtoA
+        }
+    }
+
+    fn swap_token_amounts(&self, trade_direction: &TradeDirection) -> Result<(u64, u64)> {
+        match trade_direction {
+            TradeDirection::AtoB => self
+                .pair
+                .swap_token_amounts(self.token_source_for_pda, self.token_destination_for_pda),
+            TradeDirection::BtoA => {
+                let (swap_token_a_amount, swap_token_b_amount) = self.pair.swap_token_amounts(
+                    self.token_destination_for_pda,
+                    self.token_source_for_pda,
+                )?;
+                Ok((swap_token_b_amount, swap_token_a_amount))
+            }
         }
     }
 }
 
-impl<'info> WithdrawAll<'info> {
-    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_pool_for_depositor.to_account_info().clone(),
-            to: self.pool_fee_account.to_account_info().clone(),
+impl<'info> SyncTwamm<'info> for Zap<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair> {
+        &self.pair
+    }
+
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let trade_direction = self.trade_direction();
+        self.swap_accounts()
+            .execute_virtual_orders(&trade_direction, twamm, program_id)
+    }
+}
+
+impl<'info> Zap<'info> {
+    /// The accounts of the internal swap, for filling the orders it crosses
+    fn swap_accounts(&mut self) -> SwapAccounts<'_, 'info> {
+        SwapAccounts {
+            swapper: &self.depositor,
+            pda: &self.pda,
+            pool: &self.pool,
+            pair: &mut self.pair,
+            token_source_mint: &self.token_source_mint,
+            token_destination_mint: &self.token_destination_mint,
+            token_source_for_swapper: &self.token_source_for_depositor,
+            token_destination_for_swapper: &self.token_destination_for_depositor,
+            token_source_for_pda: &self.token_source_for_pda,
+            token_destination_for_pda: &self.token_destination_for_pda,
+            pool_fee_account: &self.pool_fee_account,
+            token_program: &self.token_program,
+        }
+    }
+
+    fn to_transfer_source_in_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_depositor.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_pda.to_account_info().clone(),
             authority: self.depositor.to_account_info().clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_a_for_pda.to_account_info().clone(),
-            to: self.token_a_for_depositor.to_account_info().clone(),
+    fn to_transfer_source_out_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_pda.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_depositor.to_account_info().clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_b_for_pda.to_account_info().clone(),
-            to: self.token_b_for_depositor.to_account_info().clone(),
+    fn to_transfer_destination_out_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_for_pda.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self
+                .token_destination_for_depositor
+                .to_account_info()
+                .clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
+    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
     fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
         let cpi_accounts = Burn {
             mint: self.pool.to_account_info().clone(),
@@ -918,69 +3310,178 @@ impl<'info> WithdrawAll<'info> {
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn withdraw_fee(&self, pool_token_amount: u64) -> Result<u128> {
-        Ok(
-            if self.token_pool_for_depositor.key() == self.pair.pool_fee_account {
-                // withdrawing from the fee account, don't assess withdraw fee
-                0
-            } else {
-                self.pair
-                    .fees
-                    .owner_withdraw_fee(to_u128(pool_token_amount)?)
-                    .ok_or(crate::error::Error::FeeCalculationFailure)?
-            },
-        )
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
-}
 
-impl<'info> WithdrawSingle<'info> {
-    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_pool_for_depositor.to_account_info().clone(),
+    fn to_mint_pool_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
             to: self.pool_fee_account.to_account_info().clone(),
-            authority: self.depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_transfer_context(
+    fn to_mint_host_fee_context(
         &self,
-        direction: TradeDirection,
-    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: match direction {
-                TradeDirection::AtoB => self.token_a_for_pda.to_account_info().clone(),
-                TradeDirection::BtoA => self.token_b_for_pda.to_account_info().clone(),
-            },
-            to: self
-                .token_destination_for_depositor
-                .to_account_info()
-                .clone(),
+        host_fee_account: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: host_fee_account.clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
-        let cpi_accounts = Burn {
-            mint: self.pool.to_account_info().clone(),
-            from: self.token_pool_for_depositor.to_account_info().clone(),
-            authority: self.depositor.to_account_info().clone(),
+    fn trade_direction(&self) -> TradeDirection {
+        if self.token_source_for_pda.key() == self.pair.token_a_account {
+            TradeDirection::AtoB
+        } else {
+            TradeDirection::BtoA
+        }
+    }
+
+    fn swap_token_amounts(&self, trade_direction: &TradeDirection) -> Result<(u64, u64)> {
+        match trade_direction {
+            TradeDirection::AtoB => self
+                .pair
+                .swap_token_amounts(&self.token_source_for_pda, &self.token_destination_for_pda),
+            TradeDirection::BtoA => {
+                let (swap_token_a_amount, swap_token_b_amount) = self.pair.swap_token_amounts(
+                    &self.token_destination_for_pda,
+                    &self.token_source_for_pda,
+                )?;
+                Ok((swap_token_b_amount, swap_token_a_amount))
+            }
+        }
+    }
+
+    fn swap_fees(
+        &mut self,
+        trade_direction: &TradeDirection,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+    ) -> Result<Fees> {
+        match trade_direction {
+            TradeDirection::AtoB => self
+                .pair
+                .swap_fees(swap_source_amount, swap_destination_amount),
+            TradeDirection::BtoA => self
+                .pair
+                .swap_fees(swap_destination_amount, swap_source_amount),
+        }
+    }
+
+    /// Checks the circuit breaker and the oracle guard, then applies the owner
+    /// fee of the internal swap as `swap` does, returning the pool tokens to
+    /// mint for it and the swap source amount backing the pool tokens
+    /// afterwards.
+    fn owner_fee(
+        &mut self,
+        trade_direction: &TradeDirection,
+        result: &SwapResult,
+        pool_mint_supply: u128,
+        fees: &Fees,
+        price_feed: &Option<Account<'info, PriceFeed>>,
+    ) -> Result<(u128, u128)> {
+        let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                result.new_swap_source_amount,
+                result.new_swap_destination_amount,
+            ),
+            TradeDirection::BtoA => (
+                result.new_swap_destination_amount,
+                result.new_swap_source_amount,
+            ),
         };
-        CpiContext::new(self.token_program.clone(), cpi_accounts)
+        let swap_source_amount = result
+            .new_swap_source_amount
+            .checked_sub(result.source_amount_swapped)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let swap_destination_amount = result
+            .new_swap_destination_amount
+            .checked_add(result.destination_amount_swapped)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        let amounts_before = match trade_direction {
+            TradeDirection::AtoB => (swap_source_amount, swap_destination_amount),
+            TradeDirection::BtoA => (swap_destination_amount, swap_source_amount),
+        };
+        self.pair
+            .check_circuit_breaker(amounts_before, (swap_token_a_amount, swap_token_b_amount))?;
+        self.pair
+            .check_oracle_guard(price_feed, (swap_token_a_amount, swap_token_b_amount))?;
+        self.pair
+            .update_volatility(swap_token_a_amount, swap_token_b_amount)?;
+
+        let curve = ConstantProduct {};
+        match self.pair.owner_fee_mode {
+            OwnerFeeMode::PoolToken => Ok((
+                curve.withdraw_single_token_type_exact_out(
+                    result.owner_fee,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    pool_mint_supply,
+                    trade_direction,
+                    fees,
+                )?,
+                result.new_swap_source_amount,
+            )),
+            OwnerFeeMode::UnderlyingToken => {
+                // the owner fee stays in the source token account until it is collected
+                self.pair
+                    .accrue_protocol_fee(trade_direction, to_u64(result.owner_fee)?)?;
+                Ok((
+                    0,
+                    result
+                        .new_swap_source_amount
+                        .checked_sub(result.owner_fee)
+                        .ok_or(crate::error::Error::CalculationFailure)?,
+                ))
+            }
+        }
     }
 
-    fn trade_direction(&self) -> Result<TradeDirection> {
-        if self.token_destination_for_depositor.mint == self.pair.token_a_mint {
-            Ok(TradeDirection::AtoB)
-        } else if self.token_destination_for_depositor.mint == self.pair.token_b_mint.key() {
-            Ok(TradeDirection::BtoA)
-        } else {
-            Err(crate::error::Error::IncorrectSwapAccount.into())
+    fn mint_owner_fee(
+        &self,
+        host: &mut Option<(Account<'info, Host>, AccountInfo<'info>)>,
+        mut pool_token_amount: u128,
+        signer_seeds: &[&[&[u8]]],
+    ) -> Result<()> {
+        msg!("pool_token_amount={}", pool_token_amount);
+        if pool_token_amount == 0 {
+            return Ok(());
+        }
+        if let Some((host, host_fee_account)) = host.as_mut() {
+            let host_fee = host.host_fee(&self.pair.fees, pool_token_amount)?;
+            if host_fee > 0 {
+                token_interface::mint_to(
+                    self.to_mint_host_fee_context(host_fee_account)
+                        .with_signer(signer_seeds),
+                    to_u64(host_fee)?,
+                )?;
+                pool_token_amount = pool_token_amount
+                    .checked_sub(host_fee)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                host.fees_earned = host
+                    .fees_earned
+                    .checked_add(host_fee)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
+            }
         }
+        token_interface::mint_to(
+            self.to_mint_pool_fee_context().with_signer(signer_seeds),
+            to_u64(pool_token_amount)?,
+        )
     }
 
-    fn withdraw_fee(&self, burn_pool_token_amount: u128) -> Result<u128> {
+    fn withdraw_fee(&self, pool_token_amount: u64) -> Result<u128> {
         Ok(
             if self.token_pool_for_depositor.key() == self.pair.pool_fee_account {
                 // withdrawing from the fee account, don't assess withdraw fee
@@ -988,66 +3489,122 @@ impl<'info> WithdrawSingle<'info> {
             } else {
                 self.pair
                     .fees
-                    .owner_withdraw_fee(burn_pool_token_amount)
-                    .ok_or(crate::error::Error::FeeCalculationFailure)?
+                    .owner_withdraw_fee(to_u128(pool_token_amount)?)?
             },
         )
     }
 }
 
-impl<'info> Swap<'info> {
-    fn to_mint_pool_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
-        let cpi_accounts = MintTo {
-            mint: self.pool.to_account_info().clone(),
-            to: self.pool_fee_account.to_account_info().clone(),
+impl<'info> UpdatePair<'info> {
+    /// Zeroes the data of the pair, which is serialized again on exit. An
+    /// option set to `None` would otherwise leave its payload past the
+    /// encoding of the pair, where the fields of the next version are read.
+    fn clear_pair_data(&self) -> Result<()> {
+        self.pair.to_account_info().try_borrow_mut_data()?[8..].fill(0);
+        Ok(())
+    }
+}
+
+impl<'info> CollectProtocolFees<'info> {
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_for_pda.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_admin.to_account_info().clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn to_mint_host_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
-        let cpi_accounts = MintTo {
-            mint: self.pool.to_account_info().clone(),
-            to: self.host_fee_account.to_account_info().clone(),
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_for_pda.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_admin.to_account_info().clone(),
             authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
+}
 
-    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_source_for_swapper.to_account_info().clone(),
-            to: self.token_source_for_pda.to_account_info().clone(),
-            authority: self.swapper.to_account_info().clone(),
+impl<'info> MigratePair<'info> {
+    fn to_transfer_rent_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, system_program::Transfer<'info>> {
+        let cpi_accounts = system_program::Transfer {
+            from: self.payer.to_account_info().clone(),
+            to: self.pair.to_account_info().clone(),
         };
-        CpiContext::new(self.token_program.clone(), cpi_accounts)
+        CpiContext::new(self.system_program.to_account_info().clone(), cpi_accounts)
     }
+}
 
-    fn to_transfer_destination_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.token_destination_for_pda.to_account_info().clone(),
-            to: self.token_destination_for_swapper.to_account_info().clone(),
-            authority: self.pda.to_account_info().clone(),
+impl<'info> ClosePool<'info> {
+    fn to_close_a_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.token_a_for_pda.to_account_info().clone(),
+            destination: self.destination.clone(),
+            authority: self.pda.clone(),
         };
         CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 
-    fn trade_direction(&self) -> TradeDirection {
-        if self.token_source_for_pda.key() == self.pair.token_a_account {
-            TradeDirection::AtoB
-        } else {
-            TradeDirection::BtoA
-        }
+    fn to_close_b_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.token_b_for_pda.to_account_info().clone(),
+            destination: self.destination.clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
     }
 }
 
-struct SignerSeeds<'a>([&'a [u8]; 3], [u8; 1]);
+struct SignerSeeds<'a>([&'a [u8]; 3], [u8; 2], [u8; 1]);
 
 impl<'a> SignerSeeds<'a> {
-    pub fn value(&self) -> [&[u8]; 4] {
-        [self.0[0], self.0[1], self.0[2], &self.1]
+    pub fn value(&self) -> [&[u8]; 5] {
+        [self.0[0], self.0[1], self.0[2], &self.1, &self.2]
+    }
+}
+
+/// Program wide settings managed by the admin
+#[account]
+pub struct Config {
+    /// Allowed trade fees in basis points, each of which may have its own
+    /// pool for a given mint pair
+    pub fee_tiers: Vec<u16>,
+    pub bump: u8,
+}
+
+impl Config {
+    pub const MAX_FEE_TIERS: usize = 16;
+    pub const LEN: usize = (4 + 2 * Config::MAX_FEE_TIERS) + 1;
+
+    fn add_fee_tier(&mut self, fee_tier: u16) -> Result<()> {
+        if u128::from(fee_tier) >= crate::fees::BASIS_POINTS_DENOMINATOR
+            || self.fee_tiers.contains(&fee_tier)
+            || self.fee_tiers.len() >= Config::MAX_FEE_TIERS
+        {
+            return Err(crate::error::Error::InvalidFeeTier.into());
+        }
+        self.fee_tiers.push(fee_tier);
+        self.fee_tiers.sort_unstable();
+        Ok(())
+    }
+
+    fn remove_fee_tier(&mut self, fee_tier: u16) -> Result<()> {
+        let index = self
+            .fee_tiers
+            .iter()
+            .position(|tier| *tier == fee_tier)
+            .ok_or(crate::error::Error::InvalidFeeTier)?;
+        self.fee_tiers.remove(index);
+        Ok(())
     }
 }
+/// A pool of token A and B, created at the PDA derived from
+/// `["pair", token_a_mint, token_b_mint, fee_tier]` with the mints in
+/// ascending order, so that each pair and fee tier has a single pool.
 #[account]
 pub struct SwapPair {
     pub token_a_account: Pubkey,
@@ -1056,21 +3613,439 @@ pub struct SwapPair {
     pub token_a_mint: Pubkey,
     pub token_b_mint: Pubkey,
     pub pool_fee_account: Pubkey,
+    /// SPL Token or Token-2022, owning the mints and token accounts of the pair
+    pub token_program: Pubkey,
     pub fees: Fees,
+    /// Trade fee in basis points, which keys the pool along with the mints
+    pub fee_tier: u16,
+    pub owner_fee_mode: OwnerFeeMode,
+    /// Owner fees of token A held in `token_a_account` but not yet collected
+    pub protocol_fee_token_a_amount: u64,
+    /// Owner fees of token B held in `token_b_account` but not yet collected
+    pub protocol_fee_token_b_amount: u64,
+    /// Enables the dynamic fee mode, which overrides `Fees::trade_fee_numerator`
+    pub dynamic_fee_parameters: Option<DynamicFeeParameters>,
+    pub dynamic_fee_state: DynamicFeeState,
+    pub bump: u8,
+    /// Layout version, brought up to `SwapPair::VERSION` by `migrate_pair`.
+    /// The fields of each version follow those of the previous ones.
+    pub version: u8,
+    /// Order book of limit orders filled by the swaps of the pair, since version 2
+    pub order_book: Option<Pubkey>,
+    /// Whether the pair has a `Twamm`, whose virtual orders every swap
+    /// executes first, since version 3
+    pub twamm: bool,
+    /// Price move limits of the swaps, since version 4
+    pub circuit_breaker: CircuitBreakerParameters,
+    pub circuit_breaker_state: CircuitBreakerState,
+    /// Price band of the swaps around an oracle price, since version 5
+    pub oracle_guard: Option<OracleGuard>,
+    /// Curve around the guide price of a `PriceFeed`, replacing the constant
+    /// product of the swaps, since version 6
+    pub pmm: Option<PmmParameters>,
+    /// Number of DCA orders, farms and locked positions of the pair, which
+    /// keep it from being closed, since version 7
+    pub dependent_accounts: u32,
+    /// Whether `dependent_accounts` counts all of them, which the pairs
+    /// migrated from before version 7 don't until `recount_dependent_accounts`
+    pub dependent_accounts_counted: bool,
 }
 
 impl SwapPair {
+    pub const LEN: usize = 32 * 7
+        + Fees::LEN
+        + 2
+        + 1
+        + 8 * 2
+        + (1 + DynamicFeeParameters::LEN)
+        + DynamicFeeState::LEN
+        + 1
+        + 1
+        + (1 + 32)
+        + 1
+        + CircuitBreakerParameters::LEN
+        + CircuitBreakerState::LEN
+        + (1 + OracleGuard::LEN)
+        + (1 + PmmParameters::LEN)
+        + 4
+        + 1;
+    pub const VERSION: u8 = 7;
+
+    /// Fills in the defaults of the fields added after `self.version`. Every
+    /// version appends its fields and grows `SwapPair::LEN`, so they are read
+    /// from the zeros past the encoding of the previous version once the pair
+    /// has been reallocated. Pairs created before versioning are read as
+    /// version 0.
+    fn migrate(&mut self) {
+        if self.version < 2 {
+            self.order_book = None;
+        }
+        if self.version < 3 {
+            self.twamm = false;
+        }
+        if self.version < 4 {
+            self.circuit_breaker = CircuitBreakerParameters::default();
+            self.circuit_breaker_state = CircuitBreakerState::default();
+        }
+        if self.version < 5 {
+            self.oracle_guard = None;
+        }
+        if self.version < 6 {
+            self.pmm = None;
+        }
+        if self.version < 7 {
+            self.dependent_accounts = 0;
+            self.dependent_accounts_counted = false;
+        }
+        self.version = SwapPair::VERSION;
+    }
+
+    /// Counts a DCA order, farm or locked position created for the pair, once
+    /// the pair counts all of them
+    fn add_dependent_account(&mut self) -> Result<()> {
+        if self.dependent_accounts_counted {
+            self.dependent_accounts = self
+                .dependent_accounts
+                .checked_add(1)
+                .ok_or(crate::error::Error::CalculationFailure)?;
+        }
+        Ok(())
+    }
+
+    /// Stops counting a closed DCA order or locked position, once the pair
+    /// counts all of them
+    fn remove_dependent_account(&mut self) -> Result<()> {
+        if self.dependent_accounts_counted {
+            self.dependent_accounts = self
+                .dependent_accounts
+                .checked_sub(1)
+                .ok_or(crate::error::Error::CalculationFailure)?;
+        }
+        Ok(())
+    }
+
     fn signer_seeds<'a>(&'a self, pda: &AccountInfo, program_id: &Pubkey) -> Result<SignerSeeds> {
         let seeds = [
             b"pool".as_ref(),
             self.token_a_mint.as_ref(),
             self.token_b_mint.as_ref(),
         ];
-        let (pubkey, bump_seed) = Pubkey::find_program_address(&seeds, program_id);
+        let fee_tier = self.fee_tier.to_le_bytes();
+        let (pubkey, bump_seed) = Pubkey::find_program_address(
+            &[seeds[0], seeds[1], seeds[2], fee_tier.as_ref()],
+            program_id,
+        );
         if pubkey != pda.key() {
             return Err(ProgramError::InvalidArgument.into());
         }
-        Ok(SignerSeeds(seeds, [bump_seed]))
+        Ok(SignerSeeds(seeds, fee_tier, [bump_seed]))
+    }
+
+    /// Amounts of token A and B backing the pool tokens, excluding the
+    /// protocol fees that are held in the swap token accounts until collected.
+    fn swap_token_amounts(
+        &self,
+        token_a_for_pda: &AccountInfo,
+        token_b_for_pda: &AccountInfo,
+    ) -> Result<(u64, u64)> {
+        let token_a_amount = token_account(token_a_for_pda, &self.token_program)?.amount;
+        let token_b_amount = token_account(token_b_for_pda, &self.token_program)?.amount;
+        Ok((
+            token_a_amount
+                .checked_sub(self.protocol_fee_token_a_amount)
+                .ok_or(crate::error::Error::CalculationFailure)?,
+            token_b_amount
+                .checked_sub(self.protocol_fee_token_b_amount)
+                .ok_or(crate::error::Error::CalculationFailure)?,
+        ))
+    }
+
+    fn pool_mint_supply(&self, pool: &AccountInfo) -> Result<u64> {
+        Ok(mint(pool, &self.token_program)?.supply)
+    }
+
+    /// Fees applied to a swap from the given amounts of token A and B. In the
+    /// dynamic fee mode, the volatility references are updated first and the
+    /// trade fee follows the volatility accumulated by the previous swaps.
+    fn swap_fees(&mut self, swap_token_a_amount: u128, swap_token_b_amount: u128) -> Result<Fees> {
+        let mut fees = self.fees.clone();
+        if let Some(parameters) = &self.dynamic_fee_parameters {
+            let now = Clock::get()?.unix_timestamp;
+            let price = spot_price(swap_token_a_amount, swap_token_b_amount)?;
+            self.dynamic_fee_state
+                .update_references(parameters, now, price)?;
+            fees.trade_fee_numerator = self.dynamic_fee_state.trade_fee_numerator(parameters)?;
+            msg!("dynamic trade_fee_numerator={}", fees.trade_fee_numerator);
+        }
+        Ok(fees)
+    }
+
+    /// Rejects a swap tripping the circuit breaker, given the amounts of token
+    /// A and B before and after the swap.
+    fn check_circuit_breaker(
+        &mut self,
+        amounts_before: (u128, u128),
+        amounts_after: (u128, u128),
+    ) -> Result<()> {
+        self.circuit_breaker_state.check(
+            &self.circuit_breaker,
+            Clock::get()?.slot,
+            amounts_before,
+            amounts_after,
+        )
+    }
+
+    /// Rejects a swap leaving the spot price outside the band of the oracle
+    /// guard, given the amounts of token A and B after the swap.
+    fn check_oracle_guard(
+        &self,
+        price_feed: &Option<Account<PriceFeed>>,
+        amounts_after: (u128, u128),
+    ) -> Result<()> {
+        if let Some(oracle_guard) = &self.oracle_guard {
+            let price_feed = price_feed
+                .as_ref()
+                .ok_or(crate::error::Error::InvalidPriceFeed)?;
+            oracle_guard.check(&**price_feed, Clock::get()?.unix_timestamp, amounts_after)?;
+        }
+        Ok(())
+    }
+
+    /// The PMM curve of the pair at the guide price of its `PriceFeed`, or
+    /// `None` for the constant product, which the pair falls back to while
+    /// the guide price is stale.
+    fn pmm_curve(
+        &self,
+        pmm_price_feed: &Option<Account<PriceFeed>>,
+    ) -> Result<Option<ProactiveMarketMaker>> {
+        match &self.pmm {
+            Some(pmm) => {
+                let price_feed = pmm_price_feed
+                    .as_ref()
+                    .ok_or(crate::error::Error::InvalidPriceFeed)?;
+                pmm.curve(&**price_feed, Clock::get()?.unix_timestamp)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Rejects the operations solved on the constant product alone when the
+    /// pair swaps on the PMM curve. Single-sided liquidity valued off the
+    /// curve would move value between the two sides at the wrong price, and
+    /// the swap amount of a zap, the fills of limit orders and the virtual
+    /// orders of a TWAMM are solved on the constant product.
+    fn require_constant_product(&self) -> Result<()> {
+        if self.pmm.is_some() {
+            return Err(crate::error::Error::UnsupportedCurveOperation.into());
+        }
+        Ok(())
+    }
+
+    /// Accumulates the price move of a swap in the dynamic fee mode, given the
+    /// amounts of token A and B after the swap.
+    fn update_volatility(
+        &mut self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Result<()> {
+        if let Some(parameters) = &self.dynamic_fee_parameters {
+            let price = spot_price(swap_token_a_amount, swap_token_b_amount)?;
+            self.dynamic_fee_state
+                .update_volatility_accumulator(parameters, price)?;
+        }
+        Ok(())
+    }
+
+    fn accrue_protocol_fee(
+        &mut self,
+        trade_direction: &TradeDirection,
+        owner_fee: u64,
+    ) -> Result<()> {
+        let protocol_fee = match trade_direction {
+            TradeDirection::AtoB => &mut self.protocol_fee_token_a_amount,
+            TradeDirection::BtoA => &mut self.protocol_fee_token_b_amount,
+        };
+        *protocol_fee = protocol_fee
+            .checked_add(owner_fee)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?;
+        Ok(())
+    }
+}
+
+/// A frontend registered by the admin to receive a share of the owner fee on
+/// the swaps it routes to the pair.
+#[account]
+pub struct Host {
+    pub pair: Pubkey,
+    pub wallet: Pubkey,
+    /// Overrides `Fees::host_fee_numerator` for this host when set
+    pub host_fee_numerator: Option<u64>,
+    /// Cumulative amount of token A swapped through this host
+    pub volume_token_a: u128,
+    /// Cumulative amount of token B swapped through this host
+    pub volume_token_b: u128,
+    /// Cumulative amount of pool tokens minted to this host as fees
+    pub fees_earned: u128,
+    pub bump: u8,
+}
+
+impl Host {
+    pub const LEN: usize = 32 + 32 + (1 + 8) + 16 + 16 + 16 + 1;
+
+    fn host_fee(&self, fees: &Fees, owner_fee: u128) -> Result<u128> {
+        match self.host_fee_numerator {
+            Some(host_fee_numerator) => fees.host_fee_with_numerator(owner_fee, host_fee_numerator),
+            None => fees.host_fee(owner_fee),
+        }
+    }
+
+    fn record_volume(
+        &mut self,
+        trade_direction: &TradeDirection,
+        source_amount: u128,
+    ) -> Result<()> {
+        let volume = match trade_direction {
+            TradeDirection::AtoB => &mut self.volume_token_a,
+            TradeDirection::BtoA => &mut self.volume_token_b,
+        };
+        *volume = volume
+            .checked_add(source_amount)
+            .ok_or(crate::error::Error::CalculationFailure)?;
+        Ok(())
+    }
+}
+
+/// Reference price published by `authority`, created at the PDA derived from
+/// `["price_feed", authority, id]`. Implements `Oracle` without an external
+/// oracle program, e.g. on a local validator.
+#[account]
+pub struct PriceFeed {
+    pub authority: Pubkey,
+    pub id: u64,
+    /// Price of token A in token B as a 64.64 fixed point number, see
+    /// `spot_price`
+    pub price: u128,
+    /// Unix timestamp of the last update
+    pub publish_ts: i64,
+    pub bump: u8,
+}
+
+impl PriceFeed {
+    pub const LEN: usize = 32 + 8 + 16 + 8 + 1;
+}
+
+impl Oracle for PriceFeed {
+    fn price(&self) -> Result<OraclePrice> {
+        Ok(OraclePrice {
+            price: self.price,
+            publish_ts: self.publish_ts,
+        })
+    }
+}
+
+/// Seeds of the PDA `["pool", pool]` holding the token accounts of a multi
+/// pool or a concentrated liquidity pool
+struct PoolSignerSeeds(Pubkey, [u8; 1]);
+
+impl PoolSignerSeeds {
+    pub fn value(&self) -> [&[u8]; 3] {
+        [b"pool".as_ref(), self.0.as_ref(), &self.1]
+    }
+}
+
+fn pool_signer_seeds(
+    pool: &Pubkey,
+    pda: &AccountInfo,
+    program_id: &Pubkey,
+) -> Result<PoolSignerSeeds> {
+    let (pubkey, bump_seed) =
+        Pubkey::find_program_address(&[b"pool".as_ref(), pool.as_ref()], program_id);
+    if pubkey != pda.key() {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    Ok(PoolSignerSeeds(*pool, [bump_seed]))
+}
+
+/// Reads the price feed of the oracle guard of the pair from the front of the
+/// remaining accounts left after the TWAMM, required once the pair has an
+/// oracle guard, and returns it with the accounts that follow.
+fn load_price_feed<'a, 'info>(
+    pair: &Account<'info, SwapPair>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<Account<'info, PriceFeed>>, &'a [AccountInfo<'info>])> {
+    let price_feed_key = pair.oracle_guard.as_ref().map(|guard| guard.price_feed);
+    load_price_feed_account(price_feed_key, remaining_accounts)
+}
+
+/// Reads the price feed of the PMM curve of the pair from the front of the
+/// remaining accounts, required once the pair has a PMM curve, and returns it
+/// with the accounts that follow.
+fn load_pmm_price_feed<'a, 'info>(
+    pair: &Account<'info, SwapPair>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<Account<'info, PriceFeed>>, &'a [AccountInfo<'info>])> {
+    let price_feed_key = pair.pmm.as_ref().map(|pmm| pmm.price_feed);
+    load_price_feed_account(price_feed_key, remaining_accounts)
+}
+
+fn load_price_feed_account<'a, 'info>(
+    price_feed_key: Option<Pubkey>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<Account<'info, PriceFeed>>, &'a [AccountInfo<'info>])> {
+    let price_feed_key = match price_feed_key {
+        Some(price_feed_key) => price_feed_key,
+        None => return Ok((None, remaining_accounts)),
+    };
+    let (price_feed, remaining_accounts) = match remaining_accounts {
+        [price_feed, remaining_accounts @ ..] => (price_feed, remaining_accounts),
+        _ => return Err(crate::error::Error::InvalidPriceFeed.into()),
+    };
+    if price_feed.key() != price_feed_key {
+        return Err(crate::error::Error::InvalidPriceFeed.into());
+    }
+    Ok((
+        Some(Account::<PriceFeed>::try_from(price_feed)?),
+        remaining_accounts,
+    ))
+}
+
+/// Reads the optional host and its pool token account from the remaining
+/// accounts of a swap.
+fn load_host<'info>(
+    pair: &Account<'info, SwapPair>,
+    pool: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<Option<(Account<'info, Host>, AccountInfo<'info>)>> {
+    let (host, host_fee_account) = match remaining_accounts {
+        [] => return Ok(None),
+        [host, host_fee_account, ..] => (host, host_fee_account),
+        _ => return Err(ProgramError::NotEnoughAccountKeys.into()),
+    };
+    if !host.is_writable || !host_fee_account.is_writable {
+        return Err(crate::error::Error::InvalidHost.into());
+    }
+    let host = Account::<Host>::try_from(host)?;
+    if host.pair != pair.key() {
+        return Err(crate::error::Error::InvalidHost.into());
+    }
+    let fee_account = token_account(host_fee_account, &pair.token_program)?;
+    if fee_account.owner != host.wallet || fee_account.mint != pool.key() {
+        return Err(crate::error::Error::IncorrectFeeAccount.into());
+    }
+    Ok(Some((host, host_fee_account.clone())))
+}
+
+/// The pair of a DCA order, farm or locked position
+fn dependent_account_pair(account: &AccountInfo) -> Result<Pubkey> {
+    if let Ok(dca_order) = Account::<DcaOrder>::try_from(account) {
+        return Ok(dca_order.pair);
+    }
+    if let Ok(farm) = Account::<Farm>::try_from(account) {
+        return Ok(farm.pair);
+    }
+    match Account::<LockedPosition>::try_from(account) {
+        Ok(locked_position) => Ok(locked_position.pair),
+        Err(_) => Err(crate::error::Error::InvalidDependentAccount.into()),
     }
 }
 
@@ -1080,6 +4055,12 @@ fn admin_pubkey() -> Result<Pubkey> {
         .map_err(|_| crate::error::Error::InvalidOwner.into())
 }
 
+fn add_u64(total: u64, amount: u128) -> Result<u64> {
+    total
+        .checked_add(to_u64(amount)?)
+        .ok_or_else(|| crate::error::Error::CalculationFailure.into())
+}
+
 fn to_u128(val: u64) -> Result<u128> {
     val.try_into()
         .map_err(|_| crate::error::Error::ConversionFailure.into())
@@ -0,0 +1,485 @@
+//! Locked positions, escrowing pool tokens until an unlock time behind a
+//! transferable receipt, optionally boosting a farm stake meanwhile.
+
+use crate::error::Error;
+use crate::farm::{add_stake_delta, Farm, FarmStake};
+use crate::token_interface::{
+    is_held_by, mint, token_account, Burn, CloseAccount, MintTo, TransferChecked,
+};
+use crate::{add_u64, to_u128, token_interface, SwapPair};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_option::COption;
+
+pub fn lock_lp<'info>(
+    ctx: Context<'_, '_, '_, 'info, LockLp<'info>>,
+    id: u64,
+    amount: u64,
+    unlock_ts: i64,
+) -> Result<()> {
+    msg!("Instruction Lock LP {},{},{}", id, amount, unlock_ts);
+    if amount == 0 {
+        return Err(Error::InvalidLockedPosition.into());
+    }
+    if unlock_ts <= Clock::get()?.unix_timestamp {
+        return Err(Error::InvalidUnlockTime.into());
+    }
+
+    let receipt = match ctx.remaining_accounts {
+        [] => None,
+        [receipt_mint, receipt_for_owner, ..] => {
+            let token_program = &ctx.accounts.pair.token_program;
+            let receipt = mint(receipt_mint, token_program)?;
+            if receipt.mint_authority != COption::Some(ctx.accounts.locked_position.key())
+                || receipt.freeze_authority.is_some()
+                || receipt.supply != 0
+                || receipt.decimals != 0
+                || token_account(receipt_for_owner, token_program)?.mint != receipt_mint.key()
+            {
+                return Err(Error::InvalidLockedPosition.into());
+            }
+            Some((receipt_mint, receipt_for_owner))
+        }
+        _ => return Err(ProgramError::NotEnoughAccountKeys.into()),
+    };
+
+    // The escrow receives the amount less the transfer fee of the pool mint
+    let amount_locked = amount
+        .checked_sub(token_interface::transfer_fee(&ctx.accounts.pool, amount)?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let locked_position = &mut ctx.accounts.locked_position;
+    locked_position.pair = ctx.accounts.pair.key();
+    locked_position.owner = ctx.accounts.owner.key();
+    locked_position.id = id;
+    locked_position.pool_mint = ctx.accounts.pool.key();
+    locked_position.escrow = ctx.accounts.escrow.key();
+    locked_position.amount = amount_locked;
+    locked_position.lock_ts = Clock::get()?.unix_timestamp;
+    locked_position.unlock_ts = unlock_ts;
+    locked_position.receipt_mint = receipt.map(|(receipt_mint, _)| receipt_mint.key());
+    locked_position.farm_stake = None;
+    locked_position.boosted_shares = 0;
+    locked_position.bump = *ctx
+        .bumps
+        .get("locked_position")
+        .ok_or(Error::InvalidProgramAddress)?;
+    ctx.accounts.pair.add_dependent_account()?;
+
+    token_interface::transfer_checked(ctx.accounts.to_transfer_in_context(), amount)?;
+    if let Some((receipt_mint, receipt_for_owner)) = receipt {
+        let signer_seeds = ctx.accounts.locked_position.signer_seeds();
+        let cpi_accounts = MintTo {
+            mint: receipt_mint.clone(),
+            to: receipt_for_owner.clone(),
+            authority: ctx.accounts.locked_position.to_account_info(),
+        };
+        token_interface::mint_to(
+            CpiContext::new(ctx.accounts.token_program.clone(), cpi_accounts)
+                .with_signer(&[&signer_seeds.value()[..]]),
+            1,
+        )?;
+    }
+
+    let locked_position = &ctx.accounts.locked_position;
+    emit!(LpLocked {
+        locked_position: locked_position.key(),
+        pair: locked_position.pair,
+        owner: locked_position.owner,
+        amount: locked_position.amount,
+        unlock_ts,
+        receipt_mint: locked_position.receipt_mint,
+    });
+    Ok(())
+}
+
+pub fn unlock_lp<'info>(ctx: Context<'_, '_, '_, 'info, UnlockLp<'info>>) -> Result<()> {
+    msg!(
+        "Instruction Unlock LP {}",
+        ctx.accounts.locked_position.key()
+    );
+
+    let locked_position = &ctx.accounts.locked_position;
+    if Clock::get()?.unix_timestamp < locked_position.unlock_ts {
+        return Err(Error::PositionLocked.into());
+    }
+    let (receipt, remaining_accounts) = load_receipt(
+        locked_position,
+        &ctx.accounts.holder.key(),
+        &ctx.accounts.pair.token_program,
+        ctx.remaining_accounts,
+    )?;
+    if let Some((mut farm, mut farm_stake)) =
+        load_boosted_stake(locked_position, remaining_accounts)?
+    {
+        let shares_delta = -i128::from(locked_position.boosted_shares);
+        farm.update(Clock::get()?.unix_timestamp)?;
+        farm_stake.update_locked_shares(&farm, shares_delta)?;
+        farm.total_shares = add_stake_delta(farm.total_shares, shares_delta)?;
+        farm.exit(ctx.program_id)?;
+        farm_stake.exit(ctx.program_id)?;
+    }
+    if let Some((receipt_mint, receipt_for_holder)) = receipt {
+        let cpi_accounts = Burn {
+            mint: receipt_mint.clone(),
+            from: receipt_for_holder.clone(),
+            authority: ctx.accounts.holder.to_account_info(),
+        };
+        token_interface::burn(
+            CpiContext::new(ctx.accounts.token_program.clone(), cpi_accounts),
+            1,
+        )?;
+    }
+
+    let amount = token_account(&ctx.accounts.escrow, &ctx.accounts.pair.token_program)?.amount;
+    msg!("amount={}", amount);
+    let signer_seeds = ctx.accounts.locked_position.signer_seeds();
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::transfer_checked(
+        ctx.accounts
+            .to_transfer_out_context()
+            .with_signer(signer_seeds),
+        amount,
+    )?;
+    token_interface::close_account(
+        ctx.accounts
+            .to_close_escrow_context()
+            .with_signer(signer_seeds),
+    )?;
+    ctx.accounts.pair.remove_dependent_account()?;
+
+    emit!(LpUnlocked {
+        locked_position: ctx.accounts.locked_position.key(),
+        pair: ctx.accounts.pair.key(),
+        holder: ctx.accounts.holder.key(),
+        amount,
+    });
+    Ok(())
+}
+
+pub fn boost_farm_stake<'info>(
+    ctx: Context<'_, '_, '_, 'info, BoostFarmStake<'info>>,
+) -> Result<()> {
+    msg!(
+        "Instruction Boost Farm Stake {}",
+        ctx.accounts.locked_position.key()
+    );
+    if ctx.accounts.farm.paused {
+        return Err(Error::FarmPaused.into());
+    }
+    load_receipt(
+        &ctx.accounts.locked_position,
+        &ctx.accounts.holder.key(),
+        &ctx.accounts.farm.token_program,
+        ctx.remaining_accounts,
+    )?;
+
+    let now = Clock::get()?.unix_timestamp;
+    let shares = ctx.accounts.locked_position.boosted_shares(now)?;
+    let farm = &mut ctx.accounts.farm;
+    farm.update(now)?;
+    ctx.accounts
+        .farm_stake
+        .update_locked_shares(farm, i128::from(shares))?;
+    farm.total_shares = add_stake_delta(farm.total_shares, i128::from(shares))?;
+    msg!("shares={},total_shares={}", shares, farm.total_shares);
+
+    let locked_position = &mut ctx.accounts.locked_position;
+    locked_position.farm_stake = Some(ctx.accounts.farm_stake.key());
+    locked_position.boosted_shares = shares;
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct LockLp<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(mut, constraint = pair.pool_mint == pool.key())]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + LockedPosition::LEN,
+        seeds = [
+            b"locked_position".as_ref(),
+            pair.key().as_ref(),
+            owner.key().as_ref(),
+            id.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub locked_position: Box<Account<'info, LockedPosition>>,
+
+    /// CHECK: This is not dangerous because it must be the pool mint of the pair
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&escrow, &pair.token_program)?.mint == pool.key(),
+        constraint = token_account(&escrow, &pair.token_program)?.amount == 0,
+        constraint = is_held_by(&token_account(&escrow, &pair.token_program)?, &locked_position.key())
+    )]
+    pub escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_owner, &pair.token_program)?.mint == pool.key()
+    )]
+    pub token_pool_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnlockLp<'info> {
+    /// Owner of the position, or holder of its receipt
+    pub holder: Signer<'info>,
+
+    /// CHECK: This is not dangerous because it must be the owner of the position
+    #[account(mut, constraint = owner.key() == locked_position.owner @ Error::InvalidOwner)]
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        mut,
+        close = owner,
+        constraint = locked_position.pair == pair.key() @ Error::InvalidLockedPosition,
+        constraint = locked_position.pool_mint == pool.key() @ Error::InvalidLockedPosition,
+        constraint = locked_position.escrow == escrow.key() @ Error::InvalidLockedPosition,
+    )]
+    pub locked_position: Box<Account<'info, LockedPosition>>,
+
+    /// CHECK: This is not dangerous because it must be the pool mint of the position
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the escrow of the position
+    #[account(mut)]
+    pub escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_holder, &pair.token_program)?.mint == pool.key()
+    )]
+    pub token_pool_for_holder: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BoostFarmStake<'info> {
+    /// Owner of the position, or holder of its receipt
+    pub holder: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = farm.pool_mint == locked_position.pool_mint @ Error::InvalidLockedPosition,
+    )]
+    pub farm: Box<Account<'info, Farm>>,
+
+    #[account(
+        mut,
+        constraint = farm_stake.farm == farm.key(),
+        constraint = farm_stake.owner == holder.key() @ Error::InvalidOwner,
+    )]
+    pub farm_stake: Box<Account<'info, FarmStake>>,
+
+    #[account(
+        mut,
+        constraint = locked_position.farm_stake.is_none() @ Error::InvalidLockedPosition,
+    )]
+    pub locked_position: Box<Account<'info, LockedPosition>>,
+}
+
+impl<'info> LockLp<'info> {
+    fn to_transfer_in_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_owner.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.escrow.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> UnlockLp<'info> {
+    fn to_transfer_out_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.escrow.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_holder.to_account_info().clone(),
+            authority: self.locked_position.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_close_escrow_context(&self) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let cpi_accounts = CloseAccount {
+            account: self.escrow.to_account_info().clone(),
+            destination: self.owner.to_account_info().clone(),
+            authority: self.locked_position.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// LP tokens of a pair escrowed by `owner` until `unlock_ts`, created at the
+/// PDA derived from `["locked_position", pair, owner, id]` which holds the
+/// escrow. A receipt minted at lock time makes the position transferable, its
+/// holder unlocking the LP tokens.
+#[account]
+pub struct LockedPosition {
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub id: u64,
+    pub pool_mint: Pubkey,
+    pub escrow: Pubkey,
+    /// LP tokens received by the escrow
+    pub amount: u64,
+    pub lock_ts: i64,
+    pub unlock_ts: i64,
+    /// Mint of the single token authorizing the unlock, if any
+    pub receipt_mint: Option<Pubkey>,
+    /// Farm stake boosted by the position, if any
+    pub farm_stake: Option<Pubkey>,
+    /// Shares added to the boosted farm stake
+    pub boosted_shares: u64,
+    pub bump: u8,
+}
+
+impl LockedPosition {
+    pub const LEN: usize = 32 * 4 + 8 * 5 + (1 + 32) * 2 + 1;
+
+    /// Lock time remaining for the full boost
+    pub const MAX_BOOST_DURATION: i64 = 365 * 24 * 60 * 60;
+
+    /// Farm shares of the position, from its amount up to twice the amount
+    /// for a year or more of lock time remaining
+    fn boosted_shares(&self, now: i64) -> Result<u64> {
+        let remaining = self
+            .unlock_ts
+            .saturating_sub(now)
+            .clamp(0, Self::MAX_BOOST_DURATION);
+        let boost = to_u128(self.amount)?
+            .checked_mul(remaining as u128)
+            .and_then(|boost| boost.checked_div(Self::MAX_BOOST_DURATION as u128))
+            .ok_or(Error::CalculationFailure)?;
+        add_u64(self.amount, boost)
+    }
+
+    fn signer_seeds(&self) -> LockedPositionSignerSeeds {
+        LockedPositionSignerSeeds(self.pair, self.owner, self.id.to_le_bytes(), [self.bump])
+    }
+}
+
+/// Seeds of a `LockedPosition`, which signs for its escrow and receipt
+struct LockedPositionSignerSeeds(Pubkey, Pubkey, [u8; 8], [u8; 1]);
+
+impl LockedPositionSignerSeeds {
+    pub fn value(&self) -> [&[u8]; 5] {
+        [
+            b"locked_position".as_ref(),
+            self.0.as_ref(),
+            self.1.as_ref(),
+            &self.2,
+            &self.3,
+        ]
+    }
+}
+
+/// Emitted when LP tokens are locked, to index the locked positions
+#[event]
+pub struct LpLocked {
+    pub locked_position: Pubkey,
+    pub pair: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub unlock_ts: i64,
+    pub receipt_mint: Option<Pubkey>,
+}
+
+/// Emitted when a locked position is unlocked and closed
+#[event]
+pub struct LpUnlocked {
+    pub locked_position: Pubkey,
+    pub pair: Pubkey,
+    pub holder: Pubkey,
+    pub amount: u64,
+}
+
+/// Receipt mint and token account of its holder
+type ReceiptAccounts<'a, 'info> = (&'a AccountInfo<'info>, &'a AccountInfo<'info>);
+
+/// Checks that `holder` acts for the locked position: the holder of its
+/// receipt, read from the front of the remaining accounts, when it has one and
+/// its owner otherwise. Returns the receipt accounts and the accounts after them.
+fn load_receipt<'a, 'info>(
+    locked_position: &LockedPosition,
+    holder: &Pubkey,
+    token_program: &Pubkey,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<ReceiptAccounts<'a, 'info>>, &'a [AccountInfo<'info>])> {
+    let receipt_mint = match locked_position.receipt_mint {
+        Some(receipt_mint) => receipt_mint,
+        None if *holder == locked_position.owner => return Ok((None, remaining_accounts)),
+        None => return Err(Error::InvalidOwner.into()),
+    };
+    let (receipt_mint_info, receipt_for_holder, remaining_accounts) = match remaining_accounts {
+        [receipt_mint_info, receipt_for_holder, remaining_accounts @ ..] => {
+            (receipt_mint_info, receipt_for_holder, remaining_accounts)
+        }
+        _ => return Err(ProgramError::NotEnoughAccountKeys.into()),
+    };
+    let receipt = token_account(receipt_for_holder, token_program)?;
+    if receipt_mint_info.key() != receipt_mint
+        || receipt.mint != receipt_mint
+        || receipt.owner != *holder
+        || receipt.amount == 0
+    {
+        return Err(Error::InvalidOwner.into());
+    }
+    Ok((
+        Some((receipt_mint_info, receipt_for_holder)),
+        remaining_accounts,
+    ))
+}
+
+/// Reads the farm and the farm stake boosted by the locked position from the
+/// remaining accounts, required once the position boosts a stake.
+fn load_boosted_stake<'info>(
+    locked_position: &LockedPosition,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<Option<(Account<'info, Farm>, Account<'info, FarmStake>)>> {
+    let farm_stake_key = match locked_position.farm_stake {
+        Some(farm_stake_key) => farm_stake_key,
+        None => return Ok(None),
+    };
+    let (farm, farm_stake) = match remaining_accounts {
+        [farm, farm_stake, ..] => (farm, farm_stake),
+        _ => return Err(ProgramError::NotEnoughAccountKeys.into()),
+    };
+    if !farm.is_writable || !farm_stake.is_writable || farm_stake.key() != farm_stake_key {
+        return Err(Error::InvalidLockedPosition.into());
+    }
+    let farm_stake = Account::<FarmStake>::try_from(farm_stake)?;
+    if farm_stake.farm != farm.key() {
+        return Err(Error::InvalidLockedPosition.into());
+    }
+    Ok(Some((Account::<Farm>::try_from(farm)?, farm_stake)))
+}
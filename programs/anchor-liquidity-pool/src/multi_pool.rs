@@ -0,0 +1,1057 @@
+//! Multi pools, holding more than two tokens on the StableSwap invariant and
+//! trading any of them against the others.
+
+use crate::curve::{ConstantProduct, RoundDirection};
+use crate::error::Error;
+use crate::fees::{Fees, FIXED_CONSTRAINTS};
+use crate::stable_swap::{StableSwap, MAX_TOKENS, MIN_TOKENS};
+use crate::token_interface::{
+    is_held_by, is_token_program, mint, token_account, Burn, MintTo, TransferChecked,
+};
+use crate::{admin_pubkey, pool_signer_seeds, stable_swap, to_u128, to_u64, token_interface};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_option::COption;
+
+pub fn initialize<'info>(
+    ctx: Context<'_, '_, '_, 'info, InitializeMultiPool<'info>>,
+    fees: Fees,
+    amp: u64,
+) -> Result<()> {
+    msg!("Instruction Multi Pool Init {:?},{}", fees, amp);
+    FIXED_CONSTRAINTS.validate_fees(&fees)?;
+    fees.validate()?;
+    if !StableSwap::is_valid_amp(amp) {
+        return Err(Error::InvalidCurve.into());
+    }
+
+    // the remaining accounts are the mint and the token account of the PDA
+    // for each token, with the mints in ascending order
+    let token_count = ctx.remaining_accounts.len() / 2;
+    if ctx.remaining_accounts.len() % 2 != 0 || !(MIN_TOKENS..=MAX_TOKENS).contains(&token_count) {
+        return Err(Error::InvalidTokenCount.into());
+    }
+    let mut token_mints = [Pubkey::default(); MAX_TOKENS];
+    let mut token_accounts = [Pubkey::default(); MAX_TOKENS];
+    for (i, accounts) in ctx.remaining_accounts.chunks(2).enumerate() {
+        let (token_mint, token_for_pda) = (&accounts[0], &accounts[1]);
+        if !mint(token_mint, ctx.accounts.token_program.key)?.is_initialized
+            || token_mint.key() == ctx.accounts.pool.key()
+        {
+            return Err(Error::ExpectedMint.into());
+        }
+        if i > 0 && token_mint.key() == token_mints[i - 1] {
+            return Err(Error::RepeatedMint.into());
+        }
+        if i > 0 && token_mint.key() < token_mints[i - 1] {
+            return Err(Error::InvalidMintOrder.into());
+        }
+        let token = token_account(token_for_pda, ctx.accounts.token_program.key)?;
+        if token.mint != token_mint.key() || !is_held_by(&token, &ctx.accounts.pda.key()) {
+            return Err(Error::IncorrectSwapAccount.into());
+        }
+        if token.amount == 0 {
+            return Err(Error::EmptySupply.into());
+        }
+        token_mints[i] = token_mint.key();
+        token_accounts[i] = token_for_pda.key();
+    }
+
+    let multi_pool = &mut ctx.accounts.multi_pool;
+    multi_pool.pool_mint = ctx.accounts.pool.key();
+    multi_pool.pool_fee_account = ctx.accounts.token_pool_for_fee_receiver.key();
+    multi_pool.token_program = ctx.accounts.token_program.key();
+    multi_pool.token_count = token_count as u8;
+    multi_pool.token_mints = token_mints;
+    multi_pool.token_accounts = token_accounts;
+    multi_pool.fees = fees;
+    multi_pool.amp = amp;
+    multi_pool.bump = *ctx
+        .bumps
+        .get("multi_pool")
+        .ok_or(Error::InvalidProgramAddress)?;
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::mint_to(
+        ctx.accounts.to_mint_context().with_signer(signer_seeds),
+        to_u64(ConstantProduct {}.new_pool_supply())?,
+    )?;
+    Ok(())
+}
+
+pub fn swap<'info>(
+    ctx: Context<'_, '_, '_, 'info, MultiPoolSwap<'info>>,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Multi Pool Swap {},{}",
+        amount_in,
+        minimum_amount_out
+    );
+
+    let multi_pool = &ctx.accounts.multi_pool;
+    let source_index = multi_pool.token_index(&ctx.accounts.token_source_for_pda.key())?;
+    let destination_index =
+        multi_pool.token_index(&ctx.accounts.token_destination_for_pda.key())?;
+    let mut token_amounts = multi_pool.token_amounts(ctx.remaining_accounts)?;
+    let curve = StableSwap {
+        amp: multi_pool.amp,
+    };
+    // only the amount left after the transfer fee of the source mint reaches the pool
+    let amount_in_received = amount_in
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_source_mint,
+            amount_in,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let mut result = curve.swap(
+        source_index,
+        destination_index,
+        to_u128(amount_in_received)?,
+        &token_amounts,
+        &multi_pool.fees,
+    )?;
+
+    // the result reports what the swapper receives after the transfer fee
+    // of the destination mint, while the pool sends the full amount
+    let destination_amount_sent = to_u64(result.destination_amount_swapped)?;
+    result.destination_amount_swapped = to_u128(
+        destination_amount_sent
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_destination_mint,
+                destination_amount_sent,
+            )?)
+            .ok_or(Error::FeeCalculationFailure)?,
+    )?;
+
+    msg!("{:?}", result);
+    if result.destination_amount_swapped < to_u128(minimum_amount_out)? {
+        return Err(Error::ExceededSlippage.into());
+    }
+
+    token_amounts[source_index] = result.new_swap_source_amount;
+    token_amounts[destination_index] = result.new_swap_destination_amount;
+    let pool_token_amount = curve.owner_fee_to_pool_tokens(
+        source_index,
+        result.owner_fee,
+        &token_amounts,
+        to_u128(multi_pool.pool_mint_supply(&ctx.accounts.pool)?)?,
+    )?;
+
+    msg!("pool_token_amount={}", pool_token_amount);
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if pool_token_amount > 0 {
+        token_interface::mint_to(
+            ctx.accounts
+                .to_mint_pool_fee_context()
+                .with_signer(signer_seeds),
+            to_u64(pool_token_amount)?,
+        )?;
+    }
+    token_interface::transfer_checked(
+        ctx.accounts.to_transfer_source_context(),
+        token_interface::amount_before_transfer_fee(
+            &ctx.accounts.token_source_mint,
+            to_u64(result.source_amount_swapped)?,
+        )?,
+    )?;
+    token_interface::transfer_checked(
+        ctx.accounts
+            .to_transfer_destination_context()
+            .with_signer(signer_seeds),
+        destination_amount_sent,
+    )?;
+
+    Ok(())
+}
+
+pub fn deposit_all<'info>(
+    ctx: Context<'_, '_, '_, 'info, MultiPoolDepositAll<'info>>,
+    pool_token_amount: u64,
+    maximum_token_amounts: Vec<u64>,
+) -> Result<()> {
+    msg!(
+        "Instruction Multi Pool Deposit {},{:?}",
+        pool_token_amount,
+        maximum_token_amounts
+    );
+
+    let multi_pool = &ctx.accounts.multi_pool;
+    let accounts = multi_pool.token_transfer_accounts(ctx.remaining_accounts)?;
+    if maximum_token_amounts.len() != accounts.len() {
+        return Err(Error::InvalidTokenCount.into());
+    }
+    let token_amounts = multi_pool.token_amounts(
+        &accounts
+            .iter()
+            .map(|(_, token_for_pda, _)| (*token_for_pda).clone())
+            .collect::<Vec<_>>(),
+    )?;
+    let pool_mint_supply = to_u128(multi_pool.pool_mint_supply(&ctx.accounts.pool)?)?;
+    if pool_mint_supply == 0 {
+        return Err(Error::EmptySupply.into());
+    }
+
+    let deposit_amounts = stable_swap::pool_tokens_to_trading_tokens(
+        to_u128(pool_token_amount)?,
+        pool_mint_supply,
+        &token_amounts,
+        RoundDirection::Ceiling,
+    )?;
+    msg!("deposit_amounts={:?}", deposit_amounts);
+
+    let mut transfer_amounts = Vec::with_capacity(accounts.len());
+    for ((token_mint, _, _), (deposit_amount, maximum_amount)) in accounts
+        .iter()
+        .zip(deposit_amounts.iter().zip(maximum_token_amounts.iter()))
+    {
+        let deposit_amount = to_u64(*deposit_amount)?;
+        if deposit_amount == 0 {
+            return Err(Error::ZeroTradingTokens.into());
+        }
+        // the pool receives the deposit amount after the transfer fee
+        let transfer_amount =
+            token_interface::amount_before_transfer_fee(token_mint, deposit_amount)?;
+        if transfer_amount > *maximum_amount {
+            return Err(Error::ExceededSlippage.into());
+        }
+        transfer_amounts.push(transfer_amount);
+    }
+
+    for ((token_mint, token_for_pda, token_for_depositor), transfer_amount) in
+        accounts.iter().zip(transfer_amounts)
+    {
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_context(
+                token_for_depositor,
+                token_mint,
+                token_for_pda,
+                ctx.accounts.depositor.to_account_info(),
+            ),
+            transfer_amount,
+        )?;
+    }
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::mint_to(
+        ctx.accounts.to_mint_context().with_signer(signer_seeds),
+        pool_token_amount,
+    )?;
+
+    Ok(())
+}
+
+pub fn withdraw_all<'info>(
+    ctx: Context<'_, '_, '_, 'info, MultiPoolWithdrawAll<'info>>,
+    pool_token_amount: u64,
+    minimum_token_amounts: Vec<u64>,
+) -> Result<()> {
+    msg!(
+        "Instruction Multi Pool Withdraw {},{:?}",
+        pool_token_amount,
+        minimum_token_amounts
+    );
+
+    let multi_pool = &ctx.accounts.multi_pool;
+    let accounts = multi_pool.token_transfer_accounts(ctx.remaining_accounts)?;
+    if minimum_token_amounts.len() != accounts.len() {
+        return Err(Error::InvalidTokenCount.into());
+    }
+    let token_amounts = multi_pool.token_amounts(
+        &accounts
+            .iter()
+            .map(|(_, token_for_pda, _)| (*token_for_pda).clone())
+            .collect::<Vec<_>>(),
+    )?;
+    let withdraw_fee = multi_pool.withdraw_fee(
+        &ctx.accounts.token_pool_for_depositor.key(),
+        to_u128(pool_token_amount)?,
+    )?;
+    let burn_pool_token_amount = to_u128(pool_token_amount)?
+        .checked_sub(withdraw_fee)
+        .ok_or(Error::FeeCalculationFailure)?;
+
+    let withdraw_amounts = stable_swap::pool_tokens_to_trading_tokens(
+        burn_pool_token_amount,
+        to_u128(multi_pool.pool_mint_supply(&ctx.accounts.pool)?)?,
+        &token_amounts,
+        RoundDirection::Floor,
+    )?;
+    msg!("withdraw_amounts={:?}", withdraw_amounts);
+
+    if withdraw_amounts.iter().all(|amount| *amount == 0) {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+    for ((token_mint, _, _), (withdraw_amount, minimum_amount)) in accounts
+        .iter()
+        .zip(withdraw_amounts.iter().zip(minimum_token_amounts.iter()))
+    {
+        // the minimum is checked against what the depositor receives after the transfer fee
+        let withdraw_amount = to_u64(*withdraw_amount)?;
+        let amount_received = withdraw_amount
+            .checked_sub(token_interface::transfer_fee(token_mint, withdraw_amount)?)
+            .ok_or(Error::FeeCalculationFailure)?;
+        if amount_received < *minimum_amount {
+            return Err(Error::ExceededSlippage.into());
+        }
+    }
+
+    if withdraw_fee > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_fee_context(),
+            to_u64(withdraw_fee)?,
+        )?;
+    }
+    token_interface::burn(
+        ctx.accounts.to_burn_context(),
+        to_u64(burn_pool_token_amount)?,
+    )?;
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    for ((token_mint, token_for_pda, token_for_depositor), withdraw_amount) in
+        accounts.iter().zip(withdraw_amounts)
+    {
+        if withdraw_amount > 0 {
+            token_interface::transfer_checked(
+                ctx.accounts
+                    .to_transfer_context(
+                        token_for_pda,
+                        token_mint,
+                        token_for_depositor,
+                        ctx.accounts.pda.clone(),
+                    )
+                    .with_signer(signer_seeds),
+                to_u64(withdraw_amount)?,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn deposit_single<'info>(
+    ctx: Context<'_, '_, '_, 'info, MultiPoolDepositSingle<'info>>,
+    source_token_amount: u64,
+    minimum_pool_token_amount: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Multi Pool Deposit Single {},{}",
+        source_token_amount,
+        minimum_pool_token_amount
+    );
+
+    let multi_pool = &ctx.accounts.multi_pool;
+    let source_index = multi_pool.token_index(&ctx.accounts.token_source_for_pda.key())?;
+    let token_amounts = multi_pool.token_amounts(ctx.remaining_accounts)?;
+    let pool_mint_supply = to_u128(multi_pool.pool_mint_supply(&ctx.accounts.pool)?)?;
+    if pool_mint_supply == 0 {
+        return Err(Error::EmptySupply.into());
+    }
+    let curve = StableSwap {
+        amp: multi_pool.amp,
+    };
+    // only the amount left after the transfer fee is deposited to the pool
+    let source_amount_received = source_token_amount
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_source_mint,
+            source_token_amount,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let pool_token_amount = curve.deposit_single_token_type(
+        source_index,
+        to_u128(source_amount_received)?,
+        &token_amounts,
+        pool_mint_supply,
+        &multi_pool.fees,
+    )?;
+
+    let pool_token_amount = to_u64(pool_token_amount)?;
+    if pool_token_amount < minimum_pool_token_amount {
+        return Err(Error::ExceededSlippage.into());
+    }
+    if pool_token_amount == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::transfer_checked(ctx.accounts.to_transfer_context(), source_token_amount)?;
+    token_interface::mint_to(
+        ctx.accounts.to_mint_context().with_signer(signer_seeds),
+        pool_token_amount,
+    )?;
+
+    Ok(())
+}
+
+pub fn withdraw_single<'info>(
+    ctx: Context<'_, '_, '_, 'info, MultiPoolWithdrawSingle<'info>>,
+    pool_token_amount: u64,
+    minimum_destination_amount: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Multi Pool Withdraw Single {},{}",
+        pool_token_amount,
+        minimum_destination_amount
+    );
+
+    let multi_pool = &ctx.accounts.multi_pool;
+    let destination_index =
+        multi_pool.token_index(&ctx.accounts.token_destination_for_pda.key())?;
+    let token_amounts = multi_pool.token_amounts(ctx.remaining_accounts)?;
+    let curve = StableSwap {
+        amp: multi_pool.amp,
+    };
+    let withdraw_fee = multi_pool.withdraw_fee(
+        &ctx.accounts.token_pool_for_depositor.key(),
+        to_u128(pool_token_amount)?,
+    )?;
+    let burn_pool_token_amount = to_u128(pool_token_amount)?
+        .checked_sub(withdraw_fee)
+        .ok_or(Error::FeeCalculationFailure)?;
+
+    let destination_token_amount = curve.withdraw_single_token_type_exact_in(
+        destination_index,
+        burn_pool_token_amount,
+        &token_amounts,
+        to_u128(multi_pool.pool_mint_supply(&ctx.accounts.pool)?)?,
+        &multi_pool.fees,
+    )?;
+    let destination_token_amount = to_u64(destination_token_amount)?;
+    // the minimum is checked against what the depositor receives after the transfer fee
+    let destination_amount_received = destination_token_amount
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_destination_mint,
+            destination_token_amount,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    msg!(
+        "burn_token_amount={}, destination_token_amount={}, withdraw_fee={}",
+        burn_pool_token_amount,
+        destination_token_amount,
+        withdraw_fee
+    );
+
+    if destination_amount_received < minimum_destination_amount {
+        return Err(Error::ExceededSlippage.into());
+    }
+    if destination_token_amount == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+
+    if withdraw_fee > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts.to_transfer_fee_context(),
+            to_u64(withdraw_fee)?,
+        )?;
+    }
+    token_interface::burn(
+        ctx.accounts.to_burn_context(),
+        to_u64(burn_pool_token_amount)?,
+    )?;
+
+    let signer_seeds = pool_signer_seeds(
+        &ctx.accounts.multi_pool.key(),
+        &ctx.accounts.pda,
+        ctx.program_id,
+    )?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    token_interface::transfer_checked(
+        ctx.accounts.to_transfer_context().with_signer(signer_seeds),
+        destination_token_amount,
+    )?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(fees: Fees, amp: u64)]
+pub struct InitializeMultiPool<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, token_program.key)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, token_program.key)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + MultiPool::LEN,
+        seeds = [b"multi_pool".as_ref(), pool.key().as_ref()],
+        bump
+    )]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_initializer, token_program.key)?.mint == pool.key(),
+        constraint = token_account(&token_pool_for_initializer, token_program.key)?.owner == admin_pubkey()?
+    )]
+    pub token_pool_for_initializer: UncheckedAccount<'info>,
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_fee_receiver, token_program.key)?.mint == pool.key(),
+        constraint = token_account(&token_pool_for_fee_receiver, token_program.key)?.owner == admin_pubkey()?
+    )]
+    pub token_pool_for_fee_receiver: UncheckedAccount<'info>,
+    /// CHECK: SPL Token or Token-2022, checked against the allowlist
+    #[account(
+        constraint = is_token_program(token_program.key) @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount_in: u64, minimum_amount_out: u64)]
+pub struct MultiPoolSwap<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub swapper: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &multi_pool.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &multi_pool.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = multi_pool.pool_mint == pool.key(),
+        constraint = multi_pool.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the source token account
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the destination token account
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_swapper, &multi_pool.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_swapper, &multi_pool.token_program)?, &swapper.key())
+    )]
+    pub token_source_for_swapper: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_swapper, &multi_pool.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_swapper, &multi_pool.token_program)?, &swapper.key())
+    )]
+    pub token_destination_for_swapper: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_pda, &multi_pool.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_pda, &multi_pool.token_program)?, &pda.key())
+    )]
+    pub token_source_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_pda, &multi_pool.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_pda, &multi_pool.token_program)?, &pda.key())
+    )]
+    pub token_destination_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the multi pool
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the multi pool
+    #[account(
+        constraint = token_program.key() == multi_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(pool_token_amount: u64, maximum_token_amounts: Vec<u64>)]
+pub struct MultiPoolDepositAll<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(constraint = multi_pool.pool_mint == pool.key())]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &multi_pool.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &multi_pool.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the multi pool
+    #[account(
+        constraint = token_program.key() == multi_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(pool_token_amount: u64, minimum_token_amounts: Vec<u64>)]
+pub struct MultiPoolWithdrawAll<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        constraint = multi_pool.pool_mint == pool.key(),
+        constraint = multi_pool.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &multi_pool.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &multi_pool.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the multi pool
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the multi pool
+    #[account(
+        constraint = token_program.key() == multi_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(source_token_amount: u64, minimum_pool_token_amount: u64)]
+pub struct MultiPoolDepositSingle<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(constraint = multi_pool.pool_mint == pool.key())]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &multi_pool.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &multi_pool.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the source token account
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_depositor, &multi_pool.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_depositor, &multi_pool.token_program)?, &depositor.key())
+    )]
+    pub token_source_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_pda, &multi_pool.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_pda, &multi_pool.token_program)?, &pda.key())
+    )]
+    pub token_source_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the multi pool
+    #[account(
+        constraint = token_program.key() == multi_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(pool_token_amount: u64, minimum_destination_amount: u64)]
+pub struct MultiPoolWithdrawSingle<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub depositor: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        constraint = multi_pool.pool_mint == pool.key(),
+        constraint = multi_pool.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub multi_pool: Box<Account<'info, MultiPool>>,
+
+    /// CHECK: Mint of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = mint(&pool, &multi_pool.token_program)?.mint_authority == COption::Some(pda.key()),
+        constraint = mint(&pool, &multi_pool.token_program)?.freeze_authority.is_none()
+    )]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the destination token account
+    pub token_destination_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_depositor, &multi_pool.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_depositor, &multi_pool.token_program)?, &depositor.key())
+    )]
+    pub token_destination_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_destination_for_pda, &multi_pool.token_program)?.mint == token_destination_mint.key(),
+        constraint = is_held_by(&token_account(&token_destination_for_pda, &multi_pool.token_program)?, &pda.key())
+    )]
+    pub token_destination_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.owner == depositor.key(),
+        constraint = token_account(&token_pool_for_depositor, &multi_pool.token_program)?.mint == pool.key(),
+    )]
+    pub token_pool_for_depositor: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the multi pool
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the multi pool
+    #[account(
+        constraint = token_program.key() == multi_pool.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> InitializeMultiPool<'info> {
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_initializer.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> MultiPoolSwap<'info> {
+    fn to_mint_pool_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_swapper.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_pda.to_account_info().clone(),
+            authority: self.swapper.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_destination_context(
+        &self,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_for_pda.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self.token_destination_for_swapper.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> MultiPoolDepositAll<'info> {
+    fn to_transfer_context(
+        &self,
+        from: &AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+        to: &AccountInfo<'info>,
+        authority: AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: from.clone(),
+            mint: mint.clone(),
+            to: to.clone(),
+            authority,
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> MultiPoolWithdrawAll<'info> {
+    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_context(
+        &self,
+        from: &AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+        to: &AccountInfo<'info>,
+        authority: AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: from.clone(),
+            mint: mint.clone(),
+            to: to.clone(),
+            authority,
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: self.pool.to_account_info().clone(),
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> MultiPoolDepositSingle<'info> {
+    fn to_transfer_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_depositor.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_for_pda.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_mint_context(&self) -> CpiContext<'_, '_, '_, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.pool.to_account_info().clone(),
+            to: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> MultiPoolWithdrawSingle<'info> {
+    fn to_transfer_fee_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            mint: self.pool.to_account_info().clone(),
+            to: self.pool_fee_account.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_destination_for_pda.to_account_info().clone(),
+            mint: self.token_destination_mint.to_account_info().clone(),
+            to: self
+                .token_destination_for_depositor
+                .to_account_info()
+                .clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_burn_context(&self) -> CpiContext<'_, '_, '_, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: self.pool.to_account_info().clone(),
+            from: self.token_pool_for_depositor.to_account_info().clone(),
+            authority: self.depositor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// A StableSwap pool of 3 to 8 tokens, created at the PDA derived from
+/// `["multi_pool", pool_mint]`. Its token accounts are held by the PDA
+/// `["pool", multi_pool]`.
+#[account]
+pub struct MultiPool {
+    pub pool_mint: Pubkey,
+    pub pool_fee_account: Pubkey,
+    /// SPL Token or Token-2022, owning the mints and token accounts of the pool
+    pub token_program: Pubkey,
+    /// Number of tokens, the first `token_count` entries of the arrays are used
+    pub token_count: u8,
+    /// Mints of the tokens in ascending order
+    pub token_mints: [Pubkey; 8],
+    /// Token accounts of the tokens, in the order of `token_mints`
+    pub token_accounts: [Pubkey; 8],
+    pub fees: Fees,
+    /// Amplification coefficient of the StableSwap invariant
+    pub amp: u64,
+    pub bump: u8,
+}
+
+impl MultiPool {
+    pub const LEN: usize = 32 * 3 + 1 + 32 * MAX_TOKENS * 2 + Fees::LEN + 8 + 1;
+
+    fn token_index(&self, token_for_pda: &Pubkey) -> Result<usize> {
+        self.token_accounts[..usize::from(self.token_count)]
+            .iter()
+            .position(|token_account| token_account == token_for_pda)
+            .ok_or_else(|| Error::IncorrectSwapAccount.into())
+    }
+
+    /// Amounts of every token of the pool, given its token accounts in order
+    fn token_amounts(&self, tokens_for_pda: &[AccountInfo]) -> Result<Vec<u128>> {
+        if tokens_for_pda.len() != usize::from(self.token_count) {
+            return Err(Error::InvalidTokenCount.into());
+        }
+        tokens_for_pda
+            .iter()
+            .zip(self.token_accounts.iter())
+            .map(|(token_for_pda, token_account_key)| {
+                if token_for_pda.key() != *token_account_key {
+                    return Err(Error::IncorrectSwapAccount.into());
+                }
+                to_u128(token_account(token_for_pda, &self.token_program)?.amount)
+            })
+            .collect()
+    }
+
+    /// Splits the remaining accounts into the mint, the token account of the
+    /// pool and the token account of the depositor for every token in order
+    fn token_transfer_accounts<'a, 'info>(
+        &self,
+        remaining_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<
+        Vec<(
+            &'a AccountInfo<'info>,
+            &'a AccountInfo<'info>,
+            &'a AccountInfo<'info>,
+        )>,
+    > {
+        if remaining_accounts.len() != 3 * usize::from(self.token_count) {
+            return Err(Error::InvalidTokenCount.into());
+        }
+        remaining_accounts
+            .chunks(3)
+            .zip(self.token_mints.iter())
+            .map(|(accounts, token_mint)| {
+                let (mint, token_for_pda, token_for_depositor) =
+                    (&accounts[0], &accounts[1], &accounts[2]);
+                if mint.key() != *token_mint
+                    || token_account(token_for_depositor, &self.token_program)?.mint != *token_mint
+                {
+                    return Err(Error::IncorrectSwapAccount.into());
+                }
+                Ok((mint, token_for_pda, token_for_depositor))
+            })
+            .collect()
+    }
+
+    fn pool_mint_supply(&self, pool: &AccountInfo) -> Result<u64> {
+        Ok(mint(pool, &self.token_program)?.supply)
+    }
+
+    fn withdraw_fee(
+        &self,
+        token_pool_for_depositor: &Pubkey,
+        pool_token_amount: u128,
+    ) -> Result<u128> {
+        Ok(if *token_pool_for_depositor == self.pool_fee_account {
+            // withdrawing from the fee account, don't assess withdraw fee
+            0
+        } else {
+            self.fees.owner_withdraw_fee(pool_token_amount)?
+        })
+    }
+}
@@ -0,0 +1,71 @@
+//! Reference prices of external feeds, guarding the swaps of a pair against
+//! trading away from the market price.
+
+use crate::error::Error;
+use crate::fees::{price_move, spot_price};
+use anchor_lang::prelude::*;
+
+/// Price of token A in token B as a 64.64 fixed point number, see
+/// `spot_price`, along with the unix timestamp it was published at
+#[derive(Clone, Copy, Debug)]
+pub struct OraclePrice {
+    pub price: u128,
+    pub publish_ts: i64,
+}
+
+impl OraclePrice {
+    /// Whether the price is more than `max_staleness` seconds old at `now`
+    pub fn is_stale(&self, now: i64, max_staleness: i64) -> bool {
+        now.saturating_sub(self.publish_ts) > max_staleness
+    }
+}
+
+/// Source of reference prices, implemented by the program-owned `PriceFeed`
+/// and by the adapters of external oracles
+pub trait Oracle {
+    fn price(&self) -> Result<OraclePrice>;
+}
+
+/// Band around the price of an oracle that the spot price of a pair must stay
+/// within after every swap
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct OracleGuard {
+    /// `PriceFeed` account read by the swaps
+    pub price_feed: Pubkey,
+    /// Maximum deviation of the spot price from the oracle price, in basis
+    /// points
+    pub max_deviation_bps: u16,
+    /// Maximum age of the oracle price, in seconds
+    pub max_staleness: i64,
+}
+
+impl OracleGuard {
+    pub const LEN: usize = 32 + 2 + 8;
+
+    /// Validate that the staleness is not negative
+    pub fn validate(&self) -> Result<()> {
+        if self.max_staleness < 0 {
+            return Err(Error::InvalidPriceFeed.into());
+        }
+        Ok(())
+    }
+
+    /// Rejects a stale oracle price, or a spot price outside the band given
+    /// the amounts of token A and B after a swap
+    pub fn check(&self, oracle: &impl Oracle, now: i64, amounts_after: (u128, u128)) -> Result<()> {
+        let oracle_price = oracle.price()?;
+        if oracle_price.is_stale(now, self.max_staleness) {
+            return Err(Error::StaleOraclePrice.into());
+        }
+        if oracle_price.price == 0 {
+            return Err(Error::InvalidPriceFeed.into());
+        }
+        let price = spot_price(amounts_after.0, amounts_after.1)?;
+        let deviation = price_move(price, oracle_price.price)?;
+        msg!("oracle_deviation={}", deviation);
+        if deviation > u128::from(self.max_deviation_bps) {
+            return Err(Error::OraclePriceDeviation.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,448 @@
+//! Order book of a pair, holding the limit orders that its swaps fill once
+//! the price of the pool reaches them.
+
+use crate::curve::TradeDirection;
+use crate::error::Error;
+use crate::token_interface::{is_held_by, token_account, TransferChecked};
+use crate::{admin_pubkey, token_interface, SwapPair};
+use anchor_lang::prelude::*;
+
+pub fn initialize(ctx: Context<InitializeOrderBook>) -> Result<()> {
+    msg!("Instruction Order Book Init {}", ctx.accounts.pair.key());
+
+    ctx.accounts.pair.require_constant_product()?;
+
+    // the escrows are held by the PDA of the pair, which signs the fills
+    ctx.accounts
+        .pair
+        .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+
+    let order_book = &mut ctx.accounts.order_book;
+    order_book.pair = ctx.accounts.pair.key();
+    order_book.token_a_escrow = ctx.accounts.token_a_escrow.key();
+    order_book.token_b_escrow = ctx.accounts.token_b_escrow.key();
+    order_book.next_order_id = 0;
+    order_book.orders = Vec::new();
+    order_book.bump = *ctx
+        .bumps
+        .get("order_book")
+        .ok_or(Error::InvalidProgramAddress)?;
+    ctx.accounts.pair.order_book = Some(order_book.key());
+    Ok(())
+}
+
+pub fn place_order(
+    ctx: Context<PlaceOrder>,
+    trade_direction: TradeDirection,
+    amount_in: u64,
+    amount_out: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Place Order {:?},{},{}",
+        trade_direction,
+        amount_in,
+        amount_out
+    );
+    if amount_in == 0 || amount_out == 0 {
+        return Err(Error::ZeroTradingTokens.into());
+    }
+
+    let amount_in_received = amount_in
+        .checked_sub(token_interface::transfer_fee(
+            &ctx.accounts.token_source_mint,
+            amount_in,
+        )?)
+        .ok_or(Error::FeeCalculationFailure)?;
+    let order_id = ctx.accounts.order_book.place(
+        ctx.accounts.owner.key(),
+        trade_direction,
+        amount_in_received,
+        amount_out,
+    )?;
+    msg!("order_id={}", order_id);
+
+    token_interface::transfer_checked(ctx.accounts.to_transfer_source_context(), amount_in)?;
+    Ok(())
+}
+
+pub fn cancel_order(ctx: Context<CancelOrder>, order_id: u64) -> Result<()> {
+    msg!("Instruction Cancel Order {}", order_id);
+
+    let order = ctx
+        .accounts
+        .order_book
+        .remove(order_id, &ctx.accounts.owner.key())?;
+    let (token_a_amount, token_b_amount) = match order.trade_direction {
+        TradeDirection::AtoB => (order.amount_in_remaining, order.amount_out_filled),
+        TradeDirection::BtoA => (order.amount_out_filled, order.amount_in_remaining),
+    };
+    msg!(
+        "token_a_amount={},token_b_amount={}",
+        token_a_amount,
+        token_b_amount
+    );
+
+    let signer_seeds = ctx
+        .accounts
+        .pair
+        .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if token_a_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_a_context()
+                .with_signer(signer_seeds),
+            token_a_amount,
+        )?;
+    }
+    if token_b_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_b_context()
+                .with_signer(signer_seeds),
+            token_b_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeOrderBook<'info> {
+    #[account(mut, constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    /// CHECK: This is not dangerous because it is checked against the seeds of the pair
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.order_book.is_none() @ Error::InvalidOrderBook
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + OrderBook::LEN,
+        seeds = [b"order_book".as_ref(), pair.key().as_ref()],
+        bump
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_a_escrow, &pair.token_program)?.mint == pair.token_a_mint,
+        constraint = is_held_by(&token_account(&token_a_escrow, &pair.token_program)?, &pda.key()),
+        constraint = token_a_escrow.key() != pair.token_a_account
+    )]
+    pub token_a_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_b_escrow, &pair.token_program)?.mint == pair.token_b_mint,
+        constraint = is_held_by(&token_account(&token_b_escrow, &pair.token_program)?, &pda.key()),
+        constraint = token_b_escrow.key() != pair.token_b_account
+    )]
+    pub token_b_escrow: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(trade_direction: TradeDirection, amount_in: u64, amount_out: u64)]
+pub struct PlaceOrder<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        constraint = pair.order_book == Some(order_book.key()) @ Error::InvalidOrderBook
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(mut)]
+    pub order_book: Box<Account<'info, OrderBook>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the escrow
+    pub token_source_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_owner, &pair.token_program)?.mint == token_source_mint.key(),
+        constraint = is_held_by(&token_account(&token_source_for_owner, &pair.token_program)?, &owner.key())
+    )]
+    pub token_source_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_source_escrow.key() == order_book.source_escrow(&trade_direction) @ Error::InvalidOrderBook,
+        constraint = token_account(&token_source_escrow, &pair.token_program)?.mint == token_source_mint.key()
+    )]
+    pub token_source_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: u64)]
+pub struct CancelOrder<'info> {
+    pub owner: Signer<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        constraint = pair.order_book == Some(order_book.key()) @ Error::InvalidOrderBook,
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        mut,
+        constraint = order_book.token_a_escrow == token_a_escrow.key() @ Error::InvalidOrderBook,
+        constraint = order_book.token_b_escrow == token_b_escrow.key() @ Error::InvalidOrderBook,
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the escrow of the order book
+    #[account(mut)]
+    pub token_a_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the escrow of the order book
+    #[account(mut)]
+    pub token_b_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_owner, &pair.token_program)?.mint == token_a_mint.key(),
+        constraint = token_account(&token_a_for_owner, &pair.token_program)?.owner == owner.key()
+    )]
+    pub token_a_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_owner, &pair.token_program)?.mint == token_b_mint.key(),
+        constraint = token_account(&token_b_for_owner, &pair.token_program)?.owner == owner.key()
+    )]
+    pub token_b_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> PlaceOrder<'info> {
+    fn to_transfer_source_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_owner.to_account_info().clone(),
+            mint: self.token_source_mint.to_account_info().clone(),
+            to: self.token_source_escrow.to_account_info().clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> CancelOrder<'info> {
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_a_escrow.to_account_info().clone(),
+            mint: self.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_owner.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_b_escrow.to_account_info().clone(),
+            mint: self.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_owner.to_account_info().clone(),
+            authority: self.pda.clone(),
+        };
+        CpiContext::new(self.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Limit orders of a pair, created at the PDA derived from
+/// `["order_book", pair]`. The escrows are held by the PDA of the pair and
+/// keep both the unsold input of the orders and the proceeds of their fills
+/// until the orders are cancelled.
+#[account]
+pub struct OrderBook {
+    pub pair: Pubkey,
+    pub token_a_escrow: Pubkey,
+    pub token_b_escrow: Pubkey,
+    pub next_order_id: u64,
+    pub orders: Vec<Order>,
+    pub bump: u8,
+}
+
+impl OrderBook {
+    pub const MAX_ORDERS: usize = 32;
+    /// Maximum number of orders filled by a single swap
+    pub const MAX_FILLS: usize = 4;
+    pub const MAX_FILL_ATTEMPTS: usize = 3;
+    pub const LEN: usize = 32 * 3 + 8 + (4 + Order::LEN * OrderBook::MAX_ORDERS) + 1;
+
+    fn source_escrow(&self, trade_direction: &TradeDirection) -> Pubkey {
+        match trade_direction {
+            TradeDirection::AtoB => self.token_a_escrow,
+            TradeDirection::BtoA => self.token_b_escrow,
+        }
+    }
+
+    fn place(
+        &mut self,
+        owner: Pubkey,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        amount_out: u64,
+    ) -> Result<u64> {
+        if self.orders.len() >= OrderBook::MAX_ORDERS {
+            return Err(Error::OrderBookFull.into());
+        }
+        let id = self.next_order_id;
+        self.next_order_id = id.checked_add(1).ok_or(Error::CalculationFailure)?;
+        self.orders.push(Order {
+            id,
+            owner,
+            trade_direction,
+            amount_in,
+            amount_out,
+            amount_in_remaining: amount_in,
+            amount_out_filled: 0,
+        });
+        Ok(id)
+    }
+
+    fn remove(&mut self, order_id: u64, owner: &Pubkey) -> Result<Order> {
+        let index = self
+            .orders
+            .iter()
+            .position(|order| order.id == order_id)
+            .ok_or(Error::OrderNotFound)?;
+        if self.orders[index].owner != *owner {
+            return Err(Error::InvalidOwner.into());
+        }
+        Ok(self.orders.remove(index))
+    }
+
+    /// Indices of the unfilled orders in `trade_direction`, the lowest
+    /// `amount_out / amount_in` first, up to `OrderBook::MAX_FILLS`
+    pub fn fillable_orders(&self, trade_direction: &TradeDirection) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..self.orders.len())
+            .filter(|index| {
+                let order = &self.orders[*index];
+                order.trade_direction == *trade_direction && order.amount_in_remaining > 0
+            })
+            .collect();
+        indices.sort_by(|i, j| {
+            let (a, b) = (&self.orders[*i], &self.orders[*j]);
+            (u128::from(a.amount_out) * u128::from(b.amount_in))
+                .cmp(&(u128::from(b.amount_out) * u128::from(a.amount_in)))
+        });
+        indices.truncate(OrderBook::MAX_FILLS);
+        indices
+    }
+
+    pub fn fill(&mut self, index: usize, amount_in: u64, amount_out: u64) -> Result<()> {
+        let order = &mut self.orders[index];
+        order.amount_in_remaining = order
+            .amount_in_remaining
+            .checked_sub(amount_in)
+            .ok_or(Error::CalculationFailure)?;
+        order.amount_out_filled = order
+            .amount_out_filled
+            .checked_add(amount_out)
+            .ok_or(Error::CalculationFailure)?;
+        Ok(())
+    }
+}
+
+/// An order selling `amount_in` of the source token of `trade_direction` for
+/// at least `amount_out` of the other token
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct Order {
+    pub id: u64,
+    pub owner: Pubkey,
+    pub trade_direction: TradeDirection,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub amount_in_remaining: u64,
+    /// Proceeds of the fills held in the escrow of the destination token
+    pub amount_out_filled: u64,
+}
+
+impl Order {
+    pub const LEN: usize = 8 + 32 + 1 + 8 * 4;
+}
+
+/// The order book of a pair and its escrows, passed to a swap as remaining
+/// accounts
+pub struct OrderBookAccounts<'info> {
+    pub order_book: Account<'info, OrderBook>,
+    pub token_a_escrow: AccountInfo<'info>,
+    pub token_b_escrow: AccountInfo<'info>,
+}
+
+/// Reads the order book of the pair and its escrows from the front of the
+/// remaining accounts of a swap, required once the pair has an order book,
+/// and returns them with the accounts that follow.
+pub fn load_order_book<'a, 'info>(
+    pair: &Account<'info, SwapPair>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<OrderBookAccounts<'info>>, &'a [AccountInfo<'info>])> {
+    let order_book_key = match pair.order_book {
+        Some(order_book_key) => order_book_key,
+        None => return Ok((None, remaining_accounts)),
+    };
+    let (order_book, token_a_escrow, token_b_escrow, remaining_accounts) = match remaining_accounts
+    {
+        [order_book, token_a_escrow, token_b_escrow, remaining_accounts @ ..] => (
+            order_book,
+            token_a_escrow,
+            token_b_escrow,
+            remaining_accounts,
+        ),
+        _ => return Err(Error::InvalidOrderBook.into()),
+    };
+    if order_book.key() != order_book_key
+        || !order_book.is_writable
+        || !token_a_escrow.is_writable
+        || !token_b_escrow.is_writable
+    {
+        return Err(Error::InvalidOrderBook.into());
+    }
+    let order_book = Account::<OrderBook>::try_from(order_book)?;
+    if order_book.token_a_escrow != token_a_escrow.key()
+        || order_book.token_b_escrow != token_b_escrow.key()
+    {
+        return Err(Error::InvalidOrderBook.into());
+    }
+    Ok((
+        Some(OrderBookAccounts {
+            order_book,
+            token_a_escrow: token_a_escrow.clone(),
+            token_b_escrow: token_b_escrow.clone(),
+        }),
+        remaining_accounts,
+    ))
+}
@@ -0,0 +1,644 @@
+//! StableSwap invariant for the pools of 3 to 8 tokens.
+//!
+//! The math follows Curve's StableSwap: the invariant `D` solves
+//! `A * n^n * sum(x) + D = A * n^n * D + D^(n+1) / (n^n * prod(x))`
+//! and is found by Newton's method, as is the balance `y` of one token for a
+//! given `D` and the balances of the others. The intermediate products are
+//! computed in U256.
+
+use crate::curve::{RoundDirection, SwapResult};
+use crate::error::Error;
+use crate::Fees;
+use anchor_lang::prelude::*;
+use spl_math::uint::U256;
+
+/// Minimum number of tokens of a multi pool
+pub const MIN_TOKENS: usize = 3;
+/// Maximum number of tokens of a multi pool
+pub const MAX_TOKENS: usize = 8;
+/// Minimum amplification coefficient
+pub const MIN_AMP: u64 = 1;
+/// Maximum amplification coefficient
+pub const MAX_AMP: u64 = 1_000_000;
+
+/// Newton's method stops after this many iterations without converging
+const MAX_ITERATIONS: u8 = 255;
+
+pub struct StableSwap {
+    /// Amplification coefficient
+    pub amp: u64,
+}
+
+impl StableSwap {
+    pub fn is_valid_amp(amp: u64) -> bool {
+        (MIN_AMP..=MAX_AMP).contains(&amp)
+    }
+
+    /// `A * n^n`, written `Ann` in Curve's contracts where `n^n` is folded into `A`
+    fn amp_times_n(&self, n: usize) -> U256 {
+        U256::from(self.amp) * U256::from(n)
+    }
+
+    /// Get the invariant `D` for the given balances, zero for an empty pool.
+    pub fn compute_d(&self, amounts: &[u128]) -> Result<u128> {
+        let n = U256::from(amounts.len());
+        // at most 8 amounts below 2^128 each
+        let sum = amounts
+            .iter()
+            .fold(U256::zero(), |sum, amount| sum + U256::from(*amount));
+        if sum.is_zero() {
+            return Ok(0);
+        }
+        if amounts.contains(&0) {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let ann = self.amp_times_n(amounts.len());
+        // dividing `D_P` by the smallest balances first keeps its precision,
+        // without which the iterations cycle at extreme imbalances
+        let mut sorted_amounts = amounts.to_vec();
+        sorted_amounts.sort_unstable();
+        let mut d = sum;
+        for _ in 0..MAX_ITERATIONS {
+            let mut d_p = d;
+            for amount in &sorted_amounts {
+                d_p = d_p.checked_mul(d).ok_or(Error::CalculationFailure)?
+                    / (U256::from(*amount) * n);
+            }
+            let d_prev = d;
+            let numerator = ann
+                .checked_mul(sum)
+                .and_then(|product| product.checked_add(d_p.checked_mul(n)?))
+                .and_then(|sum| sum.checked_mul(d))
+                .ok_or(Error::CalculationFailure)?;
+            let denominator = (ann - 1)
+                .checked_mul(d)
+                .and_then(|product| product.checked_add((n + 1).checked_mul(d_p)?))
+                .ok_or(Error::CalculationFailure)?;
+            d = numerator / denominator;
+            if abs_diff(d, d_prev) <= U256::one() {
+                return to_u128(d);
+            }
+        }
+        // Newton's method didn't converge
+        Err(Error::CalculationFailure.into())
+    }
+
+    /// Get the balance of the token at `index` so that the invariant is `d`,
+    /// given the balances of the other tokens in `amounts`.
+    pub fn compute_y(&self, amounts: &[u128], index: usize, d: u128) -> Result<u128> {
+        if d == 0 {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let n = U256::from(amounts.len());
+        let ann = self.amp_times_n(amounts.len());
+        let d = U256::from(d);
+        let mut c = d;
+        let mut sum = U256::zero();
+        for (i, amount) in amounts.iter().enumerate() {
+            if i == index {
+                continue;
+            }
+            if *amount == 0 {
+                return Err(Error::InsufficientLiquidity.into());
+            }
+            let amount = U256::from(*amount);
+            sum += amount;
+            c = c.checked_mul(d).ok_or(Error::CalculationFailure)? / (amount * n);
+        }
+        c = c.checked_mul(d).ok_or(Error::CalculationFailure)? / (ann * n);
+        let b = sum + d / ann;
+        let mut y = d;
+        for _ in 0..MAX_ITERATIONS {
+            let y_prev = y;
+            let numerator = y
+                .checked_mul(y)
+                .and_then(|square| square.checked_add(c))
+                .ok_or(Error::CalculationFailure)?;
+            let denominator = y
+                .checked_mul(U256::from(2))
+                .and_then(|double| double.checked_add(b))
+                .and_then(|sum| sum.checked_sub(d))
+                .filter(|denominator| !denominator.is_zero())
+                .ok_or(Error::CalculationFailure)?;
+            y = numerator / denominator;
+            if abs_diff(y, y_prev) <= U256::one() {
+                return to_u128(y);
+            }
+        }
+        // Newton's method didn't converge
+        Err(Error::CalculationFailure.into())
+    }
+
+    /// Swap `source_amount` of the token at `source_index` for the token at
+    /// `destination_index`, debiting the trade and owner fees from the input
+    /// as the constant product curve does.
+    pub fn swap(
+        &self,
+        source_index: usize,
+        destination_index: usize,
+        source_amount: u128,
+        amounts: &[u128],
+        fees: &Fees,
+    ) -> Result<SwapResult> {
+        if source_index == destination_index {
+            return Err(Error::RepeatedMint.into());
+        }
+        let trade_fee = fees.trading_fee(source_amount)?;
+        let owner_fee = fees.owner_trading_fee(source_amount)?;
+        let total_fees = trade_fee
+            .checked_add(owner_fee)
+            .ok_or(Error::FeeCalculationFailure)?;
+        let source_amount_less_fees = source_amount
+            .checked_sub(total_fees)
+            .ok_or(Error::ZeroTradingTokens)?;
+
+        let d = self.compute_d(amounts)?;
+        let mut new_amounts = amounts.to_vec();
+        new_amounts[source_index] = amounts[source_index]
+            .checked_add(source_amount_less_fees)
+            .ok_or(Error::CalculationFailure)?;
+        let new_destination_amount = self.compute_y(&new_amounts, destination_index, d)?;
+        // round the output down by one token in favor of the pool
+        let destination_amount_swapped = amounts[destination_index]
+            .saturating_sub(new_destination_amount)
+            .saturating_sub(1);
+        if destination_amount_swapped == 0 {
+            return Err(Error::ZeroTradingTokens.into());
+        }
+
+        Ok(SwapResult {
+            new_swap_source_amount: amounts[source_index]
+                .checked_add(source_amount)
+                .ok_or(Error::CalculationFailure)?,
+            new_swap_destination_amount: amounts[destination_index] - destination_amount_swapped,
+            source_amount_swapped: source_amount,
+            destination_amount_swapped,
+            trade_fee,
+            owner_fee,
+        })
+    }
+
+    /// Get the amount of pool tokens for the deposited amount of the token at
+    /// `source_index`, with the trade fee charged on the `(n - 1) / n` of the
+    /// deposit that would have to be swapped for a proportional deposit.
+    pub fn deposit_single_token_type(
+        &self,
+        source_index: usize,
+        source_amount: u128,
+        amounts: &[u128],
+        pool_supply: u128,
+        fees: &Fees,
+    ) -> Result<u128> {
+        if source_amount == 0 {
+            return Ok(0);
+        }
+        let trade_fee = fees.trading_fee(imbalanced_amount(source_amount, amounts.len()))?;
+        let source_amount = source_amount
+            .checked_sub(trade_fee)
+            .ok_or(Error::ZeroTradingTokens)?;
+
+        let d0 = self.compute_d(amounts)?;
+        if d0 == 0 {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let mut new_amounts = amounts.to_vec();
+        new_amounts[source_index] = amounts[source_index]
+            .checked_add(source_amount)
+            .ok_or(Error::CalculationFailure)?;
+        let d1 = self.compute_d(&new_amounts)?;
+        mul_div_floor(pool_supply, d1.saturating_sub(d0), d0)
+    }
+
+    /// Get the amount of the token at `destination_index` received for
+    /// burning exactly `pool_tokens`, rounded down, with the trade fee charged
+    /// as in `deposit_single_token_type`.
+    pub fn withdraw_single_token_type_exact_in(
+        &self,
+        destination_index: usize,
+        pool_tokens: u128,
+        amounts: &[u128],
+        pool_supply: u128,
+        fees: &Fees,
+    ) -> Result<u128> {
+        if pool_tokens == 0 {
+            return Ok(0);
+        }
+        // the whole supply is worth all the tokens, not just one of them
+        if pool_tokens >= pool_supply {
+            return Err(Error::InsufficientLiquidity.into());
+        }
+        let d0 = self.compute_d(amounts)?;
+        let d1 = d0 - mul_div_ceil(d0, pool_tokens, pool_supply)?;
+        let new_destination_amount = self.compute_y(amounts, destination_index, d1)?;
+        let destination_amount = amounts[destination_index]
+            .saturating_sub(new_destination_amount)
+            .saturating_sub(1);
+        let trade_fee = fees.trading_fee(imbalanced_amount(destination_amount, amounts.len()))?;
+        destination_amount
+            .checked_sub(trade_fee)
+            .ok_or_else(|| Error::ZeroTradingTokens.into())
+    }
+
+    /// Get the amount of pool tokens worth `owner_fee` of the token at
+    /// `source_index` that stays in the pool, rounded down, given the balances
+    /// after the swap.
+    pub fn owner_fee_to_pool_tokens(
+        &self,
+        source_index: usize,
+        owner_fee: u128,
+        amounts: &[u128],
+        pool_supply: u128,
+    ) -> Result<u128> {
+        if owner_fee == 0 {
+            return Ok(0);
+        }
+        let d1 = self.compute_d(amounts)?;
+        let mut amounts_without_fee = amounts.to_vec();
+        amounts_without_fee[source_index] = amounts[source_index]
+            .checked_sub(owner_fee)
+            .ok_or(Error::FeeCalculationFailure)?;
+        let d0 = self.compute_d(&amounts_without_fee)?;
+        mul_div_floor(pool_supply, d1.saturating_sub(d0), d1)
+    }
+}
+
+/// Get the amounts of every token for the given amount of pool tokens, as
+/// `ConstantProduct::pool_tokens_to_trading_tokens` does for two tokens.
+pub fn pool_tokens_to_trading_tokens(
+    pool_tokens: u128,
+    pool_token_supply: u128,
+    amounts: &[u128],
+    round_direction: RoundDirection,
+) -> Result<Vec<u128>> {
+    if pool_token_supply == 0 {
+        return Err(Error::EmptySupply.into());
+    }
+    amounts
+        .iter()
+        .map(|amount| {
+            let token_amount = mul_div_floor(pool_tokens, *amount, pool_token_supply)?;
+            match round_direction {
+                RoundDirection::Floor => Ok(token_amount),
+                RoundDirection::Ceiling => {
+                    let remainder = (U256::from(pool_tokens) * U256::from(*amount))
+                        % U256::from(pool_token_supply);
+                    // don't round 0 up, for it to be rejected later in processing
+                    if !remainder.is_zero() && token_amount > 0 {
+                        token_amount
+                            .checked_add(1)
+                            .ok_or_else(|| Error::ConversionFailure.into())
+                    } else {
+                        Ok(token_amount)
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// The part of a single token deposit or withdrawal that is not proportional
+/// to the balances, at least one token
+fn imbalanced_amount(amount: u128, token_count: usize) -> u128 {
+    // at most `amount`, for a multi pool holds at least `MIN_TOKENS`
+    let token_count = U256::from(token_count);
+    let imbalanced_amount = U256::from(amount) * (token_count - 1) / token_count;
+    std::cmp::max(1, imbalanced_amount.as_u128())
+}
+
+/// `a * b / c` rounded down, which fails if `c` is zero
+fn mul_div_floor(a: u128, b: u128, c: u128) -> Result<u128> {
+    if c == 0 {
+        return Err(Error::CalculationFailure.into());
+    }
+    to_u128(U256::from(a) * U256::from(b) / U256::from(c))
+}
+
+/// `a * b / c` rounded up, which fails if `c` is zero
+fn mul_div_ceil(a: u128, b: u128, c: u128) -> Result<u128> {
+    if c == 0 {
+        return Err(Error::CalculationFailure.into());
+    }
+    let c = U256::from(c);
+    to_u128((U256::from(a) * U256::from(b) + c - 1) / c)
+}
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+fn to_u128(value: U256) -> Result<u128> {
+    if value > U256::from(u128::MAX) {
+        Err(Error::ConversionFailure.into())
+    } else {
+        Ok(value.as_u128())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u128 = u64::MAX as u128;
+
+    fn error<T: std::fmt::Debug>(result: Result<T>) -> ProgramError {
+        result.unwrap_err().into()
+    }
+
+    fn fees() -> Fees {
+        Fees {
+            trade_fee_numerator: 4,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 10_000,
+            owner_withdraw_fee_numerator: 0,
+            owner_withdraw_fee_denominator: 0,
+            host_fee_numerator: 20,
+            host_fee_denominator: 100,
+        }
+    }
+
+    #[test]
+    fn compute_d_of_balanced_pool_is_the_sum() {
+        for amp in [MIN_AMP, 100, MAX_AMP] {
+            let curve = StableSwap { amp };
+            assert_eq!(curve.compute_d(&[MAX; 3]).unwrap(), MAX * 3);
+            assert_eq!(curve.compute_d(&[1_000; 8]).unwrap(), 8_000);
+        }
+        assert_eq!(StableSwap { amp: 100 }.compute_d(&[0; 3]).unwrap(), 0);
+        // a pool can't hold only some of its tokens
+        assert_eq!(
+            error(StableSwap { amp: 100 }.compute_d(&[0, 1, 1])),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn compute_d_converges_at_extreme_imbalance() {
+        for amp in [MIN_AMP, 100, MAX_AMP] {
+            let curve = StableSwap { amp };
+            for amounts in [vec![1, MAX, MAX], vec![1, 1, MAX], vec![1_000, MAX, 10]] {
+                let d = curve.compute_d(&amounts).unwrap();
+                let sum: u128 = amounts.iter().sum();
+                assert!(d <= sum, "D {} exceeds the sum {}", d, sum);
+                // the balance of each token is found back from D, within 1%
+                for index in 0..amounts.len() {
+                    let y = curve.compute_y(&amounts, index, d).unwrap();
+                    assert!(
+                        y.abs_diff(amounts[index]) <= amounts[index] / 100 + 2,
+                        "{} for {} in {:?}",
+                        y,
+                        amounts[index],
+                        amounts
+                    );
+                }
+            }
+            // the products of Newton's method overflow U256
+            assert_eq!(
+                error(curve.compute_d(&[1, 1, 1, 1, 1, 1, 1, MAX])),
+                Error::CalculationFailure.into()
+            );
+        }
+    }
+
+    #[test]
+    fn compute_d_does_not_depend_on_the_order_of_the_tokens() {
+        for amp in [MIN_AMP, 100, MAX_AMP] {
+            let curve = StableSwap { amp };
+            for amounts in [[1, MAX, MAX], [1, 1, MAX], [1_000, MAX, 10]] {
+                let d = curve.compute_d(&amounts).unwrap();
+                for permutation in [[0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+                    let permuted_amounts = permutation.map(|index| amounts[index]);
+                    assert_eq!(curve.compute_d(&permuted_amounts).unwrap(), d);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn swap_keeps_the_invariant() {
+        let curve = StableSwap { amp: 100 };
+        for amounts in [
+            vec![MAX, MAX, MAX],
+            vec![1_000_000_000, 2_000_000_000, 500_000_000],
+        ] {
+            let d = curve.compute_d(&amounts).unwrap();
+            for (source_index, destination_index) in [(0, 1), (1, 2), (2, 0)] {
+                let result = curve
+                    .swap(
+                        source_index,
+                        destination_index,
+                        1_000_000,
+                        &amounts,
+                        &fees(),
+                    )
+                    .unwrap();
+                let mut new_amounts = amounts.clone();
+                new_amounts[source_index] = result.new_swap_source_amount;
+                new_amounts[destination_index] = result.new_swap_destination_amount;
+                assert!(curve.compute_d(&new_amounts).unwrap() >= d);
+            }
+        }
+        // near the peg a swap returns slightly less than its input, less the fees
+        let result = curve.swap(0, 1, 1_000_000, &[MAX; 3], &fees()).unwrap();
+        assert_eq!(result.trade_fee, 400);
+        assert_eq!(result.owner_fee, 100);
+        assert!(result.destination_amount_swapped < 1_000_000 - 500);
+        assert!(result.destination_amount_swapped > 1_000_000 - 510);
+    }
+
+    #[test]
+    fn swap_failures() {
+        let curve = StableSwap { amp: 100 };
+        assert_eq!(
+            error(curve.swap(0, 0, 1_000, &[MAX; 3], &fees())),
+            Error::RepeatedMint.into()
+        );
+        // the fees and the rounding leave nothing to receive
+        assert_eq!(
+            error(curve.swap(0, 1, 1, &[MAX; 3], &fees())),
+            Error::ZeroTradingTokens.into()
+        );
+        // the destination can't be emptied
+        assert_eq!(
+            error(curve.swap(0, 1, MAX, &[MAX, 1, MAX], &fees())),
+            Error::ZeroTradingTokens.into()
+        );
+        assert_eq!(
+            error(curve.swap(0, 1, 1_000, &[0; 3], &fees())),
+            Error::InsufficientLiquidity.into()
+        );
+        assert_eq!(
+            error(curve.swap(0, 1, 1_000, &[MAX, 0, MAX], &fees())),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn single_token_round_trip_favors_the_pool() {
+        let pool_supply = 3_000_000_000;
+        for amp in [MIN_AMP, 100, MAX_AMP] {
+            let curve = StableSwap { amp };
+            for amounts in [
+                vec![1_000_000_000; 3],
+                vec![1_000_000, 1_000_000_000, 5_000_000_000],
+            ] {
+                for source_index in 0..amounts.len() {
+                    let source_amount = 1_000_000;
+                    let pool_tokens = curve
+                        .deposit_single_token_type(
+                            source_index,
+                            source_amount,
+                            &amounts,
+                            pool_supply,
+                            &fees(),
+                        )
+                        .unwrap();
+                    assert!(pool_tokens > 0);
+                    let mut new_amounts = amounts.clone();
+                    new_amounts[source_index] += source_amount;
+                    let destination_amount = curve
+                        .withdraw_single_token_type_exact_in(
+                            source_index,
+                            pool_tokens,
+                            &new_amounts,
+                            pool_supply + pool_tokens,
+                            &fees(),
+                        )
+                        .unwrap();
+                    assert!(destination_amount < source_amount);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn single_token_without_fees_favors_the_pool() {
+        let curve = StableSwap { amp: 100 };
+        let fees = Fees {
+            trade_fee_numerator: 0,
+            ..fees()
+        };
+        let amounts = [1_000_000_000; 3];
+        let pool_tokens = curve
+            .deposit_single_token_type(0, 3_000, &amounts, 3_000_000_000, &fees)
+            .unwrap();
+        // the deposit is balanced enough to be worth its D
+        assert!(pool_tokens <= 3_000);
+        let destination_amount = curve
+            .withdraw_single_token_type_exact_in(
+                0,
+                pool_tokens,
+                &[1_000_003_000, 1_000_000_000, 1_000_000_000],
+                3_000_000_000 + pool_tokens,
+                &fees,
+            )
+            .unwrap();
+        assert!(destination_amount < 3_000);
+        assert_eq!(
+            curve
+                .deposit_single_token_type(0, 0, &amounts, 3_000_000_000, &fees)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn swap_between_eight_tokens_near_u64_max() {
+        let curve = StableSwap { amp: 100 };
+        let amounts = [MAX; 8];
+        let d = curve.compute_d(&amounts).unwrap();
+        let result = curve.swap(0, 7, MAX, &amounts, &fees()).unwrap();
+        assert_eq!(result.new_swap_source_amount, MAX * 2);
+        assert!(result.destination_amount_swapped < MAX);
+        let mut new_amounts = amounts.to_vec();
+        new_amounts[0] = result.new_swap_source_amount;
+        new_amounts[7] = result.new_swap_destination_amount;
+        assert!(curve.compute_d(&new_amounts).unwrap() >= d);
+    }
+
+    #[test]
+    fn single_token_failures() {
+        let curve = StableSwap { amp: 100 };
+        let amounts = [1_000_000_000; 3];
+        let pool_supply = 3_000_000_000;
+        // the whole supply is worth more than the balance of one token
+        for pool_tokens in [pool_supply, pool_supply + 1] {
+            assert_eq!(
+                error(curve.withdraw_single_token_type_exact_in(
+                    0,
+                    pool_tokens,
+                    &amounts,
+                    pool_supply,
+                    &fees()
+                )),
+                Error::InsufficientLiquidity.into()
+            );
+        }
+        // the minimum fee of one token takes all of a tiny withdrawal
+        assert_eq!(
+            error(curve.withdraw_single_token_type_exact_in(0, 1, &amounts, pool_supply, &fees())),
+            Error::ZeroTradingTokens.into()
+        );
+        assert_eq!(
+            error(curve.deposit_single_token_type(0, 1_000, &[0; 3], 0, &fees())),
+            Error::InsufficientLiquidity.into()
+        );
+        assert_eq!(
+            error(curve.deposit_single_token_type(0, 1_000, &[1_000, 0, 1_000], 1, &fees())),
+            Error::InsufficientLiquidity.into()
+        );
+    }
+
+    #[test]
+    fn owner_fee_to_pool_tokens_rounding() {
+        let curve = StableSwap { amp: 100 };
+        let pool_supply = 3_000_000_000;
+        let amounts = [1_000_001_000, 1_000_000_000, 1_000_000_000];
+        assert_eq!(
+            curve
+                .owner_fee_to_pool_tokens(0, 0, &amounts, pool_supply)
+                .unwrap(),
+            0
+        );
+        // the fee is worth at most its share of D
+        let pool_tokens = curve
+            .owner_fee_to_pool_tokens(0, 1_000, &amounts, pool_supply)
+            .unwrap();
+        assert!(pool_tokens > 0 && pool_tokens <= 1_000);
+        assert_eq!(
+            error(curve.owner_fee_to_pool_tokens(1, 1_000_000_001, &amounts, pool_supply)),
+            Error::FeeCalculationFailure.into()
+        );
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_rounding() {
+        let amounts = [10, 20, 30];
+        assert_eq!(
+            pool_tokens_to_trading_tokens(1, 3, &amounts, RoundDirection::Floor).unwrap(),
+            vec![3, 6, 10]
+        );
+        assert_eq!(
+            pool_tokens_to_trading_tokens(1, 3, &amounts, RoundDirection::Ceiling).unwrap(),
+            vec![4, 7, 10]
+        );
+        // 0 isn't rounded up
+        assert_eq!(
+            pool_tokens_to_trading_tokens(4, 100, &amounts, RoundDirection::Ceiling).unwrap(),
+            vec![0, 0, 2]
+        );
+        assert_eq!(
+            error(pool_tokens_to_trading_tokens(
+                1,
+                0,
+                &amounts,
+                RoundDirection::Floor
+            )),
+            Error::EmptySupply.into()
+        );
+    }
+}
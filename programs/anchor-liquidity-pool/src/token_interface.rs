@@ -0,0 +1,242 @@
+//! Token accounts, mints and CPIs shared by SPL Token and Token-2022.
+//!
+//! Both programs have the same base layout for accounts and mints, Token-2022
+//! appending its extensions after it, so the accounts are read through
+//! `StateWithExtensions` and the instructions are built for the program the
+//! pair was created with.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::invoke_signed;
+use spl_token_2022::extension::transfer_fee::{
+    TransferFee, TransferFeeConfig, MAX_FEE_BASIS_POINTS,
+};
+use spl_token_2022::extension::{BaseState, StateWithExtensions};
+pub use spl_token_2022::state::{Account as TokenAccount, Mint};
+
+/// Token programs a pair can be created with
+pub const TOKEN_PROGRAM_IDS: [Pubkey; 2] = [spl_token::ID, spl_token_2022::ID];
+
+pub fn is_token_program(program_id: &Pubkey) -> bool {
+    TOKEN_PROGRAM_IDS.contains(program_id)
+}
+
+fn unpack<T: BaseState>(info: &AccountInfo, token_program: &Pubkey) -> Result<T> {
+    if info.owner != token_program {
+        return Err(ErrorCode::AccountOwnedByWrongProgram.into());
+    }
+    let data = info.try_borrow_data()?;
+    Ok(StateWithExtensions::<T>::unpack(&data)?.base)
+}
+
+/// Reads the token account owned by `token_program`
+pub fn token_account(info: &AccountInfo, token_program: &Pubkey) -> Result<TokenAccount> {
+    unpack::<TokenAccount>(info, token_program)
+}
+
+/// Reads the mint owned by `token_program`
+pub fn mint(info: &AccountInfo, token_program: &Pubkey) -> Result<Mint> {
+    unpack::<Mint>(info, token_program)
+}
+
+/// Whether the token account is held by `owner` without delegate nor close authority
+pub fn is_held_by(account: &TokenAccount, owner: &Pubkey) -> bool {
+    account.owner == *owner && account.delegate.is_none() && account.close_authority.is_none()
+}
+
+fn epoch_transfer_fee(mint: &AccountInfo) -> Result<Option<TransferFee>> {
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<Mint>::unpack(&data)?;
+    Ok(match mint.get_extension::<TransferFeeConfig>() {
+        Ok(config) => Some(*config.get_epoch_fee(Clock::get()?.epoch)),
+        Err(_) => None,
+    })
+}
+
+/// Whether the mint has the TransferFee extension, even with a zero fee for
+/// the current epoch
+pub fn has_transfer_fee(mint: &AccountInfo) -> Result<bool> {
+    Ok(epoch_transfer_fee(mint)?.is_some())
+}
+
+/// Fee withheld by the mint when transferring `amount`, zero for mints
+/// without the TransferFee extension
+pub fn transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    match epoch_transfer_fee(mint)? {
+        Some(fee) => Ok(fee
+            .calculate(amount)
+            .ok_or(crate::error::Error::FeeCalculationFailure)?),
+        None => Ok(0),
+    }
+}
+
+/// Amount to transfer so that the recipient receives at least `amount` after
+/// the fee withheld by the mint
+pub fn amount_before_transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    let fee = match epoch_transfer_fee(mint)? {
+        Some(fee) if amount > 0 && u16::from(fee.transfer_fee_basis_points) > 0 => {
+            let basis_points = u128::from(u16::from(fee.transfer_fee_basis_points));
+            let maximum_fee = u64::from(fee.maximum_fee);
+            if basis_points == u128::from(MAX_FEE_BASIS_POINTS) {
+                maximum_fee
+            } else {
+                // ceil(amount * 10000 / (10000 - bps)) transfers at least `amount`
+                let one = u128::from(MAX_FEE_BASIS_POINTS);
+                let numerator = u128::from(amount)
+                    .checked_mul(one)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?;
+                let denominator = one - basis_points;
+                let amount_before_fee = numerator
+                    .checked_add(denominator - 1)
+                    .ok_or(crate::error::Error::FeeCalculationFailure)?
+                    / denominator;
+                let fee = u64::try_from(amount_before_fee - u128::from(amount))
+                    .map_err(|_| crate::error::Error::ConversionFailure)?;
+                std::cmp::min(fee, maximum_fee)
+            }
+        }
+        _ => 0,
+    };
+    Ok(amount
+        .checked_add(fee)
+        .ok_or(crate::error::Error::FeeCalculationFailure)?)
+}
+
+pub fn transfer_checked<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, TransferChecked<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let decimals = mint(&ctx.accounts.mint, ctx.program.key)?.decimals;
+    let ix = spl_token_2022::instruction::transfer_checked(
+        ctx.program.key,
+        ctx.accounts.from.key,
+        ctx.accounts.mint.key,
+        ctx.accounts.to.key,
+        ctx.accounts.authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.from.clone(),
+            ctx.accounts.mint.clone(),
+            ctx.accounts.to.clone(),
+            ctx.accounts.authority.clone(),
+        ],
+        ctx.signer_seeds,
+    )
+    .map_err(Into::into)
+}
+
+pub fn mint_to<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, MintTo<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let ix = spl_token_2022::instruction::mint_to(
+        ctx.program.key,
+        ctx.accounts.mint.key,
+        ctx.accounts.to.key,
+        ctx.accounts.authority.key,
+        &[],
+        amount,
+    )?;
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.to.clone(),
+            ctx.accounts.mint.clone(),
+            ctx.accounts.authority.clone(),
+        ],
+        ctx.signer_seeds,
+    )
+    .map_err(Into::into)
+}
+
+pub fn burn<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, Burn<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let ix = spl_token_2022::instruction::burn(
+        ctx.program.key,
+        ctx.accounts.from.key,
+        ctx.accounts.mint.key,
+        ctx.accounts.authority.key,
+        &[],
+        amount,
+    )?;
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.from.clone(),
+            ctx.accounts.mint.clone(),
+            ctx.accounts.authority.clone(),
+        ],
+        ctx.signer_seeds,
+    )
+    .map_err(Into::into)
+}
+
+pub fn close_account<'a, 'b, 'c, 'info>(
+    ctx: CpiContext<'a, 'b, 'c, 'info, CloseAccount<'info>>,
+) -> Result<()> {
+    let ix = spl_token_2022::instruction::close_account(
+        ctx.program.key,
+        ctx.accounts.account.key,
+        ctx.accounts.destination.key,
+        ctx.accounts.authority.key,
+        &[],
+    )?;
+    invoke_signed(
+        &ix,
+        &[
+            ctx.accounts.account.clone(),
+            ctx.accounts.destination.clone(),
+            ctx.accounts.authority.clone(),
+        ],
+        ctx.signer_seeds,
+    )
+    .map_err(Into::into)
+}
+
+#[derive(Accounts)]
+pub struct TransferChecked<'info> {
+    /// CHECK: Validated by the token program
+    pub from: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub to: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MintTo<'info> {
+    /// CHECK: Validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub to: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Burn<'info> {
+    /// CHECK: Validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub from: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseAccount<'info> {
+    /// CHECK: Validated by the token program
+    pub account: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub destination: AccountInfo<'info>,
+    /// CHECK: Validated by the token program
+    pub authority: AccountInfo<'info>,
+}
@@ -0,0 +1,777 @@
+//! TWAMM of a pair, selling its long-term orders into the pool in virtual
+//! orders spread over the slots of their intervals.
+
+use crate::curve::{ConstantProduct, TradeDirection};
+use crate::error::Error;
+use crate::fees::OwnerFeeMode;
+use crate::token_interface::{is_held_by, token_account, MintTo, TransferChecked};
+use crate::{add_u64, admin_pubkey, to_u128, to_u64, token_interface, SwapPair};
+use anchor_lang::prelude::*;
+
+pub fn initialize(ctx: Context<InitializeTwamm>) -> Result<()> {
+    msg!("Instruction TWAMM Init {}", ctx.accounts.pair.key());
+
+    ctx.accounts.pair.require_constant_product()?;
+
+    // the escrows are held by the PDA of the pair, which signs the settlements
+    ctx.accounts
+        .pair
+        .signer_seeds(&ctx.accounts.pda, ctx.program_id)?;
+    // the settlements move tokens between the escrows and the pool, which
+    // must receive exactly what is sent
+    if token_interface::has_transfer_fee(&ctx.accounts.token_a_mint)?
+        || token_interface::has_transfer_fee(&ctx.accounts.token_b_mint)?
+    {
+        return Err(Error::TransferFeeNotSupported.into());
+    }
+
+    let twamm = &mut ctx.accounts.twamm;
+    twamm.pair = ctx.accounts.pair.key();
+    twamm.token_a_escrow = ctx.accounts.token_a_escrow.key();
+    twamm.token_b_escrow = ctx.accounts.token_b_escrow.key();
+    twamm.last_executed_ts = Clock::get()?.unix_timestamp;
+    twamm.next_order_id = 0;
+    twamm.orders = Vec::new();
+    twamm.bump = *ctx.bumps.get("twamm").ok_or(Error::InvalidProgramAddress)?;
+    ctx.accounts.pair.twamm = true;
+    Ok(())
+}
+
+pub fn place_long_term_order(
+    ctx: Context<PlaceLongTermOrder>,
+    trade_direction: TradeDirection,
+    amount_in: u64,
+    duration: u64,
+) -> Result<()> {
+    msg!(
+        "Instruction Place Long Term Order {:?},{},{}",
+        trade_direction,
+        amount_in,
+        duration
+    );
+
+    ctx.accounts.virtual_orders.execute(ctx.program_id)?;
+    let order_id = ctx.accounts.virtual_orders.twamm.place(
+        ctx.accounts.owner.key(),
+        trade_direction,
+        amount_in,
+        duration,
+        Clock::get()?.unix_timestamp,
+    )?;
+    msg!("order_id={}", order_id);
+
+    token_interface::transfer_checked(
+        ctx.accounts.to_transfer_source_context(&trade_direction),
+        amount_in,
+    )?;
+    Ok(())
+}
+
+pub fn cancel_long_term_order(ctx: Context<CancelLongTermOrder>, order_id: u64) -> Result<()> {
+    msg!("Instruction Cancel Long Term Order {}", order_id);
+
+    ctx.accounts.virtual_orders.execute(ctx.program_id)?;
+    let order = ctx
+        .accounts
+        .virtual_orders
+        .twamm
+        .remove(order_id, &ctx.accounts.owner.key())?;
+    let unsold_amount = order.unsold_amount(Clock::get()?.unix_timestamp)?;
+    let (token_a_amount, token_b_amount) = match order.trade_direction {
+        TradeDirection::AtoB => (unsold_amount, order.amount_out),
+        TradeDirection::BtoA => (order.amount_out, unsold_amount),
+    };
+    msg!(
+        "token_a_amount={},token_b_amount={}",
+        token_a_amount,
+        token_b_amount
+    );
+
+    let signer_seeds = ctx
+        .accounts
+        .virtual_orders
+        .pair
+        .signer_seeds(&ctx.accounts.virtual_orders.pda, ctx.program_id)?;
+    let signer_seeds = &[&signer_seeds.value()[..]];
+    if token_a_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_a_context()
+                .with_signer(signer_seeds),
+            token_a_amount,
+        )?;
+    }
+    if token_b_amount > 0 {
+        token_interface::transfer_checked(
+            ctx.accounts
+                .to_transfer_b_context()
+                .with_signer(signer_seeds),
+            token_b_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn execute_virtual_orders(ctx: Context<ExecuteVirtualOrders>) -> Result<()> {
+    msg!(
+        "Instruction Execute Virtual Orders {}",
+        ctx.accounts.pair.key()
+    );
+    ctx.accounts.execute(ctx.program_id)
+}
+
+#[derive(Accounts)]
+pub struct InitializeTwamm<'info> {
+    #[account(mut, constraint = admin.key() == admin_pubkey()?)]
+    pub admin: Signer<'info>,
+
+    /// CHECK: This is not dangerous because it is checked against the seeds of the pair
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = !pair.twamm @ Error::InvalidTwamm,
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Twamm::LEN,
+        seeds = [b"twamm".as_ref(), pair.key().as_ref()],
+        bump
+    )]
+    pub twamm: Box<Account<'info, Twamm>>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_a_escrow, &pair.token_program)?.mint == pair.token_a_mint,
+        constraint = is_held_by(&token_account(&token_a_escrow, &pair.token_program)?, &pda.key()),
+        constraint = token_a_escrow.key() != pair.token_a_account
+    )]
+    pub token_a_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        constraint = token_account(&token_b_escrow, &pair.token_program)?.mint == pair.token_b_mint,
+        constraint = is_held_by(&token_account(&token_b_escrow, &pair.token_program)?, &pda.key()),
+        constraint = token_b_escrow.key() != pair.token_b_account
+    )]
+    pub token_b_escrow: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteVirtualOrders<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub pda: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = pair.token_a_account == token_a_for_pda.key(),
+        constraint = pair.token_b_account == token_b_for_pda.key(),
+        constraint = pair.token_a_mint == token_a_mint.key(),
+        constraint = pair.token_b_mint == token_b_mint.key(),
+        constraint = pair.pool_mint == pool.key(),
+        constraint = pair.pool_fee_account == pool_fee_account.key(),
+    )]
+    pub pair: Box<Account<'info, SwapPair>>,
+
+    #[account(
+        mut,
+        seeds = [b"twamm".as_ref(), pair.key().as_ref()],
+        bump = twamm.bump,
+        constraint = twamm.token_a_escrow == token_a_escrow.key() @ Error::InvalidTwamm,
+        constraint = twamm.token_b_escrow == token_b_escrow.key() @ Error::InvalidTwamm,
+    )]
+    pub twamm: Box<Account<'info, Twamm>>,
+
+    /// CHECK: This is not dangerous because it must be the pool mint of the pair
+    #[account(mut)]
+    pub pool: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the fee account of the pair
+    #[account(mut)]
+    pub pool_fee_account: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_a_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the mint of the pair
+    pub token_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_a_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token account of the pair
+    #[account(mut)]
+    pub token_b_for_pda: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the escrow of the TWAMM
+    #[account(mut)]
+    pub token_a_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the escrow of the TWAMM
+    #[account(mut)]
+    pub token_b_escrow: UncheckedAccount<'info>,
+
+    /// CHECK: This is not dangerous because it must be the token program of the pair
+    #[account(
+        constraint = token_program.key() == pair.token_program @ Error::IncorrectTokenProgramId
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(trade_direction: TradeDirection, amount_in: u64, duration: u64)]
+pub struct PlaceLongTermOrder<'info> {
+    pub owner: Signer<'info>,
+
+    pub virtual_orders: ExecuteVirtualOrders<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_source_for_owner, &virtual_orders.pair.token_program)?.mint == virtual_orders.source_mint(&trade_direction).key(),
+        constraint = is_held_by(&token_account(&token_source_for_owner, &virtual_orders.pair.token_program)?, &owner.key())
+    )]
+    pub token_source_for_owner: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: u64)]
+pub struct CancelLongTermOrder<'info> {
+    pub owner: Signer<'info>,
+
+    pub virtual_orders: ExecuteVirtualOrders<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_a_for_owner, &virtual_orders.pair.token_program)?.mint == virtual_orders.token_a_mint.key(),
+        constraint = token_account(&token_a_for_owner, &virtual_orders.pair.token_program)?.owner == owner.key()
+    )]
+    pub token_a_for_owner: UncheckedAccount<'info>,
+
+    /// CHECK: Token account of SPL Token or Token-2022, read in the constraints
+    #[account(
+        mut,
+        constraint = token_account(&token_b_for_owner, &virtual_orders.pair.token_program)?.mint == virtual_orders.token_b_mint.key(),
+        constraint = token_account(&token_b_for_owner, &virtual_orders.pair.token_program)?.owner == owner.key()
+    )]
+    pub token_b_for_owner: UncheckedAccount<'info>,
+}
+
+impl<'info> ExecuteVirtualOrders<'info> {
+    fn source_mint(&self, trade_direction: &TradeDirection) -> &AccountInfo<'info> {
+        match trade_direction {
+            TradeDirection::AtoB => &self.token_a_mint,
+            TradeDirection::BtoA => &self.token_b_mint,
+        }
+    }
+
+    fn source_escrow(&self, trade_direction: &TradeDirection) -> &AccountInfo<'info> {
+        match trade_direction {
+            TradeDirection::AtoB => &self.token_a_escrow,
+            TradeDirection::BtoA => &self.token_b_escrow,
+        }
+    }
+
+    fn execute(&mut self, program_id: &Pubkey) -> Result<()> {
+        let accounts = VirtualOrderAccounts {
+            pda: &self.pda,
+            pool: &self.pool,
+            pool_fee_account: &self.pool_fee_account,
+            token_a_mint: &self.token_a_mint,
+            token_b_mint: &self.token_b_mint,
+            token_a_for_pda: &self.token_a_for_pda,
+            token_b_for_pda: &self.token_b_for_pda,
+            token_a_escrow: &self.token_a_escrow,
+            token_b_escrow: &self.token_b_escrow,
+            token_program: &self.token_program,
+        };
+        accounts.execute(&mut self.pair, &mut self.twamm, program_id)
+    }
+}
+
+impl<'info> PlaceLongTermOrder<'info> {
+    fn to_transfer_source_context(
+        &self,
+        trade_direction: &TradeDirection,
+    ) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.token_source_for_owner.to_account_info().clone(),
+            mint: self.virtual_orders.source_mint(trade_direction).clone(),
+            to: self.virtual_orders.source_escrow(trade_direction).clone(),
+            authority: self.owner.to_account_info().clone(),
+        };
+        CpiContext::new(self.virtual_orders.token_program.clone(), cpi_accounts)
+    }
+}
+
+impl<'info> CancelLongTermOrder<'info> {
+    fn to_transfer_a_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.virtual_orders.token_a_escrow.to_account_info().clone(),
+            mint: self.virtual_orders.token_a_mint.to_account_info().clone(),
+            to: self.token_a_for_owner.to_account_info().clone(),
+            authority: self.virtual_orders.pda.clone(),
+        };
+        CpiContext::new(self.virtual_orders.token_program.clone(), cpi_accounts)
+    }
+
+    fn to_transfer_b_context(&self) -> CpiContext<'_, '_, '_, 'info, TransferChecked<'info>> {
+        let cpi_accounts = TransferChecked {
+            from: self.virtual_orders.token_b_escrow.to_account_info().clone(),
+            mint: self.virtual_orders.token_b_mint.to_account_info().clone(),
+            to: self.token_b_for_owner.to_account_info().clone(),
+            authority: self.virtual_orders.pda.clone(),
+        };
+        CpiContext::new(self.virtual_orders.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Accounts of the instructions reading the reserves of a pair, which must
+/// first execute the virtual orders of its TWAMM
+pub trait SyncTwamm<'info> {
+    fn pair(&self) -> &Account<'info, SwapPair>;
+
+    /// Executes the virtual orders of the TWAMM up to now against the pool
+    fn execute_virtual_orders(
+        &mut self,
+        twamm: &mut TwammAccounts<'info>,
+        program_id: &Pubkey,
+    ) -> Result<()>;
+
+    /// Loads the TWAMM of the pair from the front of `remaining_accounts` and
+    /// executes its virtual orders up to now, before the reserves are read.
+    /// Returns the accounts that follow those of the TWAMM.
+    fn sync_twamm<'a>(
+        &mut self,
+        remaining_accounts: &'a [AccountInfo<'info>],
+        program_id: &Pubkey,
+    ) -> Result<&'a [AccountInfo<'info>]> {
+        let (twamm, remaining_accounts) = load_twamm(self.pair(), remaining_accounts)?;
+        if let Some(mut twamm) = twamm {
+            self.execute_virtual_orders(&mut twamm, program_id)?;
+            twamm.twamm.exit(program_id)?;
+        }
+        Ok(remaining_accounts)
+    }
+}
+
+/// The accounts of a pair and its TWAMM settling the virtual orders
+pub struct VirtualOrderAccounts<'a, 'info> {
+    pub pda: &'a AccountInfo<'info>,
+    pub pool: &'a AccountInfo<'info>,
+    pub pool_fee_account: &'a AccountInfo<'info>,
+    pub token_a_mint: &'a AccountInfo<'info>,
+    pub token_b_mint: &'a AccountInfo<'info>,
+    pub token_a_for_pda: &'a AccountInfo<'info>,
+    pub token_b_for_pda: &'a AccountInfo<'info>,
+    pub token_a_escrow: &'a AccountInfo<'info>,
+    pub token_b_escrow: &'a AccountInfo<'info>,
+    pub token_program: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> VirtualOrderAccounts<'a, 'info> {
+    /// Executes the virtual orders up to now, then mints the owner fee in pool
+    /// tokens and moves the net amount of each token between its escrow and
+    /// the pool
+    pub fn execute(
+        &self,
+        pair: &mut SwapPair,
+        twamm: &mut Twamm,
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        let (swap_token_a_amount, swap_token_b_amount) =
+            pair.swap_token_amounts(self.token_a_for_pda, self.token_b_for_pda)?;
+        let result = twamm.execute(
+            pair,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pair.pool_mint_supply(self.pool)?,
+            Clock::get()?.unix_timestamp,
+        )?;
+        msg!("{:?}", result);
+
+        let signer_seeds = pair.signer_seeds(self.pda, program_id)?;
+        let signer_seeds = &[&signer_seeds.value()[..]];
+        if result.pool_token_amount > 0 {
+            let cpi_accounts = MintTo {
+                mint: self.pool.clone(),
+                to: self.pool_fee_account.clone(),
+                authority: self.pda.clone(),
+            };
+            token_interface::mint_to(
+                CpiContext::new(self.token_program.clone(), cpi_accounts).with_signer(signer_seeds),
+                to_u64(result.pool_token_amount)?,
+            )?;
+        }
+        self.settle(
+            self.token_a_mint,
+            self.token_a_escrow,
+            self.token_a_for_pda,
+            result.token_a_sold,
+            result.token_a_out,
+            signer_seeds,
+        )?;
+        self.settle(
+            self.token_b_mint,
+            self.token_b_escrow,
+            self.token_b_for_pda,
+            result.token_b_sold,
+            result.token_b_out,
+            signer_seeds,
+        )
+    }
+
+    /// Moves the difference between the amount sold to the pool and the
+    /// amount paid out by it, in whichever direction it goes
+    fn settle(
+        &self,
+        mint: &AccountInfo<'info>,
+        escrow: &AccountInfo<'info>,
+        token_for_pda: &AccountInfo<'info>,
+        amount_sold: u64,
+        amount_out: u64,
+        signer_seeds: &[&[&[u8]]],
+    ) -> Result<()> {
+        let (from, to, amount) = if amount_sold >= amount_out {
+            (escrow, token_for_pda, amount_sold - amount_out)
+        } else {
+            (token_for_pda, escrow, amount_out - amount_sold)
+        };
+        if amount == 0 {
+            return Ok(());
+        }
+        let cpi_accounts = TransferChecked {
+            from: from.clone(),
+            mint: mint.clone(),
+            to: to.clone(),
+            authority: self.pda.clone(),
+        };
+        token_interface::transfer_checked(
+            CpiContext::new(self.token_program.clone(), cpi_accounts).with_signer(signer_seeds),
+            amount,
+        )
+    }
+}
+
+/// Long-term orders of a pair, created at the PDA derived from
+/// `["twamm", pair]`. The escrows are held by the PDA of the pair and keep
+/// both the unsold input of the orders and the proceeds of their virtual
+/// trades until the orders are cancelled.
+#[account]
+pub struct Twamm {
+    pub pair: Pubkey,
+    pub token_a_escrow: Pubkey,
+    pub token_b_escrow: Pubkey,
+    /// Time up to which the virtual orders have been executed
+    pub last_executed_ts: i64,
+    pub next_order_id: u64,
+    pub orders: Vec<LongTermOrder>,
+    pub bump: u8,
+}
+
+impl Twamm {
+    pub const MAX_ORDERS: usize = 16;
+    pub const LEN: usize = 32 * 3 + 8 + 8 + (4 + LongTermOrder::LEN * Twamm::MAX_ORDERS) + 1;
+
+    fn place(
+        &mut self,
+        owner: Pubkey,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        duration: u64,
+        now: i64,
+    ) -> Result<u64> {
+        if self.orders.len() >= Twamm::MAX_ORDERS {
+            return Err(Error::OrderBookFull.into());
+        }
+        let sell_rate = amount_in
+            .checked_div(duration)
+            .ok_or(Error::InvalidOrderDuration)?;
+        if sell_rate == 0 {
+            return Err(Error::InvalidOrderDuration.into());
+        }
+        let expiry_ts = i64::try_from(duration)
+            .ok()
+            .and_then(|duration| now.checked_add(duration))
+            .ok_or(Error::InvalidOrderDuration)?;
+        let id = self.next_order_id;
+        self.next_order_id = id.checked_add(1).ok_or(Error::CalculationFailure)?;
+        self.orders.push(LongTermOrder {
+            id,
+            owner,
+            trade_direction,
+            sell_rate,
+            start_ts: now,
+            expiry_ts,
+            amount_in,
+            amount_out: 0,
+        });
+        Ok(id)
+    }
+
+    fn remove(&mut self, order_id: u64, owner: &Pubkey) -> Result<LongTermOrder> {
+        let index = self
+            .orders
+            .iter()
+            .position(|order| order.id == order_id)
+            .ok_or(Error::OrderNotFound)?;
+        if self.orders[index].owner != *owner {
+            return Err(Error::InvalidOwner.into());
+        }
+        Ok(self.orders.remove(index))
+    }
+
+    /// Executes the virtual orders from `last_executed_ts` up to `now`, in
+    /// periods between the expiries of the orders over which the sell rates
+    /// are constant. Each period is a virtual trade of both sides against the
+    /// pool, charged the same fees as a swap, whose proceeds are shared by the
+    /// orders of each side pro rata of their sell rates.
+    fn execute(
+        &mut self,
+        pair: &mut SwapPair,
+        swap_token_a_amount: u64,
+        swap_token_b_amount: u64,
+        pool_supply: u64,
+        now: i64,
+    ) -> Result<VirtualOrdersResult> {
+        let curve = ConstantProduct {};
+        let mut result = VirtualOrdersResult::default();
+        let mut swap_token_a_amount = to_u128(swap_token_a_amount)?;
+        let mut swap_token_b_amount = to_u128(swap_token_b_amount)?;
+        let mut pool_supply = to_u128(pool_supply)?;
+        while self.last_executed_ts < now {
+            let start_ts = self.last_executed_ts;
+            let end_ts = self
+                .orders
+                .iter()
+                .map(|order| order.expiry_ts)
+                .filter(|expiry_ts| *expiry_ts > start_ts)
+                .fold(now, std::cmp::min);
+            self.last_executed_ts = end_ts;
+            let elapsed =
+                to_u128(u64::try_from(end_ts - start_ts).map_err(|_| Error::ConversionFailure)?)?;
+
+            let (mut sell_rate_a, mut sell_rate_b) = (0u128, 0u128);
+            for order in self
+                .orders
+                .iter()
+                .filter(|order| order.expiry_ts > start_ts)
+            {
+                let sell_rate = match order.trade_direction {
+                    TradeDirection::AtoB => &mut sell_rate_a,
+                    TradeDirection::BtoA => &mut sell_rate_b,
+                };
+                *sell_rate = sell_rate
+                    .checked_add(to_u128(order.sell_rate)?)
+                    .ok_or(Error::CalculationFailure)?;
+            }
+            if sell_rate_a == 0 && sell_rate_b == 0 {
+                continue;
+            }
+            let token_a_sold = sell_rate_a
+                .checked_mul(elapsed)
+                .ok_or(Error::CalculationFailure)?;
+            let token_b_sold = sell_rate_b
+                .checked_mul(elapsed)
+                .ok_or(Error::CalculationFailure)?;
+
+            let fees = pair.swap_fees(swap_token_a_amount, swap_token_b_amount)?;
+            let owner_fee_a = fees.owner_trading_fee(token_a_sold)?;
+            let owner_fee_b = fees.owner_trading_fee(token_b_sold)?;
+            let token_a_sold_less_fees = token_a_sold
+                .checked_sub(fees.trading_fee(token_a_sold)?)
+                .and_then(|amount| amount.checked_sub(owner_fee_a))
+                .ok_or(Error::FeeCalculationFailure)?;
+            let token_b_sold_less_fees = token_b_sold
+                .checked_sub(fees.trading_fee(token_b_sold)?)
+                .and_then(|amount| amount.checked_sub(owner_fee_b))
+                .ok_or(Error::FeeCalculationFailure)?;
+            let (token_a_out, token_b_out) = curve.virtual_orders_swap(
+                token_a_sold_less_fees,
+                token_b_sold_less_fees,
+                swap_token_a_amount,
+                swap_token_b_amount,
+            )?;
+
+            // the rounding dust of the shares stays in the pool
+            let (mut token_a_paid, mut token_b_paid) = (0u128, 0u128);
+            for order in self
+                .orders
+                .iter_mut()
+                .filter(|order| order.expiry_ts > start_ts)
+            {
+                let (amount_out, sell_rate, paid) = match order.trade_direction {
+                    TradeDirection::AtoB => (token_b_out, sell_rate_a, &mut token_b_paid),
+                    TradeDirection::BtoA => (token_a_out, sell_rate_b, &mut token_a_paid),
+                };
+                let share = amount_out
+                    .checked_mul(to_u128(order.sell_rate)?)
+                    .and_then(|amount| amount.checked_div(sell_rate))
+                    .ok_or(Error::CalculationFailure)?;
+                order.amount_out = order
+                    .amount_out
+                    .checked_add(to_u64(share)?)
+                    .ok_or(Error::CalculationFailure)?;
+                *paid = paid.checked_add(share).ok_or(Error::CalculationFailure)?;
+            }
+
+            swap_token_a_amount = swap_token_a_amount
+                .checked_add(token_a_sold)
+                .and_then(|amount| amount.checked_sub(token_a_paid))
+                .ok_or(Error::CalculationFailure)?;
+            swap_token_b_amount = swap_token_b_amount
+                .checked_add(token_b_sold)
+                .and_then(|amount| amount.checked_sub(token_b_paid))
+                .ok_or(Error::CalculationFailure)?;
+            match pair.owner_fee_mode {
+                OwnerFeeMode::PoolToken => {
+                    for (trade_direction, owner_fee) in [
+                        (TradeDirection::AtoB, owner_fee_a),
+                        (TradeDirection::BtoA, owner_fee_b),
+                    ] {
+                        if owner_fee == 0 {
+                            continue;
+                        }
+                        let pool_token_amount = curve.withdraw_single_token_type_exact_out(
+                            owner_fee,
+                            swap_token_a_amount,
+                            swap_token_b_amount,
+                            pool_supply,
+                            &trade_direction,
+                            &fees,
+                        )?;
+                        pool_supply = pool_supply
+                            .checked_add(pool_token_amount)
+                            .ok_or(Error::FeeCalculationFailure)?;
+                        result.pool_token_amount = result
+                            .pool_token_amount
+                            .checked_add(pool_token_amount)
+                            .ok_or(Error::FeeCalculationFailure)?;
+                    }
+                }
+                OwnerFeeMode::UnderlyingToken => {
+                    // the owner fees stay in the token accounts until they are collected
+                    pair.accrue_protocol_fee(&TradeDirection::AtoB, to_u64(owner_fee_a)?)?;
+                    pair.accrue_protocol_fee(&TradeDirection::BtoA, to_u64(owner_fee_b)?)?;
+                    swap_token_a_amount = swap_token_a_amount
+                        .checked_sub(owner_fee_a)
+                        .ok_or(Error::FeeCalculationFailure)?;
+                    swap_token_b_amount = swap_token_b_amount
+                        .checked_sub(owner_fee_b)
+                        .ok_or(Error::FeeCalculationFailure)?;
+                }
+            }
+            pair.update_volatility(swap_token_a_amount, swap_token_b_amount)?;
+
+            result.token_a_sold = add_u64(result.token_a_sold, token_a_sold)?;
+            result.token_b_sold = add_u64(result.token_b_sold, token_b_sold)?;
+            result.token_a_out = add_u64(result.token_a_out, token_a_paid)?;
+            result.token_b_out = add_u64(result.token_b_out, token_b_paid)?;
+        }
+        Ok(result)
+    }
+}
+
+/// An order virtually selling `sell_rate` of the source token of
+/// `trade_direction` per second from `start_ts` to `expiry_ts`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LongTermOrder {
+    pub id: u64,
+    pub owner: Pubkey,
+    pub trade_direction: TradeDirection,
+    pub sell_rate: u64,
+    pub start_ts: i64,
+    pub expiry_ts: i64,
+    /// Amount escrowed, of which `sell_rate * (expiry_ts - start_ts)` is sold
+    pub amount_in: u64,
+    /// Proceeds of the virtual trades held in the escrow of the destination token
+    pub amount_out: u64,
+}
+
+impl LongTermOrder {
+    pub const LEN: usize = 8 + 32 + 1 + 8 * 5;
+
+    /// Part of `amount_in` not sold by `now`
+    fn unsold_amount(&self, now: i64) -> Result<u64> {
+        let elapsed = std::cmp::min(now, self.expiry_ts)
+            .checked_sub(self.start_ts)
+            .and_then(|elapsed| u64::try_from(elapsed).ok())
+            .ok_or(Error::ConversionFailure)?;
+        self.sell_rate
+            .checked_mul(elapsed)
+            .and_then(|amount_sold| self.amount_in.checked_sub(amount_sold))
+            .ok_or_else(|| Error::CalculationFailure.into())
+    }
+}
+
+/// Amounts of an execution of the virtual orders, sold to the pool by the
+/// orders and paid out by the pool to them
+#[derive(Debug, Default)]
+struct VirtualOrdersResult {
+    token_a_sold: u64,
+    token_b_sold: u64,
+    token_a_out: u64,
+    token_b_out: u64,
+    /// Pool tokens minted to the fee account for the owner fees
+    pool_token_amount: u128,
+}
+
+/// The TWAMM of a pair and its escrows, passed to a swap as remaining
+/// accounts
+pub struct TwammAccounts<'info> {
+    pub twamm: Account<'info, Twamm>,
+    pub token_a_escrow: AccountInfo<'info>,
+    pub token_b_escrow: AccountInfo<'info>,
+}
+
+/// Reads the TWAMM of the pair and its escrows from the front of the
+/// remaining accounts left after the order book, required once the pair has a
+/// TWAMM, and returns them with the accounts that follow.
+pub fn load_twamm<'a, 'info>(
+    pair: &Account<'info, SwapPair>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<(Option<TwammAccounts<'info>>, &'a [AccountInfo<'info>])> {
+    if !pair.twamm {
+        return Ok((None, remaining_accounts));
+    }
+    let (twamm, token_a_escrow, token_b_escrow, remaining_accounts) = match remaining_accounts {
+        [twamm, token_a_escrow, token_b_escrow, remaining_accounts @ ..] => {
+            (twamm, token_a_escrow, token_b_escrow, remaining_accounts)
+        }
+        _ => return Err(Error::InvalidTwamm.into()),
+    };
+    if !twamm.is_writable || !token_a_escrow.is_writable || !token_b_escrow.is_writable {
+        return Err(Error::InvalidTwamm.into());
+    }
+    let twamm = Account::<Twamm>::try_from(twamm)?;
+    if twamm.pair != pair.key()
+        || twamm.token_a_escrow != token_a_escrow.key()
+        || twamm.token_b_escrow != token_b_escrow.key()
+    {
+        return Err(Error::InvalidTwamm.into());
+    }
+    Ok((
+        Some(TwammAccounts {
+            twamm,
+            token_a_escrow: token_a_escrow.clone(),
+            token_b_escrow: token_b_escrow.clone(),
+        }),
+        remaining_accounts,
+    ))
+}
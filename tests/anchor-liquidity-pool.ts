@@ -2,14 +2,14 @@ import * as anchor from "@project-serum/anchor";
 import {AnchorProvider, Program} from "@project-serum/anchor";
 import {AnchorLiquidityPool} from "../target/types/anchor_liquidity_pool";
 import NodeWallet from '@project-serum/anchor/dist/cjs/nodewallet';
-import {Connection, LAMPORTS_PER_SOL, PublicKey} from "@solana/web3.js";
+import {Connection, LAMPORTS_PER_SOL, PublicKey, sendAndConfirmTransaction, SystemProgram, Transaction, TransactionInstruction} from "@solana/web3.js";
 import {
     Account, approve,
     AuthorityType,
     createAccount,
     createMint,
     getAccount,
-    getMint, Mint, mintTo,
+    getMint, Mint, MINT_SIZE, mintTo,
     setAuthority,
     TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
@@ -18,6 +18,16 @@ import {
 const POOL_TOKEN_AMOUNT = 10000000;
 const DEFAULT_TOKEN_A = 1000000;
 const DEFAULT_TOKEN_B = 1000000;
+// Trade fee in basis points of the pool under test
+const FEE_TIER = 25;
+// Snapshot of a SwapPair created before versioning, loaded by `npm run validator`
+const SWAP_PAIR_V0 = new PublicKey("5sFD1gox9rt2jdtqYsMop84BV1MFE38bS6xssRSvHkYP");
+const SWAP_PAIR_LEN = 567;
+const TOKEN_2022_PROGRAM_ID = new PublicKey("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+// Sizes of a Token-2022 mint with the TransferFeeConfig extension and of its
+// token accounts, which carry the TransferFeeAmount extension
+const TRANSFER_FEE_MINT_SIZE = 278;
+const TRANSFER_FEE_ACCOUNT_SIZE = 178;
 const connection = new Connection("http://localhost:8899", "confirmed");
 
 const options = AnchorProvider.defaultOptions();
@@ -44,6 +54,8 @@ let bAccountForUserB: PublicKey
 let aAccountForPDA: PublicKey
 let bAccountForPDA: PublicKey
 let pda: PublicKey
+let hostForUserA: PublicKey
+let config: PublicKey
 let aForUserA: Account
 let bForUserA: Account
 let aForPDA : Account
@@ -51,7 +63,11 @@ let bForPDA: Account
 let poolForAdmin: Account
 let poolForUserA: Account
 let poolMint: Mint
-const swapPair = anchor.web3.Keypair.generate();
+let swapPair: PublicKey
+let orderBook: {orderBook: PublicKey, tokenAEscrow: PublicKey, tokenBEscrow: PublicKey}
+let twamm: {twamm: PublicKey, tokenAEscrow: PublicKey, tokenBEscrow: PublicKey}
+let farm: {farm: PublicKey, stakeVault: PublicKey, rewardMint: PublicKey, rewardVault: PublicKey}
+let priceFeed: PublicKey
 
 describe("anchor-liquidity-pool", () => {
 
@@ -61,9 +77,17 @@ describe("anchor-liquidity-pool", () => {
 
         aMintPubkey = await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, TOKEN_PROGRAM_ID);
         bMintPubkey = await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, TOKEN_PROGRAM_ID);
+        // token A and B mints must be in ascending order
+        if (Buffer.compare(aMintPubkey.toBuffer(), bMintPubkey.toBuffer()) > 0) {
+            [aMintPubkey, bMintPubkey] = [bMintPubkey, aMintPubkey]
+        }
 
-        const [_pda, _] = await PublicKey.findProgramAddress([Buffer.from("pool"), aMintPubkey.toBuffer(),bMintPubkey.toBuffer()], program.programId);
+        const [_pda, _] = await PublicKey.findProgramAddress([Buffer.from("pool"), aMintPubkey.toBuffer(),bMintPubkey.toBuffer(), feeTierSeed(FEE_TIER)], program.programId);
         pda = _pda
+        const [_config, __] = await PublicKey.findProgramAddress([Buffer.from("config")], program.programId);
+        config = _config
+        const [_swapPair, ___] = await PublicKey.findProgramAddress([Buffer.from("pair"), aMintPubkey.toBuffer(),bMintPubkey.toBuffer(), feeTierSeed(FEE_TIER)], program.programId);
+        swapPair = _swapPair
 
         poolMintPubkey = await createMint(connection, admin, pda, null, 2, undefined, undefined, TOKEN_PROGRAM_ID);
 
@@ -102,6 +126,30 @@ describe("anchor-liquidity-pool", () => {
         await mintTo(connection, admin, bMint.address, bAccountForUserB, bMint.mintAuthority, DEFAULT_TOKEN_B * 10)
     })
 
+    it("Create Config", async () => {
+        // the config is program wide, so it may already exist on a reused validator
+        if (await connection.getAccountInfo(config) == null) {
+            const tx = await program.methods.initializeConfig()
+                .accounts({
+                    admin: admin.publicKey,
+                    config: config,
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                }).rpc()
+            console.log("Initialize config transaction signature", tx);
+        }
+        for (const feeTier of [1, 5, FEE_TIER, 30, 100]) {
+            if ((await program.account.config.fetch(config)).feeTiers.includes(feeTier)) {
+                continue
+            }
+            await program.methods.addFeeTier(feeTier)
+                .accounts({
+                    admin: admin.publicKey,
+                    config: config,
+                }).rpc()
+        }
+        console.log("Fee tiers", (await program.account.config.fetch(config)).feeTiers)
+    })
+
     it("Create Swap", async () => {
         const fees = {
             tradeFeeNumerator: new anchor.BN(25),
@@ -114,19 +162,22 @@ describe("anchor-liquidity-pool", () => {
             hostFeeDenominator: new anchor.BN(100),
         }
         try {
-            const tx = await program.methods.initialize(fees)
+            const tx = await program.methods.initialize(fees, {poolToken: {}}, FEE_TIER)
                 .accounts({
-                    pair: swapPair.publicKey,
+                    payer: admin.publicKey,
+                    pair: swapPair,
+                    config: config,
                     pool: poolMintPubkey,
                     pda: pda,
+                    tokenAMint: aMintPubkey,
+                    tokenBMint: bMintPubkey,
                     tokenAForPda: aAccountForPDA,
                     tokenBForPda: bAccountForPDA,
                     tokenPoolForInitializer: poolAccountForAdmin,
                     tokenPoolForFeeReceiver: poolAccountForAdmin,
                     tokenProgram: TOKEN_PROGRAM_ID,
-                }).preInstructions([
-                    await program.account.swapPair.createInstruction(swapPair),
-                ]).signers([swapPair]).rpc();
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                }).rpc();
             console.log("Initialize transaction signature", tx);
         }catch (e) {
            console.error(e)
@@ -142,7 +193,7 @@ describe("anchor-liquidity-pool", () => {
         poolForUserA = await getAccount(connection, poolAccountForUserA, null, TOKEN_PROGRAM_ID)
         console.log("Account")
         console.table([
-            {name : "Swap Pair", address: swapPair.publicKey.toBase58(), owner: (await connection.getAccountInfo(swapPair.publicKey)).owner.toBase58()},
+            {name : "Swap Pair", address: swapPair.toBase58(), owner: (await connection.getAccountInfo(swapPair)).owner.toBase58()},
         ])
 
         console.log("Token Account")
@@ -156,13 +207,371 @@ describe("anchor-liquidity-pool", () => {
         ])
     });
 
+    it("Reject Duplicate Swap", async () => {
+        const fees = (await program.account.swapPair.fetch(swapPair)).fees
+        const initialize = async (tokenAMint: PublicKey, tokenBMint: PublicKey, expectedError: string) => {
+            const [pair, _] = await PublicKey.findProgramAddress([Buffer.from("pair"), tokenAMint.toBuffer(), tokenBMint.toBuffer(), feeTierSeed(FEE_TIER)], program.programId);
+            try {
+                await program.methods.initialize(fees, {poolToken: {}}, FEE_TIER)
+                    .accounts({
+                        payer: admin.publicKey,
+                        pair: pair,
+                        config: config,
+                        pool: poolMintPubkey,
+                        pda: pda,
+                        tokenAMint: tokenAMint,
+                        tokenBMint: tokenBMint,
+                        tokenAForPda: aAccountForPDA,
+                        tokenBForPda: bAccountForPDA,
+                        tokenPoolForInitializer: poolAccountForAdmin,
+                        tokenPoolForFeeReceiver: poolAccountForAdmin,
+                        tokenProgram: TOKEN_PROGRAM_ID,
+                        systemProgram: anchor.web3.SystemProgram.programId,
+                    }).rpc();
+            } catch (e) {
+                console.log(`Swap rejected with ${expectedError}`, e.toString())
+                if (!failedWith(e, expectedError)) {
+                    throw e
+                }
+                return
+            }
+            throw new Error(`swap was created instead of failing with ${expectedError}`)
+        }
+        // the pair account of the mints and fee tier exists
+        await initialize(aMintPubkey, bMintPubkey, "already in use")
+        await initialize(aMintPubkey, aMintPubkey, "RepeatedMint")
+        await initialize(bMintPubkey, aMintPubkey, "InvalidMintOrder")
+    })
+
+    it("Find Pools", async () => {
+        const pairs = await findSwapPairs(aMintPubkey, bMintPubkey)
+        console.table(pairs.map((pair) => ({address: pair.publicKey.toBase58(), feeTier: pair.account.feeTier})))
+        if (!pairs.some((pair) => pair.publicKey.equals(swapPair))) {
+            throw new Error("swap pair not found")
+        }
+    })
+
+    it("Register Host", async () => {
+        const [host, _] = await PublicKey.findProgramAddress([Buffer.from("host"), swapPair.toBuffer(), userA.publicKey.toBuffer()], program.programId);
+        hostForUserA = host
+        const tx = await program.methods.registerHost(null)
+            .accounts({
+                admin: admin.publicKey,
+                pair: swapPair,
+                wallet: userA.publicKey,
+                host: hostForUserA,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Register host transaction signature", tx);
+    })
+
+    it("Reject Incorrect Token Program", async () => {
+        try {
+            await program.methods.depositSingle(new anchor.BN(1000), new anchor.BN(0))
+                .accounts({
+                    depositor: userA.publicKey,
+                    pair: swapPair,
+                    pool: poolMintPubkey,
+                    poolFeeAccount: poolAccountForAdmin,
+                    pda: pda,
+                    tokenAMint: aMintPubkey,
+                    tokenBMint: bMintPubkey,
+                    tokenAForPda: aAccountForPDA,
+                    tokenBForPda: bAccountForPDA,
+                    tokenSourceForDepositor: aAccountForUserA,
+                    tokenPoolForDepositor: poolAccountForUserA,
+                    tokenProgram: anchor.web3.SystemProgram.programId,
+                }).signers([userA]).rpc()
+        } catch (e) {
+            console.log("Incorrect token program rejected", e.toString())
+            if (!failedWith(e, "IncorrectTokenProgramId")) {
+                throw e
+            }
+            return
+        }
+        throw new Error("deposit with an incorrect token program succeeded")
+    })
+
+    it("Migrate V0 Pair", async () => {
+        const before = await connection.getAccountInfo(SWAP_PAIR_V0)
+        const tx = await program.methods.migratePair()
+            .accounts({
+                payer: admin.publicKey,
+                pair: SWAP_PAIR_V0,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Migrate pair transaction signature", tx);
+        const after = await connection.getAccountInfo(SWAP_PAIR_V0)
+        const pair = await program.account.swapPair.fetch(SWAP_PAIR_V0)
+        console.table([{version: pair.version, sizeBefore: before.data.length, sizeAfter: after.data.length, feeTier: pair.feeTier}])
+        if (pair.version != 7 || after.data.length != 8 + SWAP_PAIR_LEN) {
+            throw new Error("swap pair was not migrated")
+        }
+        if (pair.feeTier != 25 || !pair.tokenProgram.equals(TOKEN_PROGRAM_ID) || pair.dynamicFeeParameters.maxVolatilityAccumulator.toNumber() != 350000) {
+            throw new Error("swap pair fields changed by the migration")
+        }
+
+        // the pair may have DCA orders, farms and locked positions created
+        // before the migration, so it stays in use until they are recounted
+        if (pair.dependentAccountsCounted) {
+            throw new Error("dependent accounts of the migrated pair were counted")
+        }
+        await program.methods.recountDependentAccounts()
+            .accounts({
+                admin: admin.publicKey,
+                pair: SWAP_PAIR_V0,
+            }).rpc()
+        const recounted = await program.account.swapPair.fetch(SWAP_PAIR_V0)
+        if (!recounted.dependentAccountsCounted || recounted.dependentAccounts != 0) {
+            throw new Error("dependent accounts of the migrated pair were not recounted")
+        }
+    })
+
+    it("Multi Pool", async () => {
+        // three tokens with the mints in ascending order
+        const mints: PublicKey[] = []
+        for (let i = 0; i < 3; i++) {
+            mints.push(await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, TOKEN_PROGRAM_ID))
+        }
+        mints.sort((a, b) => Buffer.compare(a.toBuffer(), b.toBuffer()))
+
+        const lpMint = anchor.web3.Keypair.generate()
+        const [multiPool, _] = await PublicKey.findProgramAddress([Buffer.from("multi_pool"), lpMint.publicKey.toBuffer()], program.programId);
+        const [multiPoolPda, __] = await PublicKey.findProgramAddress([Buffer.from("pool"), multiPool.toBuffer()], program.programId);
+        await createMint(connection, admin, multiPoolPda, null, 2, lpMint, undefined, TOKEN_PROGRAM_ID)
+        const lpForAdmin = await createAccount(connection, admin, lpMint.publicKey, admin.publicKey, undefined, undefined, TOKEN_PROGRAM_ID);
+        const lpForUserA = await createAccount(connection, admin, lpMint.publicKey, userA.publicKey, undefined, undefined, TOKEN_PROGRAM_ID);
+
+        const tokensForPda: PublicKey[] = []
+        const tokensForUserA: PublicKey[] = []
+        for (const mint of mints) {
+            const tokenForPda = await createAccount(connection, admin, mint, admin.publicKey, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+            await setAuthority(connection, admin, tokenForPda, admin.publicKey, AuthorityType.AccountOwner, multiPoolPda, undefined, undefined, TOKEN_PROGRAM_ID)
+            await mintTo(connection, admin, mint, tokenForPda, admin, DEFAULT_TOKEN_A)
+            const tokenForUserA = await createAccount(connection, userA, mint, userA.publicKey, undefined, undefined, TOKEN_PROGRAM_ID);
+            await mintTo(connection, admin, mint, tokenForUserA, admin, DEFAULT_TOKEN_A)
+            tokensForPda.push(tokenForPda)
+            tokensForUserA.push(tokenForUserA)
+        }
+        const account = (pubkey: PublicKey, isWritable: boolean) => ({pubkey, isWritable, isSigner: false})
+
+        const fees = {
+            tradeFeeNumerator: new anchor.BN(4),
+            tradeFeeDenominator: new anchor.BN(10000),
+            ownerTradeFeeNumerator: new anchor.BN(1),
+            ownerTradeFeeDenominator: new anchor.BN(10000),
+            ownerWithdrawFeeNumerator: new anchor.BN(0),
+            ownerWithdrawFeeDenominator: new anchor.BN(0),
+            hostFeeNumerator: new anchor.BN(0),
+            hostFeeDenominator: new anchor.BN(0),
+        }
+        const tx = await program.methods.initializeMultiPool(fees, new anchor.BN(100))
+            .accounts({
+                payer: admin.publicKey,
+                pda: multiPoolPda,
+                pool: lpMint.publicKey,
+                multiPool: multiPool,
+                tokenPoolForInitializer: lpForAdmin,
+                tokenPoolForFeeReceiver: lpForAdmin,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).remainingAccounts(mints.flatMap((mint, i) => [account(mint, false), account(tokensForPda[i], true)])).rpc()
+        console.log("Initialize multi pool transaction signature", tx);
+
+        await program.methods.multiPoolSwap(new anchor.BN(10000), new anchor.BN(9900))
+            .accounts({
+                swapper: userA.publicKey,
+                pda: multiPoolPda,
+                pool: lpMint.publicKey,
+                multiPool: multiPool,
+                tokenSourceMint: mints[0],
+                tokenDestinationMint: mints[2],
+                tokenSourceForSwapper: tokensForUserA[0],
+                tokenDestinationForSwapper: tokensForUserA[2],
+                tokenSourceForPda: tokensForPda[0],
+                tokenDestinationForPda: tokensForPda[2],
+                poolFeeAccount: lpForAdmin,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts(tokensForPda.map((tokenForPda) => account(tokenForPda, false))).signers([userA]).rpc()
+
+        await program.methods.multiPoolDepositAll(new anchor.BN(POOL_TOKEN_AMOUNT), mints.map(() => new anchor.BN(DEFAULT_TOKEN_A)))
+            .accounts({
+                depositor: userA.publicKey,
+                pda: multiPoolPda,
+                multiPool: multiPool,
+                pool: lpMint.publicKey,
+                tokenPoolForDepositor: lpForUserA,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts(mints.flatMap((mint, i) => [account(mint, false), account(tokensForPda[i], true), account(tokensForUserA[i], true)])).signers([userA]).rpc()
+
+        await program.methods.multiPoolWithdrawSingle(new anchor.BN(POOL_TOKEN_AMOUNT / 10), new anchor.BN(0))
+            .accounts({
+                depositor: userA.publicKey,
+                pda: multiPoolPda,
+                multiPool: multiPool,
+                pool: lpMint.publicKey,
+                tokenDestinationMint: mints[1],
+                tokenDestinationForDepositor: tokensForUserA[1],
+                tokenDestinationForPda: tokensForPda[1],
+                tokenPoolForDepositor: lpForUserA,
+                poolFeeAccount: lpForAdmin,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts(tokensForPda.map((tokenForPda) => account(tokenForPda, true))).signers([userA]).rpc()
+
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        console.table(await Promise.all([...tokensForPda, lpForUserA, lpForAdmin].map(async (address, i) => ({
+            name: i < tokensForPda.length ? `Token ${i} for PDA` : i == tokensForPda.length ? "LP for UserA" : "LP for Admin",
+            address: address.toBase58(),
+            amount: await getTokenBalance(address),
+        }))))
+
+        // a token can't be swapped for itself
+        try {
+            await program.methods.multiPoolSwap(new anchor.BN(10000), new anchor.BN(0))
+                .accounts({
+                    swapper: userA.publicKey,
+                    pda: multiPoolPda,
+                    pool: lpMint.publicKey,
+                    multiPool: multiPool,
+                    tokenSourceMint: mints[0],
+                    tokenDestinationMint: mints[0],
+                    tokenSourceForSwapper: tokensForUserA[0],
+                    tokenDestinationForSwapper: tokensForUserA[0],
+                    tokenSourceForPda: tokensForPda[0],
+                    tokenDestinationForPda: tokensForPda[0],
+                    poolFeeAccount: lpForAdmin,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                }).remainingAccounts(tokensForPda.map((tokenForPda) => account(tokenForPda, false))).signers([userA]).rpc()
+        } catch (e) {
+            console.log("Multi pool swap of a token for itself rejected", e.toString())
+            if (!failedWith(e, "RepeatedMint")) {
+                throw e
+            }
+            return
+        }
+        throw new Error("multi pool swap of a token for itself succeeded")
+    })
+
+    it("Concentrated Liquidity", async () => {
+        const tickSpacing = 10
+        const [clPool, _] = await PublicKey.findProgramAddress([Buffer.from("cl_pool"), aMintPubkey.toBuffer(), bMintPubkey.toBuffer(), feeTierSeed(tickSpacing)], program.programId);
+        const [clPoolPda, __] = await PublicKey.findProgramAddress([Buffer.from("pool"), clPool.toBuffer()], program.programId);
+        const aForClPool = await createAccount(connection, admin, aMintPubkey, clPoolPda, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const bForClPool = await createAccount(connection, admin, bMintPubkey, clPoolPda, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+
+        const fees = {
+            tradeFeeNumerator: new anchor.BN(25),
+            tradeFeeDenominator: new anchor.BN(10000),
+            ownerTradeFeeNumerator: new anchor.BN(5),
+            ownerTradeFeeDenominator: new anchor.BN(10000),
+            ownerWithdrawFeeNumerator: new anchor.BN(0),
+            ownerWithdrawFeeDenominator: new anchor.BN(0),
+            hostFeeNumerator: new anchor.BN(20),
+            hostFeeDenominator: new anchor.BN(100),
+        }
+        // a price of 1, or tick 0
+        const tx = await program.methods.initializeClPool(fees, tickSpacing, new anchor.BN(1).shln(64))
+            .accounts({
+                payer: admin.publicKey,
+                tokenAMint: aMintPubkey,
+                tokenBMint: bMintPubkey,
+                clPool: clPool,
+                pda: clPoolPda,
+                tokenAForPda: aForClPool,
+                tokenBForPda: bForClPool,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize concentrated liquidity pool transaction signature", tx);
+
+        // tick arrays of 64 ticks, one every tick spacing
+        const tickArrays = new Map<number, PublicKey>()
+        for (const start of [-64 * tickSpacing, 0]) {
+            const [tickArray, _] = await PublicKey.findProgramAddress([Buffer.from("tick_array"), clPool.toBuffer(), i32Seed(start)], program.programId);
+            await program.methods.initializeTickArray(start)
+                .accounts({
+                    payer: admin.publicKey,
+                    clPool: clPool,
+                    tickArray: tickArray,
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                }).rpc()
+            tickArrays.set(start, tickArray)
+        }
+
+        const [tickLower, tickUpper] = [-600, 600]
+        const [position, ___] = await PublicKey.findProgramAddress([Buffer.from("position"), clPool.toBuffer(), userA.publicKey.toBuffer(), i32Seed(tickLower), i32Seed(tickUpper)], program.programId);
+        await program.methods.openPosition(tickLower, tickUpper)
+            .accounts({
+                owner: userA.publicKey,
+                clPool: clPool,
+                position: position,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).signers([userA]).rpc()
+
+        const modifyPositionAccounts = {
+            owner: userA.publicKey,
+            pda: clPoolPda,
+            clPool: clPool,
+            position: position,
+            tickArrayLower: tickArrays.get(-64 * tickSpacing),
+            tickArrayUpper: tickArrays.get(0),
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
+            tokenAForOwner: aAccountForUserA,
+            tokenBForOwner: bAccountForUserA,
+            tokenAForPda: aForClPool,
+            tokenBForPda: bForClPool,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }
+        const liquidity = new anchor.BN(10000000)
+        await program.methods.increaseLiquidity(liquidity, new anchor.BN(DEFAULT_TOKEN_A), new anchor.BN(DEFAULT_TOKEN_B))
+            .accounts(modifyPositionAccounts).signers([userA]).rpc()
+
+        // sell token A, crossing down from the tick array holding the current tick
+        await program.methods.clSwap(new anchor.BN(100000), new anchor.BN(90000), new anchor.BN(0))
+            .accounts({
+                swapper: userB.publicKey,
+                pda: clPoolPda,
+                clPool: clPool,
+                tokenSourceMint: aMintPubkey,
+                tokenDestinationMint: bMintPubkey,
+                tokenSourceForSwapper: aAccountForUserB,
+                tokenDestinationForSwapper: bAccountForUserB,
+                tokenSourceForPda: aForClPool,
+                tokenDestinationForPda: bForClPool,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts([0, -64 * tickSpacing].map((start) => ({pubkey: tickArrays.get(start), isWritable: true, isSigner: false}))).signers([userB]).rpc()
+
+        await program.methods.collectFees().accounts(modifyPositionAccounts).signers([userA]).rpc()
+        await program.methods.decreaseLiquidity(liquidity, new anchor.BN(0), new anchor.BN(0))
+            .accounts(modifyPositionAccounts).signers([userA]).rpc()
+        await program.methods.closePosition()
+            .accounts({
+                owner: userA.publicKey,
+                position: position,
+            }).signers([userA]).rpc()
+
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        const pool = await program.account.clPool.fetch(clPool)
+        console.table([
+            {name: "A for PDA", address: aForClPool.toBase58(), amount: await getTokenBalance(aForClPool)},
+            {name: "B for PDA", address: bForClPool.toBase58(), amount: await getTokenBalance(bForClPool)},
+            {name: "Protocol fee A", address: clPool.toBase58(), amount: pool.protocolFeeTokenAAmount.toString()},
+        ])
+    })
+
     it("Flow", async () => {
        try {
            await depositAll("UserA", POOL_TOKEN_AMOUNT, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
+           await depositAllByTokens("UserA", 10000, 20000, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
            await depositSingle("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA)
+           await depositSingleExactOut("UserA", 10000, userA, poolAccountForUserA, aAccountForUserA)
            await withdrawAll("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
            await withdrawSingle("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA)
-           await swap(2000000, userB,aAccountForUserB, bAccountForUserB)
+           await withdrawSingleExactIn("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA)
+           await zapIn("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
+           await zapOut("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
+           await swap(2000000, userB,aAccountForUserB, bAccountForUserB, {host: hostForUserA, hostFeeAccount: poolAccountForUserA})
            await swap(2000000, userB,aAccountForUserB, bAccountForUserB)
            await swap(2000000, userB,aAccountForUserB, bAccountForUserB)
            await swap(2000000, userB,aAccountForUserB, bAccountForUserB)
@@ -172,8 +581,931 @@ describe("anchor-liquidity-pool", () => {
        }
     })
 
+    it("Underlying Token Owner Fee", async () => {
+        const pair = await createPair({underlyingToken: {}})
+        const aForUserB = await createPairAccount(pair.aMint, userB, pair.tokenProgram)
+        const bForUserB = await createPairAccount(pair.bMint, userB, pair.tokenProgram)
+        await mintTo(connection, admin, pair.aMint, aForUserB, admin, DEFAULT_TOKEN_A, undefined, undefined, pair.tokenProgram)
+        let tx = await pairSwap(pair, 100000, userB, aForUserB, bForUserB)
+        console.log("Swap transaction signature", tx);
+        // the owner fee of 5 / 10000 stays in the pool as token A
+        const accrued = (await program.account.swapPair.fetch(pair.swapPair)).protocolFeeTokenAAmount.toNumber()
+        if (accrued != 50) {
+            throw new Error(`owner fee of ${accrued} token A was accrued`)
+        }
+
+        const aForAdmin = await createPairAccount(pair.aMint, admin, pair.tokenProgram)
+        const bForAdmin = await createPairAccount(pair.bMint, admin, pair.tokenProgram)
+        tx = await program.methods.collectProtocolFees()
+            .accounts({
+                admin: admin.publicKey,
+                pda: pair.pda,
+                pair: pair.swapPair,
+                tokenAMint: pair.aMint,
+                tokenBMint: pair.bMint,
+                tokenAForPda: pair.aForPda,
+                tokenBForPda: pair.bForPda,
+                tokenAForAdmin: aForAdmin,
+                tokenBForAdmin: bForAdmin,
+                tokenProgram: pair.tokenProgram,
+            }).rpc()
+        console.log("Collect protocol fees transaction signature", tx);
+        const collected = await getTokenBalance(aForAdmin)
+        const pairAfter = await program.account.swapPair.fetch(pair.swapPair)
+        if (collected != accrued || !pairAfter.protocolFeeTokenAAmount.isZero() || !pairAfter.protocolFeeTokenBAmount.isZero()) {
+            throw new Error(`${collected} token A was collected`)
+        }
+
+        // the host fee is paid in LP tokens, which this mode doesn't mint
+        const [host, _] = await PublicKey.findProgramAddress([Buffer.from("host"), pair.swapPair.toBuffer(), userA.publicKey.toBuffer()], program.programId);
+        try {
+            await program.methods.registerHost(null)
+                .accounts({
+                    admin: admin.publicKey,
+                    pair: pair.swapPair,
+                    wallet: userA.publicKey,
+                    host: host,
+                    systemProgram: anchor.web3.SystemProgram.programId,
+                }).rpc()
+        } catch (e) {
+            console.log("Host rejected on an underlying token pair", e.toString())
+            if (!failedWith(e, "InvalidHost")) {
+                throw e
+            }
+            return
+        }
+        throw new Error("host was registered on an underlying token pair")
+    })
+
+    it("Token-2022 Transfer Fee", async () => {
+        // 1% of every transfer of the fee mint is withheld
+        const transferFee = (amount: number) => Math.ceil(amount / 100)
+        const feeMint = await createTransferFeeMint(100, 1000000)
+        const otherMint = await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, TOKEN_2022_PROGRAM_ID)
+        const pair = await createPair({poolToken: {}}, TOKEN_2022_PROGRAM_ID, [feeMint, otherMint])
+        const feeMintIsA = pair.aMint.equals(feeMint)
+        const feeForPda = feeMintIsA ? pair.aForPda : pair.bForPda
+        const otherForPda = feeMintIsA ? pair.bForPda : pair.aForPda
+        const feeForUserB = await createPairAccount(feeMint, userB, TOKEN_2022_PROGRAM_ID)
+        const otherForUserB = await createPairAccount(otherMint, userB, TOKEN_2022_PROGRAM_ID)
+        const poolForUserB = await createPairAccount(pair.pool, userB, TOKEN_2022_PROGRAM_ID)
+        await mintTo(connection, admin, feeMint, feeForUserB, admin, DEFAULT_TOKEN_A, undefined, undefined, TOKEN_2022_PROGRAM_ID)
+
+        // the pool is credited with the amount left after the transfer fee
+        let feeForPdaBefore = await getTokenBalance(feeForPda)
+        let tx = await program.methods.depositSingle(new anchor.BN(100000), new anchor.BN(1))
+            .accounts({
+                depositor: userB.publicKey,
+                pair: pair.swapPair,
+                pool: pair.pool,
+                poolFeeAccount: pair.poolForAdmin,
+                pda: pair.pda,
+                tokenAMint: pair.aMint,
+                tokenBMint: pair.bMint,
+                tokenAForPda: pair.aForPda,
+                tokenBForPda: pair.bForPda,
+                tokenSourceForDepositor: feeForUserB,
+                tokenPoolForDepositor: poolForUserB,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            }).signers([userB]).rpc()
+        console.log("Deposit single transaction signature", tx);
+        let received = await getTokenBalance(feeForPda) - feeForPdaBefore
+        if (received != 100000 - transferFee(100000) || await getTokenBalance(poolForUserB) == 0) {
+            throw new Error(`deposit of 100000 credited the pool with ${received}`)
+        }
+
+        // swapping the fee mint in, the pool receives the amount less the transfer fee
+        const feeForUserBBefore = await getTokenBalance(feeForUserB)
+        feeForPdaBefore = await getTokenBalance(feeForPda)
+        let otherForPdaBefore = await getTokenBalance(otherForPda)
+        tx = await pairSwap(pair, 10000, userB, feeForUserB, otherForUserB)
+        console.log("Swap transaction signature", tx);
+        const sent = feeForUserBBefore - await getTokenBalance(feeForUserB)
+        received = await getTokenBalance(feeForPda) - feeForPdaBefore
+        const otherReceived = await getTokenBalance(otherForUserB)
+        if (sent == 0 || received != sent - transferFee(sent) || otherReceived != otherForPdaBefore - await getTokenBalance(otherForPda)) {
+            throw new Error(`swap sent ${sent} and credited the pool with ${received}`)
+        }
+
+        // swapping the fee mint out, the swapper receives the amount less the transfer fee
+        const feeForUserBBeforeOut = await getTokenBalance(feeForUserB)
+        feeForPdaBefore = await getTokenBalance(feeForPda)
+        tx = await pairSwap(pair, otherReceived, userB, otherForUserB, feeForUserB)
+        console.log("Swap transaction signature", tx);
+        const paid = feeForPdaBefore - await getTokenBalance(feeForPda)
+        received = await getTokenBalance(feeForUserB) - feeForUserBBeforeOut
+        if (paid == 0 || received != paid - transferFee(paid)) {
+            throw new Error(`swap paid ${paid} out of the pool and the swapper received ${received}`)
+        }
+    })
+
+    it("Limit Orders", async () => {
+        const [_orderBook, _] = await PublicKey.findProgramAddress([Buffer.from("order_book"), swapPair.toBuffer()], program.programId);
+        const tokenAEscrow = await createAccount(connection, admin, aMintPubkey, admin.publicKey, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const tokenBEscrow = await createAccount(connection, admin, bMintPubkey, admin.publicKey, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        await setAuthority(connection, admin, tokenAEscrow, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, TOKEN_PROGRAM_ID)
+        await setAuthority(connection, admin, tokenBEscrow, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, TOKEN_PROGRAM_ID)
+        let tx = await program.methods.initializeOrderBook()
+            .accounts({
+                admin: admin.publicKey,
+                pda: pda,
+                pair: swapPair,
+                orderBook: _orderBook,
+                tokenAEscrow: tokenAEscrow,
+                tokenBEscrow: tokenBEscrow,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize order book transaction signature", tx);
+        orderBook = {orderBook: _orderBook, tokenAEscrow, tokenBEscrow}
+
+        // sell B at a price the pool already exceeds, filled by the next swap from A to B
+        await placeOrder({btoA: {}}, 1000, 1000, userA, bAccountForUserA)
+        await swap(100000, userB, aAccountForUserB, bAccountForUserB)
+        const order = (await program.account.orderBook.fetch(orderBook.orderBook)).orders[0]
+        console.table([{id: order.id.toString(), amountInRemaining: order.amountInRemaining.toString(), amountOutFilled: order.amountOutFilled.toString()}])
+        if (order.amountInRemaining.toNumber() != 0 || order.amountOutFilled.toNumber() < 1000) {
+            throw new Error("limit order was not filled")
+        }
+
+        const aBefore = await getTokenBalance(aAccountForUserA)
+        tx = await program.methods.cancelOrder(order.id)
+            .accounts({
+                owner: userA.publicKey,
+                pda: pda,
+                pair: swapPair,
+                orderBook: orderBook.orderBook,
+                tokenAMint: aMintPubkey,
+                tokenBMint: bMintPubkey,
+                tokenAEscrow: orderBook.tokenAEscrow,
+                tokenBEscrow: orderBook.tokenBEscrow,
+                tokenAForOwner: aAccountForUserA,
+                tokenBForOwner: bAccountForUserA,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).signers([userA]).rpc()
+        console.log("Cancel order transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        if (await getTokenBalance(aAccountForUserA) - aBefore != order.amountOutFilled.toNumber()) {
+            throw new Error("order proceeds were not returned")
+        }
+
+        // the internal swap of a zap from A fills the orders selling B too
+        await placeOrder({btoA: {}}, 1000, 1000, userA, bAccountForUserA)
+        await zapIn("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
+        const orders = (await program.account.orderBook.fetch(orderBook.orderBook)).orders
+        const zapOrder = orders[orders.length - 1]
+        if (zapOrder.amountInRemaining.toNumber() != 0) {
+            throw new Error("limit order was not filled by the zap")
+        }
+    })
+
+    it("TWAMM", async () => {
+        const [_twamm, _] = await PublicKey.findProgramAddress([Buffer.from("twamm"), swapPair.toBuffer()], program.programId);
+        const tokenAEscrow = await createAccount(connection, admin, aMintPubkey, admin.publicKey, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const tokenBEscrow = await createAccount(connection, admin, bMintPubkey, admin.publicKey, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        await setAuthority(connection, admin, tokenAEscrow, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, TOKEN_PROGRAM_ID)
+        await setAuthority(connection, admin, tokenBEscrow, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, TOKEN_PROGRAM_ID)
+        let tx = await program.methods.initializeTwamm()
+            .accounts({
+                admin: admin.publicKey,
+                pda: pda,
+                pair: swapPair,
+                twamm: _twamm,
+                tokenAMint: aMintPubkey,
+                tokenBMint: bMintPubkey,
+                tokenAEscrow: tokenAEscrow,
+                tokenBEscrow: tokenBEscrow,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize TWAMM transaction signature", tx);
+        twamm = {twamm: _twamm, tokenAEscrow, tokenBEscrow}
+
+        // sell A over a minute, partly executed by the crank and the next swap
+        tx = await program.methods.placeLongTermOrder({atoB: {}}, new anchor.BN(60000), new anchor.BN(60))
+            .accounts({
+                owner: userA.publicKey,
+                virtualOrders: virtualOrdersAccounts(),
+                tokenSourceForOwner: aAccountForUserA,
+            }).signers([userA]).rpc()
+        console.log("Place long term order transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 3000));
+        tx = await program.methods.executeVirtualOrders()
+            .accounts(virtualOrdersAccounts()).rpc()
+        console.log("Execute virtual orders transaction signature", tx);
+        await swap(100000, userB, aAccountForUserB, bAccountForUserB)
+        const order = (await program.account.twamm.fetch(twamm.twamm)).orders[0]
+        console.table([{id: order.id.toString(), sellRate: order.sellRate.toString(), amountOut: order.amountOut.toString()}])
+        if (order.amountOut.toNumber() == 0) {
+            throw new Error("long term order was not executed")
+        }
+
+        // deposits execute the virtual orders before reading the reserves
+        const executedBefore = (await program.account.twamm.fetch(twamm.twamm)).lastExecutedTs.toNumber()
+        await new Promise((resolve) => setTimeout(resolve, 2000));
+        await depositSingle("UserA", 100000, userA, poolAccountForUserA, aAccountForUserA)
+        if ((await program.account.twamm.fetch(twamm.twamm)).lastExecutedTs.toNumber() <= executedBefore) {
+            throw new Error("deposit did not execute the virtual orders")
+        }
+
+        const bBefore = await getTokenBalance(bAccountForUserA)
+        tx = await program.methods.cancelLongTermOrder(order.id)
+            .accounts({
+                owner: userA.publicKey,
+                virtualOrders: virtualOrdersAccounts(),
+                tokenAForOwner: aAccountForUserA,
+                tokenBForOwner: bAccountForUserA,
+            }).signers([userA]).rpc()
+        console.log("Cancel long term order transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        if (await getTokenBalance(bAccountForUserA) - bBefore < order.amountOut.toNumber()) {
+            throw new Error("long term order proceeds were not returned")
+        }
+    })
+
+    it("DCA", async () => {
+        const id = new anchor.BN(0)
+        const [dcaOrder, _] = await PublicKey.findProgramAddress([Buffer.from("dca_order"), swapPair.toBuffer(), userA.publicKey.toBuffer(), id.toArrayLike(Buffer, "le", 8)], program.programId);
+        const tokenSourceEscrow = await createAccount(connection, admin, aMintPubkey, dcaOrder, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const tokenDestinationEscrow = await createAccount(connection, admin, bMintPubkey, dcaOrder, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        let tx = await program.methods.openDca(id, new anchor.BN(3000), new anchor.BN(1000), new anchor.BN(3600), new anchor.BN(1), new anchor.BN(10))
+            .accounts({
+                owner: userA.publicKey,
+                pair: swapPair,
+                dcaOrder: dcaOrder,
+                tokenSourceMint: aMintPubkey,
+                tokenDestinationMint: bMintPubkey,
+                tokenSourceForOwner: aAccountForUserA,
+                tokenSourceEscrow: tokenSourceEscrow,
+                tokenDestinationEscrow: tokenDestinationEscrow,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).signers([userA]).rpc()
+        console.log("Open DCA transaction signature", tx);
+
+        // the first cycle is due at once, the next one only after the interval
+        const crankDca = () => program.methods.crankDca()
+            .accounts({
+                cranker: userB.publicKey,
+                dcaOrder: dcaOrder,
+                pda: pda,
+                pool: poolMintPubkey,
+                pair: swapPair,
+                tokenSourceMint: aMintPubkey,
+                tokenDestinationMint: bMintPubkey,
+                tokenSourceEscrow: tokenSourceEscrow,
+                tokenDestinationEscrow: tokenDestinationEscrow,
+                tokenSourceForPda: aAccountForPDA,
+                tokenDestinationForPda: bAccountForPDA,
+                poolFeeAccount: poolAccountForAdmin,
+                tokenSourceForCranker: aAccountForUserB,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts(swapRemainingAccounts()).signers([userB]).rpc()
+        tx = await crankDca()
+        console.log("Crank DCA transaction signature", tx);
+        const order = await program.account.dcaOrder.fetch(dcaOrder)
+        console.table([{amountSwapped: order.amountSwapped.toString(), amountBought: order.amountBought.toString(), nextCycleTs: order.nextCycleTs.toString()}])
+        if (order.amountSwapped.toNumber() != 1000 || order.amountBought.toNumber() == 0) {
+            throw new Error("DCA cycle was not swapped")
+        }
+        try {
+            await crankDca()
+        } catch (e) {
+            console.log("Second crank rejected", e.toString())
+            const bBefore = await getTokenBalance(bAccountForUserA)
+            tx = await program.methods.withdrawDca()
+                .accounts({
+                    owner: userA.publicKey,
+                    pair: swapPair,
+                    dcaOrder: dcaOrder,
+                    tokenDestinationMint: bMintPubkey,
+                    tokenDestinationEscrow: tokenDestinationEscrow,
+                    tokenDestinationForOwner: bAccountForUserA,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                }).signers([userA]).rpc()
+            console.log("Withdraw DCA transaction signature", tx);
+            tx = await program.methods.closeDca()
+                .accounts({
+                    owner: userA.publicKey,
+                    pair: swapPair,
+                    dcaOrder: dcaOrder,
+                    tokenSourceMint: aMintPubkey,
+                    tokenDestinationMint: bMintPubkey,
+                    tokenSourceEscrow: tokenSourceEscrow,
+                    tokenDestinationEscrow: tokenDestinationEscrow,
+                    tokenSourceForOwner: aAccountForUserA,
+                    tokenDestinationForOwner: bAccountForUserA,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                }).signers([userA]).rpc()
+            console.log("Close DCA transaction signature", tx);
+            await new Promise((resolve) => setTimeout(resolve, 500));
+            if (await getTokenBalance(bAccountForUserA) - bBefore != order.amountBought.toNumber()) {
+                throw new Error("DCA proceeds were not withdrawn")
+            }
+            if (await connection.getAccountInfo(dcaOrder) != null) {
+                throw new Error("DCA order was not closed")
+            }
+            return
+        }
+        throw new Error("DCA cycle was cranked before it was due")
+    })
+
+    it("Farming", async () => {
+        const [_farm, _] = await PublicKey.findProgramAddress([Buffer.from("farm"), swapPair.toBuffer()], program.programId);
+        const stakeVault = await createAccount(connection, admin, poolMintPubkey, _farm, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const rewardMint = await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, TOKEN_PROGRAM_ID);
+        const rewardVault = await createAccount(connection, admin, rewardMint, _farm, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        await mintTo(connection, admin, rewardMint, rewardVault, admin.publicKey, 1000000)
+        let tx = await program.methods.initializeFarm()
+            .accounts({
+                admin: admin.publicKey,
+                pair: swapPair,
+                farm: _farm,
+                pool: poolMintPubkey,
+                stakeVault: stakeVault,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize farm transaction signature", tx);
+        tx = await program.methods.addFarmReward(new anchor.BN(100))
+            .accounts({
+                admin: admin.publicKey,
+                farm: _farm,
+                rewardMint: rewardMint,
+                rewardVault: rewardVault,
+            }).rpc()
+        console.log("Add farm reward transaction signature", tx);
+        farm = {farm: _farm, stakeVault, rewardMint, rewardVault}
+
+        const [farmStake, __] = await PublicKey.findProgramAddress([Buffer.from("farm_stake"), _farm.toBuffer(), userA.publicKey.toBuffer()], program.programId);
+        tx = await program.methods.initializeFarmStake()
+            .accounts({
+                owner: userA.publicKey,
+                farm: _farm,
+                farmStake: farmStake,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).signers([userA]).rpc()
+        console.log("Initialize farm stake transaction signature", tx);
+        const modifyStakeAccounts = {
+            owner: userA.publicKey,
+            farm: _farm,
+            farmStake: farmStake,
+            pool: poolMintPubkey,
+            stakeVault: stakeVault,
+            tokenPoolForOwner: poolAccountForUserA,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }
+        tx = await program.methods.stake(new anchor.BN(1000))
+            .accounts(modifyStakeAccounts).signers([userA]).rpc()
+        console.log("Stake transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 3000));
+
+        // unstaking stays possible while the farm is paused
+        tx = await program.methods.setFarmPaused(true)
+            .accounts({admin: admin.publicKey, farm: _farm}).rpc()
+        console.log("Pause farm transaction signature", tx);
+        tx = await program.methods.unstake(new anchor.BN(1000))
+            .accounts(modifyStakeAccounts).signers([userA]).rpc()
+        console.log("Unstake transaction signature", tx);
+
+        const rewardForUserA = await createAccount(connection, userA, rewardMint, userA.publicKey, undefined, undefined, TOKEN_PROGRAM_ID);
+        tx = await program.methods.claimRewards()
+            .accounts({
+                owner: userA.publicKey,
+                farm: _farm,
+                farmStake: farmStake,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            }).remainingAccounts([
+                {pubkey: rewardMint, isWritable: false, isSigner: false},
+                {pubkey: rewardVault, isWritable: true, isSigner: false},
+                {pubkey: rewardForUserA, isWritable: true, isSigner: false},
+            ]).signers([userA]).rpc()
+        console.log("Claim rewards transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 500));
+        const rewards = await getTokenBalance(rewardForUserA)
+        console.table([{name: "Rewards for UserA", address: rewardForUserA.toBase58(), amount: rewards}])
+        if (rewards == 0 || await getTokenBalance(stakeVault) != 0) {
+            throw new Error("rewards were not claimed")
+        }
+    })
+
+    it("LP Lock", async () => {
+        const id = new anchor.BN(0)
+        const [lockedPosition, _] = await PublicKey.findProgramAddress([Buffer.from("locked_position"), swapPair.toBuffer(), userA.publicKey.toBuffer(), id.toArrayLike(Buffer, "le", 8)], program.programId);
+        const escrow = await createAccount(connection, admin, poolMintPubkey, lockedPosition, anchor.web3.Keypair.generate(), undefined, TOKEN_PROGRAM_ID);
+        const receiptMint = await createMint(connection, admin, lockedPosition, null, 0, undefined, undefined, TOKEN_PROGRAM_ID);
+        const receiptForUserA = await createAccount(connection, userA, receiptMint, userA.publicKey, undefined, undefined, TOKEN_PROGRAM_ID);
+        const unlockTs = Math.floor(Date.now() / 1000) + 3600
+        let tx = await program.methods.lockLp(id, new anchor.BN(1000), new anchor.BN(unlockTs))
+            .accounts({
+                owner: userA.publicKey,
+                pair: swapPair,
+                lockedPosition: lockedPosition,
+                pool: poolMintPubkey,
+                escrow: escrow,
+                tokenPoolForOwner: poolAccountForUserA,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).remainingAccounts([
+                {pubkey: receiptMint, isWritable: true, isSigner: false},
+                {pubkey: receiptForUserA, isWritable: true, isSigner: false},
+            ]).signers([userA]).rpc()
+        console.log("Lock LP transaction signature", tx);
+        const positions = await findLockedPositions(swapPair)
+        console.table(positions.map(({publicKey, account}) => ({address: publicKey.toBase58(), amount: account.amount.toString(), unlockTs: account.unlockTs.toString()})))
+        if (await getTokenBalance(receiptForUserA) != 1 || !positions.some(({publicKey}) => publicKey.equals(lockedPosition))) {
+            throw new Error("LP tokens were not locked")
+        }
+
+        // the lock boosts the farm stake with up to twice its LP tokens
+        const [farmStake, __] = await PublicKey.findProgramAddress([Buffer.from("farm_stake"), farm.farm.toBuffer(), userA.publicKey.toBuffer()], program.programId);
+        tx = await program.methods.setFarmPaused(false)
+            .accounts({admin: admin.publicKey, farm: farm.farm}).rpc()
+        console.log("Resume farm transaction signature", tx);
+        const receiptAccounts = [
+            {pubkey: receiptMint, isWritable: true, isSigner: false},
+            {pubkey: receiptForUserA, isWritable: true, isSigner: false},
+        ]
+        tx = await program.methods.boostFarmStake()
+            .accounts({
+                holder: userA.publicKey,
+                farm: farm.farm,
+                farmStake: farmStake,
+                lockedPosition: lockedPosition,
+            }).remainingAccounts(receiptAccounts).signers([userA]).rpc()
+        console.log("Boost farm stake transaction signature", tx);
+        const stake = await program.account.farmStake.fetch(farmStake)
+        console.table([{lockedShares: stake.lockedShares.toString()}])
+        if (stake.lockedShares.toNumber() <= 1000) {
+            throw new Error("farm stake was not boosted")
+        }
+
+        try {
+            await program.methods.unlockLp()
+                .accounts({
+                    holder: userA.publicKey,
+                    owner: userA.publicKey,
+                    pair: swapPair,
+                    lockedPosition: lockedPosition,
+                    pool: poolMintPubkey,
+                    escrow: escrow,
+                    tokenPoolForHolder: poolAccountForUserA,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                }).remainingAccounts([
+                    ...receiptAccounts,
+                    {pubkey: farm.farm, isWritable: true, isSigner: false},
+                    {pubkey: farmStake, isWritable: true, isSigner: false},
+                ]).signers([userA]).rpc()
+        } catch (e) {
+            console.log("Early unlock rejected", e.toString())
+            return
+        }
+        throw new Error("LP tokens were unlocked before the unlock time")
+    })
+
+    it("Circuit Breaker", async () => {
+        const setCircuitBreaker = (maxPriceImpactBps: number) => program.methods.setCircuitBreaker({maxPriceImpactBps, maxWindowMoveBps: 0, windowSlots: 0})
+            .accounts({admin: admin.publicKey, pair: swapPair}).rpc()
+        let tx = await setCircuitBreaker(1)
+        console.log("Set circuit breaker transaction signature", tx);
+        let tripped = false
+        try {
+            await swap(100000, userB, aAccountForUserB, bAccountForUserB)
+        } catch (e) {
+            console.log("Swap rejected by the circuit breaker", e.toString())
+            if (!failedWith(e, "CircuitBreakerTripped")) {
+                throw e
+            }
+            tripped = true
+        }
+        if (!tripped) {
+            throw new Error("swap moving the price beyond the limit succeeded")
+        }
+
+        // withdrawals stay allowed while the circuit breaker is tripped
+        const poolAmount = await getTokenBalance(poolAccountForUserA)
+        await withdrawAll("userA", 100, userA, poolAccountForUserA, aAccountForUserA, bAccountForUserA)
+        if (await getTokenBalance(poolAccountForUserA) != poolAmount - 100) {
+            throw new Error("withdrawal was rejected by the circuit breaker")
+        }
+        tx = await setCircuitBreaker(0)
+        console.log("Reset circuit breaker transaction signature", tx);
+    })
+
+    it("Oracle Guard", async () => {
+        const id = new anchor.BN(0)
+        const [_priceFeed, _] = await PublicKey.findProgramAddress([Buffer.from("price_feed"), admin.publicKey.toBuffer(), id.toArrayLike(Buffer, "le", 8)], program.programId);
+        let tx = await program.methods.initializePriceFeed(id)
+            .accounts({
+                authority: admin.publicKey,
+                priceFeed: _priceFeed,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize price feed transaction signature", tx);
+        // publish the spot price of the pool, as a 64.64 fixed point number
+        const pair = await program.account.swapPair.fetch(swapPair)
+        const aAmount = new anchor.BN(await getTokenBalance(aAccountForPDA)).sub(pair.protocolFeeTokenAAmount)
+        const bAmount = new anchor.BN(await getTokenBalance(bAccountForPDA)).sub(pair.protocolFeeTokenBAmount)
+        const price = bAmount.shln(64).div(aAmount)
+        const updatePriceFeed = (price: anchor.BN) => program.methods.updatePriceFeed(price)
+            .accounts({authority: admin.publicKey, priceFeed: _priceFeed}).rpc()
+        tx = await updatePriceFeed(price)
+        console.log("Update price feed transaction signature", tx);
+        tx = await program.methods.setOracleGuard({priceFeed: _priceFeed, maxDeviationBps: 500, maxStaleness: new anchor.BN(60)})
+            .accounts({admin: admin.publicKey, pair: swapPair}).rpc()
+        console.log("Set oracle guard transaction signature", tx);
+        priceFeed = _priceFeed
+        await swap(100, userB, aAccountForUserB, bAccountForUserB)
+
+        // the pool is now far from the oracle price
+        tx = await updatePriceFeed(price.muln(2))
+        console.log("Update price feed transaction signature", tx);
+        try {
+            await swap(100, userB, aAccountForUserB, bAccountForUserB)
+        } catch (e) {
+            console.log("Swap rejected by the oracle guard", e.toString())
+            if (!failedWith(e, "OraclePriceDeviation")) {
+                throw e
+            }
+            tx = await program.methods.setOracleGuard(null)
+                .accounts({admin: admin.publicKey, pair: swapPair}).rpc()
+            console.log("Remove oracle guard transaction signature", tx);
+            priceFeed = undefined
+            return
+        }
+        throw new Error("swap away from the oracle price succeeded")
+    })
+
+    it("PMM", async () => {
+        const id = new anchor.BN(1)
+        const [pmmPriceFeed, _] = await PublicKey.findProgramAddress([Buffer.from("price_feed"), admin.publicKey.toBuffer(), id.toArrayLike(Buffer, "le", 8)], program.programId);
+        let tx = await program.methods.initializePriceFeed(id)
+            .accounts({
+                authority: admin.publicKey,
+                priceFeed: pmmPriceFeed,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        console.log("Initialize price feed transaction signature", tx);
+        tx = await program.methods.updatePriceFeed(new anchor.BN(1).shln(64))
+            .accounts({authority: admin.publicKey, priceFeed: pmmPriceFeed}).rpc()
+        console.log("Update price feed transaction signature", tx);
+        // the limit orders and virtual orders of the pair fill on the constant product
+        try {
+            await program.methods.setPmm({priceFeed: pmmPriceFeed, k: new anchor.BN(100), maxStaleness: new anchor.BN(60)})
+                .accounts({admin: admin.publicKey, pair: swapPair}).rpc()
+        } catch (e) {
+            console.log("PMM rejected on a pair with an order book", e.toString())
+            if (!failedWith(e, "UnsupportedCurveOperation")) {
+                throw e
+            }
+            return
+        }
+        throw new Error("PMM was set on a pair with an order book")
+    })
+
+    it("PMM Swap", async () => {
+        const id = new anchor.BN(1)
+        const [pmmPriceFeed, _] = await PublicKey.findProgramAddress([Buffer.from("price_feed"), admin.publicKey.toBuffer(), id.toArrayLike(Buffer, "le", 8)], program.programId);
+        const pmmRemainingAccounts = [{pubkey: pmmPriceFeed, isWritable: false, isSigner: false}]
+        const setPmm = (pair: Pair, maxStaleness: number) => program.methods.setPmm({priceFeed: pmmPriceFeed, k: new anchor.BN(100), maxStaleness: new anchor.BN(maxStaleness)})
+            .accounts({admin: admin.publicKey, pair: pair.swapPair}).rpc()
+        // swaps 100000 token A of UserB, returning the token B received
+        const swapReceived = async (pair: Pair, remainingAccounts: anchor.web3.AccountMeta[]) => {
+            const aForUserB = await createPairAccount(pair.aMint, userB, pair.tokenProgram)
+            const bForUserB = await createPairAccount(pair.bMint, userB, pair.tokenProgram)
+            await mintTo(connection, admin, pair.aMint, aForUserB, admin, 100000, undefined, undefined, pair.tokenProgram)
+            const tx = await pairSwap(pair, 100000, userB, aForUserB, bForUserB, remainingAccounts)
+            console.log("Swap transaction signature", tx);
+            return await getTokenBalance(bForUserB)
+        }
+        // the guide price is the price of the new pools
+        let tx = await program.methods.updatePriceFeed(new anchor.BN(1).shln(64))
+            .accounts({authority: admin.publicKey, priceFeed: pmmPriceFeed}).rpc()
+        console.log("Update price feed transaction signature", tx);
+        const constantProductReceived = await swapReceived(await createPair({poolToken: {}}), [])
+
+        const pmmPair = await createPair({poolToken: {}})
+        tx = await setPmm(pmmPair, 60)
+        console.log("Set PMM transaction signature", tx);
+        const pmmReceived = await swapReceived(pmmPair, pmmRemainingAccounts)
+        if (pmmReceived <= constantProductReceived) {
+            throw new Error(`PMM swap received ${pmmReceived} token B, no more than the ${constantProductReceived} of the constant product`)
+        }
+
+        // the PMM pair falls back to the constant product once the guide price is stale
+        const stalePair = await createPair({poolToken: {}})
+        tx = await setPmm(stalePair, 0)
+        console.log("Set PMM transaction signature", tx);
+        await new Promise((resolve) => setTimeout(resolve, 2000));
+        const staleReceived = await swapReceived(stalePair, pmmRemainingAccounts)
+        if (staleReceived != constantProductReceived) {
+            throw new Error(`stale PMM swap received ${staleReceived} token B instead of the ${constantProductReceived} of the constant product`)
+        }
+
+        // single-sided liquidity is priced off the PMM curve
+        const aForUserB = await createPairAccount(pmmPair.aMint, userB, pmmPair.tokenProgram)
+        const poolForUserB = await createPairAccount(pmmPair.pool, userB, pmmPair.tokenProgram)
+        await mintTo(connection, admin, pmmPair.aMint, aForUserB, admin, 100000, undefined, undefined, pmmPair.tokenProgram)
+        try {
+            await program.methods.depositSingle(new anchor.BN(100000), new anchor.BN(0))
+                .accounts({
+                    depositor: userB.publicKey,
+                    pair: pmmPair.swapPair,
+                    pool: pmmPair.pool,
+                    poolFeeAccount: pmmPair.poolForAdmin,
+                    pda: pmmPair.pda,
+                    tokenAMint: pmmPair.aMint,
+                    tokenBMint: pmmPair.bMint,
+                    tokenAForPda: pmmPair.aForPda,
+                    tokenBForPda: pmmPair.bForPda,
+                    tokenSourceForDepositor: aForUserB,
+                    tokenPoolForDepositor: poolForUserB,
+                    tokenProgram: pmmPair.tokenProgram,
+                }).signers([userB]).rpc()
+        } catch (e) {
+            console.log("Single-sided deposit rejected on a PMM pair", e.toString())
+            if (!failedWith(e, "UnsupportedCurveOperation")) {
+                throw e
+            }
+            return
+        }
+        throw new Error("single-sided deposit succeeded on a PMM pair")
+    })
+
+    it("Close Pool", async () => {
+        const closePool = (pair: Pair) => program.methods.closePool()
+            .accounts({
+                admin: admin.publicKey,
+                pda: pair.pda,
+                pair: pair.swapPair,
+                pool: pair.pool,
+                tokenAForPda: pair.aForPda,
+                tokenBForPda: pair.bForPda,
+                destination: admin.publicKey,
+                tokenProgram: pair.tokenProgram,
+            }).rpc()
+        const expectRejected = async (pair: Pair, expectedError: string) => {
+            try {
+                await closePool(pair)
+            } catch (e) {
+                console.log(`Close pool rejected with ${expectedError}`, e.toString())
+                if (!failedWith(e, expectedError)) {
+                    throw e
+                }
+                return
+            }
+            throw new Error(`pool was closed instead of failing with ${expectedError}`)
+        }
+
+        const pair = await createPair({poolToken: {}})
+        await expectRejected(pair, "InvalidSupply")
+        await drainPair(pair)
+        const tx = await closePool(pair)
+        console.log("Close pool transaction signature", tx);
+        if (await connection.getAccountInfo(pair.swapPair) != null || await connection.getAccountInfo(pair.aForPda) != null) {
+            throw new Error("pool accounts were not closed")
+        }
+
+        // the escrows of an order book keep the pair open
+        const inUse = await createPair({poolToken: {}})
+        await drainPair(inUse)
+        const [_orderBook, _] = await PublicKey.findProgramAddress([Buffer.from("order_book"), inUse.swapPair.toBuffer()], program.programId);
+        const tokenAEscrow = await createPairAccount(inUse.aMint, admin, inUse.tokenProgram)
+        const tokenBEscrow = await createPairAccount(inUse.bMint, admin, inUse.tokenProgram)
+        await setAuthority(connection, admin, tokenAEscrow, admin.publicKey, AuthorityType.AccountOwner, inUse.pda, undefined, undefined, inUse.tokenProgram)
+        await setAuthority(connection, admin, tokenBEscrow, admin.publicKey, AuthorityType.AccountOwner, inUse.pda, undefined, undefined, inUse.tokenProgram)
+        await program.methods.initializeOrderBook()
+            .accounts({
+                admin: admin.publicKey,
+                pda: inUse.pda,
+                pair: inUse.swapPair,
+                orderBook: _orderBook,
+                tokenAEscrow: tokenAEscrow,
+                tokenBEscrow: tokenBEscrow,
+                systemProgram: anchor.web3.SystemProgram.programId,
+            }).rpc()
+        await expectRejected(inUse, "PairInUse")
+        await mintTo(connection, admin, inUse.aMint, inUse.aForPda, admin, 1, undefined, undefined, inUse.tokenProgram)
+        await expectRejected(inUse, "PoolNotEmpty")
+    })
+
 });
 
+// The accounts of a pool of two new mints, for the tests that need a pair of their own
+type Pair = {
+    swapPair: PublicKey,
+    pda: PublicKey,
+    pool: PublicKey,
+    aMint: PublicKey,
+    bMint: PublicKey,
+    aForPda: PublicKey,
+    bForPda: PublicKey,
+    poolForAdmin: PublicKey,
+    tokenProgram: PublicKey,
+}
+
+// A token account of `mint` owned by `owner`, not an associated one so that
+// its owner can be changed
+const createPairAccount = async (mint: PublicKey, owner: anchor.web3.Keypair, tokenProgram: PublicKey) => {
+    if ((await connection.getAccountInfo(mint)).data.length == MINT_SIZE) {
+        return await createAccount(connection, admin, mint, owner.publicKey, anchor.web3.Keypair.generate(), undefined, tokenProgram)
+    }
+    // the TransferFeeAmount extension of the account makes it larger than
+    // the spl-token client expects
+    const account = anchor.web3.Keypair.generate()
+    const initializeAccount3 = new TransactionInstruction({
+        programId: TOKEN_2022_PROGRAM_ID,
+        keys: [
+            {pubkey: account.publicKey, isSigner: false, isWritable: true},
+            {pubkey: mint, isSigner: false, isWritable: false},
+        ],
+        data: Buffer.concat([Buffer.from([18]), owner.publicKey.toBuffer()]),
+    })
+    const tx = new Transaction().add(
+        SystemProgram.createAccount({
+            fromPubkey: admin.publicKey,
+            newAccountPubkey: account.publicKey,
+            space: TRANSFER_FEE_ACCOUNT_SIZE,
+            lamports: await connection.getMinimumBalanceForRentExemption(TRANSFER_FEE_ACCOUNT_SIZE),
+            programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        initializeAccount3,
+    )
+    await sendAndConfirmTransaction(connection, tx, [admin, account])
+    return account.publicKey
+}
+
+// A Token-2022 mint of Admin with the TransferFee extension, withholding
+// `basisPoints` of every transfer up to `maximumFee`
+const createTransferFeeMint = async (basisPoints: number, maximumFee: number) => {
+    const mint = anchor.web3.Keypair.generate()
+    const initializeTransferFeeConfig = new TransactionInstruction({
+        programId: TOKEN_2022_PROGRAM_ID,
+        keys: [{pubkey: mint.publicKey, isSigner: false, isWritable: true}],
+        data: Buffer.concat([
+            Buffer.from([26, 0, 1]),
+            admin.publicKey.toBuffer(),
+            Buffer.from([1]),
+            admin.publicKey.toBuffer(),
+            new anchor.BN(basisPoints).toArrayLike(Buffer, "le", 2),
+            new anchor.BN(maximumFee).toArrayLike(Buffer, "le", 8),
+        ]),
+    })
+    const initializeMint2 = new TransactionInstruction({
+        programId: TOKEN_2022_PROGRAM_ID,
+        keys: [{pubkey: mint.publicKey, isSigner: false, isWritable: true}],
+        data: Buffer.concat([Buffer.from([20, 2]), admin.publicKey.toBuffer(), Buffer.from([0])]),
+    })
+    const tx = new Transaction().add(
+        SystemProgram.createAccount({
+            fromPubkey: admin.publicKey,
+            newAccountPubkey: mint.publicKey,
+            space: TRANSFER_FEE_MINT_SIZE,
+            lamports: await connection.getMinimumBalanceForRentExemption(TRANSFER_FEE_MINT_SIZE),
+            programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        initializeTransferFeeConfig,
+        initializeMint2,
+    )
+    await sendAndConfirmTransaction(connection, tx, [admin, mint])
+    return mint.publicKey
+}
+
+// The mint of a token account of either token program
+const tokenAccountMint = async (pubkey: PublicKey) => {
+    return new PublicKey((await connection.getAccountInfo(pubkey)).data.subarray(0, 32))
+}
+
+// Creates a pool of DEFAULT_TOKEN_A and DEFAULT_TOKEN_B with the fees of the
+// pool under test, of new mints unless `mints` are given
+const createPair = async (ownerFeeMode: {poolToken: {}} | {underlyingToken: {}}, tokenProgram: PublicKey = TOKEN_PROGRAM_ID, mints?: PublicKey[]) => {
+    let [aMint, bMint] = mints ?? [
+        await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, tokenProgram),
+        await createMint(connection, admin, admin.publicKey, null, 2, undefined, undefined, tokenProgram),
+    ]
+    if (Buffer.compare(aMint.toBuffer(), bMint.toBuffer()) > 0) {
+        [aMint, bMint] = [bMint, aMint]
+    }
+    const [pda, _] = await PublicKey.findProgramAddress([Buffer.from("pool"), aMint.toBuffer(), bMint.toBuffer(), feeTierSeed(FEE_TIER)], program.programId);
+    const [pair, __] = await PublicKey.findProgramAddress([Buffer.from("pair"), aMint.toBuffer(), bMint.toBuffer(), feeTierSeed(FEE_TIER)], program.programId);
+    const pool = await createMint(connection, admin, pda, null, 2, undefined, undefined, tokenProgram)
+    const poolForAdmin = await createPairAccount(pool, admin, tokenProgram)
+    const aForPda = await createPairAccount(aMint, admin, tokenProgram)
+    const bForPda = await createPairAccount(bMint, admin, tokenProgram)
+    await mintTo(connection, admin, aMint, aForPda, admin, DEFAULT_TOKEN_A, undefined, undefined, tokenProgram)
+    await mintTo(connection, admin, bMint, bForPda, admin, DEFAULT_TOKEN_B, undefined, undefined, tokenProgram)
+    await setAuthority(connection, admin, aForPda, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, tokenProgram)
+    await setAuthority(connection, admin, bForPda, admin.publicKey, AuthorityType.AccountOwner, pda, undefined, undefined, tokenProgram)
+
+    const fees = (await program.account.swapPair.fetch(swapPair)).fees
+    const tx = await program.methods.initialize(fees, ownerFeeMode, FEE_TIER)
+        .accounts({
+            payer: admin.publicKey,
+            pair: pair,
+            config: config,
+            pool: pool,
+            pda: pda,
+            tokenAMint: aMint,
+            tokenBMint: bMint,
+            tokenAForPda: aForPda,
+            tokenBForPda: bForPda,
+            tokenPoolForInitializer: poolForAdmin,
+            tokenPoolForFeeReceiver: poolForAdmin,
+            tokenProgram: tokenProgram,
+            systemProgram: anchor.web3.SystemProgram.programId,
+        }).rpc();
+    console.log("Initialize transaction signature", tx);
+    return {swapPair: pair, pda, pool, aMint, bMint, aForPda, bForPda, poolForAdmin, tokenProgram} as Pair
+}
+
+// Withdraws all the liquidity of a pair created by `createPair`, which Admin
+// holds in the fee account, leaving the pool without supply or tokens
+const drainPair = async (pair: Pair) => {
+    const aForAdmin = await createPairAccount(pair.aMint, admin, pair.tokenProgram)
+    const bForAdmin = await createPairAccount(pair.bMint, admin, pair.tokenProgram)
+    const tx = await program.methods.withdrawAll(new anchor.BN(await getTokenBalance(pair.poolForAdmin)), new anchor.BN(0), new anchor.BN(0))
+        .accounts({
+            depositor: admin.publicKey,
+            pair: pair.swapPair,
+            poolFeeAccount: pair.poolForAdmin,
+            pool: pair.pool,
+            pda: pair.pda,
+            tokenAMint: pair.aMint,
+            tokenBMint: pair.bMint,
+            tokenAForPda: pair.aForPda,
+            tokenBForPda: pair.bForPda,
+            tokenAForDepositor: aForAdmin,
+            tokenBForDepositor: bForAdmin,
+            tokenPoolForDepositor: pair.poolForAdmin,
+            tokenProgram: pair.tokenProgram,
+        }).rpc()
+    console.log("Drain transaction signature", tx);
+}
+
+// Swaps on a pair created by `createPair`, from token A unless `sourceForUser`
+// holds token B
+const pairSwap = async (pair: Pair, amountIn: number, swapper: anchor.web3.Keypair, sourceForUser: PublicKey, destinationForUser: PublicKey, remainingAccounts: anchor.web3.AccountMeta[] = []) => {
+    const sourceIsA = (await tokenAccountMint(sourceForUser)).equals(pair.aMint)
+    return await program.methods.swap(new anchor.BN(amountIn), new anchor.BN(0))
+        .accounts({
+            swapper: swapper.publicKey,
+            pair: pair.swapPair,
+            poolFeeAccount: pair.poolForAdmin,
+            pool: pair.pool,
+            pda: pair.pda,
+            tokenSourceMint: sourceIsA ? pair.aMint : pair.bMint,
+            tokenDestinationMint: sourceIsA ? pair.bMint : pair.aMint,
+            tokenSourceForPda: sourceIsA ? pair.aForPda : pair.bForPda,
+            tokenDestinationForPda: sourceIsA ? pair.bForPda : pair.aForPda,
+            tokenSourceForSwapper: sourceForUser,
+            tokenDestinationForSwapper: destinationForUser,
+            tokenProgram: pair.tokenProgram,
+        }).remainingAccounts(remainingAccounts).signers([swapper]).rpc()
+}
+
+// Variants of the program `Error`, whose index is their custom program error code
+const ERRORS = [
+    "AlreadyInUse", "InvalidProgramAddress", "InvalidOwner", "InvalidOutputOwner", "ExpectedMint",  // 0.
+    "ExpectedAccount", "EmptySupply", "InvalidSupply", "InvalidDelegate", "InvalidInput",  // 5.
+    "IncorrectSwapAccount", "IncorrectPoolMint", "InvalidOutput", "CalculationFailure", "InvalidInstruction",  // 10.
+    "RepeatedMint", "ExceededSlippage", "InvalidCloseAuthority", "InvalidFreezeAuthority", "IncorrectFeeAccount",  // 15.
+    "ZeroTradingTokens", "FeeCalculationFailure", "ConversionFailure", "InvalidFee", "IncorrectTokenProgramId",  // 20.
+    "UnsupportedCurveType", "InvalidCurve", "UnsupportedCurveOperation", "InvalidHost", "InvalidFeeTier",  // 25.
+    "InvalidMintOrder", "PoolNotEmpty", "InvalidTokenCount", "InvalidTickSpacing", "InvalidTickRange",  // 30.
+    "InvalidTickArray", "InvalidSqrtPrice", "PositionNotEmpty", "OrderBookFull", "OrderNotFound",  // 35.
+    "InvalidOrderBook", "InvalidOrderDuration", "InvalidTwamm", "TransferFeeNotSupported", "InvalidDcaOrder",  // 40.
+    "DcaCycleNotDue", "DcaOrderCompleted", "FarmPaused", "InvalidFarmReward", "InsufficientStake",  // 45.
+    "PositionLocked", "InvalidUnlockTime", "InvalidLockedPosition", "CircuitBreakerTripped", "InvalidCircuitBreaker",  // 50.
+    "InvalidPriceFeed", "StaleOraclePrice", "OraclePriceDeviation", "InsufficientLiquidity", "PairInUse",  // 55.
+    "InvalidDependentAccount",  // 60.
+]
+
+// Whether the instruction failed with the program `Error` `name`, or else
+// with a log containing `name`
+const failedWith = (e: any, name: string) => {
+    const code = ERRORS.indexOf(name)
+    if (code >= 0) {
+        return new RegExp(`custom program error: 0x${code.toString(16)}\\b`).test(e.toString())
+    }
+    return (e.logs ?? []).some((log: string) => log.includes(name))
+}
+
+const feeTierSeed = (feeTier: number) => new anchor.BN(feeTier).toArrayLike(Buffer, "le", 2)
+const i32Seed = (value: number) => new anchor.BN(value).toTwos(32).toArrayLike(Buffer, "le", 4)
+
+// Every pool of a mint pair, one for each fee tier it was created with
+const findSwapPairs = async (aMint: PublicKey, bMint: PublicKey) => {
+    return await program.account.swapPair.all([
+        {memcmp: {offset: 8 + 32 * 3, bytes: aMint.toBase58()}},
+        {memcmp: {offset: 8 + 32 * 4, bytes: bMint.toBase58()}},
+    ])
+}
+
+// Every locked position of a pool, optionally only those locked by an owner
+const findLockedPositions = async (pair: PublicKey, owner?: PublicKey) => {
+    return await program.account.lockedPosition.all([
+        {memcmp: {offset: 8, bytes: pair.toBase58()}},
+        ...(owner ? [{memcmp: {offset: 8 + 32, bytes: owner.toBase58()}}] : []),
+    ])
+}
+
 const getTokenBalance = async (pubkey: PublicKey) => {
     try {
         return parseInt(
@@ -194,16 +1526,19 @@ const depositAll = async (name: string, amount: number, user: anchor.web3.Keypai
     const tx = await program.methods.depositAll(new anchor.BN(amount), new anchor.BN(maxTokenA), new anchor.BN(maxTokenB))
         .accounts({
             depositor: user.publicKey,
-            pair: swapPair.publicKey,
+            pair: swapPair,
             pool: poolMintPubkey,
+            poolFeeAccount: poolAccountForAdmin,
             pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
             tokenAForPda: aAccountForPDA,
             tokenBForPda: bAccountForPDA,
             tokenAForDepositor: aForUser,
             tokenBForDepositor: bForUser,
             tokenPoolForDepositor: poolForUser,
             tokenProgram: TOKEN_PROGRAM_ID,
-        }).signers([user]).rpc()
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
     console.log("Deposit transaction signature", tx);
     await new Promise((resolve) => setTimeout(resolve, 500));
     console.table([
@@ -216,20 +1551,51 @@ const depositAll = async (name: string, amount: number, user: anchor.web3.Keypai
     ])
 }
 
+const depositAllByTokens = async (name: string, amountA: number, amountB: number, user: anchor.web3.Keypair, poolForUser: PublicKey, aForUser: PublicKey, bForUser: PublicKey) => {
+    const tx = await program.methods.depositAllByTokens(new anchor.BN(amountA), new anchor.BN(amountB), new anchor.BN(Math.floor(amountA * 0.99)), new anchor.BN(0))
+        .accounts({
+            depositor: user.publicKey,
+            pair: swapPair,
+            pool: poolMintPubkey,
+            poolFeeAccount: poolAccountForAdmin,
+            pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
+            tokenAForPda: aAccountForPDA,
+            tokenBForPda: bAccountForPDA,
+            tokenAForDepositor: aForUser,
+            tokenBForDepositor: bForUser,
+            tokenPoolForDepositor: poolForUser,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
+    console.log("Deposit by tokens transaction signature", tx);
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    console.table([
+        {name : `A for ${name}`, address: aForUser.toBase58(), amount: await getTokenBalance(aForUser)},
+        {name : `B for ${name}`, address: bForUser.toBase58(), amount: await getTokenBalance(bForUser)},
+        {name : `LP for ${name}`, address: poolForUser.toBase58(), amount: await getTokenBalance(poolForUser)},
+        {name : "A for PDA ", address: aAccountForPDA.toBase58(), amount: await getTokenBalance(aAccountForPDA)},
+        {name : "B for PDA ", address: bAccountForPDA.toBase58(), amount:  await getTokenBalance(bAccountForPDA)},
+    ])
+}
+
 const depositSingle = async (name: string, amount: number, user: anchor.web3.Keypair, poolForUser: PublicKey, sourceForUser: PublicKey) => {
     poolMint = await getMint(connection, poolMintPubkey, null, TOKEN_PROGRAM_ID)
     const tx = await program.methods.depositSingle(new anchor.BN(amount), new anchor.BN(amount / 10))
         .accounts({
             depositor: user.publicKey,
-            pair: swapPair.publicKey,
+            pair: swapPair,
             pool: poolMintPubkey,
+            poolFeeAccount: poolAccountForAdmin,
             pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
             tokenAForPda: aAccountForPDA,
             tokenBForPda: bAccountForPDA,
             tokenSourceForDepositor: sourceForUser,
             tokenPoolForDepositor: poolForUser,
             tokenProgram: TOKEN_PROGRAM_ID,
-        }).signers([user]).rpc()
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
     console.log("Deposit Single transaction signature", tx);
     await new Promise((resolve) => setTimeout(resolve, 500));
     console.table([
@@ -242,6 +1608,35 @@ const depositSingle = async (name: string, amount: number, user: anchor.web3.Key
     ])
 }
 
+const depositSingleExactOut = async (name: string, amount: number, user: anchor.web3.Keypair, poolForUser: PublicKey, sourceForUser: PublicKey) => {
+    poolMint = await getMint(connection, poolMintPubkey, null, TOKEN_PROGRAM_ID)
+    const tx = await program.methods.depositSingleExactOut(new anchor.BN(amount), new anchor.BN(POOL_TOKEN_AMOUNT * 10))
+        .accounts({
+            depositor: user.publicKey,
+            pair: swapPair,
+            pool: poolMintPubkey,
+            poolFeeAccount: poolAccountForAdmin,
+            pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
+            tokenAForPda: aAccountForPDA,
+            tokenBForPda: bAccountForPDA,
+            tokenSourceForDepositor: sourceForUser,
+            tokenPoolForDepositor: poolForUser,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
+    console.log("Deposit Single Exact Out transaction signature", tx);
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    console.table([
+        {name : `A for ${name}`, address: aAccountForUserA.toBase58(), amount: await getTokenBalance(aAccountForUserA)},
+        {name : `B for ${name}`, address: bAccountForUserA.toBase58(), amount: await getTokenBalance(bAccountForUserA)},
+        {name : `LP for ${name}`, address: poolForUser.toBase58(), amount: await getTokenBalance(poolForUser)},
+        {name : "A for PDA ", address: aAccountForPDA.toBase58(), amount: await getTokenBalance(aAccountForPDA)},
+        {name : "B for PDA ", address: bAccountForPDA.toBase58(), amount:  await getTokenBalance(bAccountForPDA)},
+        {name : "LP for Admin", address: poolAccountForAdmin.toBase58(), amount: await getTokenBalance(poolAccountForAdmin)},
+    ])
+}
+
 const withdrawAll = async (name: string, amount: number, user: anchor.web3.Keypair, poolForUser: PublicKey, aForUser: PublicKey, bForUser: PublicKey) => {
     poolMint = await getMint(connection, poolMintPubkey, null, TOKEN_PROGRAM_ID)
     const feeAmount = 0;
@@ -251,17 +1646,19 @@ const withdrawAll = async (name: string, amount: number, user: anchor.web3.Keypa
     const tx = await program.methods.withdrawAll(new anchor.BN(amount), new anchor.BN(minTokenA), new anchor.BN(minTokenB))
         .accounts({
             depositor: user.publicKey,
-            pair: swapPair.publicKey,
+            pair: swapPair,
             poolFeeAccount: poolAccountForAdmin,
             pool: poolMintPubkey,
             pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
             tokenAForPda: aAccountForPDA,
             tokenBForPda: bAccountForPDA,
             tokenAForDepositor: aForUser,
             tokenBForDepositor: bForUser,
             tokenPoolForDepositor: poolForUser,
             tokenProgram: TOKEN_PROGRAM_ID,
-        }).signers([user]).rpc()
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
     console.log("Withdraw transaction signature", tx);
     await new Promise((resolve) => setTimeout(resolve, 500));
     console.table([
@@ -279,16 +1676,18 @@ const withdrawSingle = async (name: string, amount: number,user: anchor.web3.Key
     const tx = await program.methods.withdrawSingle(new anchor.BN(amount), new anchor.BN(POOL_TOKEN_AMOUNT * 10))
         .accounts({
             depositor: user.publicKey,
-            pair: swapPair.publicKey,
+            pair: swapPair,
             poolFeeAccount: poolAccountForAdmin,
             pool: poolMintPubkey,
             pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
             tokenAForPda: aAccountForPDA,
             tokenBForPda: bAccountForPDA,
             tokenDestinationForDepositor: destinationForUser,
             tokenPoolForDepositor: poolForUser,
             tokenProgram: TOKEN_PROGRAM_ID,
-        }).signers([user]).rpc()
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
     console.log("Withdraw single transaction signature", tx);
     await new Promise((resolve) => setTimeout(resolve, 500));
     console.table([
@@ -302,22 +1701,162 @@ const withdrawSingle = async (name: string, amount: number,user: anchor.web3.Key
 }
 
 
-const swap = async (amountIn: number, swapper: anchor.web3.Keypair, sourceForUser: PublicKey, destinationForUser: PublicKey) => {
+const withdrawSingleExactIn = async (name: string, amount: number,user: anchor.web3.Keypair, poolForUser: PublicKey, destinationForUser: PublicKey) => {
+    poolMint = await getMint(connection, poolMintPubkey, null, TOKEN_PROGRAM_ID)
+    const tx = await program.methods.withdrawSingleExactIn(new anchor.BN(amount), new anchor.BN(0))
+        .accounts({
+            depositor: user.publicKey,
+            pair: swapPair,
+            poolFeeAccount: poolAccountForAdmin,
+            pool: poolMintPubkey,
+            pda: pda,
+            tokenAMint: aMintPubkey,
+            tokenBMint: bMintPubkey,
+            tokenAForPda: aAccountForPDA,
+            tokenBForPda: bAccountForPDA,
+            tokenDestinationForDepositor: destinationForUser,
+            tokenPoolForDepositor: poolForUser,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }).remainingAccounts(twammRemainingAccounts()).signers([user]).rpc()
+    console.log("Withdraw single exact in transaction signature", tx);
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    console.table([
+        {name : `A for ${name}`, address: aAccountForUserA.toBase58(), amount: await getTokenBalance(aAccountForUserA)},
+        {name : `B for ${name}`, address: bAccountForUserA.toBase58(), amount: await getTokenBalance(bAccountForUserA)},
+        {name : `LP for ${name}`, address: poolForUser.toBase58(), amount: await getTokenBalance(poolForUser)},
+        {name : "A for PDA ", address: aAccountForPDA.toBase58(), amount: await getTokenBalance(aAccountForPDA)},
+        {name : "B for PDA ", address: bAccountForPDA.toBase58(), amount:  await getTokenBalance(bAccountForPDA)},
+        {name : "LP for Admin", address: poolAccountForAdmin.toBase58(), amount: await getTokenBalance(poolAccountForAdmin)},
+    ])
+}
+
+
+const zapAccounts = (user: anchor.web3.Keypair, poolForUser: PublicKey, sourceForUser: PublicKey, destinationForUser: PublicKey) => {
+    const sourceIsA = sourceForUser == aAccountForUserA || sourceForUser == aAccountForUserB
+    return {
+        depositor: user.publicKey,
+        pair: swapPair,
+        poolFeeAccount: poolAccountForAdmin,
+        pool: poolMintPubkey,
+        pda: pda,
+        tokenSourceMint: sourceIsA ? aMintPubkey : bMintPubkey,
+        tokenDestinationMint: sourceIsA ? bMintPubkey : aMintPubkey,
+        tokenSourceForPda: sourceIsA ? aAccountForPDA : bAccountForPDA,
+        tokenDestinationForPda: sourceIsA ? bAccountForPDA : aAccountForPDA,
+        tokenSourceForDepositor: sourceForUser,
+        tokenDestinationForDepositor: destinationForUser,
+        tokenPoolForDepositor: poolForUser,
+        tokenProgram: TOKEN_PROGRAM_ID,
+    }
+}
+
+const zapIn = async (name: string, amountIn: number, user: anchor.web3.Keypair, poolForUser: PublicKey, sourceForUser: PublicKey, destinationForUser: PublicKey) => {
+    const tx = await program.methods.zapIn(new anchor.BN(amountIn), new anchor.BN(0))
+        .accounts(zapAccounts(user, poolForUser, sourceForUser, destinationForUser))
+        .remainingAccounts(swapRemainingAccounts())
+        .signers([user]).rpc()
+    console.log("Zap in transaction signature", tx);
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    console.table([
+        {name : `A for ${name}`, address: aAccountForUserA.toBase58(), amount: await getTokenBalance(aAccountForUserA)},
+        {name : `B for ${name}`, address: bAccountForUserA.toBase58(), amount: await getTokenBalance(bAccountForUserA)},
+        {name : `LP for ${name}`, address: poolForUser.toBase58(), amount: await getTokenBalance(poolForUser)},
+        {name : "A for PDA ", address: aAccountForPDA.toBase58(), amount: await getTokenBalance(aAccountForPDA)},
+        {name : "B for PDA ", address: bAccountForPDA.toBase58(), amount:  await getTokenBalance(bAccountForPDA)},
+        {name : "LP for Admin", address: poolAccountForAdmin.toBase58(), amount: await getTokenBalance(poolAccountForAdmin)},
+    ])
+}
+
+const zapOut = async (name: string, poolTokenAmount: number, user: anchor.web3.Keypair, poolForUser: PublicKey, sourceForUser: PublicKey, destinationForUser: PublicKey) => {
+    const tx = await program.methods.zapOut(new anchor.BN(poolTokenAmount), new anchor.BN(0))
+        .accounts(zapAccounts(user, poolForUser, sourceForUser, destinationForUser))
+        .remainingAccounts(swapRemainingAccounts())
+        .signers([user]).rpc()
+    console.log("Zap out transaction signature", tx);
+    await new Promise((resolve) => setTimeout(resolve, 500));
+    console.table([
+        {name : `A for ${name}`, address: aAccountForUserA.toBase58(), amount: await getTokenBalance(aAccountForUserA)},
+        {name : `B for ${name}`, address: bAccountForUserA.toBase58(), amount: await getTokenBalance(bAccountForUserA)},
+        {name : `LP for ${name}`, address: poolForUser.toBase58(), amount: await getTokenBalance(poolForUser)},
+        {name : "A for PDA ", address: aAccountForPDA.toBase58(), amount: await getTokenBalance(aAccountForPDA)},
+        {name : "B for PDA ", address: bAccountForPDA.toBase58(), amount:  await getTokenBalance(bAccountForPDA)},
+        {name : "LP for Admin", address: poolAccountForAdmin.toBase58(), amount: await getTokenBalance(poolAccountForAdmin)},
+    ])
+}
+
+const placeOrder = async (tradeDirection: {atoB: {}} | {btoA: {}}, amountIn: number, amountOut: number, owner: anchor.web3.Keypair, sourceForOwner: PublicKey) => {
+    const isAtoB = "atoB" in tradeDirection
+    const tx = await program.methods.placeOrder(tradeDirection, new anchor.BN(amountIn), new anchor.BN(amountOut))
+        .accounts({
+            owner: owner.publicKey,
+            pair: swapPair,
+            orderBook: orderBook.orderBook,
+            tokenSourceMint: isAtoB ? aMintPubkey : bMintPubkey,
+            tokenSourceForOwner: sourceForOwner,
+            tokenSourceEscrow: isAtoB ? orderBook.tokenAEscrow : orderBook.tokenBEscrow,
+            tokenProgram: TOKEN_PROGRAM_ID,
+        }).signers([owner]).rpc()
+    console.log("Place order transaction signature", tx);
+}
+
+const virtualOrdersAccounts = () => ({
+    pda: pda,
+    pair: swapPair,
+    twamm: twamm.twamm,
+    pool: poolMintPubkey,
+    poolFeeAccount: poolAccountForAdmin,
+    tokenAMint: aMintPubkey,
+    tokenBMint: bMintPubkey,
+    tokenAForPda: aAccountForPDA,
+    tokenBForPda: bAccountForPDA,
+    tokenAEscrow: twamm.tokenAEscrow,
+    tokenBEscrow: twamm.tokenBEscrow,
+    tokenProgram: TOKEN_PROGRAM_ID,
+})
+
+// The TWAMM accounts every deposit and withdrawal of the pair takes once it has them
+const twammRemainingAccounts = () => twamm ? [
+    {pubkey: twamm.twamm, isWritable: true, isSigner: false},
+    {pubkey: twamm.tokenAEscrow, isWritable: true, isSigner: false},
+    {pubkey: twamm.tokenBEscrow, isWritable: true, isSigner: false},
+] : []
+
+// The order book, TWAMM and price feed accounts every swap and zap of the pair takes once it has them
+const swapRemainingAccounts = () => [
+    ...(orderBook ? [
+        {pubkey: orderBook.orderBook, isWritable: true, isSigner: false},
+        {pubkey: orderBook.tokenAEscrow, isWritable: true, isSigner: false},
+        {pubkey: orderBook.tokenBEscrow, isWritable: true, isSigner: false},
+    ] : []),
+    ...twammRemainingAccounts(),
+    ...(priceFeed ? [
+        {pubkey: priceFeed, isWritable: false, isSigner: false},
+    ] : []),
+]
+
+const swap = async (amountIn: number, swapper: anchor.web3.Keypair, sourceForUser: PublicKey, destinationForUser: PublicKey, host?: {host: PublicKey, hostFeeAccount: PublicKey}) => {
     poolMint = await getMint(connection, poolMintPubkey, null, TOKEN_PROGRAM_ID)
     const tx = await program.methods.swap(new anchor.BN(amountIn), new anchor.BN(amountIn * 0.01))
         .accounts({
             swapper: swapper.publicKey,
-            pair: swapPair.publicKey,
+            pair: swapPair,
             poolFeeAccount: poolAccountForAdmin,
-            hostFeeAccount: poolAccountForAdmin,
             pool: poolMintPubkey,
             pda: pda,
+            tokenSourceMint: sourceForUser == aAccountForUserB ? aMintPubkey : bMintPubkey,
+            tokenDestinationMint: sourceForUser == aAccountForUserB ? bMintPubkey : aMintPubkey,
             tokenSourceForPda: sourceForUser == aAccountForUserB ? aAccountForPDA : bAccountForPDA,
             tokenDestinationForPda: sourceForUser == aAccountForUserB ? bAccountForPDA :aAccountForPDA,
             tokenSourceForSwapper: sourceForUser,
             tokenDestinationForSwapper: destinationForUser,
             tokenProgram: TOKEN_PROGRAM_ID,
-        }).signers([swapper]).rpc()
+        }).remainingAccounts([
+            ...swapRemainingAccounts(),
+            ...(host ? [
+                {pubkey: host.host, isWritable: true, isSigner: false},
+                {pubkey: host.hostFeeAccount, isWritable: true, isSigner: false},
+            ] : []),
+        ]).signers([swapper]).rpc()
     console.log("Swap transaction signature", tx);
     await new Promise((resolve) => setTimeout(resolve, 500));
     console.table([